@@ -0,0 +1,130 @@
+use std::error::Error;
+use std::net::IpAddr;
+
+use crate::cidr::Cidr;
+
+// Loopback, RFC 1918, link-local and unique local networks.
+pub const PRIVATE_NETWORKS: &str =
+    "127.0.0.0/8,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,169.254.0.0/16,::1,fc00::/7,fe80::/10";
+
+/// An ordered list of networks, each allowed or denied. The first network
+/// containing the client decides; clients no network contains are denied.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    rules: Vec<(Cidr, bool)>,
+}
+
+impl AccessList {
+    /// Parses a comma separated list of networks, where `!` in front of a
+    /// network denies it, `any` stands for every address and `none` for no
+    /// address at all.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for entry in text.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (allow, network) = match entry.strip_prefix('!') {
+                Some(network) => (false, network.trim()),
+                None => (true, entry),
+            };
+            match network {
+                "any" => {
+                    rules.push(("0.0.0.0/0".parse()?, allow));
+                    rules.push(("::/0".parse()?, allow));
+                }
+                "none" => {}
+                _ => rules.push((network.parse()?, allow)),
+            }
+        }
+        Ok(AccessList { rules })
+    }
+
+    pub fn allows(&self, address: IpAddr) -> bool {
+        self.rules
+            .iter()
+            .find(|(network, _)| network.contains(address))
+            .is_some_and(|(_, allow)| *allow)
+    }
+}
+
+/// Which clients may use each kind of service.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    /// Answers from outside our zones: hosts overrides, forwarding and
+    /// recursion.
+    pub recursion: AccessList,
+    /// Answers from our zones.
+    pub query: AccessList,
+    /// AXFR and IXFR.
+    pub transfer: AccessList,
+}
+
+impl Acl {
+    /// Reads ALLOW_RECURSION (default: loopback and private networks),
+    /// ALLOW_QUERY (default: any) and ALLOW_TRANSFER (default: none).
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(Acl {
+            recursion: env_list("ALLOW_RECURSION", PRIVATE_NETWORKS)?,
+            query: env_list("ALLOW_QUERY", "any")?,
+            transfer: env_list("ALLOW_TRANSFER", "none")?,
+        })
+    }
+}
+
+fn env_list(name: &str, default: &str) -> Result<AccessList, Box<dyn Error>> {
+    let value = std::env::var(name).unwrap_or_else(|_| default.to_string());
+    AccessList::parse(&value).map_err(|e| format!("Invalid value for {}: {}", name, e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(list: &AccessList, addresses: &[&str]) -> Vec<bool> {
+        addresses
+            .iter()
+            .map(|address| list.allows(address.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn defaults_to_private_recursion_public_queries_and_no_transfers() {
+        // The defaults apply as long as the environment sets none of these.
+        if ["ALLOW_RECURSION", "ALLOW_QUERY", "ALLOW_TRANSFER"]
+            .iter()
+            .any(|name| std::env::var_os(name).is_some())
+        {
+            return;
+        }
+        let acl = Acl::from_env().unwrap();
+        let clients = [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.31.0.1",
+            "192.168.1.1",
+            "169.254.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "192.0.2.1",
+            "172.32.0.1",
+            "2001:db8::1",
+        ];
+        let private = [
+            true, true, true, true, true, true, true, true, false, false, false,
+        ];
+        assert_eq!(allowed(&acl.recursion, &clients), private);
+        assert_eq!(allowed(&acl.query, &clients), [true; 11]);
+        assert_eq!(allowed(&acl.transfer, &clients), [false; 11]);
+    }
+
+    #[test]
+    fn lets_the_first_matching_network_decide() {
+        let list = AccessList::parse("!10.0.0.1, 10.0.0.0/8, !any").unwrap();
+        assert_eq!(
+            allowed(&list, &["10.0.0.1", "10.0.0.2", "192.0.2.1"]),
+            [false, true, false]
+        );
+        let list = AccessList::parse("none").unwrap();
+        assert_eq!(allowed(&list, &["127.0.0.1", "::1"]), [false, false]);
+        assert!(AccessList::parse("10.0.0.0/33").is_err());
+    }
+}
@@ -0,0 +1,350 @@
+pub mod suffix;
+
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use log::info;
+
+use crate::blocklist::suffix::SuffixSet;
+use crate::dns::edns::ExtendedError;
+use crate::dns::header::ResponseCode;
+use crate::dns::message::DNSMessage;
+use crate::dns::resource_record::{RecordClass, RecordType, ResourceRecord};
+
+const DEFAULT_TTL: u32 = 300;
+
+// Names hosts-format lists map to themselves rather than block.
+const HOSTS_SELF_NAMES: [&str; 6] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+/// How blocked names are answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockingMode {
+    NXDomain,
+    Refused,
+    /// A and AAAA queries get the given addresses; other types get an empty
+    /// answer. `0.0.0.0` and `::` make the "null" mode.
+    Addresses(Vec<IpAddr>),
+}
+
+impl BlockingMode {
+    /// Parses `nxdomain`, `refused`, `null`, or a comma separated list of
+    /// IPv4 and/or IPv6 addresses to answer with.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.to_ascii_lowercase().as_str() {
+            "nxdomain" => Ok(BlockingMode::NXDomain),
+            "refused" => Ok(BlockingMode::Refused),
+            "null" => Ok(BlockingMode::Addresses(vec![
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            ])),
+            _ => text
+                .split(',')
+                .map(|address| address.trim().parse::<IpAddr>())
+                .collect::<Result<Vec<_>, _>>()
+                .map(BlockingMode::Addresses)
+                .map_err(|_| format!("Invalid blocking mode {:?}", text)),
+        }
+    }
+}
+
+/// Names to refuse to resolve, with exceptions.
+#[derive(Debug)]
+pub struct Blocklist {
+    blocked: SuffixSet,
+    allowed: SuffixSet,
+    mode: BlockingMode,
+    ttl: u32,
+}
+
+impl Blocklist {
+    /// Loads the comma separated files in BLOCKLISTS and ALLOWLISTS, answering
+    /// as BLOCKING_MODE says (default `null`) with BLOCKLIST_TTL. Returns
+    /// `None` when no blocklists are set.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let blocklists = env_paths("BLOCKLISTS");
+        if blocklists.is_empty() {
+            return Ok(None);
+        }
+        let mode = match std::env::var("BLOCKING_MODE") {
+            Ok(mode) => BlockingMode::parse(&mode)?,
+            Err(_) => BlockingMode::parse("null")?,
+        };
+        let ttl = match std::env::var("BLOCKLIST_TTL") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| format!("Invalid value for BLOCKLIST_TTL: {:?}", value))?,
+            Err(_) => DEFAULT_TTL,
+        };
+
+        let mut blocklist = Blocklist {
+            blocked: SuffixSet::default(),
+            allowed: SuffixSet::default(),
+            mode,
+            ttl,
+        };
+        for path in &blocklists {
+            blocklist.load(Path::new(path), false)?;
+        }
+        for path in &env_paths("ALLOWLISTS") {
+            blocklist.load(Path::new(path), true)?;
+        }
+        info!(
+            "Blocking {} domains ({} allowed)",
+            blocklist.blocked.len(),
+            blocklist.allowed.len()
+        );
+        Ok(Some(blocklist))
+    }
+
+    /// Adds the entries of a list in hosts, plain domain or Adblock format.
+    /// Adblock exceptions (`@@||name^`) always go to the allowlist.
+    pub fn load(&mut self, path: &Path, allowlist: bool) -> Result<(), Box<dyn Error>> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        for (name, exception) in text.lines().flat_map(parse_line) {
+            if allowlist || exception {
+                self.allowed.insert(&name);
+            } else {
+                self.blocked.insert(&name);
+            }
+        }
+        Ok(())
+    }
+
+    /// True when `name` or a parent domain is blocked and not allowed.
+    pub fn is_blocked(&self, name: &str) -> bool {
+        self.blocked.contains(name) && !self.allowed.contains(name)
+    }
+
+    /// Builds the blocking response for a query whose name is blocked.
+    pub fn answer(&self, query: &DNSMessage) -> Option<DNSMessage> {
+        let question = match query.questions.as_slice() {
+            [question] if query.header.flags.opcode == 0 => question,
+            _ => return None,
+        };
+        if !self.is_blocked(&question.name) {
+            return None;
+        }
+        info!("Blocked {} {}", question.name, question.record_type);
+
+        let mut response = DNSMessage::response_to(query);
+        response.header.flags.ra = true;
+        response.set_extended_error(ExtendedError::Blocked, "");
+        match &self.mode {
+            BlockingMode::NXDomain => response.set_response_code(ResponseCode::NXDomain),
+            BlockingMode::Refused => response.set_response_code(ResponseCode::Refused),
+            BlockingMode::Addresses(addresses) => {
+                response.answers = addresses
+                    .iter()
+                    .filter_map(|address| match (address, question.record_type) {
+                        (IpAddr::V4(v4), RecordType::A) => Some(v4.octets().to_vec()),
+                        (IpAddr::V6(v6), RecordType::AAAA) => Some(v6.octets().to_vec()),
+                        _ => None,
+                    })
+                    .map(|data| {
+                        ResourceRecord::new(
+                            question.name.clone(),
+                            question.record_type,
+                            RecordClass::IN,
+                            self.ttl,
+                            data,
+                        )
+                    })
+                    .collect();
+            }
+        }
+        Some(response)
+    }
+}
+
+fn env_paths(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Returns the names on one line, each flagged when it is an Adblock
+// exception. Adblock rules with wildcards, paths or options other than
+// `$important` say nothing about whole domains and are skipped.
+fn parse_line(line: &str) -> Vec<(String, bool)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return Vec::new();
+    }
+    let line = line.split('#').next().unwrap_or("").trim();
+
+    if let Some(rule) = line.strip_prefix("@@||") {
+        return adblock_domain(rule)
+            .map(|name| vec![(name, true)])
+            .unwrap_or_default();
+    }
+    if let Some(rule) = line.strip_prefix("||") {
+        return adblock_domain(rule)
+            .map(|name| vec![(name, false)])
+            .unwrap_or_default();
+    }
+
+    let mut fields = line.split_whitespace().peekable();
+    // Hosts format: an address followed by names.
+    if fields
+        .peek()
+        .is_some_and(|field| field.parse::<IpAddr>().is_ok())
+    {
+        fields.next();
+    }
+    // Lines such as `0.0.0.0 0.0.0.0` map an address to itself.
+    fields
+        .filter(|name| name.parse::<IpAddr>().is_err())
+        .filter(|name| !HOSTS_SELF_NAMES.contains(&name.to_ascii_lowercase().as_str()))
+        .filter_map(domain)
+        .map(|name| (name, false))
+        .collect()
+}
+
+fn adblock_domain(rule: &str) -> Option<String> {
+    let (name, options) = match rule.split_once('$') {
+        Some((name, options)) => (name, Some(options)),
+        None => (rule, None),
+    };
+    if options.is_some_and(|options| options != "important") {
+        return None;
+    }
+    domain(name.strip_suffix('^').unwrap_or(name))
+}
+
+fn domain(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.');
+    let valid = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.');
+    valid.then(|| name.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn blocked(name: &str) -> (String, bool) {
+        (name.to_string(), false)
+    }
+
+    #[test]
+    fn parses_hosts_lines() {
+        assert_eq!(
+            parse_line("0.0.0.0 ads.example  Tracker.Example. # comment"),
+            vec![blocked("ads.example"), blocked("tracker.example")]
+        );
+        assert_eq!(parse_line(":: ads.example"), vec![blocked("ads.example")]);
+        assert!(parse_line("127.0.0.1 localhost").is_empty());
+        assert!(parse_line("::1 ip6-localhost ip6-loopback").is_empty());
+        assert!(parse_line("0.0.0.0 0.0.0.0").is_empty());
+        assert!(parse_line("# 0.0.0.0 commented.example").is_empty());
+        assert!(parse_line("0.0.0.0 bad/name.example").is_empty());
+    }
+
+    #[test]
+    fn parses_plain_domain_lines() {
+        assert_eq!(parse_line("ads.example"), vec![blocked("ads.example")]);
+        assert_eq!(parse_line("  ads.example.  "), vec![blocked("ads.example")]);
+        assert!(parse_line("").is_empty());
+    }
+
+    #[test]
+    fn parses_adblock_lines() {
+        assert_eq!(parse_line("||ads.example^"), vec![blocked("ads.example")]);
+        assert_eq!(
+            parse_line("||ads.example^$important"),
+            vec![blocked("ads.example")]
+        );
+        assert_eq!(
+            parse_line("@@||good.ads.example^"),
+            vec![("good.ads.example".to_string(), true)]
+        );
+        // Comments, section headers, and rules that don't cover whole domains.
+        assert!(parse_line("! Title: list").is_empty());
+        assert!(parse_line("[Adblock Plus 2.0]").is_empty());
+        assert!(parse_line("||ads.example^$third-party").is_empty());
+        assert!(parse_line("||ads.example/banner^").is_empty());
+        assert!(parse_line("||*.example^").is_empty());
+    }
+
+    #[test]
+    fn allowlist_overrides_blocklist() {
+        let dir = std::env::temp_dir().join(format!("tinydns-blocklist-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let blocks = dir.join("blocks");
+        let allows = dir.join("allows");
+        fs::write(
+            &blocks,
+            "0.0.0.0 ads.example\n||tracker.test^\n@@||cdn.tracker.test^\n",
+        )
+        .unwrap();
+        fs::write(&allows, "good.ads.example\n").unwrap();
+
+        let mut blocklist = Blocklist {
+            blocked: SuffixSet::default(),
+            allowed: SuffixSet::default(),
+            mode: BlockingMode::NXDomain,
+            ttl: DEFAULT_TTL,
+        };
+        blocklist.load(&blocks, false).unwrap();
+        blocklist.load(&allows, true).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(blocklist.is_blocked("ads.example"));
+        assert!(blocklist.is_blocked("x.ads.example"));
+        assert!(!blocklist.is_blocked("good.ads.example"));
+        assert!(!blocklist.is_blocked("x.good.ads.example"));
+        assert!(blocklist.is_blocked("tracker.test"));
+        assert!(!blocklist.is_blocked("cdn.tracker.test"));
+        assert!(!blocklist.is_blocked("example"));
+    }
+
+    #[test]
+    fn answers_by_blocking_mode() {
+        let mut blocklist = Blocklist {
+            blocked: SuffixSet::default(),
+            allowed: SuffixSet::default(),
+            mode: BlockingMode::parse("null").unwrap(),
+            ttl: 60,
+        };
+        blocklist.blocked.insert("ads.example");
+
+        let response = blocklist
+            .answer(&DNSMessage::query(1, "x.ads.example", RecordType::AAAA))
+            .unwrap();
+        assert_eq!(response.header.flags.rcode, ResponseCode::NoError as u8);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].data, vec![0; 16]);
+        assert_eq!(response.answers[0].ttl, 60);
+        let response = blocklist
+            .answer(&DNSMessage::query(1, "ads.example", RecordType::MX))
+            .unwrap();
+        assert!(response.answers.is_empty());
+        assert!(blocklist
+            .answer(&DNSMessage::query(1, "example", RecordType::A))
+            .is_none());
+
+        blocklist.mode = BlockingMode::parse("refused").unwrap();
+        let response = blocklist
+            .answer(&DNSMessage::query(1, "ads.example", RecordType::A))
+            .unwrap();
+        assert_eq!(response.header.flags.rcode, ResponseCode::Refused as u8);
+        assert!(response.answers.is_empty());
+    }
+}
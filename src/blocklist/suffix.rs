@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use crate::dns::name::{labels, normalize};
+
+/// A set of domains that also covers every name beneath them, stored as a
+/// trie of labels from the root down so a lookup costs one step per label.
+#[derive(Debug, Default)]
+pub struct SuffixSet {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    terminal: bool,
+}
+
+impl SuffixSet {
+    pub fn insert(&mut self, name: &str) {
+        let name = normalize(name);
+        let mut node = &mut self.root;
+        for label in labels(&name).rev() {
+            // Already covered by a shorter suffix.
+            if node.terminal {
+                return;
+            }
+            node = node.children.entry(label.into()).or_default();
+        }
+        if !node.terminal {
+            node.terminal = true;
+            self.len += 1;
+        }
+        // Entries beneath this one are now redundant. They stay counted, as
+        // `len` only reports how many names were added.
+        node.children.clear();
+    }
+
+    /// True when `name` or one of its ancestors is in the set.
+    pub fn contains(&self, name: &str) -> bool {
+        let name = normalize(name);
+        let mut node = &self.root;
+        for label in labels(&name).rev() {
+            if node.terminal {
+                return true;
+            }
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        node.terminal
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covers_names_beneath_each_entry() {
+        let mut set = SuffixSet::default();
+        set.insert("Ads.Example.");
+        set.insert("tracker.test");
+
+        assert!(set.contains("ads.example"));
+        assert!(set.contains("x.y.ADS.example."));
+        assert!(set.contains("tracker.test"));
+        assert!(!set.contains("example"));
+        assert!(!set.contains("bads.example"));
+        assert!(!set.contains("ads.example.org"));
+        assert!(!set.contains("test"));
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn shorter_suffixes_absorb_longer_ones() {
+        let mut set = SuffixSet::default();
+        set.insert("a.b.example");
+        assert!(!set.contains("c.b.example"));
+
+        set.insert("example");
+        assert!(set.contains("c.b.example"));
+        // Already covered, so not added again.
+        set.insert("d.example");
+        set.insert("example");
+        assert_eq!(set.len(), 2);
+        assert!(set.root.children["example"].children.is_empty());
+    }
+}
@@ -0,0 +1,12 @@
+use std::error::Error;
+
+/// Parses the environment variable `name`, falling back to `default` when it
+/// is not set.
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> Result<T, Box<dyn Error>> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("Invalid value for {}: {:?}", name, value).into()),
+        Err(_) => Ok(default),
+    }
+}
@@ -0,0 +1,287 @@
+use std::error::Error;
+use std::fmt;
+use std::hash::Hasher;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use siphasher::sip::SipHasher24;
+
+use crate::dns::edns::Edns;
+use crate::dns::message::DNSMessage;
+
+/// Option code of DNS Cookies (RFC 7873).
+pub const COOKIE: u16 = 10;
+/// Extended response code for a missing or stale server cookie.
+pub const BADCOOKIE: u16 = 23;
+
+pub const CLIENT_COOKIE_LENGTH: usize = 8;
+const SERVER_COOKIE_LENGTH: usize = 16;
+/// The room the COOKIE option takes in a response.
+pub const RESPONSE_OPTION_LENGTH: usize = 4 + CLIENT_COOKIE_LENGTH + SERVER_COOKIE_LENGTH;
+const VERSION: u8 = 1;
+// Server cookies are accepted for an hour and from clocks up to five
+// minutes ahead, and replaced once half an hour old (RFC 9018 section 4.3).
+const LIFETIME: u64 = 3600;
+const REFRESH: u64 = 1800;
+const CLOCK_SKEW: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CookieError {
+    /// The option's length fits neither a client cookie nor a client and
+    /// server cookie; answered with FORMERR.
+    Malformed,
+}
+
+impl fmt::Display for CookieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CookieError::Malformed => write!(f, "Malformed COOKIE option"),
+        }
+    }
+}
+
+/// The COOKIE option of a query (RFC 7873 section 4).
+#[derive(Debug, Clone)]
+pub struct QueryCookie {
+    pub client: [u8; CLIENT_COOKIE_LENGTH],
+    /// The server cookie from an earlier response, if any; 8 to 32 bytes.
+    pub server: Vec<u8>,
+}
+
+impl QueryCookie {
+    pub fn find(edns: &Edns) -> Result<Option<Self>, CookieError> {
+        let Some((_, data)) = edns.options.iter().find(|(code, _)| *code == COOKIE) else {
+            return Ok(None);
+        };
+        if data.len() != CLIENT_COOKIE_LENGTH && !(16..=40).contains(&data.len()) {
+            return Err(CookieError::Malformed);
+        }
+        let mut client = [0u8; CLIENT_COOKIE_LENGTH];
+        client.copy_from_slice(&data[..CLIENT_COOKIE_LENGTH]);
+        Ok(Some(QueryCookie {
+            client,
+            server: data[CLIENT_COOKIE_LENGTH..].to_vec(),
+        }))
+    }
+}
+
+/// Whether DNS_COOKIES leaves cookies on, for the queries we send upstream
+/// as well as for those we answer.
+pub fn enabled() -> bool {
+    std::env::var("DNS_COOKIES").as_deref() != Ok("off")
+}
+
+/// Issues and checks server cookies in the interoperable format of RFC 9018,
+/// so that servers behind one anycast address can share a secret.
+pub struct Cookies {
+    secret: [u8; 16],
+    /// Whether UDP queries with a client cookie but no valid server cookie
+    /// are turned away with BADCOOKIE.
+    pub required: bool,
+}
+
+impl Cookies {
+    /// Reads DNS_COOKIES (`on`, the default, `off` or `required`) and
+    /// COOKIE_SECRET, 32 hex digits; without one a random secret is chosen
+    /// at startup.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        if !enabled() {
+            return Ok(None);
+        }
+        let required = match std::env::var("DNS_COOKIES").as_deref() {
+            Ok("on") | Err(_) => false,
+            Ok("required") => true,
+            Ok(other) => return Err(format!("Invalid value for DNS_COOKIES: {:?}", other).into()),
+        };
+        let secret = match std::env::var("COOKIE_SECRET") {
+            Ok(hex) => parse_secret(&hex).ok_or("COOKIE_SECRET must be 32 hex digits")?,
+            Err(_) => rand::random(),
+        };
+        Ok(Some(Cookies { secret, required }))
+    }
+
+    /// Whether the query's server cookie is one of ours for this client and
+    /// has not expired.
+    pub fn is_valid(&self, cookie: &QueryCookie, client: IpAddr) -> bool {
+        self.is_valid_at(cookie, client, now())
+    }
+
+    fn is_valid_at(&self, cookie: &QueryCookie, client: IpAddr, now: u64) -> bool {
+        let Ok(server) = <[u8; SERVER_COOKIE_LENGTH]>::try_from(cookie.server.as_slice()) else {
+            return false;
+        };
+        let timestamp = timestamp(&server);
+        server[0] == VERSION
+            && timestamp <= now + CLOCK_SKEW
+            && now.saturating_sub(timestamp) < LIFETIME
+            && server[8..] == self.hash(&cookie.client, &server[..8], client)
+    }
+
+    /// The COOKIE option for the response to `cookie`: the client cookie
+    /// followed by the server cookie, reused while it is fresh.
+    pub fn response_option(&self, cookie: &QueryCookie, client: IpAddr) -> Vec<u8> {
+        self.response_option_at(cookie, client, now())
+    }
+
+    fn response_option_at(&self, cookie: &QueryCookie, client: IpAddr, now: u64) -> Vec<u8> {
+        let mut option = cookie.client.to_vec();
+        if self.is_valid_at(cookie, client, now)
+            && now.saturating_sub(timestamp(&cookie.server)) < REFRESH
+        {
+            option.extend_from_slice(&cookie.server);
+            return option;
+        }
+        let mut header = vec![VERSION, 0, 0, 0];
+        header.extend_from_slice(&(now as u32).to_be_bytes());
+        let hash = self.hash(&cookie.client, &header, client);
+        option.extend_from_slice(&header);
+        option.extend_from_slice(&hash);
+        option
+    }
+
+    /// The BADCOOKIE response to a query whose server cookie is missing or
+    /// invalid, carrying a fresh one for the client to retry with.
+    pub fn bad_cookie(
+        &self,
+        query: &DNSMessage,
+        cookie: &QueryCookie,
+        client: IpAddr,
+    ) -> DNSMessage {
+        let mut response = DNSMessage::response_to(query);
+        response.header.flags.rcode = (BADCOOKIE & 0xF) as u8;
+        if let Some(edns) = response.edns.as_mut() {
+            edns.extended_rcode = (BADCOOKIE >> 4) as u8;
+            edns.options
+                .push((COOKIE, self.response_option(cookie, client)));
+        }
+        response
+    }
+
+    // SipHash-2-4 of client cookie | version | reserved | timestamp |
+    // client address.
+    fn hash(&self, client_cookie: &[u8], header: &[u8], client: IpAddr) -> [u8; 8] {
+        let mut hasher = SipHasher24::new_with_key(&self.secret);
+        hasher.write(client_cookie);
+        hasher.write(header);
+        let client = match client {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(client, IpAddr::V4),
+            IpAddr::V4(_) => client,
+        };
+        match client {
+            IpAddr::V4(v4) => hasher.write(&v4.octets()),
+            IpAddr::V6(v6) => hasher.write(&v6.octets()),
+        }
+        hasher.finish().to_le_bytes()
+    }
+}
+
+// The time a server cookie was issued.
+fn timestamp(server: &[u8]) -> u64 {
+    u32::from_be_bytes([server[4], server[5], server[6], server[7]]) as u64
+}
+
+fn parse_secret(hex: &str) -> Option<[u8; 16]> {
+    let hex = hex.trim();
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut secret = [0u8; 16];
+    for (index, byte) in secret.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(secret)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 9018 appendix A.1 and A.2.
+    const SECRET: &str = "e5e973e5a6b2a43f48e7dc849e37bfcf";
+    const CLIENT: &str = "198.51.100.100";
+    const CLIENT_COOKIE: &str = "2464c4abcf10c957";
+    const ISSUED: u64 = 1559731985;
+    const FIRST_SERVER_COOKIE: &str = "010000005cf79f111f8130c3eee29480";
+    const RENEWED_AT: u64 = 1559734385;
+    const RENEWED_SERVER_COOKIE: &str = "010000005cf7a871d4a564a1442aca77";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn cookies() -> Cookies {
+        Cookies {
+            secret: parse_secret(SECRET).unwrap(),
+            required: false,
+        }
+    }
+
+    fn cookie(server: &str) -> QueryCookie {
+        QueryCookie {
+            client: hex(CLIENT_COOKIE).try_into().unwrap(),
+            server: hex(server),
+        }
+    }
+
+    #[test]
+    fn issues_the_rfc_9018_example_cookies() {
+        let cookies = cookies();
+        let client = CLIENT.parse().unwrap();
+        let first = cookies.response_option_at(&cookie(""), client, ISSUED);
+        assert_eq!(
+            first,
+            hex(&(CLIENT_COOKIE.to_string() + FIRST_SERVER_COOKIE))
+        );
+        // Forty minutes on, the cookie is still valid but due for renewal.
+        let query = cookie(FIRST_SERVER_COOKIE);
+        assert!(cookies.is_valid_at(&query, client, RENEWED_AT));
+        let renewed = cookies.response_option_at(&query, client, RENEWED_AT);
+        assert_eq!(
+            renewed,
+            hex(&(CLIENT_COOKIE.to_string() + RENEWED_SERVER_COOKIE))
+        );
+    }
+
+    #[test]
+    fn reuses_fresh_cookies_and_rejects_stale_ones() {
+        let cookies = cookies();
+        let client = CLIENT.parse().unwrap();
+        let query = cookie(FIRST_SERVER_COOKIE);
+        let reused = cookies.response_option_at(&query, client, ISSUED + REFRESH - 1);
+        assert_eq!(reused[CLIENT_COOKIE_LENGTH..], hex(FIRST_SERVER_COOKIE));
+        assert!(!cookies.is_valid_at(&query, client, ISSUED + LIFETIME));
+        assert!(!cookies.is_valid_at(&query, client, ISSUED - CLOCK_SKEW - 1));
+        assert!(cookies.is_valid_at(&query, client, ISSUED - CLOCK_SKEW));
+        // The hash binds the cookie to the client address and its cookie.
+        assert!(!cookies.is_valid_at(&query, "198.51.100.101".parse().unwrap(), ISSUED));
+        let mut other = cookie(FIRST_SERVER_COOKIE);
+        other.client[0] ^= 1;
+        assert!(!cookies.is_valid_at(&other, client, ISSUED));
+    }
+
+    #[test]
+    fn reads_the_cookie_option() {
+        let mut edns = Edns::new(1232);
+        assert!(QueryCookie::find(&edns).unwrap().is_none());
+        edns.options.push((COOKIE, hex(CLIENT_COOKIE)));
+        let found = QueryCookie::find(&edns).unwrap().unwrap();
+        assert!(found.server.is_empty());
+        for length in [7, 9, 15, 41] {
+            edns.options = vec![(COOKIE, vec![0; length])];
+            assert_eq!(
+                QueryCookie::find(&edns).unwrap_err(),
+                CookieError::Malformed
+            );
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use crypto_box::aead::Aead;
+use crypto_box::{ChaChaBox, PublicKey, SalsaBox, SecretKey};
+use ring::signature::{self, Ed25519KeyPair, UnparsedPublicKey};
+
+use crate::dns::name::normalize;
+
+/// Where DNSCrypt servers usually listen.
+pub const DEFAULT_PORT: u16 = 443;
+
+const CERT_MAGIC: [u8; 4] = *b"DNSC";
+/// Starts every encrypted response.
+pub const RESOLVER_MAGIC: [u8; 8] = *b"r6fnvWj8";
+pub const CLIENT_MAGIC_LENGTH: usize = 8;
+pub const PUBLIC_KEY_LENGTH: usize = 32;
+pub const HALF_NONCE_LENGTH: usize = 12;
+/// Client magic, client public key and client nonce.
+pub const QUERY_HEADER_LENGTH: usize = CLIENT_MAGIC_LENGTH + PUBLIC_KEY_LENGTH + HALF_NONCE_LENGTH;
+/// Resolver magic and the full nonce.
+pub const RESPONSE_HEADER_LENGTH: usize = RESOLVER_MAGIC.len() + 2 * HALF_NONCE_LENGTH;
+const TAG_LENGTH: usize = 16;
+const SIGNATURE_LENGTH: usize = 64;
+const CERT_LENGTH: usize =
+    4 + 2 + 2 + SIGNATURE_LENGTH + PUBLIC_KEY_LENGTH + CLIENT_MAGIC_LENGTH + 12;
+/// Padded queries over UDP are at least this long.
+pub const MIN_UDP_QUERY_LENGTH: usize = 256;
+const PADDING_BLOCK: usize = 64;
+const STAMP_PROTOCOL: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsCryptError {
+    /// Too short, or not what it claims to be.
+    Malformed,
+    /// A certificate whose signature does not match the provider key.
+    BadSignature,
+    /// A certificate for an encryption system we do not know.
+    UnknownConstruction,
+    /// A box that does not open with the key and nonce given.
+    Decryption,
+}
+
+impl fmt::Display for DnsCryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsCryptError::Malformed => write!(f, "Malformed DNSCrypt message"),
+            DnsCryptError::BadSignature => write!(f, "Bad DNSCrypt certificate signature"),
+            DnsCryptError::UnknownConstruction => write!(f, "Unknown DNSCrypt construction"),
+            DnsCryptError::Decryption => write!(f, "DNSCrypt message does not decrypt"),
+        }
+    }
+}
+
+impl Error for DnsCryptError {}
+
+/// The encryption systems of DNSCrypt v2, by their certificate es-version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Construction {
+    XSalsa20Poly1305 = 1,
+    XChaCha20Poly1305 = 2,
+}
+
+impl Construction {
+    pub const ALL: [Construction; 2] = [
+        Construction::XSalsa20Poly1305,
+        Construction::XChaCha20Poly1305,
+    ];
+
+    fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(Construction::XSalsa20Poly1305),
+            2 => Some(Construction::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// A resolver certificate: the short-term key queries are encrypted to,
+/// signed with the provider's long-term Ed25519 key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub construction: Construction,
+    pub resolver_key: [u8; PUBLIC_KEY_LENGTH],
+    /// Starts the queries encrypted to this certificate's key.
+    pub client_magic: [u8; CLIENT_MAGIC_LENGTH],
+    pub serial: u32,
+    pub valid_from: u32,
+    pub valid_until: u32,
+}
+
+impl Certificate {
+    /// The certificate as published in the provider name's TXT record.
+    pub fn sign(&self, provider: &Ed25519KeyPair) -> Vec<u8> {
+        let signed = self.signed_part();
+        let mut cert = CERT_MAGIC.to_vec();
+        cert.extend_from_slice(&(self.construction as u16).to_be_bytes());
+        cert.extend_from_slice(&[0, 0]);
+        cert.extend_from_slice(provider.sign(&signed).as_ref());
+        cert.extend_from_slice(&signed);
+        cert
+    }
+
+    /// Reads a certificate, checking its signature against the provider's
+    /// public key.
+    pub fn parse(data: &[u8], provider_key: &[u8]) -> Result<Self, DnsCryptError> {
+        if data.len() < CERT_LENGTH || data[0..4] != CERT_MAGIC {
+            return Err(DnsCryptError::Malformed);
+        }
+        let construction = Construction::from_u16(u16::from_be_bytes([data[4], data[5]]))
+            .ok_or(DnsCryptError::UnknownConstruction)?;
+        let (signature, signed) = data[8..].split_at(SIGNATURE_LENGTH);
+        UnparsedPublicKey::new(&signature::ED25519, provider_key)
+            .verify(signed, signature)
+            .map_err(|_| DnsCryptError::BadSignature)?;
+        let word = |at: usize| u32::from_be_bytes(signed[at..at + 4].try_into().unwrap());
+        let mut resolver_key = [0u8; PUBLIC_KEY_LENGTH];
+        resolver_key.copy_from_slice(&signed[..PUBLIC_KEY_LENGTH]);
+        let mut client_magic = [0u8; CLIENT_MAGIC_LENGTH];
+        client_magic.copy_from_slice(&signed[PUBLIC_KEY_LENGTH..PUBLIC_KEY_LENGTH + 8]);
+        Ok(Certificate {
+            construction,
+            resolver_key,
+            client_magic,
+            serial: word(40),
+            valid_from: word(44),
+            valid_until: word(48),
+        })
+    }
+
+    /// Whether the certificate is valid at `now`, in seconds since the epoch
+    /// like `unix_time`.
+    pub fn is_current(&self, now: u32) -> bool {
+        (self.valid_from..self.valid_until).contains(&now)
+    }
+
+    // Everything after the signature; there are no extensions.
+    fn signed_part(&self) -> Vec<u8> {
+        let mut signed = self.resolver_key.to_vec();
+        signed.extend_from_slice(&self.client_magic);
+        signed.extend_from_slice(&self.serial.to_be_bytes());
+        signed.extend_from_slice(&self.valid_from.to_be_bytes());
+        signed.extend_from_slice(&self.valid_until.to_be_bytes());
+        signed
+    }
+}
+
+/// The key a client and a resolver share for one certificate.
+pub enum SharedKey {
+    XSalsa20Poly1305(SalsaBox),
+    XChaCha20Poly1305(ChaChaBox),
+}
+
+impl SharedKey {
+    pub fn new(
+        construction: Construction,
+        public: &[u8; PUBLIC_KEY_LENGTH],
+        secret: &SecretKey,
+    ) -> Self {
+        let public = PublicKey::from(*public);
+        match construction {
+            Construction::XSalsa20Poly1305 => {
+                SharedKey::XSalsa20Poly1305(SalsaBox::new(&public, secret))
+            }
+            Construction::XChaCha20Poly1305 => {
+                SharedKey::XChaCha20Poly1305(ChaChaBox::new(&public, secret))
+            }
+        }
+    }
+
+    /// Encrypts `message` with the tag in front, as crypto_box does.
+    pub fn seal(&self, nonce: &[u8; 2 * HALF_NONCE_LENGTH], message: &[u8]) -> Vec<u8> {
+        let sealed = match self {
+            SharedKey::XSalsa20Poly1305(key) => key.encrypt(nonce.into(), message),
+            SharedKey::XChaCha20Poly1305(key) => key.encrypt(nonce.into(), message),
+        };
+        // Only additional data, which is not used here, makes this fail.
+        sealed.expect("sealing without additional data")
+    }
+
+    pub fn open(
+        &self,
+        nonce: &[u8; 2 * HALF_NONCE_LENGTH],
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, DnsCryptError> {
+        let opened = match self {
+            SharedKey::XSalsa20Poly1305(key) => key.decrypt(nonce.into(), sealed),
+            SharedKey::XChaCha20Poly1305(key) => key.decrypt(nonce.into(), sealed),
+        };
+        opened.map_err(|_| DnsCryptError::Decryption)
+    }
+}
+
+/// Seconds since the epoch, as certificates count time.
+pub fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+/// Pads a message for encryption (ISO/IEC 7816-4: 0x80, then zeros) to a
+/// multiple of 64 bytes and at least `min_length`.
+pub fn pad(message: &mut Vec<u8>, min_length: usize) {
+    let length = padded_length(message.len()).max(min_length.next_multiple_of(PADDING_BLOCK));
+    message.push(0x80);
+    message.resize(length, 0);
+}
+
+/// How long `length` bytes are once padded and sealed, without a minimum.
+pub fn sealed_length(length: usize) -> usize {
+    padded_length(length) + TAG_LENGTH
+}
+
+fn padded_length(length: usize) -> usize {
+    (length + 1).next_multiple_of(PADDING_BLOCK)
+}
+
+/// Strips the padding `pad` adds.
+pub fn unpad(message: &mut Vec<u8>) -> Result<(), DnsCryptError> {
+    let end = message
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .filter(|&end| message[end] == 0x80)
+        .ok_or(DnsCryptError::Malformed)?;
+    message.truncate(end);
+    Ok(())
+}
+
+/// A DNSCrypt server stamp (`sdns://`): where the server is, the provider's
+/// public key and the provider name its certificates are published under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamp {
+    pub address: SocketAddr,
+    pub provider_key: [u8; PUBLIC_KEY_LENGTH],
+    pub provider_name: String,
+}
+
+impl Stamp {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid DNSCrypt stamp {:?}", text);
+        let data = text
+            .strip_prefix("sdns://")
+            .and_then(|encoded| URL_SAFE_NO_PAD.decode(encoded).ok())
+            .ok_or_else(invalid)?;
+        // The protocol, then eight bytes of properties (DNSSEC, no logs, no
+        // filters) that make no difference here.
+        if data.len() < 9 || data[0] != STAMP_PROTOCOL {
+            return Err(invalid());
+        }
+        let mut rest = &data[9..];
+        let mut field = || -> Option<&[u8]> {
+            let (&length, tail) = rest.split_first()?;
+            let (value, tail) = tail.split_at_checked(length as usize)?;
+            rest = tail;
+            Some(value)
+        };
+        let (Some(address), Some(key), Some(name)) = (field(), field(), field()) else {
+            return Err(invalid());
+        };
+        let address = std::str::from_utf8(address).map_err(|_| invalid())?;
+        let address = match address.parse::<SocketAddr>() {
+            Ok(address) => address,
+            Err(_) => address
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+                .map_err(|_| invalid())?,
+        };
+        Ok(Stamp {
+            address,
+            provider_key: key.try_into().map_err(|_| invalid())?,
+            provider_name: normalize(std::str::from_utf8(name).map_err(|_| invalid())?),
+        })
+    }
+}
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address = match (self.address.port(), self.address.ip()) {
+            (DEFAULT_PORT, IpAddr::V4(ip)) => ip.to_string(),
+            (DEFAULT_PORT, IpAddr::V6(ip)) => format!("[{}]", ip),
+            _ => self.address.to_string(),
+        };
+        let mut data = vec![STAMP_PROTOCOL, 0, 0, 0, 0, 0, 0, 0, 0];
+        for field in [
+            address.as_bytes(),
+            &self.provider_key,
+            self.provider_name.as_bytes(),
+        ] {
+            data.push(field.len() as u8);
+            data.extend_from_slice(field);
+        }
+        write!(f, "sdns://{}", URL_SAFE_NO_PAD.encode(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto_box::aead::OsRng;
+    use ring::signature::KeyPair;
+
+    fn certificate(construction: Construction) -> Certificate {
+        Certificate {
+            construction,
+            resolver_key: [1; PUBLIC_KEY_LENGTH],
+            client_magic: [2; CLIENT_MAGIC_LENGTH],
+            serial: 3,
+            valid_from: 1000,
+            valid_until: 2000,
+        }
+    }
+
+    #[test]
+    fn certificates_round_trip_through_their_signature() {
+        let provider = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let provider_key = provider.public_key().as_ref();
+        for construction in Construction::ALL {
+            let cert = certificate(construction);
+            let signed = cert.sign(&provider);
+            assert_eq!(signed.len(), CERT_LENGTH);
+            assert_eq!(Certificate::parse(&signed, provider_key).unwrap(), cert);
+        }
+        assert!(certificate(Construction::XSalsa20Poly1305).is_current(1000));
+        assert!(!certificate(Construction::XSalsa20Poly1305).is_current(2000));
+    }
+
+    #[test]
+    fn rejects_tampered_certificates() {
+        let provider = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let other = Ed25519KeyPair::from_seed_unchecked(&[8; 32]).unwrap();
+        let mut signed = certificate(Construction::XChaCha20Poly1305).sign(&provider);
+        assert_eq!(
+            Certificate::parse(&signed, other.public_key().as_ref()),
+            Err(DnsCryptError::BadSignature)
+        );
+        *signed.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Certificate::parse(&signed, provider.public_key().as_ref()),
+            Err(DnsCryptError::BadSignature)
+        );
+        signed[5] = 3;
+        assert_eq!(
+            Certificate::parse(&signed, provider.public_key().as_ref()),
+            Err(DnsCryptError::UnknownConstruction)
+        );
+        assert_eq!(
+            Certificate::parse(&signed[..CERT_LENGTH - 1], provider.public_key().as_ref()),
+            Err(DnsCryptError::Malformed)
+        );
+    }
+
+    #[test]
+    fn client_and_resolver_share_a_key() {
+        let client = SecretKey::generate(&mut OsRng);
+        let resolver = SecretKey::generate(&mut OsRng);
+        let nonce = [9; 2 * HALF_NONCE_LENGTH];
+        for construction in Construction::ALL {
+            let sealing = SharedKey::new(construction, resolver.public_key().as_bytes(), &client);
+            let opening = SharedKey::new(construction, client.public_key().as_bytes(), &resolver);
+            let sealed = sealing.seal(&nonce, b"query");
+            assert_eq!(sealed.len(), b"query".len() + TAG_LENGTH);
+            assert_eq!(opening.open(&nonce, &sealed).unwrap(), b"query");
+            assert_eq!(
+                opening.open(&[0; 2 * HALF_NONCE_LENGTH], &sealed),
+                Err(DnsCryptError::Decryption)
+            );
+        }
+    }
+
+    #[test]
+    fn pads_to_blocks_and_minimum_lengths() {
+        let mut message = vec![1; 63];
+        pad(&mut message, 0);
+        assert_eq!(message.len(), 64);
+        assert_eq!(message[63], 0x80);
+        unpad(&mut message).unwrap();
+        assert_eq!(message, vec![1; 63]);
+
+        let mut message = vec![1; 64];
+        pad(&mut message, MIN_UDP_QUERY_LENGTH);
+        assert_eq!(message.len(), MIN_UDP_QUERY_LENGTH);
+        unpad(&mut message).unwrap();
+        assert_eq!(message.len(), 64);
+
+        assert_eq!(sealed_length(64), 128 + TAG_LENGTH);
+        assert_eq!(unpad(&mut vec![1, 0, 0]), Err(DnsCryptError::Malformed));
+    }
+
+    #[test]
+    fn stamps_round_trip() {
+        for address in ["192.0.2.1:443", "192.0.2.1:8443", "[2001:db8::1]:443"] {
+            let stamp = Stamp {
+                address: address.parse().unwrap(),
+                provider_key: [5; PUBLIC_KEY_LENGTH],
+                provider_name: "2.dnscrypt-cert.example.test".to_string(),
+            };
+            assert_eq!(Stamp::parse(&stamp.to_string()).unwrap(), stamp);
+        }
+    }
+
+    #[test]
+    fn rejects_other_stamps() {
+        // A DNS over HTTPS stamp (protocol 2).
+        let doh = format!(
+            "sdns://{}",
+            URL_SAFE_NO_PAD.encode([2, 0, 0, 0, 0, 0, 0, 0, 0])
+        );
+        assert!(Stamp::parse(&doh).is_err());
+        assert!(Stamp::parse("sdns://!!").is_err());
+        let truncated = format!(
+            "sdns://{}",
+            URL_SAFE_NO_PAD.encode([1, 0, 0, 0, 0, 0, 0, 0, 0, 9, b'1'])
+        );
+        assert!(Stamp::parse(&truncated).is_err());
+    }
+}
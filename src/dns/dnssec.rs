@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use ring::digest;
+
+use crate::dns::name::{encode_name, labels, normalize, parse_name};
+use crate::dns::resource_record::{RecordType, ResourceRecord};
+
+/// DNSKEY flag of keys that sign zone data (RFC 4034 section 2.1.1).
+pub const ZONE_KEY: u16 = 0x0100;
+/// DNSKEY flag of keys their owner has withdrawn (RFC 5011 section 3).
+pub const REVOKED_KEY: u16 = 0x0080;
+/// NSEC3 flag of spans that may hide unsigned delegations (RFC 5155).
+pub const OPT_OUT: u8 = 0x01;
+
+/// RRSIG RDATA (RFC 4034 section 3.1).
+#[derive(Debug, Clone)]
+pub struct Rrsig {
+    pub type_covered: RecordType,
+    pub algorithm: u8,
+    pub labels: u8,
+    pub original_ttl: u32,
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    pub signer: String,
+    pub signature: Vec<u8>,
+}
+
+impl Rrsig {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let fixed = data.get(..18)?;
+        let (signer, end) = parse_name(data, 18).ok()?;
+        let u32_at = |at: usize| {
+            u32::from_be_bytes([fixed[at], fixed[at + 1], fixed[at + 2], fixed[at + 3]])
+        };
+        Some(Rrsig {
+            type_covered: RecordType::from_u16(u16::from_be_bytes([fixed[0], fixed[1]])),
+            algorithm: fixed[2],
+            labels: fixed[3],
+            original_ttl: u32_at(4),
+            expiration: u32_at(8),
+            inception: u32_at(12),
+            key_tag: u16::from_be_bytes([fixed[16], fixed[17]]),
+            signer,
+            signature: data[end..].to_vec(),
+        })
+    }
+
+    /// The RDATA fields the signature covers, with the signer's name in
+    /// canonical form (RFC 4034 section 3.1.8.1).
+    pub fn signed_fields(&self) -> Vec<u8> {
+        let mut out = self.type_covered.to_u16().to_be_bytes().to_vec();
+        out.push(self.algorithm);
+        out.push(self.labels);
+        out.extend_from_slice(&self.original_ttl.to_be_bytes());
+        out.extend_from_slice(&self.expiration.to_be_bytes());
+        out.extend_from_slice(&self.inception.to_be_bytes());
+        out.extend_from_slice(&self.key_tag.to_be_bytes());
+        encode_name(&self.signer.to_ascii_lowercase(), &mut out);
+        out
+    }
+
+    /// Whether `now` falls between inception and expiration, in the serial
+    /// number arithmetic of RFC 1982 the timestamps use.
+    pub fn is_current(&self, now: u32) -> bool {
+        (now.wrapping_sub(self.inception) as i32) >= 0
+            && (self.expiration.wrapping_sub(now) as i32) >= 0
+    }
+}
+
+/// The type an RRSIG record covers; `None` for any other record.
+pub fn covered_type(record: &ResourceRecord) -> Option<RecordType> {
+    if record.record_type != RecordType::RRSIG {
+        return None;
+    }
+    record
+        .data
+        .get(..2)
+        .map(|b| RecordType::from_u16(u16::from_be_bytes([b[0], b[1]])))
+}
+
+/// Groups loose records into RRsets by owner name and type, each with the
+/// RRSIGs covering it. Signatures over records not among them are dropped.
+pub fn rrsets(records: &[ResourceRecord]) -> Vec<(Vec<ResourceRecord>, Vec<ResourceRecord>)> {
+    let mut rrsets: HashMap<(String, RecordType), (Vec<ResourceRecord>, Vec<ResourceRecord>)> =
+        HashMap::new();
+    for record in records {
+        match covered_type(record) {
+            Some(covered) => rrsets
+                .entry((normalize(&record.name), covered))
+                .or_default()
+                .1
+                .push(record.clone()),
+            None => rrsets
+                .entry((normalize(&record.name), record.record_type))
+                .or_default()
+                .0
+                .push(record.clone()),
+        }
+    }
+    rrsets
+        .into_values()
+        .filter(|(rrset, _)| !rrset.is_empty())
+        .collect()
+}
+
+/// DNSKEY RDATA (RFC 4034 section 2.1).
+#[derive(Debug, Clone)]
+pub struct Dnskey {
+    pub flags: u16,
+    pub protocol: u8,
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+    pub key_tag: u16,
+}
+
+impl Dnskey {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let fixed = data.get(..4)?;
+        Some(Dnskey {
+            flags: u16::from_be_bytes([fixed[0], fixed[1]]),
+            protocol: fixed[2],
+            algorithm: fixed[3],
+            public_key: data[4..].to_vec(),
+            key_tag: key_tag(data),
+        })
+    }
+
+    /// Whether the key may sign zone data at all.
+    pub fn is_usable(&self) -> bool {
+        self.flags & ZONE_KEY != 0 && self.flags & REVOKED_KEY == 0 && self.protocol == 3
+    }
+}
+
+/// The key tag of a DNSKEY's RDATA (RFC 4034 appendix B).
+pub fn key_tag(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for (index, byte) in data.iter().enumerate() {
+        sum += if index % 2 == 0 {
+            (*byte as u32) << 8
+        } else {
+            *byte as u32
+        };
+    }
+    sum += (sum >> 16) & 0xFFFF;
+    (sum & 0xFFFF) as u16
+}
+
+/// DS RDATA (RFC 4034 section 5.1).
+#[derive(Debug, Clone)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+impl Ds {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let fixed = data.get(..4)?;
+        Some(Ds {
+            key_tag: u16::from_be_bytes([fixed[0], fixed[1]]),
+            algorithm: fixed[2],
+            digest_type: fixed[3],
+            digest: data[4..].to_vec(),
+        })
+    }
+
+    pub fn digest_algorithm(&self) -> Option<&'static digest::Algorithm> {
+        match self.digest_type {
+            1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+            2 => Some(&digest::SHA256),
+            4 => Some(&digest::SHA384),
+            _ => None,
+        }
+    }
+
+    /// Whether `dnskey`, owned by `owner`, is the key this DS refers to.
+    pub fn matches(&self, owner: &str, dnskey: &ResourceRecord) -> bool {
+        let Some(algorithm) = self.digest_algorithm() else {
+            return false;
+        };
+        let Some(key) = Dnskey::parse(&dnskey.data) else {
+            return false;
+        };
+        if key.key_tag != self.key_tag || key.algorithm != self.algorithm {
+            return false;
+        }
+        let mut input = Vec::new();
+        encode_name(&owner.to_ascii_lowercase(), &mut input);
+        input.extend_from_slice(&dnskey.data);
+        digest::digest(algorithm, &input).as_ref() == self.digest.as_slice()
+    }
+}
+
+/// The record types present at a name, from an NSEC or NSEC3 type bitmap
+/// (RFC 4034 section 4.1.2).
+#[derive(Debug, Clone, Default)]
+pub struct TypeBitmap {
+    types: Vec<u16>,
+}
+
+impl TypeBitmap {
+    pub fn parse(mut data: &[u8]) -> Option<Self> {
+        let mut types = Vec::new();
+        while !data.is_empty() {
+            let window = *data.first()? as u16;
+            let length = *data.get(1)? as usize;
+            let bits = data.get(2..2 + length)?;
+            for (index, byte) in bits.iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (0x80 >> bit) != 0 {
+                        types.push(window * 256 + (index * 8 + bit) as u16);
+                    }
+                }
+            }
+            data = &data[2 + length..];
+        }
+        Some(TypeBitmap { types })
+    }
+
+    pub fn contains(&self, record_type: RecordType) -> bool {
+        self.types.contains(&record_type.to_u16())
+    }
+}
+
+/// NSEC RDATA (RFC 4034 section 4.1).
+#[derive(Debug, Clone)]
+pub struct Nsec {
+    pub next: String,
+    pub types: TypeBitmap,
+}
+
+impl Nsec {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (next, end) = parse_name(data, 0).ok()?;
+        Some(Nsec {
+            next,
+            types: TypeBitmap::parse(&data[end..])?,
+        })
+    }
+}
+
+/// NSEC3 RDATA (RFC 5155 section 3.2).
+#[derive(Debug, Clone)]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    pub next_hashed: Vec<u8>,
+    pub types: TypeBitmap,
+}
+
+impl Nsec3 {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let fixed = data.get(..5)?;
+        let salt_end = 5 + fixed[4] as usize;
+        let salt = data.get(5..salt_end)?.to_vec();
+        let hash_length = *data.get(salt_end)? as usize;
+        let next_end = salt_end + 1 + hash_length;
+        let next_hashed = data.get(salt_end + 1..next_end)?.to_vec();
+        Some(Nsec3 {
+            hash_algorithm: fixed[0],
+            flags: fixed[1],
+            iterations: u16::from_be_bytes([fixed[2], fixed[3]]),
+            salt,
+            next_hashed,
+            types: TypeBitmap::parse(&data[next_end..])?,
+        })
+    }
+
+    /// The hashed owner name of `name` with this record's parameters
+    /// (RFC 5155 section 5). Only SHA-1, the one defined algorithm, exists.
+    pub fn hash(&self, name: &str) -> Option<Vec<u8>> {
+        if self.hash_algorithm != 1 {
+            return None;
+        }
+        let mut wire = Vec::new();
+        encode_name(&name.to_ascii_lowercase(), &mut wire);
+        let mut hash = wire;
+        for _ in 0..=self.iterations {
+            hash.extend_from_slice(&self.salt);
+            hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &hash)
+                .as_ref()
+                .to_vec();
+        }
+        Some(hash)
+    }
+}
+
+/// Decodes the base32hex first label of an NSEC3 owner name (RFC 4648
+/// section 7) into the hash it stands for.
+pub fn hashed_owner(owner: &str) -> Option<Vec<u8>> {
+    let label = labels(owner).next()?;
+    let mut out = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in label.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'A'..=b'V' => c - b'A' + 10,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
@@ -0,0 +1,344 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::message::DNSParseError;
+use super::question::Question;
+use super::resource_record::{RecordType, ResourceRecord};
+
+/// UDP payload size tinydns advertises, per the DNS flag day 2020 advice.
+pub const SERVER_UDP_PAYLOAD: u16 = 1232;
+
+/// Option code of EDNS Client Subnet (RFC 7871).
+pub const CLIENT_SUBNET: u16 = 8;
+/// Option code of EDNS padding (RFC 7830).
+pub const PADDING: u16 = 12;
+/// Option code of Extended DNS Errors (RFC 8914).
+pub const EXTENDED_ERROR: u16 = 15;
+
+/// Block sizes padded messages are rounded up to (RFC 8467 section 4.1).
+pub const QUERY_PADDING_BLOCK: usize = 128;
+pub const RESPONSE_PADDING_BLOCK: usize = 468;
+
+/// The contents of an OPT pseudo-record (RFC 6891). It is kept apart from
+/// the other additional records because its class and TTL fields carry
+/// EDNS parameters rather than a class and a TTL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<(u16, Vec<u8>)>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Self {
+        Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// Parses the OPT record at `offset`, returning it with the offset of
+    /// the next record.
+    pub fn parse(buf: &[u8], offset: usize) -> Result<(Self, usize), DNSParseError> {
+        // The owner of an OPT record is always the root.
+        if buf.get(offset) != Some(&0) {
+            return Err(DNSParseError::InvalidResourceRecord);
+        }
+        let fixed = buf
+            .get(offset + 1..offset + 11)
+            .ok_or(DNSParseError::InvalidResourceRecord)?;
+        if u16::from_be_bytes([fixed[0], fixed[1]]) != RecordType::OPT.to_u16() {
+            return Err(DNSParseError::InvalidResourceRecord);
+        }
+
+        let udp_payload_size = u16::from_be_bytes([fixed[2], fixed[3]]);
+        let extended_rcode = fixed[4];
+        let version = fixed[5];
+        let dnssec_ok = fixed[6] & 0x80 != 0;
+        let data_length = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+
+        let mut index = offset + 11;
+        let end = index + data_length;
+        let data = buf
+            .get(index..end)
+            .ok_or(DNSParseError::InvalidResourceRecord)?;
+
+        let mut options = Vec::new();
+        index = 0;
+        while index < data.len() {
+            let header = data
+                .get(index..index + 4)
+                .ok_or(DNSParseError::InvalidResourceRecord)?;
+            let code = u16::from_be_bytes([header[0], header[1]]);
+            let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let value = data
+                .get(index + 4..index + 4 + length)
+                .ok_or(DNSParseError::InvalidResourceRecord)?;
+            options.push((code, value.to_vec()));
+            index += 4 + length;
+        }
+
+        Ok((
+            Edns {
+                udp_payload_size,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                options,
+            },
+            end,
+        ))
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(0);
+        out.extend_from_slice(&RecordType::OPT.to_u16().to_be_bytes());
+        out.extend_from_slice(&self.udp_payload_size.to_be_bytes());
+        out.push(self.extended_rcode);
+        out.push(self.version);
+        out.push(if self.dnssec_ok { 0x80 } else { 0 });
+        out.push(0);
+        let data_length: usize = self.options.iter().map(|(_, v)| 4 + v.len()).sum();
+        out.extend_from_slice(&(data_length as u16).to_be_bytes());
+        for (code, value) in &self.options {
+            out.extend_from_slice(&code.to_be_bytes());
+            out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            out.extend_from_slice(value);
+        }
+    }
+
+    /// The client subnet option, if there is a well formed one.
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        self.options
+            .iter()
+            .find(|(code, _)| *code == CLIENT_SUBNET)
+            .and_then(|(_, data)| ClientSubnet::parse(data))
+    }
+
+    /// Replaces any client subnet option with `subnet`.
+    pub fn set_client_subnet(&mut self, subnet: Option<ClientSubnet>) {
+        self.options.retain(|(code, _)| *code != CLIENT_SUBNET);
+        if let Some(subnet) = subnet {
+            self.options.push((CLIENT_SUBNET, subnet.to_bytes()));
+        }
+    }
+
+    pub fn remove_options(&mut self, codes: &[u16]) {
+        self.options.retain(|(code, _)| !codes.contains(code));
+    }
+
+    pub fn has_option(&self, code: u16) -> bool {
+        self.options.iter().any(|(existing, _)| *existing == code)
+    }
+
+    /// Replaces any extended error with `error`, explained by `text`.
+    pub fn set_extended_error(&mut self, error: ExtendedError, text: &str) {
+        self.options.retain(|(code, _)| *code != EXTENDED_ERROR);
+        let mut data = (error as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(text.as_bytes());
+        self.options.push((EXTENDED_ERROR, data));
+    }
+
+    pub fn wire_length(&self) -> usize {
+        11 + self
+            .options
+            .iter()
+            .map(|(_, value)| 4 + value.len())
+            .sum::<usize>()
+    }
+}
+
+/// Extended DNS Error codes (RFC 8914 section 4) that tinydns sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedError {
+    Other = 0,
+    ForgedAnswer = 4,
+    DnssecBogus = 6,
+    SignatureExpired = 7,
+    DnskeyMissing = 9,
+    RrsigsMissing = 10,
+    NsecMissing = 12,
+    Blocked = 15,
+    Prohibited = 18,
+    NoReachableAuthority = 22,
+    NetworkError = 23,
+}
+
+/// Appends an option to the OPT record of a serialized message. The OPT
+/// record must be the last record, as it is in every message tinydns
+/// builds; otherwise nothing is changed and `false` is returned.
+pub fn append_option(message: &mut Vec<u8>, code: u16, data: &[u8]) -> bool {
+    let Some(start) = last_record(message) else {
+        return false;
+    };
+    let fixed = &message[start..];
+    if fixed.len() < 11 || fixed[0] != 0 || fixed[1..3] != RecordType::OPT.to_u16().to_be_bytes() {
+        return false;
+    }
+    let length = u16::from_be_bytes([fixed[9], fixed[10]]) as usize + 4 + data.len();
+    let Ok(length) = u16::try_from(length) else {
+        return false;
+    };
+    if message.len() + 4 + data.len() > u16::MAX as usize {
+        return false;
+    }
+    message[start + 9..start + 11].copy_from_slice(&length.to_be_bytes());
+    message.extend_from_slice(&code.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(data);
+    true
+}
+
+/// Pads a serialized message with a padding option to a multiple of
+/// `block` bytes (RFC 7830), if it has an OPT record to hold one.
+pub fn pad(message: &mut Vec<u8>, block: usize) -> bool {
+    let padded = (message.len() + 4).next_multiple_of(block);
+    append_option(message, PADDING, &vec![0; padded - message.len() - 4])
+}
+
+// Where the last record of a serialized message starts, if it has any.
+fn last_record(message: &[u8]) -> Option<usize> {
+    let count = |at: usize| {
+        message
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    };
+    let questions = count(4)?;
+    let records = count(6)? + count(8)? + count(10)?;
+    if records == 0 {
+        return None;
+    }
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = Question::parse(message, offset).ok()?.1;
+    }
+    for _ in 0..records - 1 {
+        offset = ResourceRecord::parse(message, offset).ok()?.1;
+    }
+    let (_, end) = ResourceRecord::parse(message, offset).ok()?;
+    (end == message.len()).then_some(offset)
+}
+
+/// The network a query is asked on behalf of, and in responses how much of
+/// it the answer depends on (RFC 7871 section 6).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    pub address: IpAddr,
+    pub source_prefix: u8,
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let family = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+        let source_prefix = *data.get(2)?;
+        let scope_prefix = *data.get(3)?;
+        let bytes = &data[4..];
+        if bytes.len() != (source_prefix as usize).div_ceil(8) {
+            return None;
+        }
+        let address = match family {
+            1 if source_prefix <= 32 => {
+                let mut octets = [0u8; 4];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 if source_prefix <= 128 => {
+                let mut octets = [0u8; 16];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some(ClientSubnet {
+            address,
+            source_prefix,
+            scope_prefix,
+        })
+    }
+
+    /// The option data, with the address cut to the bytes the source
+    /// prefix covers.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let (family, octets) = match self.address {
+            IpAddr::V4(v4) => (1u16, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (2u16, v6.octets().to_vec()),
+        };
+        let mut out = family.to_be_bytes().to_vec();
+        out.push(self.source_prefix);
+        out.push(self.scope_prefix);
+        out.extend_from_slice(&octets[..(self.source_prefix as usize).div_ceil(8)]);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::message::DNSMessage;
+    use crate::dns::resource_record::RecordClass;
+
+    // A query for example.test A with an empty OPT record: 12 bytes of
+    // header, 18 of question and 11 of OPT.
+    fn query() -> Vec<u8> {
+        DNSMessage::query(1, "example.test", RecordType::A).to_bytes()
+    }
+
+    #[test]
+    fn appends_options_to_the_opt_record() {
+        let mut message = query();
+        assert_eq!(message.len(), 41);
+        assert!(append_option(&mut message, 65001, &[1, 2, 3]));
+        assert_eq!(message.len(), 48);
+        // RDLENGTH of the OPT record now covers the option.
+        assert_eq!(message[39..41], [0, 7]);
+        let edns = DNSMessage::parse(&message).unwrap().edns.unwrap();
+        assert_eq!(edns.options, [(65001, vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn leaves_messages_not_ending_in_opt_alone() {
+        let mut plain = DNSMessage::query(1, "example.test", RecordType::A);
+        plain.edns = None;
+        let mut bytes = plain.to_bytes();
+        assert!(!append_option(&mut bytes, PADDING, &[]));
+        assert_eq!(bytes, plain.to_bytes());
+
+        plain.additional_records.push(ResourceRecord::new(
+            "example.test".to_string(),
+            RecordType::A,
+            RecordClass::IN,
+            60,
+            vec![192, 0, 2, 1],
+        ));
+        let mut bytes = plain.to_bytes();
+        assert!(!append_option(&mut bytes, PADDING, &[]));
+        assert_eq!(bytes, plain.to_bytes());
+    }
+
+    // RFC 8467 section 4.1: queries in blocks of 128 bytes, responses 468.
+    #[test]
+    fn pads_to_the_block_size() {
+        let mut message = query();
+        assert!(pad(&mut message, QUERY_PADDING_BLOCK));
+        assert_eq!(message.len(), 128);
+        let edns = DNSMessage::parse(&message).unwrap().edns.unwrap();
+        assert_eq!(edns.options, [(PADDING, vec![0; 128 - 41 - 4])]);
+
+        let mut message = query();
+        assert!(pad(&mut message, RESPONSE_PADDING_BLOCK));
+        assert_eq!(message.len(), 468);
+
+        // Exactly one block once the option header is added: no padding bytes.
+        let mut message = query();
+        append_option(&mut message, 65001, &vec![0; 468 - 41 - 4 - 4]);
+        assert!(pad(&mut message, RESPONSE_PADDING_BLOCK));
+        assert_eq!(message.len(), 468);
+        let edns = DNSMessage::parse(&message).unwrap().edns.unwrap();
+        assert_eq!(edns.options[1], (PADDING, Vec::new()));
+    }
+}
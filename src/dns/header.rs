@@ -18,7 +18,7 @@
 
 use crate::dns::message::DNSParseError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct Header {
     pub transaction_id: u16,
     pub flags: Flags,
@@ -28,7 +28,7 @@ pub struct Header {
     pub additional_count: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct Flags {
     pub qr: bool,
     pub opcode: u8,
@@ -36,7 +36,11 @@ pub struct Flags {
     pub tc: bool,
     pub rd: bool,
     pub ra: bool,
-    pub z: u8,
+    pub z: bool,
+    /// Authentic data: the answer was validated with DNSSEC.
+    pub ad: bool,
+    /// Checking disabled: the client validates for itself.
+    pub cd: bool,
     pub rcode: u8,
 }
 
@@ -64,7 +68,9 @@ impl Header {
             tc: flags_byte1 & 0x02 != 0,
             rd: flags_byte1 & 0x01 != 0,
             ra: flags_byte2 & 0x80 != 0,
-            z: (flags_byte2 >> 4) & 0x07,
+            z: flags_byte2 & 0x40 != 0,
+            ad: flags_byte2 & 0x20 != 0,
+            cd: flags_byte2 & 0x10 != 0,
             rcode: flags_byte2 & 0x0F,
         };
 
@@ -78,4 +84,40 @@ impl Header {
             additional_count,
         })
     }
+
+    pub fn to_bytes(&self) -> [u8; 12] {
+        let flags = &self.flags;
+        let flags_byte1 = (flags.qr as u8) << 7
+            | (flags.opcode & 0x0F) << 3
+            | (flags.aa as u8) << 2
+            | (flags.tc as u8) << 1
+            | flags.rd as u8;
+        let flags_byte2 = (flags.ra as u8) << 7
+            | (flags.z as u8) << 6
+            | (flags.ad as u8) << 5
+            | (flags.cd as u8) << 4
+            | (flags.rcode & 0x0F);
+
+        let mut bytes = [0u8; 12];
+        bytes[0..2].copy_from_slice(&self.transaction_id.to_be_bytes());
+        bytes[2] = flags_byte1;
+        bytes[3] = flags_byte2;
+        bytes[4..6].copy_from_slice(&self.question_count.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.answer_count.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.authority_count.to_be_bytes());
+        bytes[10..12].copy_from_slice(&self.additional_count.to_be_bytes());
+        bytes
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCode {
+    NoError = 0,
+    FormErr = 1,
+    ServFail = 2,
+    NXDomain = 3,
+    NotImp = 4,
+    Refused = 5,
+    NotAuth = 9,
 }
@@ -1,23 +1,31 @@
-use super::header::Header;
+use super::edns::{Edns, ExtendedError, SERVER_UDP_PAYLOAD};
+use super::header::{Flags, Header, ResponseCode};
+use super::name::parse_name;
 use super::question::Question;
 use super::resource_record::{RecordClass, RecordType, ResourceRecord};
 use log_execution_time::log_execution_time;
+use std::collections::HashMap;
 use std::fmt;
-use std::io::ErrorKind;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DNSMessage {
     pub header: Header,
     pub questions: Vec<Question>,
-    // pub answers: Vec<ResourceRecord>,
-    // pub authority_records: Vec<ResourceRecord>,
-    // pub additional_records: Vec<ResourceRecord>,
+    pub answers: Vec<ResourceRecord>,
+    pub authority_records: Vec<ResourceRecord>,
+    pub additional_records: Vec<ResourceRecord>,
+    pub edns: Option<Edns>,
 }
 
+/// Largest DNS message a client can receive without EDNS (RFC 1035).
+pub const MAX_UDP_PAYLOAD: usize = 512;
+
+#[derive(Debug)]
 pub enum DNSParseError {
     InvalidHeader,
     InvalidQuestion,
     InvalidResourceRecord,
+    InvalidName,
     BufferTooShort,
 }
 
@@ -25,48 +33,291 @@ impl DNSMessage {
     #[log_execution_time]
     pub fn parse(query_buffer: &[u8]) -> Result<Self, DNSParseError> {
         let header = Header::parse(query_buffer).map_err(|_| DNSParseError::InvalidHeader)?;
-        let questions = Self::parse_questions(query_buffer, header.question_count);
-
-        // let mut index = 12 + query_buffer[12..].len(); // Skip the header and questions
-        // let mut answers = Vec::new();
-        // for _ in 0..header.answer_count {
-        //     let answer = ResourceRecord::parse(&query_buffer[index..]);
-        //     answers.push(answer);
-        //     index += 1; // Move to the next answer
-        // }
-        //
-        // let mut authority_records = Vec::new();
-        // for _ in 0..header.authority_count {
-        //     let authority_record = ResourceRecord::parse(&query_buffer[index..]);
-        //     authority_records.push(authority_record);
-        //     index += 1; // Move to the next authority record
-        // }
-        //
-        // let mut additional_records = Vec::new();
-        // for _ in 0..header.additional_count {
-        //     let additional_record = ResourceRecord::parse(&query_buffer[index..]);
-        //     additional_records.push(additional_record);
-        //     index += 1; // Move to the next additional record
-        // }
+        let (questions, index) = Self::parse_questions(query_buffer, header.question_count)?;
+
+        let (answers, index) = Self::parse_records(query_buffer, index, header.answer_count)?;
+        let (authority_records, index) =
+            Self::parse_records(query_buffer, index, header.authority_count)?;
+        let (additional_records, edns) =
+            Self::parse_additional(query_buffer, index, header.additional_count)?;
 
         Ok(DNSMessage {
             header,
             questions,
-            // answers,
-            // authority_records,
-            // additional_records,
+            answers,
+            authority_records,
+            additional_records,
+            edns,
         })
     }
 
-    fn parse_questions(query_buffer: &[u8], question_count: u16) -> Vec<Question> {
+    /// Builds an EDNS query for `name` with recursion not desired, as sent
+    /// to authoritative servers.
+    pub fn query(transaction_id: u16, name: &str, record_type: RecordType) -> Self {
+        DNSMessage {
+            header: Header {
+                transaction_id,
+                ..Header::default()
+            },
+            questions: vec![Question {
+                name: name.to_string(),
+                record_type,
+                class: RecordClass::IN,
+            }],
+            answers: Vec::new(),
+            authority_records: Vec::new(),
+            additional_records: Vec::new(),
+            edns: Some(Edns::new(SERVER_UDP_PAYLOAD)),
+        }
+    }
+
+    /// Starts a response to `query`: same ID, opcode, RD and CD bits and
+    /// question.
+    /// EDNS is answered with EDNS, echoing the DO bit.
+    pub fn response_to(query: &DNSMessage) -> Self {
+        DNSMessage {
+            header: Header {
+                transaction_id: query.header.transaction_id,
+                flags: Flags {
+                    qr: true,
+                    opcode: query.header.flags.opcode,
+                    rd: query.header.flags.rd,
+                    cd: query.header.flags.cd,
+                    ..Flags::default()
+                },
+                ..Header::default()
+            },
+            questions: query.questions.clone(),
+            answers: Vec::new(),
+            authority_records: Vec::new(),
+            additional_records: Vec::new(),
+            edns: query.edns.as_ref().map(|edns| Edns {
+                dnssec_ok: edns.dnssec_ok,
+                ..Edns::new(SERVER_UDP_PAYLOAD)
+            }),
+        }
+    }
+
+    /// The largest UDP response the sender of this query can accept, capped
+    /// by what we are willing to send.
+    pub fn max_response_size(&self) -> usize {
+        match &self.edns {
+            Some(edns) => {
+                (edns.udp_payload_size.min(SERVER_UDP_PAYLOAD) as usize).max(MAX_UDP_PAYLOAD)
+            }
+            None => MAX_UDP_PAYLOAD,
+        }
+    }
+
+    /// Adds A and AAAA records for the hosts named by NS, MX and SRV records
+    /// in the answer and authority sections (RFC 1035 section 3.3.9,
+    /// RFC 2782). `resolve` supplies whatever addresses are known locally.
+    pub fn add_additional_addresses<F>(&mut self, resolve: F)
+    where
+        F: Fn(&str, RecordType) -> Vec<ResourceRecord>,
+    {
+        let targets: Vec<String> = self
+            .answers
+            .iter()
+            .chain(&self.authority_records)
+            .filter(|record| {
+                matches!(
+                    record.record_type,
+                    RecordType::NS | RecordType::MX | RecordType::SRV
+                )
+            })
+            .filter_map(ResourceRecord::target_name)
+            .collect();
+
+        for target in targets {
+            // An SRV target of "." means the service is not available.
+            if target.is_empty() {
+                continue;
+            }
+            for record_type in [RecordType::A, RecordType::AAAA] {
+                for record in resolve(&target, record_type) {
+                    let duplicate = self
+                        .answers
+                        .iter()
+                        .chain(&self.additional_records)
+                        .any(|existing| existing == &record);
+                    if !duplicate {
+                        self.additional_records.push(record);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn set_response_code(&mut self, rcode: ResponseCode) {
+        self.header.flags.rcode = rcode as u8;
+    }
+
+    /// Explains an error to clients that speak EDNS (RFC 8914); others
+    /// have nowhere to put it.
+    pub fn set_extended_error(&mut self, error: ExtendedError, text: &str) {
+        if let Some(edns) = self.edns.as_mut() {
+            edns.set_extended_error(error, text);
+        }
+    }
+
+    /// Removes the RRSIG, NSEC and NSEC3 records a client that did not set
+    /// DO has no use for (RFC 4035 section 3.2.1), unless it asked for them.
+    pub fn strip_dnssec_records(&mut self) {
+        let asked: Vec<RecordType> = self.questions.iter().map(|q| q.record_type).collect();
+        let keep = |record: &ResourceRecord| {
+            !matches!(
+                record.record_type,
+                RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3
+            ) || asked.contains(&record.record_type)
+        };
+        self.answers.retain(keep);
+        self.authority_records.retain(keep);
+        self.additional_records.retain(keep);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with_limit(u16::MAX as usize)
+    }
+
+    /// Serializes the message in at most `limit` bytes. Additional records
+    /// that do not fit are left out without setting TC, since they are only
+    /// a convenience; if the answer and authority sections themselves do not
+    /// fit, they are dropped and TC is set so the client retries over TCP.
+    pub fn to_bytes_with_limit(&self, limit: usize) -> Vec<u8> {
+        let mut header = self.header.clone();
+        let opt_length = self.edns.as_ref().map_or(0, Edns::wire_length);
+
+        let mut out = vec![0u8; 12];
+        let mut compression = NameCompression::default();
+        for question in &self.questions {
+            question.write(&mut out, &mut compression);
+        }
+        let questions_end = out.len();
+
+        for record in self.answers.iter().chain(&self.authority_records) {
+            record.write(&mut out, &mut compression);
+        }
+        header.answer_count = self.answers.len() as u16;
+        header.authority_count = self.authority_records.len() as u16;
+        header.additional_count = 0;
+
+        if out.len() + opt_length > limit {
+            out.truncate(questions_end);
+            header.flags.tc = true;
+            header.answer_count = 0;
+            header.authority_count = 0;
+        } else {
+            for record in &self.additional_records {
+                let mark = out.len();
+                record.write(&mut out, &mut compression);
+                if out.len() + opt_length > limit {
+                    out.truncate(mark);
+                    compression.forget_from(mark);
+                    break;
+                }
+                header.additional_count += 1;
+            }
+        }
+
+        if let Some(edns) = &self.edns {
+            edns.write(&mut out);
+            header.additional_count += 1;
+        }
+
+        header.question_count = self.questions.len() as u16;
+        out[..12].copy_from_slice(&header.to_bytes());
+        out
+    }
+
+    fn parse_questions(
+        query_buffer: &[u8],
+        question_count: u16,
+    ) -> Result<(Vec<Question>, usize), DNSParseError> {
         let mut questions = Vec::new();
         let mut index = 12; // Skip the header
         for _ in 0..question_count {
-            let question = Question::parse(&query_buffer[index..]);
+            let (question, next) = Question::parse(query_buffer, index)?;
             questions.push(question);
-            index += 1; // Move to the next question
+            index = next; // Move to the next question
+        }
+        Ok((questions, index))
+    }
+
+    fn parse_records(
+        query_buffer: &[u8],
+        mut index: usize,
+        count: u16,
+    ) -> Result<(Vec<ResourceRecord>, usize), DNSParseError> {
+        let mut records = Vec::new();
+        for _ in 0..count {
+            let (record, next) = ResourceRecord::parse(query_buffer, index)?;
+            records.push(record);
+            index = next; // Move to the next record
+        }
+        Ok((records, index))
+    }
+
+    // Like `parse_records`, but lifts the OPT pseudo-record out of the section.
+    fn parse_additional(
+        query_buffer: &[u8],
+        mut index: usize,
+        count: u16,
+    ) -> Result<(Vec<ResourceRecord>, Option<Edns>), DNSParseError> {
+        let mut records = Vec::new();
+        let mut edns = None;
+        for _ in 0..count {
+            let (_, type_offset) = parse_name(query_buffer, index)?;
+            let record_type = query_buffer
+                .get(type_offset..type_offset + 2)
+                .map(|bytes| RecordType::from_u16(u16::from_be_bytes([bytes[0], bytes[1]])));
+            if record_type == Some(RecordType::OPT) {
+                let (opt, next) = Edns::parse(query_buffer, index)?;
+                edns = Some(opt);
+                index = next;
+            } else {
+                let (record, next) = ResourceRecord::parse(query_buffer, index)?;
+                records.push(record);
+                index = next;
+            }
+        }
+        Ok((records, edns))
+    }
+}
+
+/// Tracks where names were written in a message so later occurrences can be
+/// replaced by a pointer (RFC 1035 section 4.1.4).
+#[derive(Default)]
+pub struct NameCompression {
+    offsets: HashMap<String, u16>,
+}
+
+impl NameCompression {
+    pub fn write_name(&mut self, name: &str, out: &mut Vec<u8>) {
+        let mut rest = name;
+        while !rest.is_empty() {
+            let key = rest.to_ascii_lowercase();
+            if let Some(&offset) = self.offsets.get(&key) {
+                out.extend_from_slice(&(0xC000 | offset).to_be_bytes());
+                return;
+            }
+            // Pointers only have 14 bits of offset.
+            if out.len() < 0x3FFF {
+                self.offsets.insert(key, out.len() as u16);
+            }
+            let (label, remainder) = rest.split_once('.').unwrap_or((rest, ""));
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+            rest = remainder;
         }
-        questions
+        out.push(0);
+    }
+
+    /// Drops pointers to anything written at or after `offset`, for when the
+    /// message is cut back to that length.
+    pub fn forget_from(&mut self, offset: usize) {
+        self.offsets
+            .retain(|_, &mut written| (written as usize) < offset);
     }
 }
 
@@ -77,7 +328,60 @@ impl fmt::Display for DNSParseError {
             DNSParseError::InvalidHeader => write!(f, "Invalid DNS Header"),
             DNSParseError::InvalidQuestion => write!(f, "Invalid DNS Question"),
             DNSParseError::InvalidResourceRecord => write!(f, "Invalid DNS Resource Record"),
+            DNSParseError::InvalidName => write!(f, "Invalid DNS Name"),
             DNSParseError::BufferTooShort => write!(f, "Buffer is too short"),
         }
     }
 }
+
+impl std::error::Error for DNSParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(answers: usize, additional: usize) -> DNSMessage {
+        let query = DNSMessage::query(1, "example.test", RecordType::TXT);
+        let mut response = DNSMessage::response_to(&query);
+        let record = |name: &str, length: usize| {
+            ResourceRecord::new(
+                name.to_string(),
+                RecordType::TXT,
+                RecordClass::IN,
+                60,
+                [vec![length as u8 - 1], vec![b'x'; length - 1]].concat(),
+            )
+        };
+        response.answers = (0..answers).map(|_| record("example.test", 100)).collect();
+        response.additional_records = (0..additional)
+            .map(|n| record(&format!("extra{}.example.test", n), 100))
+            .collect();
+        response
+    }
+
+    #[test]
+    fn drops_additional_records_before_truncating() {
+        let bytes = response(2, 10).to_bytes_with_limit(MAX_UDP_PAYLOAD);
+        assert!(bytes.len() <= MAX_UDP_PAYLOAD);
+        let parsed = DNSMessage::parse(&bytes).unwrap();
+        assert!(!parsed.header.flags.tc);
+        assert_eq!(parsed.answers.len(), 2);
+        assert!(!parsed.additional_records.is_empty());
+        assert!(parsed.additional_records.len() < 10);
+        // The OPT record stays whatever else is dropped.
+        assert!(parsed.edns.is_some());
+        let whole = DNSMessage::parse(&response(2, 10).to_bytes()).unwrap();
+        assert_eq!(whole.additional_records.len(), 10);
+    }
+
+    #[test]
+    fn truncates_when_the_answer_does_not_fit() {
+        let bytes = response(6, 2).to_bytes_with_limit(MAX_UDP_PAYLOAD);
+        let parsed = DNSMessage::parse(&bytes).unwrap();
+        assert!(parsed.header.flags.tc);
+        assert_eq!(parsed.questions.len(), 1);
+        assert!(parsed.answers.is_empty());
+        assert!(parsed.additional_records.is_empty());
+        assert!(parsed.edns.is_some());
+    }
+}
@@ -1,4 +1,10 @@
-pub mod message;
+pub mod cookie;
+pub mod dnscrypt;
+pub mod dnssec;
+pub mod edns;
 pub mod header;
+pub mod message;
+pub mod name;
 pub mod question;
-pub mod resource_record;
\ No newline at end of file
+pub mod resource_record;
+pub mod tsig;
@@ -0,0 +1,112 @@
+use std::net::IpAddr;
+
+use crate::dns::message::DNSParseError;
+
+// Names are kept in presentation form without the trailing dot, so the root
+// is the empty string and `www.example.com` is stored as such.
+
+const MAX_POINTER_JUMPS: usize = 64;
+
+/// Appends the uncompressed wire form of `name` to `out`.
+pub fn encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in labels(name) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// Reads a possibly compressed name starting at `offset` in `buf`, returning
+/// the name and the offset just past it in the original (uncompressed) stream.
+pub fn parse_name(buf: &[u8], offset: usize) -> Result<(String, usize), DNSParseError> {
+    let mut name = String::new();
+    let mut index = offset;
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let length = *buf.get(index).ok_or(DNSParseError::BufferTooShort)? as usize;
+        match length & 0xC0 {
+            0x00 if length == 0 => {
+                index += 1;
+                break;
+            }
+            0x00 => {
+                let label = buf
+                    .get(index + 1..index + 1 + length)
+                    .ok_or(DNSParseError::BufferTooShort)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                if name.len() > 255 {
+                    return Err(DNSParseError::InvalidName);
+                }
+                index += 1 + length;
+            }
+            0xC0 => {
+                let low = *buf.get(index + 1).ok_or(DNSParseError::BufferTooShort)? as usize;
+                jumps += 1;
+                if jumps > MAX_POINTER_JUMPS {
+                    return Err(DNSParseError::InvalidName);
+                }
+                end.get_or_insert(index + 2);
+                index = ((length & 0x3F) << 8) | low;
+            }
+            _ => return Err(DNSParseError::InvalidName),
+        }
+    }
+
+    Ok((name, end.unwrap_or(index)))
+}
+
+/// Iterates over the labels of a name, yielding nothing for the root.
+pub fn labels(name: &str) -> impl DoubleEndedIterator<Item = &str> {
+    name.split('.').filter(|label| !label.is_empty())
+}
+
+/// Case-insensitive comparison as required by RFC 4343.
+pub fn names_equal(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// Returns true when `name` is `ancestor` or lies beneath it.
+pub fn is_subdomain(name: &str, ancestor: &str) -> bool {
+    if ancestor.is_empty() || names_equal(name, ancestor) {
+        return true;
+    }
+    name.len() > ancestor.len()
+        && name.as_bytes()[name.len() - ancestor.len() - 1] == b'.'
+        && names_equal(&name[name.len() - ancestor.len()..], ancestor)
+}
+
+/// Returns the name with its leftmost label removed, or `None` for the root.
+pub fn parent(name: &str) -> Option<&str> {
+    if name.is_empty() {
+        return None;
+    }
+    Some(name.split_once('.').map_or("", |(_, rest)| rest))
+}
+
+/// Lower-cases a name for use as a lookup key.
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The `in-addr.arpa` or `ip6.arpa` name PTR queries for `address` use.
+pub fn reverse_name(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(v6) => {
+            let mut name = String::with_capacity(72);
+            for byte in v6.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0x0f, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
@@ -1,6 +1,8 @@
+use super::message::{DNSParseError, NameCompression};
+use super::name::parse_name;
 use super::resource_record::{RecordClass, RecordType};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Question {
     pub name: String,
     pub record_type: RecordType,
@@ -8,43 +10,35 @@ pub struct Question {
 }
 
 impl Question {
-    pub fn parse(query_buffer: &[u8]) -> Self {
-        let mut index = 0;
-
+    /// Parses the question at `offset`, returning it with the offset of the
+    /// next section entry.
+    pub fn parse(query_buffer: &[u8], offset: usize) -> Result<(Self, usize), DNSParseError> {
         // Parse the domain name
-        let mut domain_name = String::new();
-        loop {
-            let length = query_buffer[index] as usize;
-            if length == 0 {
-                index += 1; // Move past the null byte
-                break; // End of the domain name
-            }
-            index += 1;
-            domain_name.push_str(&String::from_utf8_lossy(
-                &query_buffer[index..index + length],
-            ));
-            index += length;
-            domain_name.push('.'); // Add the dot between labels
-        }
-        domain_name.pop(); // Remove the trailing dot
+        let (domain_name, index) = parse_name(query_buffer, offset)?;
+
+        let fixed = query_buffer
+            .get(index..index + 4)
+            .ok_or(DNSParseError::InvalidQuestion)?;
 
         // Parse the query type (next 2 bytes)
-        let query_type = RecordType::from_u16(u16::from_be_bytes([
-            query_buffer[index],
-            query_buffer[index + 1],
-        ]));
-        index += 2;
+        let query_type = RecordType::from_u16(u16::from_be_bytes([fixed[0], fixed[1]]));
 
         // Parse the query class (next 2 bytes)
-        let query_class = RecordClass::from_u16(u16::from_be_bytes([
-            query_buffer[index],
-            query_buffer[index + 1],
-        ]));
+        let query_class = RecordClass::from_u16(u16::from_be_bytes([fixed[2], fixed[3]]));
+
+        Ok((
+            Question {
+                name: domain_name,
+                record_type: query_type,
+                class: query_class,
+            },
+            index + 4,
+        ))
+    }
 
-        Question {
-            name: domain_name,
-            record_type: query_type,
-            class: query_class,
-        }
+    pub fn write(&self, out: &mut Vec<u8>, compression: &mut NameCompression) {
+        compression.write_name(&self.name, out);
+        out.extend_from_slice(&self.record_type.to_u16().to_be_bytes());
+        out.extend_from_slice(&self.class.to_u16().to_be_bytes());
     }
 }
@@ -1,4 +1,9 @@
-#[derive(Debug)]
+use std::fmt;
+
+use super::message::{DNSParseError, NameCompression};
+use super::name::{encode_name, parse_name};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ResourceRecord {
     pub name: String,
     pub record_type: RecordType,
@@ -9,69 +14,159 @@ pub struct ResourceRecord {
 }
 
 impl ResourceRecord {
-    pub fn parse(query_buffer: &[u8]) -> Self {
-        let mut index = 0;
+    pub fn new(
+        name: String,
+        record_type: RecordType,
+        class: RecordClass,
+        ttl: u32,
+        data: Vec<u8>,
+    ) -> Self {
+        ResourceRecord {
+            name,
+            record_type,
+            class,
+            ttl,
+            data_length: data.len() as u16,
+            data,
+        }
+    }
 
+    /// Parses the record at `offset`, returning it with the offset of the
+    /// next record. Compressed names inside RDATA are expanded so the record
+    /// stays meaningful once it is copied out of the message.
+    pub fn parse(query_buffer: &[u8], offset: usize) -> Result<(Self, usize), DNSParseError> {
         // Parse the domain name
-        let mut domain_name = String::new();
-        loop {
-            let length = query_buffer[index] as usize;
-            if length == 0 {
-                index += 1; // Move past the null byte
-                break; // End of the domain name
-            }
-            index += 1;
-            domain_name.push_str(&String::from_utf8_lossy(
-                &query_buffer[index..index + length],
-            ));
-            index += length;
-            domain_name.push('.'); // Add the dot between labels
-        }
-        domain_name.pop(); // Remove the trailing dot
+        let (domain_name, index) = parse_name(query_buffer, offset)?;
+
+        let fixed = query_buffer
+            .get(index..index + 10)
+            .ok_or(DNSParseError::InvalidResourceRecord)?;
 
         // Parse the query type (next 2 bytes)
-        let record_type = RecordType::from_u16(u16::from_be_bytes([
-            query_buffer[index],
-            query_buffer[index + 1],
-        ]));
-        index += 2;
+        let record_type = RecordType::from_u16(u16::from_be_bytes([fixed[0], fixed[1]]));
 
         // Parse the query class (next 2 bytes)
-        let class = RecordClass::from_u16(u16::from_be_bytes([
-            query_buffer[index],
-            query_buffer[index + 1],
-        ]));
-        index += 2;
+        let class = RecordClass::from_u16(u16::from_be_bytes([fixed[2], fixed[3]]));
 
         // Parse the TTL (next 4 bytes)
-        let ttl = u32::from_be_bytes([
-            query_buffer[index],
-            query_buffer[index + 1],
-            query_buffer[index + 2],
-            query_buffer[index + 3],
-        ]);
-        index += 4;
+        let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
 
         // Parse the data length (next 2 bytes)
-        let data_length = u16::from_be_bytes([query_buffer[index], query_buffer[index + 1]]);
-        index += 2;
+        let data_length = u16::from_be_bytes([fixed[8], fixed[9]]);
+        let data_start = index + 10;
+        let data_end = data_start + data_length as usize;
+        if data_end > query_buffer.len() {
+            return Err(DNSParseError::InvalidResourceRecord);
+        }
 
         // Parse the data
-        let data = query_buffer[index..index + data_length as usize].to_vec();
+        let data = expand_rdata(query_buffer, data_start, data_end, record_type)?;
 
-        ResourceRecord {
-            name: domain_name,
-            record_type,
-            class,
-            ttl,
-            data_length,
-            data,
+        Ok((
+            ResourceRecord::new(domain_name, record_type, class, ttl, data),
+            data_end,
+        ))
+    }
+
+    /// Appends the record in wire format. The owner name is compressed
+    /// against names already written; RDATA is copied as stored.
+    pub fn write(&self, out: &mut Vec<u8>, compression: &mut NameCompression) {
+        compression.write_name(&self.name, out);
+        out.extend_from_slice(&self.record_type.to_u16().to_be_bytes());
+        out.extend_from_slice(&self.class.to_u16().to_be_bytes());
+        out.extend_from_slice(&self.ttl.to_be_bytes());
+        out.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.data);
+    }
+
+    /// The RDATA in DNSSEC canonical form (RFC 4034 section 6.2): names
+    /// embedded in it are lower-cased.
+    pub fn canonical_data(&self) -> Vec<u8> {
+        let Some((prefix, names, _)) = name_layout(self.record_type) else {
+            return self.data.clone();
+        };
+        let Some(mut data) = self.data.get(..prefix).map(<[u8]>::to_vec) else {
+            return self.data.clone();
+        };
+        let mut index = prefix;
+        for _ in 0..names {
+            let Ok((name, next)) = parse_name(&self.data, index) else {
+                return self.data.clone();
+            };
+            encode_name(&name.to_ascii_lowercase(), &mut data);
+            index = next;
         }
+        data.extend_from_slice(&self.data[index..]);
+        data
+    }
+
+    /// Returns the first domain name carried in the RDATA of record types
+    /// that point at another name (NS, CNAME, MX, SRV, ...).
+    pub fn target_name(&self) -> Option<String> {
+        let offset = match self.record_type {
+            RecordType::NS | RecordType::CNAME | RecordType::PTR | RecordType::DNAME => 0,
+            RecordType::MX | RecordType::AFSDB | RecordType::RT | RecordType::KX => 2,
+            RecordType::SRV => 6,
+            _ => return None,
+        };
+        parse_name(&self.data, offset).ok().map(|(name, _)| name)
     }
 }
 
+// Where the names sit in the RDATA of types that embed them: (bytes before
+// the first name, number of names, bytes after the names).
+fn name_layout(record_type: RecordType) -> Option<(usize, usize, usize)> {
+    match record_type {
+        RecordType::NS
+        | RecordType::CNAME
+        | RecordType::PTR
+        | RecordType::DNAME
+        | RecordType::MB
+        | RecordType::MD
+        | RecordType::MF
+        | RecordType::MG
+        | RecordType::MR => Some((0, 1, 0)),
+        RecordType::MX | RecordType::AFSDB | RecordType::RT | RecordType::KX => Some((2, 1, 0)),
+        RecordType::SRV => Some((6, 1, 0)),
+        RecordType::MINFO | RecordType::RP => Some((0, 2, 0)),
+        RecordType::SOA => Some((0, 2, 20)),
+        _ => None,
+    }
+}
+
+// Rewrites the RDATA of RFC 1035 types (plus MX-like and SRV records) with
+// any compression pointers expanded. Other types are copied verbatim.
+fn expand_rdata(
+    buf: &[u8],
+    start: usize,
+    end: usize,
+    record_type: RecordType,
+) -> Result<Vec<u8>, DNSParseError> {
+    let Some(layout) = name_layout(record_type) else {
+        return Ok(buf[start..end].to_vec());
+    };
+    let (prefix, names, suffix) = layout;
+
+    if start + prefix > end {
+        return Err(DNSParseError::InvalidResourceRecord);
+    }
+    let mut data = buf[start..start + prefix].to_vec();
+    let mut index = start + prefix;
+    for _ in 0..names {
+        let (name, next) = parse_name(buf, index)?;
+        encode_name(&name, &mut data);
+        index = next;
+    }
+    if index + suffix != end {
+        return Err(DNSParseError::InvalidResourceRecord);
+    }
+    data.extend_from_slice(&buf[index..end]);
+    Ok(data)
+}
+
 #[repr(u16)]
-#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RecordClass {
     IN = 1,
     CH = 3,
@@ -79,7 +174,7 @@ pub enum RecordClass {
     NONE = 254,
     ANY = 255,
     OPT = 41,
-    Unknown = 0,
+    Unknown(u16) = 0,
 }
 
 impl RecordClass {
@@ -91,12 +186,52 @@ impl RecordClass {
             254 => RecordClass::NONE,
             255 => RecordClass::ANY,
             41 => RecordClass::OPT,
-            _ => RecordClass::Unknown,
+            other => RecordClass::Unknown(other),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            RecordClass::IN => 1,
+            RecordClass::CH => 3,
+            RecordClass::HS => 4,
+            RecordClass::NONE => 254,
+            RecordClass::ANY => 255,
+            RecordClass::OPT => 41,
+            RecordClass::Unknown(value) => value,
+        }
+    }
+
+    /// Looks up a class by its master file mnemonic or the generic
+    /// `CLASSnnn` form from RFC 3597.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let upper = name.to_ascii_uppercase();
+        if let Some(number) = upper.strip_prefix("CLASS") {
+            return number.parse::<u16>().ok().map(RecordClass::from_u16);
+        }
+        match upper.as_str() {
+            "IN" => Some(RecordClass::IN),
+            "CH" | "CS" => Some(RecordClass::CH),
+            "HS" => Some(RecordClass::HS),
+            "NONE" => Some(RecordClass::NONE),
+            "ANY" => Some(RecordClass::ANY),
+            _ => None,
         }
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for RecordClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordClass::OPT | RecordClass::Unknown(_) => write!(f, "CLASS{}", self.to_u16()),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+#[repr(u16)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RecordType {
     A = 1,            // IPv4 Address
     NS = 2,           // Name Server
@@ -184,7 +319,7 @@ pub enum RecordType {
     TA = 32768,       // Trust Authority
     DLV = 32769,      // DNSSEC Lookaside Validation
     Reserved = 65535, // Reserved
-    Unknown = 0,      // Unknown query type
+    Unknown(u16) = 0, // Any other type, by its code
 }
 
 impl RecordType {
@@ -276,7 +411,207 @@ impl RecordType {
             32768 => RecordType::TA,
             32769 => RecordType::DLV,
             65535 => RecordType::Reserved,
-            _ => RecordType::Unknown,
+            other => RecordType::Unknown(other),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::NS => 2,
+            RecordType::MD => 3,
+            RecordType::MF => 4,
+            RecordType::CNAME => 5,
+            RecordType::SOA => 6,
+            RecordType::MB => 7,
+            RecordType::MG => 8,
+            RecordType::MR => 9,
+            RecordType::Null => 10,
+            RecordType::PTR => 12,
+            RecordType::HINFO => 13,
+            RecordType::MINFO => 14,
+            RecordType::MX => 15,
+            RecordType::TXT => 16,
+            RecordType::RP => 17,
+            RecordType::AFSDB => 18,
+            RecordType::X25 => 19,
+            RecordType::ISDN => 20,
+            RecordType::RT => 21,
+            RecordType::NSAPPTR => 23,
+            RecordType::SIG => 24,
+            RecordType::KEY => 25,
+            RecordType::PX => 26,
+            RecordType::GPOS => 27,
+            RecordType::AAAA => 28,
+            RecordType::LOC => 29,
+            RecordType::NXT => 30,
+            RecordType::EID => 31,
+            RecordType::NIMLOC => 32,
+            RecordType::SRV => 33,
+            RecordType::ATMA => 34,
+            RecordType::NAPTR => 35,
+            RecordType::KX => 36,
+            RecordType::CERT => 37,
+            RecordType::DNAME => 39,
+            RecordType::OPT => 41,
+            RecordType::APL => 42,
+            RecordType::DS => 43,
+            RecordType::SSHFP => 44,
+            RecordType::IPSECKEY => 45,
+            RecordType::RRSIG => 46,
+            RecordType::NSEC => 47,
+            RecordType::DNSKEY => 48,
+            RecordType::DHCID => 49,
+            RecordType::NSEC3 => 50,
+            RecordType::NSEC3PARAM => 51,
+            RecordType::TLSA => 52,
+            RecordType::SMIMEA => 53,
+            RecordType::HIP => 55,
+            RecordType::NINFO => 56,
+            RecordType::RKEY => 57,
+            RecordType::TALINK => 58,
+            RecordType::CDS => 59,
+            RecordType::CDNSKEY => 60,
+            RecordType::OPENPGPKEY => 61,
+            RecordType::CSYNC => 62,
+            RecordType::ZONEMD => 63,
+            RecordType::SVCB => 64,
+            RecordType::HTTPS => 65,
+            RecordType::SPF => 99,
+            RecordType::UINFO => 100,
+            RecordType::UID => 101,
+            RecordType::GID => 102,
+            RecordType::UNSPEC => 103,
+            RecordType::NID => 104,
+            RecordType::L32 => 105,
+            RecordType::L64 => 106,
+            RecordType::LP => 107,
+            RecordType::EUI48 => 108,
+            RecordType::EUI64 => 109,
+            RecordType::NXNAME => 128,
+            RecordType::URI => 256,
+            RecordType::CAA => 257,
+            RecordType::AVC => 258,
+            RecordType::AMTRELAY => 260,
+            RecordType::TKEY => 249,
+            RecordType::TSIG => 250,
+            RecordType::IXFR => 251,
+            RecordType::AXFR => 252,
+            RecordType::MAILB => 253,
+            RecordType::MAILA => 254,
+            RecordType::ANY => 255,
+            RecordType::TA => 32768,
+            RecordType::DLV => 32769,
+            RecordType::Reserved => 65535,
+            RecordType::Unknown(value) => value,
+        }
+    }
+
+    /// Looks up a type by its master file mnemonic (e.g. `MX`) or by the
+    /// generic `TYPEnnn` form from RFC 3597.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let upper = name.to_ascii_uppercase();
+        if let Some(number) = upper.strip_prefix("TYPE") {
+            return number.parse::<u16>().ok().map(RecordType::from_u16);
+        }
+        match upper.as_str() {
+            "A" => Some(RecordType::A),
+            "NS" => Some(RecordType::NS),
+            "MD" => Some(RecordType::MD),
+            "MF" => Some(RecordType::MF),
+            "CNAME" => Some(RecordType::CNAME),
+            "SOA" => Some(RecordType::SOA),
+            "MB" => Some(RecordType::MB),
+            "MG" => Some(RecordType::MG),
+            "MR" => Some(RecordType::MR),
+            "NULL" => Some(RecordType::Null),
+            "PTR" => Some(RecordType::PTR),
+            "HINFO" => Some(RecordType::HINFO),
+            "MINFO" => Some(RecordType::MINFO),
+            "MX" => Some(RecordType::MX),
+            "TXT" => Some(RecordType::TXT),
+            "RP" => Some(RecordType::RP),
+            "AFSDB" => Some(RecordType::AFSDB),
+            "X25" => Some(RecordType::X25),
+            "ISDN" => Some(RecordType::ISDN),
+            "RT" => Some(RecordType::RT),
+            "NSAP-PTR" => Some(RecordType::NSAPPTR),
+            "SIG" => Some(RecordType::SIG),
+            "KEY" => Some(RecordType::KEY),
+            "PX" => Some(RecordType::PX),
+            "GPOS" => Some(RecordType::GPOS),
+            "AAAA" => Some(RecordType::AAAA),
+            "LOC" => Some(RecordType::LOC),
+            "NXT" => Some(RecordType::NXT),
+            "EID" => Some(RecordType::EID),
+            "NIMLOC" => Some(RecordType::NIMLOC),
+            "SRV" => Some(RecordType::SRV),
+            "ATMA" => Some(RecordType::ATMA),
+            "NAPTR" => Some(RecordType::NAPTR),
+            "KX" => Some(RecordType::KX),
+            "CERT" => Some(RecordType::CERT),
+            "DNAME" => Some(RecordType::DNAME),
+            "OPT" => Some(RecordType::OPT),
+            "APL" => Some(RecordType::APL),
+            "DS" => Some(RecordType::DS),
+            "SSHFP" => Some(RecordType::SSHFP),
+            "IPSECKEY" => Some(RecordType::IPSECKEY),
+            "RRSIG" => Some(RecordType::RRSIG),
+            "NSEC" => Some(RecordType::NSEC),
+            "DNSKEY" => Some(RecordType::DNSKEY),
+            "DHCID" => Some(RecordType::DHCID),
+            "NSEC3" => Some(RecordType::NSEC3),
+            "NSEC3PARAM" => Some(RecordType::NSEC3PARAM),
+            "TLSA" => Some(RecordType::TLSA),
+            "SMIMEA" => Some(RecordType::SMIMEA),
+            "HIP" => Some(RecordType::HIP),
+            "NINFO" => Some(RecordType::NINFO),
+            "RKEY" => Some(RecordType::RKEY),
+            "TALINK" => Some(RecordType::TALINK),
+            "CDS" => Some(RecordType::CDS),
+            "CDNSKEY" => Some(RecordType::CDNSKEY),
+            "OPENPGPKEY" => Some(RecordType::OPENPGPKEY),
+            "CSYNC" => Some(RecordType::CSYNC),
+            "ZONEMD" => Some(RecordType::ZONEMD),
+            "SVCB" => Some(RecordType::SVCB),
+            "HTTPS" => Some(RecordType::HTTPS),
+            "SPF" => Some(RecordType::SPF),
+            "UINFO" => Some(RecordType::UINFO),
+            "UID" => Some(RecordType::UID),
+            "GID" => Some(RecordType::GID),
+            "UNSPEC" => Some(RecordType::UNSPEC),
+            "NID" => Some(RecordType::NID),
+            "L32" => Some(RecordType::L32),
+            "L64" => Some(RecordType::L64),
+            "LP" => Some(RecordType::LP),
+            "EUI48" => Some(RecordType::EUI48),
+            "EUI64" => Some(RecordType::EUI64),
+            "NXNAME" => Some(RecordType::NXNAME),
+            "URI" => Some(RecordType::URI),
+            "CAA" => Some(RecordType::CAA),
+            "AVC" => Some(RecordType::AVC),
+            "AMTRELAY" => Some(RecordType::AMTRELAY),
+            "TKEY" => Some(RecordType::TKEY),
+            "TSIG" => Some(RecordType::TSIG),
+            "IXFR" => Some(RecordType::IXFR),
+            "AXFR" => Some(RecordType::AXFR),
+            "MAILB" => Some(RecordType::MAILB),
+            "MAILA" => Some(RecordType::MAILA),
+            "ANY" => Some(RecordType::ANY),
+            "TA" => Some(RecordType::TA),
+            "DLV" => Some(RecordType::DLV),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordType::Null => write!(f, "NULL"),
+            RecordType::NSAPPTR => write!(f, "NSAP-PTR"),
+            RecordType::Reserved | RecordType::Unknown(_) => write!(f, "TYPE{}", self.to_u16()),
+            other => write!(f, "{:?}", other),
         }
     }
 }
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::hmac;
+
+use crate::dns::message::DNSParseError;
+use crate::dns::name::{encode_name, normalize, parse_name};
+use crate::dns::question::Question;
+use crate::dns::resource_record::{RecordClass, RecordType, ResourceRecord};
+
+// Seconds of clock skew allowed between signer and verifier (RFC 8945
+// section 5.2.3 recommends 300).
+const FUDGE: u16 = 300;
+
+/// TSIG error codes (RFC 8945 section 3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigError {
+    Signature = 16,
+    Key = 17,
+    Time = 18,
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsigError::Signature => write!(f, "BADSIG"),
+            TsigError::Key => write!(f, "BADKEY"),
+            TsigError::Time => write!(f, "BADTIME"),
+        }
+    }
+}
+
+/// A shared secret for signing messages.
+pub struct TsigKey {
+    pub name: String,
+    algorithm_name: &'static str,
+    key: hmac::Key,
+}
+
+impl TsigKey {
+    pub fn new(name: &str, algorithm: &str, secret: &[u8]) -> Result<Self, String> {
+        let (algorithm_name, algorithm) = match normalize(algorithm).as_str() {
+            "hmac-sha1" => ("hmac-sha1", hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY),
+            "hmac-sha256" => ("hmac-sha256", hmac::HMAC_SHA256),
+            "hmac-sha384" => ("hmac-sha384", hmac::HMAC_SHA384),
+            "hmac-sha512" => ("hmac-sha512", hmac::HMAC_SHA512),
+            _ => return Err(format!("Unsupported TSIG algorithm {:?}", algorithm)),
+        };
+        Ok(TsigKey {
+            name: normalize(name),
+            algorithm_name,
+            key: hmac::Key::new(algorithm, secret),
+        })
+    }
+
+    /// The size of the TSIG record signing a response with this key adds.
+    pub fn record_length(&self) -> usize {
+        let mut names = Vec::new();
+        encode_name(&self.name, &mut names);
+        encode_name(self.algorithm_name, &mut names);
+        let mac = self.key.algorithm().digest_algorithm().output_len();
+        names.len() + 10 + 16 + mac
+    }
+}
+
+/// The TSIG keys the server knows, by name.
+#[derive(Default)]
+pub struct KeyRing {
+    keys: HashMap<String, TsigKey>,
+}
+
+impl KeyRing {
+    /// Reads TSIG_KEYS, a comma separated list of `name=algorithm:secret`
+    /// entries with base64 secrets, e.g.
+    /// `internal-key=hmac-sha256:c2VjcmV0c2VjcmV0c2VjcmV0`.
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let mut keyring = KeyRing::default();
+        let keys = std::env::var("TSIG_KEYS").unwrap_or_default();
+        for entry in keys.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let invalid = || {
+                format!(
+                    "Invalid TSIG_KEYS entry {:?}, expected name=algorithm:secret",
+                    entry
+                )
+            };
+            let (name, rest) = entry.split_once('=').ok_or_else(invalid)?;
+            let (algorithm, secret) = rest.split_once(':').ok_or_else(invalid)?;
+            let secret = BASE64.decode(secret.trim()).map_err(|_| invalid())?;
+            let key = TsigKey::new(name.trim(), algorithm.trim(), &secret)?;
+            keyring.keys.insert(key.name.clone(), key);
+        }
+        Ok(keyring)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TsigKey> {
+        self.keys.get(&normalize(name))
+    }
+}
+
+/// The TSIG record at the end of a message, with enough context to verify it
+/// and to sign the reply.
+pub struct Signature {
+    pub key_name: String,
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+    // The message as it was before signing: the TSIG record removed, ARCOUNT
+    // reduced and the original ID restored.
+    unsigned: Vec<u8>,
+}
+
+impl Signature {
+    /// Finds the TSIG record of a message. It must be the last additional
+    /// record; messages without one yield `None`.
+    pub fn find(message: &[u8]) -> Result<Option<Self>, DNSParseError> {
+        let count = |at: usize| -> Result<usize, DNSParseError> {
+            message
+                .get(at..at + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+                .ok_or(DNSParseError::BufferTooShort)
+        };
+        let (questions, records) = (count(4)?, count(6)? + count(8)? + count(10)?);
+        // A TSIG record outside the additional section is not a signature.
+        let Some(additional) = count(10)?.checked_sub(1) else {
+            return Ok(None);
+        };
+        let mut offset = 12;
+        for _ in 0..questions {
+            offset = Question::parse(message, offset)?.1;
+        }
+        for _ in 0..records - 1 {
+            offset = ResourceRecord::parse(message, offset)?.1;
+        }
+        let start = offset;
+        let (record, end) = ResourceRecord::parse(message, start)?;
+        if record.record_type != RecordType::TSIG {
+            return Ok(None);
+        }
+        if end != message.len() {
+            return Err(DNSParseError::InvalidResourceRecord);
+        }
+
+        let data = &record.data;
+        let (algorithm, mut at) = parse_name(data, 0)?;
+        let field = |at: usize, len: usize| {
+            data.get(at..at + len)
+                .ok_or(DNSParseError::InvalidResourceRecord)
+        };
+        let u16_at = |at: usize| field(at, 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+        let time = field(at, 6)?;
+        let time_signed = time.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+        let fudge = u16_at(at + 6)?;
+        let mac_len = u16_at(at + 8)? as usize;
+        let mac = field(at + 10, mac_len)?.to_vec();
+        at += 10 + mac_len;
+        let original_id = u16_at(at)?;
+        let error = u16_at(at + 2)?;
+        let other_len = u16_at(at + 4)? as usize;
+        let other = field(at + 6, other_len)?.to_vec();
+
+        let mut unsigned = message[..start].to_vec();
+        unsigned[0..2].copy_from_slice(&original_id.to_be_bytes());
+        unsigned[10..12].copy_from_slice(&(additional as u16).to_be_bytes());
+
+        Ok(Some(Signature {
+            key_name: normalize(&record.name),
+            algorithm: normalize(&algorithm),
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+            unsigned,
+        }))
+    }
+
+    /// The message without its TSIG record, as it was before signing.
+    pub fn unsigned_message(&self) -> &[u8] {
+        &self.unsigned
+    }
+
+    /// Checks a request's MAC and time against the named key.
+    pub fn verify<'k>(&self, keyring: &'k KeyRing) -> Result<&'k TsigKey, TsigError> {
+        self.verify_at(keyring, now())
+    }
+
+    fn verify_at<'k>(&self, keyring: &'k KeyRing, now: u64) -> Result<&'k TsigKey, TsigError> {
+        let key = keyring
+            .get(&self.key_name)
+            .filter(|key| key.algorithm_name == self.algorithm)
+            .ok_or(TsigError::Key)?;
+        let mut data = self.unsigned.clone();
+        data.extend(variables(
+            &self.key_name,
+            &self.algorithm,
+            self.time_signed,
+            self.fudge,
+            self.error,
+            &self.other,
+        ));
+        hmac::verify(&key.key, &data, &self.mac).map_err(|_| TsigError::Signature)?;
+        if now.abs_diff(self.time_signed) > self.fudge as u64 {
+            return Err(TsigError::Time);
+        }
+        Ok(key)
+    }
+
+    /// Signs `response`, a reply to the request this signature came from,
+    /// by appending a TSIG record (RFC 8945 section 5.3).
+    pub fn sign_response(&self, key: &TsigKey, response: &mut Vec<u8>) {
+        self.sign_response_at(key, response, now());
+    }
+
+    fn sign_response_at(&self, key: &TsigKey, response: &mut Vec<u8>, time_signed: u64) {
+        let mut data = (self.mac.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&self.mac);
+        data.extend_from_slice(response);
+        data.extend(variables(
+            &key.name,
+            key.algorithm_name,
+            time_signed,
+            FUDGE,
+            0,
+            &[],
+        ));
+        let mac = hmac::sign(&key.key, &data);
+        append_tsig(
+            response,
+            &key.name,
+            key.algorithm_name,
+            time_signed,
+            mac.as_ref(),
+            self.original_id,
+            0,
+        );
+    }
+
+    /// Appends an unsigned TSIG record carrying `error` to a response, for
+    /// requests that failed verification.
+    pub fn append_error(&self, error: TsigError, response: &mut Vec<u8>) {
+        append_tsig(
+            response,
+            &self.key_name,
+            &self.algorithm,
+            now(),
+            &[],
+            self.original_id,
+            error as u16,
+        );
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// The TSIG fields covered by the MAC besides the message itself.
+fn variables(
+    key_name: &str,
+    algorithm: &str,
+    time_signed: u64,
+    fudge: u16,
+    error: u16,
+    other: &[u8],
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_name(&normalize(key_name), &mut out);
+    out.extend_from_slice(&RecordClass::ANY.to_u16().to_be_bytes());
+    out.extend_from_slice(&0u32.to_be_bytes());
+    encode_name(algorithm, &mut out);
+    out.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+    out.extend_from_slice(&fudge.to_be_bytes());
+    out.extend_from_slice(&error.to_be_bytes());
+    out.extend_from_slice(&(other.len() as u16).to_be_bytes());
+    out.extend_from_slice(other);
+    out
+}
+
+fn append_tsig(
+    message: &mut Vec<u8>,
+    key_name: &str,
+    algorithm: &str,
+    time_signed: u64,
+    mac: &[u8],
+    original_id: u16,
+    error: u16,
+) {
+    let mut data = Vec::new();
+    encode_name(algorithm, &mut data);
+    data.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+    data.extend_from_slice(&FUDGE.to_be_bytes());
+    data.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    data.extend_from_slice(mac);
+    data.extend_from_slice(&original_id.to_be_bytes());
+    data.extend_from_slice(&error.to_be_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes());
+
+    encode_name(key_name, message);
+    message.extend_from_slice(&(RecordType::TSIG.to_u16()).to_be_bytes());
+    message.extend_from_slice(&RecordClass::ANY.to_u16().to_be_bytes());
+    message.extend_from_slice(&0u32.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(&data);
+    let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The vectors below were computed independently with HMAC-SHA256 over
+    // the RFC 8945 section 4.3 digest layout.
+    const SECRET: &str = "dGlueWRucyB0c2lnIHRlc3Qgc2VjcmV0";
+    const TIME: u64 = 1_700_000_000;
+    const REQUEST_MAC: &str = "87ebd75309bcd5edb656e47aa8e9a55b6703393b34e624b48dc9097952097510";
+    const RESPONSE_MAC: &str = "caa61482ddc6bd1e5995cf4f7f74ce49c728ec1816df89ef149a47fe67a511ee";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn keyring() -> KeyRing {
+        let key = TsigKey::new("test.key", "hmac-sha256", &BASE64.decode(SECRET).unwrap()).unwrap();
+        KeyRing {
+            keys: HashMap::from([(key.name.clone(), key)]),
+        }
+    }
+
+    // ID 0x1234 with RD set, asking for example.test A.
+    fn message(flags: u16) -> Vec<u8> {
+        let mut message = [0x1234, flags, 1, 0, 0, 0]
+            .iter()
+            .flat_map(|field: &u16| field.to_be_bytes())
+            .collect();
+        encode_name("example.test", &mut message);
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        message
+    }
+
+    fn request(key_name: &str, mac: &[u8]) -> Vec<u8> {
+        let mut request = message(0x0100);
+        append_tsig(&mut request, key_name, "hmac-sha256", TIME, mac, 0x1234, 0);
+        request
+    }
+
+    #[test]
+    fn verifies_a_known_request() {
+        let keyring = keyring();
+        let request = request("test.key", &hex(REQUEST_MAC));
+        let signature = Signature::find(&request).unwrap().unwrap();
+        assert_eq!(signature.unsigned_message(), message(0x0100));
+        let key = signature.verify_at(&keyring, TIME + 300).unwrap();
+        assert_eq!(key.name, "test.key");
+    }
+
+    #[test]
+    fn rejects_bad_keys_signatures_and_times() {
+        let keyring = keyring();
+        let verify = |request: &[u8], now: u64| {
+            let signature = Signature::find(request).unwrap().unwrap();
+            signature.verify_at(&keyring, now).map(|_| ())
+        };
+        let signed = request("test.key", &hex(REQUEST_MAC));
+        assert_eq!(verify(&signed, TIME - 301), Err(TsigError::Time));
+        assert_eq!(verify(&signed, TIME + 301), Err(TsigError::Time));
+
+        let mut tampered = signed.clone();
+        tampered[3] ^= 0x10;
+        assert_eq!(verify(&tampered, TIME), Err(TsigError::Signature));
+        let mut mac = hex(REQUEST_MAC);
+        mac[0] ^= 1;
+        assert_eq!(
+            verify(&request("test.key", &mac), TIME),
+            Err(TsigError::Signature)
+        );
+
+        let unknown = request("other.key", &hex(REQUEST_MAC));
+        assert_eq!(verify(&unknown, TIME), Err(TsigError::Key));
+        let mut sha1 = message(0x0100);
+        append_tsig(
+            &mut sha1,
+            "test.key",
+            "hmac-sha1",
+            TIME,
+            &[0; 20],
+            0x1234,
+            0,
+        );
+        assert_eq!(verify(&sha1, TIME), Err(TsigError::Key));
+    }
+
+    #[test]
+    fn signs_a_known_response() {
+        let keyring = keyring();
+        let signature = Signature::find(&request("test.key", &hex(REQUEST_MAC)))
+            .unwrap()
+            .unwrap();
+        let mut response = message(0x8180);
+        signature.sign_response_at(keyring.get("test.key").unwrap(), &mut response, TIME + 1);
+        let signed = Signature::find(&response).unwrap().unwrap();
+        assert_eq!(signed.unsigned_message(), message(0x8180));
+        assert_eq!(signed.time_signed, TIME + 1);
+        assert_eq!(signed.mac, hex(RESPONSE_MAC));
+        assert_eq!(signed.error, 0);
+        assert_eq!(
+            response.len() - message(0x8180).len(),
+            keyring.get("test.key").unwrap().record_length()
+        );
+    }
+
+    #[test]
+    fn appends_unsigned_errors() {
+        let signature = Signature::find(&request("other.key", &hex(REQUEST_MAC)))
+            .unwrap()
+            .unwrap();
+        for error in [TsigError::Key, TsigError::Signature, TsigError::Time] {
+            let mut response = message(0x8180);
+            response[0..2].copy_from_slice(&0x4321u16.to_be_bytes());
+            signature.append_error(error, &mut response);
+            let appended = Signature::find(&response).unwrap().unwrap();
+            assert_eq!(appended.key_name, "other.key");
+            assert_eq!(appended.algorithm, "hmac-sha256");
+            assert!(appended.mac.is_empty());
+            assert_eq!(appended.original_id, 0x1234);
+            assert_eq!(appended.error, error as u16);
+        }
+        assert_eq!(TsigError::Time.to_string(), "BADTIME");
+    }
+}
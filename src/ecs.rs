@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::acl::{AccessList, PRIVATE_NETWORKS};
+use crate::cidr::Cidr;
+use crate::config::env_or;
+use crate::dns::edns::ClientSubnet;
+use crate::dns::header::ResponseCode;
+use crate::dns::message::DNSMessage;
+use crate::dns::name::normalize;
+use crate::dns::resource_record::RecordType;
+
+// Upper bound on how long a forwarded answer is kept, whatever its TTLs say.
+const MAX_TTL: u32 = 86400;
+// Past this many answers expired ones are swept, and if that is not enough
+// the oldest go, an eighth of the cache at a time.
+const MAX_ANSWERS: usize = 10_000;
+// Subnets kept for one question; the oldest goes first.
+const MAX_SCOPES: usize = 64;
+
+/// How much of a client's address is revealed to upstream servers with EDNS
+/// Client Subnet (RFC 7871).
+#[derive(Debug, Clone)]
+pub struct EcsConfig {
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Clients whose subnet is never sent.
+    pub exclude: AccessList,
+}
+
+impl EcsConfig {
+    /// Reads EDNS_CLIENT_SUBNET=on, which enables client subnets, along with
+    /// ECS_IPV4_PREFIX (default 24), ECS_IPV6_PREFIX (default 56) and
+    /// ECS_EXCLUDE (networks, default: loopback and private networks).
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        if std::env::var("EDNS_CLIENT_SUBNET").as_deref() != Ok("on") {
+            return Ok(None);
+        }
+        let ipv4_prefix = env_or("ECS_IPV4_PREFIX", 24)?;
+        let ipv6_prefix = env_or("ECS_IPV6_PREFIX", 56)?;
+        if ipv4_prefix > 32 || ipv6_prefix > 128 {
+            return Err("ECS prefix lengths must fit the address family".into());
+        }
+        let exclude = std::env::var("ECS_EXCLUDE").unwrap_or_else(|_| PRIVATE_NETWORKS.to_string());
+        Ok(Some(EcsConfig {
+            ipv4_prefix,
+            ipv6_prefix,
+            exclude: AccessList::parse(&exclude)
+                .map_err(|e| format!("Invalid value for ECS_EXCLUDE: {}", e))?,
+        }))
+    }
+
+    /// The subnet to send upstream for a query. A subnet the client sent
+    /// itself is used in place of its address, but never more of it than
+    /// the configured prefix; a source prefix of 0 asks for none at all.
+    pub fn subnet_for(&self, query: &DNSMessage, client: IpAddr) -> Option<ClientSubnet> {
+        let (address, prefix) = match query.edns.as_ref().and_then(|edns| edns.client_subnet()) {
+            Some(subnet) if subnet.source_prefix == 0 => return None,
+            Some(subnet) => (subnet.address, subnet.source_prefix),
+            None if self.exclude.allows(client) => return None,
+            None => (client, u8::MAX),
+        };
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            IpAddr::V4(_) => address,
+        };
+        let prefix = prefix.min(match address {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        });
+        let network = Cidr::new(address, prefix).expect("prefix checked in config");
+        Some(ClientSubnet {
+            address: network.network(),
+            source_prefix: prefix,
+            scope_prefix: 0,
+        })
+    }
+}
+
+/// Puts back the client's own view of ECS in a response to its query: no
+/// option unless it sent one, and its own subnet when it did, with the
+/// scope the answer to the subnet we `sent` holds for.
+pub fn restore_client_subnet(
+    query: &DNSMessage,
+    sent: Option<&ClientSubnet>,
+    response: &mut DNSMessage,
+) {
+    let Some(query_edns) = &query.edns else {
+        response.edns = None;
+        return;
+    };
+    let scope_prefix = sent
+        .and_then(|sent| answer_scope(sent, response))
+        .map_or(0, |scope| scope.prefix());
+    let Some(edns) = response.edns.as_mut() else {
+        return;
+    };
+    let subnet = query_edns.client_subnet().map(|subnet| ClientSubnet {
+        scope_prefix: scope_prefix.min(subnet.source_prefix),
+        ..subnet
+    });
+    edns.set_client_subnet(subnet);
+}
+
+/// The network an upstream answer to `sent` holds for: its scope prefix of
+/// the subnet, or `None` when the answer holds for every client. Replies
+/// that do not echo the subnet sent are treated as not supporting ECS.
+pub fn answer_scope(sent: &ClientSubnet, response: &DNSMessage) -> Option<Cidr> {
+    let echoed = response.edns.as_ref()?.client_subnet()?;
+    if echoed.address != sent.address
+        || echoed.source_prefix != sent.source_prefix
+        || echoed.scope_prefix == 0
+    {
+        return None;
+    }
+    Cidr::new(sent.address, echoed.scope_prefix.min(sent.source_prefix)).ok()
+}
+
+struct ScopedAnswer {
+    scope: Cidr,
+    response: DNSMessage,
+    stored: Instant,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct ScopedAnswers {
+    answers: HashMap<(String, RecordType), Vec<ScopedAnswer>>,
+    // Answers across all questions, kept as they change.
+    count: usize,
+}
+
+/// Forwarded answers that hold only for clients in a subnet (RFC 7871
+/// section 7.3), so that other clients in it are answered without asking
+/// upstream again. Answers that hold for every client are not cached here.
+#[derive(Default)]
+pub struct SubnetCache {
+    entries: Mutex<ScopedAnswers>,
+}
+
+impl SubnetCache {
+    /// The cached answer to `query` for the most specific scope containing
+    /// the subnet `sent` upstream for it. Its TTLs are reduced by the time
+    /// spent in the cache and its ECS option echoes `sent`.
+    pub fn get(&self, query: &DNSMessage, sent: &ClientSubnet) -> Option<DNSMessage> {
+        let question = query.questions.first()?;
+        let entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let cached = entries
+            .answers
+            .get(&(normalize(&question.name), question.record_type))?
+            .iter()
+            .filter(|cached| cached.expires > now)
+            .filter(|cached| {
+                cached.scope.contains(sent.address) && cached.scope.prefix() <= sent.source_prefix
+            })
+            .max_by_key(|cached| cached.scope.prefix())?;
+
+        let mut response = cached.response.clone();
+        response.header.transaction_id = query.header.transaction_id;
+        let elapsed = now.duration_since(cached.stored).as_secs() as u32;
+        for record in response
+            .answers
+            .iter_mut()
+            .chain(&mut response.authority_records)
+            .chain(&mut response.additional_records)
+        {
+            record.ttl = record.ttl.saturating_sub(elapsed);
+        }
+        if let Some(edns) = response.edns.as_mut() {
+            edns.set_client_subnet(Some(ClientSubnet {
+                scope_prefix: cached.scope.prefix(),
+                ..*sent
+            }));
+        }
+        Some(response)
+    }
+
+    /// Stores an upstream answer to a query that carried `sent`, if the
+    /// upstream scoped it to a subnet. Only answers and negative answers
+    /// with records to take a TTL from are kept.
+    pub fn insert(&self, sent: &ClientSubnet, response: &DNSMessage) {
+        let Some(scope) = answer_scope(sent, response) else {
+            return;
+        };
+        let Some(question) = response.questions.first() else {
+            return;
+        };
+        let rcode = response.header.flags.rcode;
+        if rcode != ResponseCode::NoError as u8 && rcode != ResponseCode::NXDomain as u8 {
+            return;
+        }
+        let Some(ttl) = response
+            .answers
+            .iter()
+            .chain(&response.authority_records)
+            .chain(&response.additional_records)
+            .map(|record| record.ttl)
+            .min()
+        else {
+            return;
+        };
+        let now = Instant::now();
+        let answer = ScopedAnswer {
+            scope,
+            response: response.clone(),
+            stored: now,
+            expires: now + Duration::from_secs(ttl.min(MAX_TTL) as u64),
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.make_room();
+        let key = (normalize(&question.name), question.record_type);
+        let scopes = entries.answers.entry(key).or_default();
+        let before = scopes.len();
+        scopes.retain(|cached| cached.scope != scope);
+        if scopes.len() >= MAX_SCOPES {
+            scopes.remove(0);
+        }
+        scopes.push(answer);
+        let after = scopes.len();
+        entries.count = entries.count + after - before;
+    }
+}
+
+impl ScopedAnswers {
+    fn make_room(&mut self) {
+        if self.count < MAX_ANSWERS {
+            return;
+        }
+        let now = Instant::now();
+        self.retain(|cached| cached.expires > now);
+        if self.count < MAX_ANSWERS {
+            return;
+        }
+        // Everything stored at or before the cutoff goes, which leaves an
+        // eighth of the cache free.
+        let mut stored: Vec<Instant> = self
+            .answers
+            .values()
+            .flatten()
+            .map(|cached| cached.stored)
+            .collect();
+        let excess = self.count + 1 - MAX_ANSWERS + MAX_ANSWERS / 8;
+        let cutoff = *stored.select_nth_unstable(excess.min(self.count) - 1).1;
+        self.retain(|cached| cached.stored > cutoff);
+    }
+
+    fn retain(&mut self, keep: impl Fn(&ScopedAnswer) -> bool) {
+        self.answers.retain(|_, scopes| {
+            scopes.retain(&keep);
+            !scopes.is_empty()
+        });
+        self.count = self.answers.values().map(Vec::len).sum();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::resource_record::{RecordClass, ResourceRecord};
+
+    fn config() -> EcsConfig {
+        EcsConfig {
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            exclude: AccessList::parse("10.0.0.0/8").unwrap(),
+        }
+    }
+
+    fn subnet(network: &str, source_prefix: u8, scope_prefix: u8) -> ClientSubnet {
+        ClientSubnet {
+            address: network.parse().unwrap(),
+            source_prefix,
+            scope_prefix,
+        }
+    }
+
+    fn query(sent: Option<ClientSubnet>) -> DNSMessage {
+        let mut query = DNSMessage::query(1, "www.example.test", RecordType::A);
+        query.edns.as_mut().unwrap().set_client_subnet(sent);
+        query
+    }
+
+    // An upstream answer to a query that carried `sent`, echoing it with
+    // `scope_prefix`.
+    fn answer(sent: ClientSubnet, scope_prefix: u8) -> DNSMessage {
+        let mut response = DNSMessage::response_to(&query(Some(sent)));
+        response.answers.push(ResourceRecord::new(
+            "www.example.test".to_string(),
+            RecordType::A,
+            RecordClass::IN,
+            300,
+            vec![192, 0, 2, 1],
+        ));
+        response
+            .edns
+            .as_mut()
+            .unwrap()
+            .set_client_subnet(Some(ClientSubnet {
+                scope_prefix,
+                ..sent
+            }));
+        response
+    }
+
+    #[test]
+    fn truncates_client_addresses() {
+        let config = config();
+        let none = query(None);
+        let sent =
+            |query: &DNSMessage, client: &str| config.subnet_for(query, client.parse().unwrap());
+        assert_eq!(sent(&none, "192.0.2.77"), Some(subnet("192.0.2.0", 24, 0)));
+        assert_eq!(
+            sent(&none, "2001:db8:1:2ff::1"),
+            Some(subnet("2001:db8:1:200::", 56, 0))
+        );
+        assert_eq!(
+            sent(&none, "::ffff:192.0.2.77"),
+            Some(subnet("192.0.2.0", 24, 0))
+        );
+        assert_eq!(sent(&none, "10.1.2.3"), None);
+    }
+
+    #[test]
+    fn uses_the_subnet_a_client_sends() {
+        let config = config();
+        let client = "10.1.2.3".parse().unwrap();
+        // Never more of it than configured, and less when the client asks.
+        let wide = query(Some(subnet("198.51.100.77", 32, 0)));
+        assert_eq!(
+            config.subnet_for(&wide, client),
+            Some(subnet("198.51.100.0", 24, 0))
+        );
+        let narrow = query(Some(subnet("198.51.100.0", 16, 0)));
+        assert_eq!(
+            config.subnet_for(&narrow, client),
+            Some(subnet("198.51.0.0", 16, 0))
+        );
+        let opted_out = query(Some(subnet("0.0.0.0", 0, 0)));
+        assert_eq!(config.subnet_for(&opted_out, client), None);
+    }
+
+    #[test]
+    fn reads_the_scope_of_answers() {
+        let sent = subnet("192.0.2.0", 24, 0);
+        let scope = |response: &DNSMessage| answer_scope(&sent, response).map(|s| s.to_string());
+        assert_eq!(scope(&answer(sent, 20)), Some("192.0.0.0/20".to_string()));
+        // A scope longer than the source is cut to it.
+        assert_eq!(scope(&answer(sent, 28)), Some("192.0.2.0/24".to_string()));
+        assert_eq!(scope(&answer(sent, 0)), None);
+        assert_eq!(scope(&answer(subnet("198.51.100.0", 24, 0), 24)), None);
+        let mut unechoed = answer(sent, 24);
+        unechoed.edns.as_mut().unwrap().set_client_subnet(None);
+        assert_eq!(scope(&unechoed), None);
+    }
+
+    #[test]
+    fn echoes_the_client_subnet_with_the_answer_scope() {
+        let sent = subnet("192.0.2.0", 24, 0);
+        let echoed = |query: &DNSMessage| {
+            let mut response = answer(sent, 20);
+            restore_client_subnet(query, Some(&sent), &mut response);
+            response.edns.map(|edns| edns.client_subnet())
+        };
+        // Clients that sent no option get none back.
+        assert_eq!(echoed(&query(None)), Some(None));
+        let mut plain = query(None);
+        plain.edns = None;
+        assert_eq!(echoed(&plain), None);
+        // The client's own subnet comes back, with no wider a scope than
+        // its source prefix.
+        let own = subnet("192.0.2.128", 25, 0);
+        assert_eq!(
+            echoed(&query(Some(own))),
+            Some(Some(subnet("192.0.2.128", 25, 20)))
+        );
+        let short = subnet("192.0.0.0", 16, 0);
+        assert_eq!(
+            echoed(&query(Some(short))),
+            Some(Some(subnet("192.0.0.0", 16, 16)))
+        );
+    }
+
+    #[test]
+    fn caches_answers_for_their_scope() {
+        let cache = SubnetCache::default();
+        let sent = subnet("192.0.2.0", 24, 0);
+        cache.insert(&sent, &answer(sent, 16));
+        // Another client in the scope gets the answer, echoing its subnet.
+        let neighbour = subnet("192.0.3.0", 24, 0);
+        let mut asking = query(Some(neighbour));
+        asking.header.transaction_id = 99;
+        let cached = cache.get(&asking, &neighbour).unwrap();
+        assert_eq!(cached.header.transaction_id, 99);
+        assert_eq!(cached.answers[0].data, [192, 0, 2, 1]);
+        assert_eq!(
+            cached.edns.unwrap().client_subnet(),
+            Some(subnet("192.0.3.0", 24, 16))
+        );
+        assert!(cache.get(&asking, &subnet("198.51.100.0", 24, 0)).is_none());
+        // A subnet shorter than the scope covers clients it does not hold for.
+        assert!(cache.get(&asking, &subnet("192.0.0.0", 8, 0)).is_none());
+    }
+
+    #[test]
+    fn does_not_cache_answers_for_everyone() {
+        let cache = SubnetCache::default();
+        let sent = subnet("192.0.2.0", 24, 0);
+        cache.insert(&sent, &answer(sent, 0));
+        let mut failure = answer(sent, 24);
+        failure.set_response_code(ResponseCode::ServFail);
+        cache.insert(&sent, &failure);
+        assert!(cache.get(&query(Some(sent)), &sent).is_none());
+    }
+}
@@ -0,0 +1,358 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crypto_box::aead::OsRng;
+use crypto_box::SecretKey;
+use log::info;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Instant};
+
+use crate::dns::dnscrypt::{
+    pad, unix_time, unpad, Certificate, SharedKey, Stamp, HALF_NONCE_LENGTH, MIN_UDP_QUERY_LENGTH,
+    RESOLVER_MAGIC, RESPONSE_HEADER_LENGTH,
+};
+use crate::dns::message::DNSMessage;
+use crate::dns::resource_record::RecordType;
+use crate::forwarder::udp::{exchange_tcp_within, exchange_udp};
+use crate::forwarder::upstream::UpstreamError;
+
+// Large enough for any response a padded query lets through.
+const RECEIVE_BUFFER: usize = 4096;
+
+/// A DNSCrypt v2 server, given by its stamp. The server's certificate is
+/// fetched on first use and again once it expires or the server stops
+/// answering; queries go over UDP, with TCP for truncated replies.
+pub struct DnsCryptUpstream {
+    stamp: Stamp,
+    secret: SecretKey,
+    certificate: tokio::sync::Mutex<Option<Arc<(Certificate, SharedKey)>>>,
+}
+
+impl DnsCryptUpstream {
+    pub fn new(stamp: Stamp) -> Self {
+        DnsCryptUpstream {
+            stamp,
+            secret: SecretKey::generate(&mut OsRng),
+            certificate: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub async fn exchange(
+        &self,
+        query: &[u8],
+        timeout_duration: Duration,
+    ) -> Result<Vec<u8>, UpstreamError> {
+        if query.len() < 12 {
+            return Err("Query too short".into());
+        }
+        let deadline = Instant::now() + timeout_duration;
+        let current = self.certificate(timeout_duration).await?;
+        let (certificate, shared) = current.as_ref();
+
+        let (packet, nonce) = self.seal(certificate, shared, query, MIN_UDP_QUERY_LENGTH);
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let response = match self.exchange_udp(&packet, &nonce, shared, remaining).await {
+            Ok(response) => response,
+            Err(e) => {
+                // A server that restarted with new keys ignores queries for
+                // the old ones, so the certificate is fetched again.
+                let mut slot = self.certificate.lock().await;
+                if slot
+                    .as_ref()
+                    .is_some_and(|slot| Arc::ptr_eq(slot, &current))
+                {
+                    *slot = None;
+                }
+                return Err(e);
+            }
+        };
+        // TC is bit 1 of the third header byte.
+        if response[2] & 0x02 == 0 {
+            return Ok(response);
+        }
+        let (packet, nonce) = self.seal(certificate, shared, query, 0);
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let response = exchange_tcp_within(self.stamp.address, &packet, remaining).await?;
+        open(shared, &nonce, &response)
+    }
+
+    // The current certificate and the key shared with it, fetched anew when
+    // there is none or it has expired.
+    async fn certificate(
+        &self,
+        timeout_duration: Duration,
+    ) -> Result<Arc<(Certificate, SharedKey)>, UpstreamError> {
+        let mut slot = self.certificate.lock().await;
+        let now = unix_time();
+        if let Some(current) = slot.as_ref().filter(|current| current.0.is_current(now)) {
+            return Ok(current.clone());
+        }
+        let certificate = self.fetch_certificate(now, timeout_duration).await?;
+        info!(
+            "Using DNSCrypt certificate {} ({:?}) of {}",
+            certificate.serial, certificate.construction, self.stamp.provider_name
+        );
+        let shared = SharedKey::new(
+            certificate.construction,
+            &certificate.resolver_key,
+            &self.secret,
+        );
+        let current = Arc::new((certificate, shared));
+        *slot = Some(current.clone());
+        Ok(current)
+    }
+
+    // Asks the server for its certificates in the clear and picks the newest
+    // one the provider signed, preferring XChaCha20 among equals.
+    async fn fetch_certificate(
+        &self,
+        now: u32,
+        timeout_duration: Duration,
+    ) -> Result<Certificate, UpstreamError> {
+        let query = DNSMessage::query(rand::random(), &self.stamp.provider_name, RecordType::TXT);
+        let response = exchange_udp(
+            self.stamp.address,
+            &query.to_bytes(),
+            timeout_duration,
+            |_| true,
+        )
+        .await?;
+        let response = DNSMessage::parse(&response)?;
+        response
+            .answers
+            .iter()
+            .filter(|record| record.record_type == RecordType::TXT)
+            .filter_map(|record| {
+                let text = txt_data(&record.data)?;
+                Certificate::parse(&text, &self.stamp.provider_key).ok()
+            })
+            .filter(|certificate| certificate.is_current(now))
+            .max_by_key(|certificate| (certificate.serial, certificate.construction))
+            .ok_or_else(|| {
+                format!(
+                    "No valid DNSCrypt certificate for {}",
+                    self.stamp.provider_name
+                )
+                .into()
+            })
+    }
+
+    // The encrypted query and the nonce it was sealed with.
+    fn seal(
+        &self,
+        certificate: &Certificate,
+        shared: &SharedKey,
+        query: &[u8],
+        min_length: usize,
+    ) -> (Vec<u8>, [u8; 2 * HALF_NONCE_LENGTH]) {
+        let mut nonce = [0u8; 2 * HALF_NONCE_LENGTH];
+        nonce[..HALF_NONCE_LENGTH].copy_from_slice(&rand::random::<[u8; HALF_NONCE_LENGTH]>());
+        let mut padded = query.to_vec();
+        pad(&mut padded, min_length);
+        let mut packet = certificate.client_magic.to_vec();
+        packet.extend_from_slice(self.secret.public_key().as_bytes());
+        packet.extend_from_slice(&nonce[..HALF_NONCE_LENGTH]);
+        packet.extend(shared.seal(&nonce, &padded));
+        (packet, nonce)
+    }
+
+    // Sends an encrypted query and waits for the reply that opens with our
+    // nonce; anything else on the socket is ignored.
+    async fn exchange_udp(
+        &self,
+        packet: &[u8],
+        nonce: &[u8; 2 * HALF_NONCE_LENGTH],
+        shared: &SharedKey,
+        timeout_duration: Duration,
+    ) -> Result<Vec<u8>, UpstreamError> {
+        let server = self.stamp.address;
+        let bind_addr = if server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(server).await?;
+        socket.send(packet).await?;
+
+        let deadline = Instant::now() + timeout_duration;
+        let mut buffer = vec![0u8; RECEIVE_BUFFER];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let len = match timeout(remaining, socket.recv(&mut buffer)).await {
+                Ok(result) => result?,
+                Err(_) => return Err("Timeout while waiting for response".into()),
+            };
+            if let Ok(response) = open(shared, nonce, &buffer[..len]) {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+// Decrypts a response to the query sealed with `nonce`.
+fn open(
+    shared: &SharedKey,
+    nonce: &[u8; 2 * HALF_NONCE_LENGTH],
+    packet: &[u8],
+) -> Result<Vec<u8>, UpstreamError> {
+    if packet.len() < RESPONSE_HEADER_LENGTH
+        || packet[..RESOLVER_MAGIC.len()] != RESOLVER_MAGIC
+        || packet[RESOLVER_MAGIC.len()..][..HALF_NONCE_LENGTH] != nonce[..HALF_NONCE_LENGTH]
+    {
+        return Err("Not a DNSCrypt response to our query".into());
+    }
+    let full_nonce: [u8; 2 * HALF_NONCE_LENGTH] = packet
+        [RESOLVER_MAGIC.len()..RESPONSE_HEADER_LENGTH]
+        .try_into()
+        .expect("nonce length");
+    let mut response = shared.open(&full_nonce, &packet[RESPONSE_HEADER_LENGTH..])?;
+    unpad(&mut response)?;
+    if response.len() < 12 {
+        return Err("Response too short".into());
+    }
+    Ok(response)
+}
+
+// The character strings of a TXT record, joined.
+fn txt_data(data: &[u8]) -> Option<Vec<u8>> {
+    let mut text = Vec::new();
+    let mut rest = data;
+    while let Some((&length, tail)) = rest.split_first() {
+        let (string, tail) = tail.split_at_checked(length as usize)?;
+        text.extend_from_slice(string);
+        rest = tail;
+    }
+    Some(text)
+}
+
+impl fmt::Debug for DnsCryptUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsCryptUpstream")
+            .field("stamp", &self.stamp)
+            .finish()
+    }
+}
+
+impl fmt::Display for DnsCryptUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.stamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dnscrypt::Construction;
+    use crate::server::dnscrypt::{bind_dnscrypt, serve_dnscrypt, DnsCryptServer};
+    use crate::zone::catalog::Catalog;
+    use crate::zone::parser::parse_zone_str;
+    use crate::zone::Zone;
+    use crate::Resolvers;
+
+    // Forty addresses for one name do not fit the reply to a padded query.
+    const ZONE: &str = "\
+@ 3600 IN SOA ns.example.test. admin.example.test. 1 3600 600 86400 300
+@ 3600 IN NS ns.example.test.
+www 300 IN A 192.0.2.1
+many 300 IN A 192.0.2.1
+many 300 IN A 192.0.2.2
+many 300 IN A 192.0.2.3
+many 300 IN A 192.0.2.4
+many 300 IN A 192.0.2.5
+many 300 IN A 192.0.2.6
+many 300 IN A 192.0.2.7
+many 300 IN A 192.0.2.8
+many 300 IN A 192.0.2.9
+many 300 IN A 192.0.2.10
+many 300 IN A 192.0.2.11
+many 300 IN A 192.0.2.12
+many 300 IN A 192.0.2.13
+many 300 IN A 192.0.2.14
+many 300 IN A 192.0.2.15
+many 300 IN A 192.0.2.16
+many 300 IN A 192.0.2.17
+many 300 IN A 192.0.2.18
+many 300 IN A 192.0.2.19
+many 300 IN A 192.0.2.20
+many 300 IN A 192.0.2.21
+many 300 IN A 192.0.2.22
+many 300 IN A 192.0.2.23
+many 300 IN A 192.0.2.24
+many 300 IN A 192.0.2.25
+many 300 IN A 192.0.2.26
+many 300 IN A 192.0.2.27
+many 300 IN A 192.0.2.28
+many 300 IN A 192.0.2.29
+many 300 IN A 192.0.2.30
+many 300 IN A 192.0.2.31
+many 300 IN A 192.0.2.32
+many 300 IN A 192.0.2.33
+many 300 IN A 192.0.2.34
+many 300 IN A 192.0.2.35
+many 300 IN A 192.0.2.36
+many 300 IN A 192.0.2.37
+many 300 IN A 192.0.2.38
+many 300 IN A 192.0.2.39
+many 300 IN A 192.0.2.40
+";
+
+    async fn upstream() -> DnsCryptUpstream {
+        let mut catalog = Catalog::default();
+        catalog.add(Zone::new(
+            "example.test",
+            parse_zone_str(ZONE, "example.test").unwrap(),
+        ));
+        let resolvers = Arc::new(Resolvers::serving(catalog));
+        let server = DnsCryptServer::new("2.dnscrypt-cert.example.test", &[7; 32]).unwrap();
+        let (socket, listener) = bind_dnscrypt("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let stamp = server.stamp(socket.local_addr().unwrap());
+        tokio::spawn(serve_dnscrypt(
+            socket,
+            listener,
+            Arc::new(server),
+            resolvers,
+        ));
+        DnsCryptUpstream::new(stamp)
+    }
+
+    async fn resolve(upstream: &DnsCryptUpstream, name: &str) -> DNSMessage {
+        let query = DNSMessage::query(0x1234, name, RecordType::A).to_bytes();
+        let response = upstream
+            .exchange(&query, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let response = DNSMessage::parse(&response).unwrap();
+        assert_eq!(response.header.transaction_id, 0x1234);
+        response
+    }
+
+    #[tokio::test]
+    async fn exchanges_encrypted_queries() {
+        let upstream = upstream().await;
+        let response = resolve(&upstream, "www.example.test").await;
+        assert_eq!(response.answers[0].data, [192, 0, 2, 1]);
+        let certificate = upstream.certificate.lock().await.clone().unwrap();
+        assert_eq!(certificate.0.construction, Construction::XChaCha20Poly1305);
+    }
+
+    #[tokio::test]
+    async fn retries_truncated_replies_over_tcp() {
+        let upstream = upstream().await;
+        let response = resolve(&upstream, "many.example.test").await;
+        assert!(!response.header.flags.tc);
+        assert_eq!(response.answers.len(), 40);
+    }
+
+    #[tokio::test]
+    async fn rejects_certificates_of_other_providers() {
+        let mut upstream = upstream().await;
+        upstream.stamp.provider_key = [0; 32];
+        let query = DNSMessage::query(1, "www.example.test", RecordType::A).to_bytes();
+        assert!(upstream
+            .exchange(&query, Duration::from_secs(5))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::future::{ready, Ready};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::service::Service;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use tokio::time::timeout;
+
+use crate::forwarder::tls::client_config;
+use crate::forwarder::upstream::UpstreamError;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// A DNS over HTTPS server (RFC 8484). The client keeps connections open
+/// between queries and multiplexes them over HTTP/2 when the server offers it.
+pub struct HttpsUpstream {
+    uri: Uri,
+    client: Client<HttpsConnector<HttpConnector<FixedAddresses>>>,
+}
+
+// The server's addresses, looked up once when the upstream is configured so
+// that queries never depend on the system resolver, which may be us.
+#[derive(Clone)]
+struct FixedAddresses(Vec<SocketAddr>);
+
+impl Service<Name> for FixedAddresses {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Name) -> Self::Future {
+        ready(Ok(self.0.clone().into_iter()))
+    }
+}
+
+impl HttpsUpstream {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let uri: Uri = url
+            .parse()
+            .map_err(|_| format!("Invalid upstream URL {:?}", url))?;
+        let host = uri
+            .host()
+            .ok_or_else(|| format!("Upstream URL {:?} has no host", url))?;
+        let port = uri.port_u16().unwrap_or(443);
+        let addresses = (host.trim_start_matches('[').trim_end_matches(']'), port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Cannot resolve {}: {}", host, e))?
+            .collect();
+
+        let mut http = HttpConnector::new_with_resolver(FixedAddresses(addresses));
+        http.enforce_http(false);
+        http.set_nodelay(true);
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config((*client_config()?).clone())
+            .https_only()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(http);
+        Ok(HttpsUpstream {
+            uri,
+            client: Client::builder().build(connector),
+        })
+    }
+
+    pub async fn exchange(
+        &self,
+        query: &[u8],
+        timeout_duration: Duration,
+    ) -> Result<Vec<u8>, UpstreamError> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Body::from(query.to_vec()))?;
+        let exchange = async {
+            let response = self.client.request(request).await?;
+            if response.status() != StatusCode::OK {
+                return Err(format!("HTTP status {}", response.status()).into());
+            }
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            Ok::<_, UpstreamError>(body.to_vec())
+        };
+        timeout(timeout_duration, exchange)
+            .await
+            .map_err(|_| UpstreamError::from("Timeout while waiting for response"))?
+    }
+}
+
+impl fmt::Debug for HttpsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpsUpstream")
+            .field("uri", &self.uri)
+            .finish()
+    }
+}
+
+impl fmt::Display for HttpsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.uri)
+    }
+}
@@ -0,0 +1,310 @@
+mod dnscrypt;
+mod https;
+mod quic;
+mod tls;
+mod udp;
+pub mod upstream;
+
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use log::warn;
+use rand::seq::SliceRandom;
+
+use crate::dns::name::{is_subdomain, labels, normalize};
+use crate::forwarder::upstream::{Upstream, UpstreamError};
+
+const DEFAULT_UPSTREAM: &str = "8.8.8.8:53";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The order in which the servers of a group are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Always start with the first server, moving on only when it fails.
+    Failover,
+    /// Start with the next server in turn for every query.
+    RoundRobin,
+    /// Start with a randomly chosen server.
+    Random,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "failover" => Some(Strategy::Failover),
+            "round-robin" | "round_robin" => Some(Strategy::RoundRobin),
+            "random" => Some(Strategy::Random),
+            _ => None,
+        }
+    }
+}
+
+/// Servers that share a timeout and a strategy for picking between them.
+#[derive(Debug)]
+pub struct UpstreamGroup {
+    pub upstreams: Vec<Upstream>,
+    pub timeout: Duration,
+    pub strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl UpstreamGroup {
+    pub fn new(upstreams: Vec<Upstream>, timeout: Duration, strategy: Strategy) -> Self {
+        UpstreamGroup {
+            upstreams,
+            timeout,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sends the query to the group's servers in strategy order until one
+    /// answers, giving each server the group's timeout.
+    pub async fn forward(&self, query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+        let mut last_error: UpstreamError = "No upstream servers configured".into();
+        for upstream in self.ordered() {
+            match upstream.exchange(query, self.timeout).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    warn!("Upstream {} failed: {}", upstream, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn ordered(&self) -> Vec<&Upstream> {
+        let mut ordered: Vec<&Upstream> = self.upstreams.iter().collect();
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin => {
+                if !ordered.is_empty() {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % ordered.len();
+                    ordered.rotate_left(start);
+                }
+            }
+            Strategy::Random => ordered.shuffle(&mut rand::thread_rng()),
+        }
+        ordered
+    }
+}
+
+impl fmt::Display for UpstreamGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let servers: Vec<String> = self.upstreams.iter().map(|u| u.to_string()).collect();
+        write!(
+            f,
+            "{} ({:?}, {}ms)",
+            servers.join(","),
+            self.strategy,
+            self.timeout.as_millis()
+        )
+    }
+}
+
+/// Routes queries to upstream groups by the longest matching name suffix.
+#[derive(Debug, Default)]
+pub struct Forwarder {
+    // (suffix, group); the root "" matches every name.
+    routes: Vec<(String, UpstreamGroup)>,
+}
+
+impl Forwarder {
+    /// Reads the routing table from the file named by FORWARD_RULES. Names
+    /// no rule covers go to UPSTREAM (default 8.8.8.8:53), unless
+    /// `default_route` is false because something else answers them.
+    pub fn from_env(default_route: bool) -> Result<Self, Box<dyn Error>> {
+        let rules = std::env::var("FORWARD_RULES").ok();
+        Forwarder::new(rules.as_deref().map(Path::new), default_route)
+    }
+
+    /// Like `from_env`, with the rules file given.
+    pub fn new(rules: Option<&Path>, default_route: bool) -> Result<Self, Box<dyn Error>> {
+        let mut forwarder = match rules {
+            Some(path) => Forwarder::load(path)?,
+            None => Forwarder::default(),
+        };
+        if default_route && forwarder.route("").is_none() {
+            let servers =
+                std::env::var("UPSTREAM").unwrap_or_else(|_| DEFAULT_UPSTREAM.to_string());
+            let upstreams = servers
+                .split(',')
+                .map(|server| Upstream::parse(server.trim()))
+                .collect::<Result<Vec<_>, _>>()?;
+            forwarder.add(
+                "",
+                UpstreamGroup::new(upstreams, DEFAULT_TIMEOUT, Strategy::Failover),
+            );
+        }
+        Ok(forwarder)
+    }
+
+    /// Reads rules of the form
+    ///
+    /// ```text
+    /// # suffix        servers                 options
+    /// corp.internal   10.0.0.53,10.0.0.54     timeout=1500 strategy=round-robin
+    /// .               8.8.8.8,1.1.1.1:53      strategy=random
+    /// ```
+    ///
+    /// `timeout` is in milliseconds (default 5000) and `strategy` is one of
+    /// `failover` (the default), `round-robin` or `random`.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut forwarder = Forwarder::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (suffix, group) = parse_rule(line)
+                .map_err(|message| format!("{}:{}: {}", path.display(), index + 1, message))?;
+            forwarder.add(&suffix, group);
+        }
+        Ok(forwarder)
+    }
+
+    /// Adds a route, replacing any earlier one for the same suffix.
+    pub fn add(&mut self, suffix: &str, group: UpstreamGroup) {
+        let suffix = normalize(suffix);
+        self.routes.retain(|(existing, _)| *existing != suffix);
+        self.routes.push((suffix, group));
+    }
+
+    /// The group for the longest suffix covering `name`.
+    pub fn route(&self, name: &str) -> Option<&UpstreamGroup> {
+        self.routes
+            .iter()
+            .filter(|(suffix, _)| is_subdomain(name, suffix))
+            .max_by_key(|(suffix, _)| labels(suffix).count())
+            .map(|(_, group)| group)
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = (&str, &UpstreamGroup)> {
+        self.routes
+            .iter()
+            .map(|(suffix, group)| (suffix.as_str(), group))
+    }
+}
+
+fn parse_rule(line: &str) -> Result<(String, UpstreamGroup), String> {
+    let mut fields = line.split_whitespace();
+    let suffix = fields.next().ok_or("Missing name suffix")?.to_string();
+    let servers = fields.next().ok_or("Missing upstream servers")?;
+    let upstreams = servers
+        .split(',')
+        .filter(|server| !server.is_empty())
+        .map(Upstream::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    if upstreams.is_empty() {
+        return Err("Missing upstream servers".to_string());
+    }
+
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut strategy = Strategy::Failover;
+    for option in fields {
+        match option.split_once('=') {
+            Some(("timeout", value)) => {
+                let millis: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid timeout {:?}", value))?;
+                timeout = Duration::from_millis(millis);
+            }
+            Some(("strategy", value)) => {
+                strategy = Strategy::from_name(value)
+                    .ok_or_else(|| format!("Unknown strategy {:?}", value))?;
+            }
+            _ => return Err(format!("Unknown option {:?}", option)),
+        }
+    }
+    Ok((suffix, UpstreamGroup::new(upstreams, timeout, strategy)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::dns::header::ResponseCode;
+    use crate::dns::message::DNSMessage;
+    use crate::dns::resource_record::{RecordClass, RecordType, ResourceRecord};
+
+    // A server on loopback answering every A query with `address`.
+    async fn stub(address: [u8; 4]) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, client)) = socket.recv_from(&mut buffer).await {
+                let Ok(query) = DNSMessage::parse(&buffer[..len]) else {
+                    continue;
+                };
+                let mut response = DNSMessage::response_to(&query);
+                let name = query.questions[0].name.clone();
+                response.answers.push(ResourceRecord::new(
+                    name,
+                    RecordType::A,
+                    RecordClass::IN,
+                    60,
+                    address.to_vec(),
+                ));
+                let _ = socket.send_to(&response.to_bytes(), client).await;
+            }
+        });
+        local.to_string()
+    }
+
+    // A server on loopback that never answers.
+    async fn silent() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local = socket.local_addr().unwrap().to_string();
+        (socket, local)
+    }
+
+    fn query(name: &str) -> Vec<u8> {
+        DNSMessage::query(0x1234, name, RecordType::A).to_bytes()
+    }
+
+    #[tokio::test]
+    async fn routes_by_longest_suffix() {
+        let (corp, other) = (stub([10, 0, 0, 1]).await, stub([192, 0, 2, 1]).await);
+        let mut forwarder = Forwarder::default();
+        forwarder.add("", parse_rule(&format!(". {}", other)).unwrap().1);
+        forwarder.add(
+            "corp.test",
+            parse_rule(&format!("corp.test {}", corp)).unwrap().1,
+        );
+
+        let group = forwarder.route("host.corp.test").unwrap();
+        let response = DNSMessage::parse(&group.forward(&query("host.corp.test")).await.unwrap());
+        assert_eq!(response.unwrap().answers[0].data, [10, 0, 0, 1]);
+
+        let group = forwarder.route("www.example").unwrap();
+        let response = DNSMessage::parse(&group.forward(&query("www.example")).await.unwrap());
+        assert_eq!(response.unwrap().answers[0].data, [192, 0, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_server() {
+        let (_socket, dead) = silent().await;
+        let live = stub([192, 0, 2, 1]).await;
+        let (_, group) = parse_rule(&format!(". {},{} timeout=200", dead, live)).unwrap();
+        let response = DNSMessage::parse(&group.forward(&query("www.example")).await.unwrap());
+        let response = response.unwrap();
+        assert_eq!(response.header.transaction_id, 0x1234);
+        assert_eq!(response.header.flags.rcode, ResponseCode::NoError as u8);
+        assert_eq!(response.answers[0].data, [192, 0, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn reports_when_no_server_answers() {
+        let (_socket, dead) = silent().await;
+        let (_, group) = parse_rule(&format!(". {} timeout=100", dead)).unwrap();
+        assert!(group.forward(&query("www.example")).await.is_err());
+    }
+}
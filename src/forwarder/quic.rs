@@ -0,0 +1,187 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use quinn::{ClientConfig, Connection, Endpoint};
+use tokio::time::timeout;
+use tokio_rustls::rustls;
+
+use crate::forwarder::tls::client_config;
+use crate::forwarder::upstream::UpstreamError;
+use crate::server::quic::{DOQ_NO_ERROR, DOQ_REQUEST_CANCELLED};
+
+// Room for any response and its length prefix.
+const MAX_RESPONSE: usize = u16::MAX as usize + 2;
+
+/// A DNS over QUIC server (RFC 9250). Queries share one connection, each on
+/// a stream of its own, which is reopened when the server closes it.
+pub struct QuicUpstream {
+    address: SocketAddr,
+    name: String,
+    config: ClientConfig,
+    // The endpoint is kept with the connection it drives.
+    connection: tokio::sync::Mutex<Option<(Endpoint, Connection)>>,
+}
+
+impl QuicUpstream {
+    pub fn new(address: SocketAddr, name: &str) -> Result<Self, String> {
+        let mut tls = (*client_config()?).clone();
+        tls.alpn_protocols = vec![b"doq".to_vec()];
+        Ok(QuicUpstream::with_tls(address, name, tls))
+    }
+
+    fn with_tls(address: SocketAddr, name: &str, tls: rustls::ClientConfig) -> Self {
+        QuicUpstream {
+            address,
+            name: name.to_string(),
+            config: ClientConfig::new(Arc::new(tls)),
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub async fn exchange(
+        &self,
+        query: &[u8],
+        timeout_duration: Duration,
+    ) -> Result<Vec<u8>, UpstreamError> {
+        if query.len() < 12 {
+            return Err("Query too short".into());
+        }
+        let (connection, reused) = timeout(timeout_duration, self.connection())
+            .await
+            .map_err(|_| UpstreamError::from("Timeout while connecting"))??;
+        match timeout(timeout_duration, exchange(&connection, query)).await {
+            Ok(Ok(response)) => Ok(response),
+            // The server may have closed an idle connection just as the query
+            // went out; that deserves a fresh one.
+            Ok(Err(_)) if reused => {
+                let (connection, _) = timeout(timeout_duration, self.connection())
+                    .await
+                    .map_err(|_| UpstreamError::from("Timeout while connecting"))??;
+                timeout(timeout_duration, exchange(&connection, query))
+                    .await
+                    .map_err(|_| UpstreamError::from("Timeout while waiting for response"))?
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err("Timeout while waiting for response".into()),
+        }
+    }
+
+    // The open connection, or a new one if there is none. The flag says
+    // whether it was already open.
+    async fn connection(&self) -> Result<(Connection, bool), UpstreamError> {
+        let mut slot = self.connection.lock().await;
+        if let Some((_, connection)) = slot.as_ref() {
+            if connection.close_reason().is_none() {
+                return Ok((connection.clone(), true));
+            }
+        }
+        if let Some((endpoint, _)) = slot.take() {
+            endpoint.close(DOQ_NO_ERROR, b"");
+        }
+        let bind_addr = if self.address.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let mut endpoint = Endpoint::client(bind_addr.parse()?)?;
+        endpoint.set_default_client_config(self.config.clone());
+        let connection = endpoint.connect(self.address, &self.name)?.await?;
+        *slot = Some((endpoint, connection.clone()));
+        Ok((connection, false))
+    }
+}
+
+// Sends a query on a new stream with the zero message ID the protocol asks
+// for, and gives the response the query's ID.
+async fn exchange(connection: &Connection, query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&[0, 0]);
+    framed.extend_from_slice(&query[2..]);
+    send.write_all(&framed).await?;
+    send.finish().await?;
+
+    let message = match recv.read_to_end(MAX_RESPONSE).await {
+        Ok(message) => message,
+        Err(e) => {
+            let _ = recv.stop(DOQ_REQUEST_CANCELLED);
+            return Err(e.into());
+        }
+    };
+    let mut response = match message.split_first_chunk::<2>() {
+        Some((length, response)) if u16::from_be_bytes(*length) as usize == response.len() => {
+            response.to_vec()
+        }
+        _ => return Err("Malformed response length".into()),
+    };
+    if response.len() < 12 {
+        return Err("Response too short".into());
+    }
+    response[0..2].copy_from_slice(&query[0..2]);
+    Ok(response)
+}
+
+impl fmt::Debug for QuicUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicUpstream")
+            .field("address", &self.address)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl fmt::Display for QuicUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "quic://{}#{}", self.address, self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::message::DNSMessage;
+    use crate::dns::resource_record::RecordType;
+    use crate::server::quic::{bind_quic, serve_quic};
+    use crate::server::tls::load_tls_config;
+    use crate::server::tls::tests::{client_config, resolvers, CERT, KEY};
+
+    async fn upstream() -> QuicUpstream {
+        let config = load_tls_config(CERT, KEY).unwrap();
+        let endpoint = bind_quic("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let address = endpoint.local_addr().unwrap();
+        tokio::spawn(serve_quic(endpoint, resolvers()));
+        QuicUpstream::with_tls(address, "localhost", client_config(b"doq"))
+    }
+
+    #[tokio::test]
+    async fn exchanges_queries_over_one_connection() {
+        let upstream = upstream().await;
+        let mut ids = Vec::new();
+        for id in [0x1234, 0x5678] {
+            let query = DNSMessage::query(id, "www.example.test", RecordType::A).to_bytes();
+            let response = upstream
+                .exchange(&query, Duration::from_secs(5))
+                .await
+                .unwrap();
+            let response = DNSMessage::parse(&response).unwrap();
+            assert_eq!(response.header.transaction_id, id);
+            assert_eq!(response.answers[0].data, [192, 0, 2, 1]);
+            let slot = upstream.connection.lock().await;
+            ids.push(slot.as_ref().unwrap().1.stable_id());
+        }
+        assert_eq!(ids[0], ids[1]);
+    }
+
+    #[tokio::test]
+    async fn rejects_servers_with_other_names() {
+        let mut upstream = upstream().await;
+        upstream.name = "example.test".to_string();
+        let query = DNSMessage::query(1, "www.example.test", RecordType::A).to_bytes();
+        assert!(upstream
+            .exchange(&query, Duration::from_secs(5))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Notify};
+use tokio::time::timeout;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+use crate::forwarder::upstream::UpstreamError;
+
+/// The TLS settings shared by all encrypted upstreams: the webpki roots,
+/// plus the certificates in UPSTREAM_CA_FILE when it is set.
+pub fn client_config() -> Result<Arc<ClientConfig>, String> {
+    static CONFIG: OnceLock<Result<Arc<ClientConfig>, String>> = OnceLock::new();
+    CONFIG.get_or_init(load_client_config).clone()
+}
+
+fn load_client_config() -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    if let Ok(path) = std::env::var("UPSTREAM_CA_FILE") {
+        let file = File::open(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let (added, _) = roots.add_parsable_certificates(&certs);
+        if added == 0 {
+            return Err(format!("{}: no certificates found", path));
+        }
+    }
+    Ok(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+/// A DNS over TLS server (RFC 7858). Queries share one connection, several
+/// at a time, which is reopened when the server closes it or stops answering.
+pub struct TlsUpstream {
+    address: SocketAddr,
+    name: String,
+    server_name: ServerName,
+    connector: TlsConnector,
+    connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+}
+
+// One open connection. Queries are sent with IDs unique to the connection so
+// that replies can be matched however the server orders them.
+struct Connection {
+    writer: tokio::sync::Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    pending: Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>,
+    next_id: AtomicU16,
+    closed: AtomicBool,
+    shutdown: Notify,
+}
+
+impl TlsUpstream {
+    pub fn new(address: SocketAddr, name: &str) -> Result<Self, String> {
+        let server_name = ServerName::try_from(name)
+            .map_err(|_| format!("Invalid TLS server name {:?}", name))?;
+        Ok(TlsUpstream {
+            address,
+            name: name.to_string(),
+            server_name,
+            connector: TlsConnector::from(client_config()?),
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    pub async fn exchange(
+        &self,
+        query: &[u8],
+        timeout_duration: Duration,
+    ) -> Result<Vec<u8>, UpstreamError> {
+        let (connection, reused) = timeout(timeout_duration, self.connection())
+            .await
+            .map_err(|_| UpstreamError::from("Timeout while connecting"))??;
+        match timeout(timeout_duration, connection.exchange(query)).await {
+            Ok(Ok(response)) => Ok(response),
+            // The server may have closed an idle connection just as the query
+            // went out; that deserves a fresh one.
+            Ok(Err(_)) if reused => {
+                let (connection, _) = timeout(timeout_duration, self.connection())
+                    .await
+                    .map_err(|_| UpstreamError::from("Timeout while connecting"))??;
+                timeout(timeout_duration, connection.exchange(query))
+                    .await
+                    .map_err(|_| connection.timed_out())?
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(connection.timed_out()),
+        }
+    }
+
+    // The open connection, or a new one if there is none. The flag says
+    // whether it was already open.
+    async fn connection(&self) -> Result<(Arc<Connection>, bool), UpstreamError> {
+        let mut slot = self.connection.lock().await;
+        if let Some(connection) = slot.as_ref() {
+            if !connection.closed.load(Ordering::Relaxed) {
+                return Ok((connection.clone(), true));
+            }
+        }
+        let stream = TcpStream::connect(self.address).await?;
+        stream.set_nodelay(true)?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        let (reader, writer) = split(stream);
+        let connection = Arc::new(Connection {
+            writer: tokio::sync::Mutex::new(writer),
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU16::new(rand::random()),
+            closed: AtomicBool::new(false),
+            shutdown: Notify::new(),
+        });
+        tokio::spawn(read_responses(reader, connection.clone(), self.address));
+        *slot = Some(connection.clone());
+        Ok((connection, false))
+    }
+}
+
+impl Connection {
+    // A server that lets a query time out is not trusted with the next one:
+    // the connection is dropped so that it is reopened.
+    fn timed_out(&self) -> UpstreamError {
+        self.close();
+        "Timeout while waiting for response".into()
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.shutdown.notify_one();
+    }
+
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+        if query.len() < 12 {
+            return Err("Query too short".into());
+        }
+        let (sender, receiver) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            let mut id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            while pending.contains_key(&id) {
+                id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            }
+            pending.insert(id, sender);
+            id
+        };
+        let _pending = Pending {
+            connection: self,
+            id,
+        };
+        let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&id.to_be_bytes());
+        framed.extend_from_slice(&query[2..]);
+
+        let sent = {
+            let mut writer = self.writer.lock().await;
+            match writer.write_all(&framed).await {
+                Ok(()) => writer.flush().await,
+                Err(e) => Err(e),
+            }
+        };
+        if let Err(e) = sent {
+            self.close();
+            return Err(e.into());
+        }
+        let mut response = receiver
+            .await
+            .map_err(|_| UpstreamError::from("Connection closed"))?;
+        response[0..2].copy_from_slice(&query[0..2]);
+        Ok(response)
+    }
+}
+
+// Forgets a query when its caller stops waiting for it.
+struct Pending<'a> {
+    connection: &'a Connection,
+    id: u16,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.connection.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+// Hands replies to the queries waiting for them until the connection ends or
+// is closed, which fails every query still waiting.
+async fn read_responses(
+    reader: ReadHalf<TlsStream<TcpStream>>,
+    connection: Arc<Connection>,
+    address: SocketAddr,
+) {
+    tokio::select! {
+        _ = dispatch(reader, &connection, address) => {}
+        _ = connection.shutdown.notified() => {
+            debug!("Dropping connection to {}", address);
+        }
+    }
+    connection.closed.store(true, Ordering::Relaxed);
+    connection.pending.lock().unwrap().clear();
+}
+
+async fn dispatch(
+    mut reader: ReadHalf<TlsStream<TcpStream>>,
+    connection: &Connection,
+    address: SocketAddr,
+) {
+    loop {
+        let mut length = [0u8; 2];
+        if let Err(e) = reader.read_exact(&mut length).await {
+            debug!("Connection to {} closed: {}", address, e);
+            break;
+        }
+        let mut response = vec![0u8; u16::from_be_bytes(length) as usize];
+        if let Err(e) = reader.read_exact(&mut response).await {
+            debug!("Connection to {} closed: {}", address, e);
+            break;
+        }
+        if response.len() < 12 {
+            continue;
+        }
+        let id = u16::from_be_bytes([response[0], response[1]]);
+        if let Some(sender) = connection.pending.lock().unwrap().remove(&id) {
+            let _ = sender.send(response);
+        }
+    }
+}
+
+impl fmt::Debug for TlsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsUpstream")
+            .field("address", &self.address)
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl fmt::Display for TlsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tls://{}#{}", self.address, self.name)
+    }
+}
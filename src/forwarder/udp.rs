@@ -0,0 +1,172 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Instant};
+
+use crate::dns::cookie::{self, QueryCookie, BADCOOKIE, CLIENT_COOKIE_LENGTH, COOKIE};
+use crate::dns::edns::append_option;
+use crate::dns::message::DNSMessage;
+use crate::forwarder::upstream::UpstreamError;
+
+// Large enough for any EDNS response a client is likely to ask for.
+const RECEIVE_BUFFER: usize = 4096;
+
+/// A plain DNS server, queried over UDP with TCP for truncated replies.
+/// Queries with EDNS carry a client cookie, along with the server cookie of
+/// the server's last reply (RFC 7873 section 5.1), unless DNS_COOKIES is
+/// `off`.
+#[derive(Debug)]
+pub struct UdpUpstream {
+    pub address: SocketAddr,
+    client_cookie: Option<[u8; CLIENT_COOKIE_LENGTH]>,
+    server_cookie: Mutex<Option<Vec<u8>>>,
+}
+
+impl UdpUpstream {
+    pub fn new(address: SocketAddr) -> Self {
+        UdpUpstream {
+            address,
+            client_cookie: cookie::enabled().then(rand::random),
+            server_cookie: Mutex::new(None),
+        }
+    }
+
+    pub async fn exchange(
+        &self,
+        query: &[u8],
+        timeout_duration: Duration,
+    ) -> Result<Vec<u8>, UpstreamError> {
+        if query.len() < 12 {
+            return Err("Query too short".into());
+        }
+        let Some(client_cookie) = self.client_cookie else {
+            return exchange_udp(self.address, query, timeout_duration, |_| true).await;
+        };
+        // Replies echoing another client cookie are forged (RFC 7873
+        // section 5.3); the real one may still arrive.
+        let genuine = |response: &[u8]| {
+            response_cookie(response).is_none_or(|cookie| cookie.client == client_cookie)
+        };
+        let Some(mut with_cookie) = self.with_cookie(query, client_cookie) else {
+            return exchange_udp(self.address, query, timeout_duration, |_| true).await;
+        };
+        // BADCOOKIE carries a fresh server cookie to retry with, and after
+        // that the query goes over TCP.
+        for _ in 0..2 {
+            let response =
+                exchange_udp(self.address, &with_cookie, timeout_duration, genuine).await?;
+            if !self.remember_cookie(&response) {
+                return Ok(response);
+            }
+            with_cookie = self
+                .with_cookie(query, client_cookie)
+                .unwrap_or(with_cookie);
+        }
+        exchange_tcp_within(self.address, &with_cookie, timeout_duration).await
+    }
+
+    // The query with our COOKIE option added, if it has EDNS to hold one.
+    fn with_cookie(
+        &self,
+        query: &[u8],
+        client_cookie: [u8; CLIENT_COOKIE_LENGTH],
+    ) -> Option<Vec<u8>> {
+        let mut option = client_cookie.to_vec();
+        option.extend(self.server_cookie.lock().unwrap().iter().flatten());
+        let mut query = query.to_vec();
+        append_option(&mut query, COOKIE, &option).then_some(query)
+    }
+
+    // Keeps the server cookie of a reply, and says whether the reply was
+    // BADCOOKIE.
+    fn remember_cookie(&self, response: &[u8]) -> bool {
+        let Ok(response) = DNSMessage::parse(response) else {
+            return false;
+        };
+        let Some(edns) = response.edns.as_ref() else {
+            return false;
+        };
+        if let Ok(Some(cookie)) = QueryCookie::find(edns) {
+            if !cookie.server.is_empty() {
+                *self.server_cookie.lock().unwrap() = Some(cookie.server);
+            }
+        }
+        (edns.extended_rcode as u16) << 4 | response.header.flags.rcode as u16 == BADCOOKIE
+    }
+}
+
+impl fmt::Display for UdpUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.address)
+    }
+}
+
+// The COOKIE option of a reply, if it has a well formed one.
+fn response_cookie(response: &[u8]) -> Option<QueryCookie> {
+    let response = DNSMessage::parse(response).ok()?;
+    QueryCookie::find(response.edns.as_ref()?).ok()?
+}
+
+// Sends a query and waits for the reply with its ID that `accept` takes.
+// Truncated replies are retried over TCP.
+pub(super) async fn exchange_udp(
+    server: SocketAddr,
+    query: &[u8],
+    timeout_duration: Duration,
+    accept: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, UpstreamError> {
+    let bind_addr = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let local_socket = UdpSocket::bind(bind_addr).await?;
+    local_socket.connect(server).await?;
+    local_socket.send(query).await?;
+
+    let deadline = Instant::now() + timeout_duration;
+    let mut response = vec![0u8; RECEIVE_BUFFER];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let len = match timeout(remaining, local_socket.recv(&mut response)).await {
+            Ok(result) => result?,
+            Err(_) => return Err("Timeout while waiting for response".into()),
+        };
+        if len < 12 || response[0..2] != query[0..2] || !accept(&response[..len]) {
+            continue;
+        }
+        // TC is bit 1 of the third header byte.
+        if response[2] & 0x02 != 0 {
+            return exchange_tcp_within(server, query, timeout_duration).await;
+        }
+        response.truncate(len);
+        return Ok(response);
+    }
+}
+
+pub(super) async fn exchange_tcp_within(
+    server: SocketAddr,
+    query: &[u8],
+    timeout_duration: Duration,
+) -> Result<Vec<u8>, UpstreamError> {
+    timeout(timeout_duration, exchange_tcp(server, query))
+        .await
+        .map_err(|_| UpstreamError::from("Timeout while waiting for TCP response"))?
+}
+
+async fn exchange_tcp(server: SocketAddr, query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+    let mut stream = TcpStream::connect(server).await?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await?;
+
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length).await?;
+    let mut response = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::dns::dnscrypt::Stamp;
+use crate::dns::edns::{pad, QUERY_PADDING_BLOCK};
+use crate::forwarder::dnscrypt::DnsCryptUpstream;
+use crate::forwarder::https::HttpsUpstream;
+use crate::forwarder::quic::QuicUpstream;
+use crate::forwarder::tls::TlsUpstream;
+use crate::forwarder::udp::UdpUpstream;
+
+pub type UpstreamError = Box<dyn Error + Send + Sync>;
+
+/// A server queries can be forwarded to.
+#[derive(Debug, Clone)]
+pub enum Upstream {
+    Udp(Arc<UdpUpstream>),
+    Tls(Arc<TlsUpstream>),
+    Https(Arc<HttpsUpstream>),
+    Quic(Arc<QuicUpstream>),
+    DnsCrypt(Arc<DnsCryptUpstream>),
+}
+
+impl Upstream {
+    /// Parses `address[:port]`, with IPv6 addresses optionally in brackets,
+    /// `tls://host[:port][#name]`, `quic://host[:port][#name]` or
+    /// `https://host[:port]/path`, or a DNSCrypt stamp (`sdns://`). The
+    /// certificate of a DNS over TLS or QUIC server must match `name`, which
+    /// defaults to the host. Host names are looked up once, here.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text.starts_with("sdns://") {
+            let stamp = Stamp::parse(text)?;
+            return Ok(Upstream::DnsCrypt(Arc::new(DnsCryptUpstream::new(stamp))));
+        }
+        if text.starts_with("https://") {
+            return Ok(Upstream::Https(Arc::new(HttpsUpstream::new(text)?)));
+        }
+        if let Some(rest) = text.strip_prefix("quic://") {
+            let (address, name) = parse_named_host(rest, text)?;
+            return Ok(Upstream::Quic(Arc::new(QuicUpstream::new(address, &name)?)));
+        }
+        let Some(rest) = text.strip_prefix("tls://") else {
+            let address = parse_socket_addr(text, 53)?;
+            return Ok(Upstream::Udp(Arc::new(UdpUpstream::new(address))));
+        };
+        let (address, name) = parse_named_host(rest, text)?;
+        Ok(Upstream::Tls(Arc::new(TlsUpstream::new(address, &name)?)))
+    }
+
+    /// Sends a raw query and returns the raw response. Replies whose ID does
+    /// not match the query are dropped; truncated replies are retried over TCP.
+    pub async fn exchange(
+        &self,
+        query: &[u8],
+        timeout_duration: Duration,
+    ) -> Result<Vec<u8>, UpstreamError> {
+        // Encrypted queries are padded to hide their length (RFC 8467).
+        let padded = || {
+            let mut padded = query.to_vec();
+            pad(&mut padded, QUERY_PADDING_BLOCK);
+            padded
+        };
+        match self {
+            Upstream::Udp(upstream) => upstream.exchange(query, timeout_duration).await,
+            Upstream::Tls(upstream) => upstream.exchange(&padded(), timeout_duration).await,
+            Upstream::Https(upstream) => upstream.exchange(&padded(), timeout_duration).await,
+            Upstream::Quic(upstream) => upstream.exchange(&padded(), timeout_duration).await,
+            // DNSCrypt pads queries itself.
+            Upstream::DnsCrypt(upstream) => upstream.exchange(query, timeout_duration).await,
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Udp(upstream) => write!(f, "{}", upstream),
+            Upstream::Tls(upstream) => write!(f, "{}", upstream),
+            Upstream::Https(upstream) => write!(f, "{}", upstream),
+            Upstream::Quic(upstream) => write!(f, "{}", upstream),
+            Upstream::DnsCrypt(upstream) => write!(f, "{}", upstream),
+        }
+    }
+}
+
+// Parses `host[:port][#name]` for the encrypted transports on port 853,
+// returning the server's address and the name its certificate must match.
+fn parse_named_host(rest: &str, text: &str) -> Result<(SocketAddr, String), String> {
+    let (host, name) = match rest.split_once('#') {
+        Some((host, name)) => (host, Some(name)),
+        None => (rest, None),
+    };
+    let (address, hostname) = match parse_socket_addr(host, 853) {
+        Ok(address) => (address, address.ip().to_string()),
+        Err(_) => {
+            let (hostname, port) = match host.rsplit_once(':') {
+                Some((hostname, port)) => (
+                    hostname,
+                    port.parse()
+                        .map_err(|_| format!("Invalid port in {:?}", text))?,
+                ),
+                None => (host, 853),
+            };
+            let address = (hostname, port)
+                .to_socket_addrs()
+                .map_err(|e| format!("Cannot resolve {}: {}", hostname, e))?
+                .next()
+                .ok_or_else(|| format!("Cannot resolve {}", hostname))?;
+            (address, hostname.to_string())
+        }
+    };
+    Ok((address, name.map_or(hostname, str::to_string)))
+}
+
+/// Parses `address`, `address:port`, `ipv6` or `[ipv6]:port`.
+pub fn parse_socket_addr(text: &str, default_port: u16) -> Result<SocketAddr, String> {
+    if let Ok(address) = text.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    let host = text.trim_start_matches('[').trim_end_matches(']');
+    host.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, default_port))
+        .map_err(|_| format!("Invalid server address {:?}", text))
+}
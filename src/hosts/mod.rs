@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+
+use crate::dns::message::DNSMessage;
+use crate::dns::name::{encode_name, normalize, reverse_name};
+use crate::dns::resource_record::{RecordClass, RecordType, ResourceRecord};
+
+const DEFAULT_TTL: u32 = 60;
+// Modification times are checked at most this often.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Addresses pinned in hosts files, answered ahead of everything else.
+pub struct Hosts {
+    paths: Vec<PathBuf>,
+    ttl: u32,
+    table: RwLock<HostsTable>,
+    last_check: Mutex<Instant>,
+}
+
+#[derive(Default)]
+struct HostsTable {
+    modified: Vec<Option<SystemTime>>,
+    addresses: HashMap<String, Vec<IpAddr>>,
+    // Reverse name to the canonical names of the lines listing the address.
+    names: HashMap<String, Vec<String>>,
+}
+
+impl Hosts {
+    /// Loads the comma separated files in HOSTS_FILES, answering with
+    /// HOSTS_TTL (default 60 seconds). Returns `None` when no files are set.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        Hosts::from_list(&std::env::var("HOSTS_FILES").unwrap_or_default())
+    }
+
+    /// Like `from_env`, with the files given as a comma separated list.
+    pub fn from_list(files: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        let paths: Vec<PathBuf> = files
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        if paths.is_empty() {
+            return Ok(None);
+        }
+        let ttl = match std::env::var("HOSTS_TTL") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| format!("Invalid value for HOSTS_TTL: {:?}", value))?,
+            Err(_) => DEFAULT_TTL,
+        };
+        Ok(Some(Hosts::load(paths, ttl)?))
+    }
+
+    pub fn load(paths: Vec<PathBuf>, ttl: u32) -> Result<Self, Box<dyn Error>> {
+        let table = read_tables(&paths)?;
+        info!(
+            "Loaded {} host names from {} file(s)",
+            table.addresses.len(),
+            paths.len()
+        );
+        Ok(Hosts {
+            paths,
+            ttl,
+            table: RwLock::new(table),
+            last_check: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Answers A and AAAA queries for listed names and PTR queries for listed
+    /// addresses. A listed name without addresses of the asked type gets an
+    /// empty answer rather than being looked up elsewhere.
+    pub fn answer(&self, query: &DNSMessage) -> Option<DNSMessage> {
+        if query.header.flags.opcode != 0 || query.questions.len() != 1 {
+            return None;
+        }
+        let question = &query.questions[0];
+        if question.class != RecordClass::IN {
+            return None;
+        }
+        self.reload_if_changed();
+
+        let table = self.table.read().unwrap();
+        let name = normalize(&question.name);
+        let answers: Vec<ResourceRecord> = match question.record_type {
+            RecordType::A | RecordType::AAAA => table
+                .addresses
+                .get(&name)?
+                .iter()
+                .filter_map(|address| match (address, question.record_type) {
+                    (IpAddr::V4(v4), RecordType::A) => Some(v4.octets().to_vec()),
+                    (IpAddr::V6(v6), RecordType::AAAA) => Some(v6.octets().to_vec()),
+                    _ => None,
+                })
+                .map(|data| self.record(&question.name, question.record_type, data))
+                .collect(),
+            RecordType::PTR => table
+                .names
+                .get(&name)?
+                .iter()
+                .map(|target| {
+                    let mut data = Vec::new();
+                    encode_name(target, &mut data);
+                    self.record(&question.name, RecordType::PTR, data)
+                })
+                .collect(),
+            _ => return None,
+        };
+
+        let mut response = DNSMessage::response_to(query);
+        response.header.flags.aa = true;
+        response.header.flags.ra = true;
+        response.answers = answers;
+        Some(response)
+    }
+
+    fn record(&self, name: &str, record_type: RecordType, data: Vec<u8>) -> ResourceRecord {
+        ResourceRecord::new(
+            name.to_string(),
+            record_type,
+            RecordClass::IN,
+            self.ttl,
+            data,
+        )
+    }
+
+    fn reload_if_changed(&self) {
+        {
+            let mut last_check = self.last_check.lock().unwrap();
+            if last_check.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+            *last_check = Instant::now();
+        }
+        if modification_times(&self.paths) == self.table.read().unwrap().modified {
+            return;
+        }
+        // A file caught half written, or removed, leaves the old table in use.
+        match read_tables(&self.paths) {
+            Ok(table) => {
+                info!("Reloaded {} host names", table.addresses.len());
+                *self.table.write().unwrap() = table;
+            }
+            Err(e) => warn!("Failed to reload hosts files: {}", e),
+        }
+    }
+}
+
+fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn read_tables(paths: &[PathBuf]) -> Result<HostsTable, Box<dyn Error>> {
+    let mut table = HostsTable {
+        modified: modification_times(paths),
+        ..HostsTable::default()
+    };
+    for path in paths {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        parse_hosts(&text, &mut table);
+    }
+    Ok(table)
+}
+
+// Each line is an address followed by a canonical name and any aliases.
+// Lines that do not start with an address are ignored, as the resolver
+// library does.
+fn parse_hosts(text: &str, table: &mut HostsTable) {
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let Some(address) = fields.next() else {
+            continue;
+        };
+        // Zone indices (fe80::1%eth0) mean nothing outside this host.
+        let Ok(address) = address.parse::<IpAddr>() else {
+            continue;
+        };
+        let names: Vec<String> = fields.map(normalize).collect();
+        let Some(canonical) = names.first() else {
+            continue;
+        };
+
+        for name in &names {
+            let addresses = table.addresses.entry(name.clone()).or_default();
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        let targets = table.names.entry(reverse_name(address)).or_default();
+        if !targets.contains(canonical) {
+            targets.push(canonical.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    fn table(text: &str) -> HostsTable {
+        let mut table = HostsTable::default();
+        parse_hosts(text, &mut table);
+        table
+    }
+
+    fn addresses(table: &HostsTable, name: &str) -> Vec<String> {
+        table.addresses[name]
+            .iter()
+            .map(IpAddr::to_string)
+            .collect()
+    }
+
+    fn answers(hosts: &Hosts, name: &str, record_type: RecordType) -> Option<Vec<Vec<u8>>> {
+        let response = hosts.answer(&DNSMessage::query(1, name, record_type))?;
+        Some(response.answers.into_iter().map(|r| r.data).collect())
+    }
+
+    #[test]
+    fn parses_names_aliases_and_comments() {
+        let table = table(
+            "\
+# The usual entries
+127.0.0.1   localhost
+192.0.2.10  Server.Example.test server   # an alias
+192.0.2.11  server.example.test
+2001:db8::10 server.example.test
+fe80::1%eth0 linklocal
+not-an-address ignored
+192.0.2.12
+",
+        );
+        assert_eq!(
+            addresses(&table, "server.example.test"),
+            ["192.0.2.10", "192.0.2.11", "2001:db8::10"]
+        );
+        assert_eq!(addresses(&table, "server"), ["192.0.2.10"]);
+        assert!(!table.addresses.contains_key("linklocal"));
+        assert!(!table.addresses.contains_key("ignored"));
+        // Reverse names point at the canonical name of each line.
+        assert_eq!(
+            table.names["10.2.0.192.in-addr.arpa"],
+            ["server.example.test"]
+        );
+        let v6 = reverse_name("2001:db8::10".parse().unwrap());
+        assert!(v6.ends_with("8.b.d.0.1.0.0.2.ip6.arpa"));
+        assert_eq!(table.names[&v6], ["server.example.test"]);
+        assert!(!table.names.contains_key("12.2.0.192.in-addr.arpa"));
+    }
+
+    #[test]
+    fn answers_addresses_and_pointers() {
+        let dir = std::env::temp_dir().join(format!("tinydns-hosts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hosts");
+        fs::write(&path, "192.0.2.10 server.example.test\n").unwrap();
+        let hosts = Hosts::load(vec![path.clone()], 60).unwrap();
+
+        assert_eq!(
+            answers(&hosts, "SERVER.example.test", RecordType::A),
+            Some(vec![vec![192, 0, 2, 10]])
+        );
+        // A listed name without the asked type is answered, but empty.
+        assert_eq!(
+            answers(&hosts, "server.example.test", RecordType::AAAA),
+            Some(vec![])
+        );
+        assert_eq!(answers(&hosts, "other.example.test", RecordType::A), None);
+        assert_eq!(answers(&hosts, "server.example.test", RecordType::MX), None);
+        let mut target = Vec::new();
+        encode_name("server.example.test", &mut target);
+        assert_eq!(
+            answers(&hosts, "10.2.0.192.in-addr.arpa", RecordType::PTR),
+            Some(vec![target])
+        );
+
+        // Changes are picked up once the check interval has passed.
+        fs::write(&path, "192.0.2.20 server.example.test\n").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000))
+            .unwrap();
+        assert_eq!(
+            answers(&hosts, "server.example.test", RecordType::A),
+            Some(vec![vec![192, 0, 2, 10]])
+        );
+        *hosts.last_check.lock().unwrap() -= RELOAD_CHECK_INTERVAL;
+        assert_eq!(
+            answers(&hosts, "server.example.test", RecordType::A),
+            Some(vec![vec![192, 0, 2, 20]])
+        );
+
+        // A file that disappears leaves the last table in use.
+        fs::remove_dir_all(&dir).unwrap();
+        *hosts.last_check.lock().unwrap() -= RELOAD_CHECK_INTERVAL;
+        assert_eq!(
+            answers(&hosts, "server.example.test", RecordType::A),
+            Some(vec![vec![192, 0, 2, 20]])
+        );
+    }
+}
@@ -1,54 +1,555 @@
+mod acl;
+mod blocklist;
+mod cidr;
+mod config;
 mod dns;
+mod ecs;
+mod forwarder;
+mod hosts;
+mod ratelimit;
+mod recursor;
+mod rpz;
+mod rrl;
+mod server;
+mod validator;
+mod view;
+mod zone;
 
 use log::LevelFilter;
 use log::{error, info, warn};
 use std::error::Error;
-use tokio::net::UdpSocket;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::time::{timeout, Duration};
 
+use crate::acl::Acl;
+use crate::blocklist::Blocklist;
+use crate::dns::cookie::{Cookies, QueryCookie, COOKIE, RESPONSE_OPTION_LENGTH};
+use crate::dns::edns::{
+    append_option, pad, ClientSubnet, Edns, ExtendedError, PADDING, RESPONSE_PADDING_BLOCK,
+    SERVER_UDP_PAYLOAD,
+};
+use crate::dns::header::ResponseCode;
 use crate::dns::message::DNSMessage;
+use crate::dns::resource_record::RecordType;
+use crate::dns::tsig::{KeyRing, Signature, TsigKey};
+use crate::ecs::{restore_client_subnet, EcsConfig};
+use crate::ratelimit::{ClientLimitConfig, ClientLimiter, LimitAction};
+use crate::recursor::RecursorConfig;
+use crate::rpz::{Policy, Verdict};
+use crate::rrl::{ResponseRateLimiter, RrlAction, RrlConfig};
+use crate::server::dnscrypt::{bind_dnscrypt, serve_dnscrypt, DnsCryptServer};
+use crate::server::https::serve_https;
+use crate::server::proxy::ProxyProtocol;
+use crate::server::quic::{bind_quic, serve_quic};
+use crate::server::tcp::{bind_tcp, serve_tcp};
+use crate::server::tls::{serve_tls, tls_config_from_env};
+use crate::server::udp::{bind_udp, serve_udp};
+use crate::server::{Listener, Transport};
+use crate::validator::{Security, ValidatorConfig};
+use crate::view::View;
 
-async fn run_dns_server(addr: &str) -> Result<(), Box<dyn Error>> {
-    let socket = UdpSocket::bind(addr).await?;
-    info!("DNS Server is running on {}", addr);
+// Everything that can answer a query. The client's view supplies the data
+// that differs between clients.
+struct Resolvers {
+    acl: Acl,
+    limiter: Option<ClientLimiter>,
+    keys: KeyRing,
+    views: Vec<View>,
+    blocklist: Option<Blocklist>,
+    policy: Option<Policy>,
+    rrl: Option<ResponseRateLimiter>,
+    ecs: Option<EcsConfig>,
+    cookies: Option<Cookies>,
+}
 
-    // Reuse the same socket for forwarding requests
-    let remote_dns_server = "8.8.8.8:53"; // Google's public DNS server
+impl Resolvers {
+    // Answers one query from any transport, returning the response to send,
+    // if any. Only UDP responses are rate limited.
+    async fn handle(
+        &self,
+        query: &[u8],
+        client: SocketAddr,
+        local: SocketAddr,
+        transport: Transport,
+    ) -> Option<Vec<u8>> {
+        let message = match DNSMessage::parse(query) {
+            Ok(message) => message,
+            Err(e) => {
+                // Let the default upstream make sense of it, if there is one
+                // and the client may use it.
+                warn!("Failed to parse DNS message from {}: {}", client, e);
+                let view = self
+                    .views
+                    .iter()
+                    .find(|view| view.matches(client.ip(), local, None));
+                return match view.and_then(|view| view.forwarder.route("")) {
+                    Some(group)
+                        if self.acl.recursion.allows(client.ip()) && self.admit(client.ip()) =>
+                    {
+                        match group.forward(query).await {
+                            Ok(response) => Some(response),
+                            Err(e) => {
+                                error!("Error forwarding query to remote server: {}", e);
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+            }
+        };
+        info!(
+            "Received DNS Message from {} over {}: {:?}",
+            client, transport, message
+        );
+        let response = self
+            .answer(&message, query, client, local, transport)
+            .await?;
+        match &self.rrl {
+            Some(rrl) if transport == Transport::Udp => rate_limit(rrl, &message, response, client),
+            _ => Some(response),
+        }
+    }
 
-    let mut buf = [0u8; 512]; // Standard DNS message size
-    let mut shutdown_signal = signal(SignalKind::interrupt())?;
+    // Checks the query's cookie and TSIG signature, answers it as if it had
+    // not been signed, adds our EDNS options to the response and signs it
+    // with the same key. Queries that fail verification get NOTAUTH
+    // (RFC 8945 section 5.2), and those whose server cookie is required but
+    // missing get BADCOOKIE (RFC 7873 section 5.2.3).
+    async fn answer(
+        &self,
+        message: &DNSMessage,
+        query: &[u8],
+        client: SocketAddr,
+        local: SocketAddr,
+        transport: Transport,
+    ) -> Option<Vec<u8>> {
+        let limit = transport.max_response_size(message);
+        let cookie = match (&self.cookies, &message.edns) {
+            (Some(_), Some(edns)) => match QueryCookie::find(edns) {
+                Ok(cookie) => cookie,
+                Err(e) => {
+                    warn!("{} from {}", e, client);
+                    let mut response = DNSMessage::response_to(message);
+                    response.set_response_code(ResponseCode::FormErr);
+                    return Some(response.to_bytes_with_limit(limit));
+                }
+            },
+            _ => None,
+        };
+        if let (Some(cookies), Some(cookie)) = (&self.cookies, &cookie) {
+            if cookies.required
+                && transport == Transport::Udp
+                && !cookies.is_valid(cookie, client.ip())
+            {
+                info!("Bad server cookie from {}", client);
+                let response = cookies.bad_cookie(message, cookie, client.ip());
+                return Some(response.to_bytes_with_limit(limit));
+            }
+        }
 
-    loop {
-        tokio::select! {
-            _ = shutdown_signal.recv() => {
-                info!("Shutdown signal received. Closing server...");
-                break;
+        let (signature, key) = match Signature::find(query) {
+            Ok(Some(signature)) => match signature.verify(&self.keys) {
+                Ok(key) => (Some(signature), Some(key)),
+                Err(error) => {
+                    warn!(
+                        "TSIG {} for key {} from {}",
+                        error, signature.key_name, client
+                    );
+                    let mut response = DNSMessage::response_to(message);
+                    response.set_response_code(ResponseCode::NotAuth);
+                    let mut response = response.to_bytes();
+                    signature.append_error(error, &mut response);
+                    return Some(response);
+                }
+            },
+            _ => (None, None),
+        };
+        let unsigned = signature
+            .as_ref()
+            .map_or(query, |signature| signature.unsigned_message());
+        // The cookie and signature are added to the serialized response, so
+        // room is left for them within what the client can receive.
+        let reserved = match (&self.cookies, &cookie) {
+            (Some(_), Some(_)) => RESPONSE_OPTION_LENGTH,
+            _ => 0,
+        } + key.map_or(0, TsigKey::record_length);
+        let limit = limit.saturating_sub(reserved);
+        let result = self
+            .answer_query(
+                message,
+                unsigned,
+                client,
+                local,
+                transport,
+                limit,
+                key.map(|key| key.name.as_str()),
+            )
+            .await;
+        let mut response = match result {
+            Ok(response) => response?,
+            Err(e) => {
+                error!("Error forwarding query to remote server: {}", e);
+                let mut response = DNSMessage::response_to(message);
+                response.header.flags.ra = true;
+                response.set_response_code(ResponseCode::ServFail);
+                response.set_extended_error(
+                    ExtendedError::NoReachableAuthority,
+                    "No upstream server answered",
+                );
+                response.to_bytes_with_limit(limit)
             }
-            result = socket.recv_from(&mut buf) => {
-                match result {
-                    Ok((len, addr)) => {
-                        match DNSMessage::parse(&buf[0..len]) {
-                            Ok(message) => info!("Received DNS Message from {}: {:?}", addr, message),
-                            Err(e) => warn!("Failed to parse DNS message from {}: {}", addr, e),
-                        }
+        };
 
-                        match forward_query(remote_dns_server, &buf[0..len]).await {
-                            Ok(response) => {
-                                if let Err(e) = socket.send_to(&response, addr).await {
-                                    error!("Error sending response to {}: {}", addr, e);
-                                }
-                            }
-                            Err(e) => error!("Error forwarding query to remote server: {}", e),
-                        }
+        if let (Some(cookies), Some(cookie)) = (&self.cookies, &cookie) {
+            append_option(
+                &mut response,
+                COOKIE,
+                &cookies.response_option(cookie, client.ip()),
+            );
+        }
+        // Encrypted responses are padded when the query was (RFC 8467
+        // section 4.1), before signing so that the MAC covers the padding.
+        let padded = message
+            .edns
+            .as_ref()
+            .is_some_and(|edns| edns.has_option(PADDING));
+        if padded
+            && matches!(
+                transport,
+                Transport::Tls | Transport::Https | Transport::Quic
+            )
+        {
+            pad(&mut response, RESPONSE_PADDING_BLOCK);
+        }
+        if let (Some(signature), Some(key)) = (signature, key) {
+            signature.sign_response(key, &mut response);
+        }
+        Some(response)
+    }
+
+    // Hosts file overrides come first, then authoritative data, then the
+    // blocklist. Anything else is resolved by forwarding rules or recursion,
+    // with response policy applied around it. In recursive mode only names
+    // covered by an explicit rule are forwarded. `None` means no response is
+    // sent at all. Clients the ACL does not allow a service get REFUSED.
+    #[allow(clippy::too_many_arguments)]
+    async fn answer_query(
+        &self,
+        message: &DNSMessage,
+        query: &[u8],
+        client: SocketAddr,
+        local: SocketAddr,
+        transport: Transport,
+        limit: usize,
+        key: Option<&str>,
+    ) -> Result<Option<Vec<u8>>, forwarder::upstream::UpstreamError> {
+        if !self.admit(client.ip()) {
+            return Ok(self
+                .over_limit(message, client)
+                .map(|response| response.to_bytes_with_limit(limit)));
+        }
+        let Some(question) = message.questions.first() else {
+            return Ok(Some(refused(message, client).to_bytes_with_limit(limit)));
+        };
+        if matches!(question.record_type, RecordType::AXFR | RecordType::IXFR) {
+            // Zone transfers are not served, which clients allowed them are
+            // told rather than being handed an empty answer.
+            let response = if self.acl.transfer.allows(client.ip()) {
+                not_implemented(message)
+            } else {
+                refused(message, client)
+            };
+            return Ok(Some(response.to_bytes_with_limit(limit)));
+        }
+
+        let Some(view) = self
+            .views
+            .iter()
+            .find(|view| view.matches(client.ip(), local, key))
+        else {
+            return Ok(Some(refused(message, client).to_bytes_with_limit(limit)));
+        };
+
+        let recursion = self.acl.recursion.allows(client.ip());
+        let overridden = view
+            .hosts
+            .as_ref()
+            .filter(|_| recursion)
+            .and_then(|hosts| hosts.answer(message));
+        if let Some(response) = overridden {
+            return Ok(Some(response.to_bytes_with_limit(limit)));
+        }
+        if view.catalog.find_zone(&question.name).is_some() {
+            if !self.acl.query.allows(client.ip()) {
+                return Ok(Some(refused(message, client).to_bytes_with_limit(limit)));
+            }
+            if let Some(response) = view.catalog.answer(message) {
+                return Ok(Some(response.to_bytes_with_limit(limit)));
+            }
+        }
+        if !recursion {
+            return Ok(Some(refused(message, client).to_bytes_with_limit(limit)));
+        }
+        if let Some(response) = self
+            .blocklist
+            .as_ref()
+            .and_then(|blocklist| blocklist.answer(message))
+        {
+            return Ok(Some(response.to_bytes_with_limit(limit)));
+        }
+
+        let policy = match self
+            .policy
+            .as_ref()
+            .map(|policy| (policy, policy.check_query(message, client, transport)))
+        {
+            Some((_, Verdict::Respond(response))) => {
+                return Ok(Some(response.to_bytes_with_limit(limit)))
+            }
+            Some((_, Verdict::Drop)) => return Ok(None),
+            Some((policy, Verdict::NoMatch)) => Some(policy),
+            Some((_, Verdict::Passthru)) | None => None,
+        };
+
+        let response = match self.resolve(view, message, query, client, limit).await? {
+            Some(response) => response,
+            None => {
+                return Ok(self
+                    .over_limit(message, client)
+                    .map(|response| response.to_bytes_with_limit(limit)))
+            }
+        };
+        let Some(policy) = policy else {
+            return Ok(Some(response));
+        };
+        // Response IP triggers need the addresses in the answer.
+        let verdict = match DNSMessage::parse(&response) {
+            Ok(parsed) => policy.check_response(message, &parsed, client, transport),
+            Err(_) => Verdict::NoMatch,
+        };
+        match verdict {
+            Verdict::Respond(rewritten) => Ok(Some(rewritten.to_bytes_with_limit(limit))),
+            Verdict::Drop => Ok(None),
+            Verdict::NoMatch | Verdict::Passthru => Ok(Some(response)),
+        }
+    }
+
+    // Sends the query upstream, or returns `None` when the client already
+    // has as many upstream queries outstanding as it may. With client subnets
+    // enabled the client's subnet goes along, and forwarded answers scoped
+    // to a subnet are cached for it. With validation enabled the query asks
+    // for DNSSEC records; `finish` prepares the response.
+    async fn resolve(
+        &self,
+        view: &View,
+        message: &DNSMessage,
+        query: &[u8],
+        client: SocketAddr,
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>, forwarder::upstream::UpstreamError> {
+        let qname = message.questions.first().map_or("", |q| q.name.as_str());
+        let subnet = self
+            .ecs
+            .as_ref()
+            .and_then(|ecs| ecs.subnet_for(message, client.ip()));
+        let route = view.forwarder.route(qname);
+        let cached = subnet
+            .as_ref()
+            .filter(|_| route.is_some())
+            .and_then(|sent| view.subnets.get(message, sent));
+        if let Some(cached) = cached {
+            let response = self.finish(view, message, subnet.as_ref(), cached).await;
+            return Ok(Some(response.to_bytes_with_limit(limit)));
+        }
+        let _in_flight = match &self.limiter {
+            Some(limiter) => match limiter.begin_upstream(client.ip()) {
+                Some(in_flight) => Some(in_flight),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+        let response = match route {
+            Some(group) => {
+                if message.edns.is_none() && subnet.is_none() && view.validator.is_none() {
+                    return group.forward(query).await.map(Some);
+                }
+                // EDNS options are hop by hop (RFC 6891 section 6.1.1):
+                // cookies and padding stay between us and each side, and
+                // only the subnet allowed above is passed on. The unsigned
+                // query is rebuilt, since TSIG would not survive the change
+                // anyway.
+                let mut upstream_query = DNSMessage::parse(query)?;
+                let edns = upstream_query
+                    .edns
+                    .get_or_insert_with(|| Edns::new(SERVER_UDP_PAYLOAD));
+                edns.remove_options(&[COOKIE, PADDING]);
+                if self.ecs.is_some() {
+                    edns.set_client_subnet(subnet);
+                }
+                // A validator needs the signatures, and the data even when
+                // the upstream thinks it bogus.
+                if view.validator.is_some() {
+                    edns.dnssec_ok = true;
+                    upstream_query.header.flags.cd = true;
+                }
+                let response = group.forward(&upstream_query.to_bytes()).await?;
+                let mut response = match DNSMessage::parse(&response) {
+                    Ok(response) => response,
+                    // What cannot be parsed cannot be validated either.
+                    Err(e) if view.validator.is_some() => {
+                        warn!("Unparseable response for {}: {}", qname, e);
+                        let mut servfail = DNSMessage::response_to(message);
+                        servfail.header.flags.ra = true;
+                        servfail.set_response_code(ResponseCode::ServFail);
+                        servfail.set_extended_error(
+                            ExtendedError::DnssecBogus,
+                            "Unparseable upstream response",
+                        );
+                        return Ok(Some(servfail.to_bytes_with_limit(limit)));
+                    }
+                    Err(_) => return Ok(Some(response)),
+                };
+                if let Some(edns) = response.edns.as_mut() {
+                    edns.remove_options(&[COOKIE, PADDING]);
+                }
+                if let Some(sent) = &subnet {
+                    view.subnets.insert(sent, &response);
+                }
+                response
+            }
+            None => match &view.recursor {
+                Some(recursor) => recursor.answer(message, subnet.as_ref()).await,
+                None => return Err(format!("No upstream for {}", qname).into()),
+            },
+        };
+        let response = self.finish(view, message, subnet.as_ref(), response).await;
+        Ok(Some(response.to_bytes_with_limit(limit)))
+    }
+
+    // Hands an upstream or recursive answer back to the client: with its own
+    // ECS option, validated unless it set CD, and without the DNSSEC records
+    // it did not ask for with DO. Bogus answers become SERVFAIL.
+    async fn finish(
+        &self,
+        view: &View,
+        message: &DNSMessage,
+        subnet: Option<&ClientSubnet>,
+        mut response: DNSMessage,
+    ) -> DNSMessage {
+        if self.ecs.is_some() {
+            restore_client_subnet(message, subnet, &mut response);
+        }
+        if message.edns.is_none() {
+            response.edns = None;
+        }
+        let dnssec_ok = message.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+        if let Some(validator) = &view.validator {
+            response.header.flags.cd = message.header.flags.cd;
+            response.header.flags.ad = false;
+            if !message.header.flags.cd {
+                match validator.validate(&response, view).await {
+                    // Only clients that show they understand AD get it
+                    // (RFC 6840 section 5.7).
+                    Security::Secure => {
+                        response.header.flags.ad = dnssec_ok || message.header.flags.ad
+                    }
+                    Security::Insecure => {}
+                    Security::Bogus(error, text) => {
+                        warn!("DNSSEC validation failed: {}", text);
+                        let mut servfail = DNSMessage::response_to(message);
+                        servfail.header.flags.ra = true;
+                        servfail.set_response_code(ResponseCode::ServFail);
+                        servfail.set_extended_error(error, &text);
+                        return servfail;
                     }
-                    Err(e) => error!("Error receiving from socket: {}", e),
                 }
             }
         }
+        if !dnssec_ok {
+            response.strip_dnssec_records();
+        }
+        response
     }
 
-    Ok(())
+    fn admit(&self, client: IpAddr) -> bool {
+        self.limiter
+            .as_ref()
+            .is_none_or(|limiter| limiter.admit(client))
+    }
+
+    // The response to a query over the client's limits, if any is sent.
+    fn over_limit(&self, query: &DNSMessage, client: SocketAddr) -> Option<DNSMessage> {
+        match self.limiter.as_ref().map(ClientLimiter::action) {
+            Some(LimitAction::Drop) => None,
+            _ => {
+                let mut response = refused(query, client);
+                response.set_extended_error(ExtendedError::Other, "Query rate limit exceeded");
+                Some(response)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Resolvers {
+    // Answers any client from `catalog`, with every optional check off.
+    fn serving(catalog: zone::catalog::Catalog) -> Self {
+        let any = || acl::AccessList::parse("any").unwrap();
+        Resolvers {
+            acl: Acl {
+                recursion: any(),
+                query: any(),
+                transfer: any(),
+            },
+            limiter: None,
+            keys: KeyRing::default(),
+            views: vec![View::serving(catalog)],
+            blocklist: None,
+            policy: None,
+            rrl: None,
+            ecs: None,
+            cookies: None,
+        }
+    }
+}
+
+// Applies response rate limiting to a UDP response. Slipped responses carry
+// no data, only TC so that genuine clients retry over TCP.
+fn rate_limit(
+    rrl: &ResponseRateLimiter,
+    query: &DNSMessage,
+    response: Vec<u8>,
+    client: SocketAddr,
+) -> Option<Vec<u8>> {
+    let Ok(parsed) = DNSMessage::parse(&response) else {
+        return Some(response);
+    };
+    match rrl.check(client.ip(), &parsed) {
+        RrlAction::Send => Some(response),
+        RrlAction::Drop => None,
+        RrlAction::Slip => {
+            let mut truncated = DNSMessage::response_to(query);
+            truncated.header.flags.tc = true;
+            Some(truncated.to_bytes())
+        }
+    }
+}
+
+fn refused(query: &DNSMessage, client: SocketAddr) -> DNSMessage {
+    info!("Refused query from {}", client);
+    let mut response = DNSMessage::response_to(query);
+    response.set_response_code(ResponseCode::Refused);
+    response.set_extended_error(ExtendedError::Prohibited, "");
+    response
+}
+
+fn not_implemented(query: &DNSMessage) -> DNSMessage {
+    let mut response = DNSMessage::response_to(query);
+    response.set_response_code(ResponseCode::NotImp);
+    response
 }
 
 #[tokio::main]
@@ -58,46 +559,322 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Initialize logger
     env_logger::builder().filter_level(LevelFilter::Info).init();
 
-    let port = std::env::var("PORT").unwrap_or_else(|_| "53".to_string());
-    let addr = format!("0.0.0.0:{}", port);
-
     info!("tinydns v0.1.0");
-    if let Err(e) = run_dns_server(&addr).await {
-        error!("DNS server encountered an error: {}", e);
+    let tls = tls_config_from_env()?;
+    let dnscrypt = DnsCryptServer::from_env()?.map(Arc::new);
+    let proxy = ProxyProtocol::from_env()?.map(Arc::new);
+    let listeners = Listener::from_env(tls.is_some())?;
+
+    // RESOLVER_MODE=recursive resolves from the root servers; anything else
+    // forwards to the upstream server.
+    let recursion = match std::env::var("RESOLVER_MODE").as_deref() {
+        Ok("recursive") => {
+            let config = RecursorConfig::from_env()?;
+            info!(
+                "Resolving recursively from {} root servers",
+                config.root_hints.len()
+            );
+            Some(config)
+        }
+        _ => None,
+    };
+
+    let validation = ValidatorConfig::from_env()?;
+    if let Some(config) = &validation {
+        info!(
+            "Validating DNSSEC from {} trust anchor records",
+            config.trust_anchors.len()
+        );
+        for domain in &config.negative_trust_anchors {
+            info!("Not validating {}", domain);
+        }
+    }
+
+    let views = match view::load_views(recursion.as_ref(), validation.as_ref()) {
+        Ok(views) => views,
+        Err(e) => {
+            error!("Failed to load zones: {}", e);
+            return Err(e);
+        }
+    };
+    view::check_destinations(&views, &listeners)?;
+    // ZONE_DUMP_DIR gets a canonical copy of every zone as loaded, one
+    // directory per view.
+    if let Ok(dir) = std::env::var("ZONE_DUMP_DIR") {
+        for view in &views {
+            let dir = Path::new(&dir).join(&view.name);
+            view.catalog
+                .dump(&dir)
+                .map_err(|e| format!("ZONE_DUMP_DIR: {}: {}", dir.display(), e))?;
+        }
+    }
+    for view in &views {
+        for (suffix, group) in view.forwarder.routes() {
+            info!(
+                "Forwarding {} to {} in view {}",
+                if suffix.is_empty() { "." } else { suffix },
+                group,
+                view.name
+            );
+        }
+    }
+    let blocklist = Blocklist::from_env()?;
+    let policy = Policy::from_env()?;
+
+    let rrl = RrlConfig::from_env()?.map(|config| {
+        info!(
+            "Rate limiting responses to {} per second",
+            config.responses_per_second
+        );
+        ResponseRateLimiter::new(config)
+    });
+
+    let limiter = ClientLimitConfig::from_env()?.map(ClientLimiter::new);
+
+    let ecs = EcsConfig::from_env()?;
+    if let Some(ecs) = &ecs {
+        info!(
+            "Sending client subnets upstream, up to /{} for IPv4 and /{} for IPv6",
+            ecs.ipv4_prefix, ecs.ipv6_prefix
+        );
+    }
+
+    let resolvers = Arc::new(Resolvers {
+        acl: Acl::from_env()?,
+        limiter,
+        keys: KeyRing::from_env()?,
+        views,
+        blocklist,
+        policy,
+        rrl,
+        ecs,
+        cookies: Cookies::from_env()?,
+    });
+
+    // A listener that cannot be bound stops the server rather than leaving
+    // it half up, unless it is a default one the host may not support.
+    let mut servers = Vec::new();
+    for listener in &listeners {
+        for &transport in &listener.transports {
+            let address = listener.address;
+            let server = match transport {
+                Transport::Udp => bind_udp(address).await.map(|socket| {
+                    tokio::spawn(serve_udp(socket, resolvers.clone(), proxy.clone()))
+                }),
+                Transport::Tcp => bind_tcp(address).await.map(|listener| {
+                    tokio::spawn(serve_tcp(listener, resolvers.clone(), proxy.clone()))
+                }),
+                Transport::DnsCrypt => {
+                    let Some(server) = dnscrypt.clone() else {
+                        return Err(format!(
+                            "Listening on {} over {} needs DNSCRYPT_PROVIDER_NAME and DNSCRYPT_PROVIDER_KEY",
+                            address, transport
+                        )
+                        .into());
+                    };
+                    bind_dnscrypt(address).await.map(|(socket, listener)| {
+                        tokio::spawn(serve_dnscrypt(socket, listener, server, resolvers.clone()))
+                    })
+                }
+                Transport::Tls | Transport::Https | Transport::Quic => {
+                    let Some(config) = tls.clone() else {
+                        return Err(format!(
+                            "Listening on {} over {} needs TLS_CERT_FILE and TLS_KEY_FILE",
+                            address, transport
+                        )
+                        .into());
+                    };
+                    let resolvers = resolvers.clone();
+                    match transport {
+                        Transport::Quic => bind_quic(address, &config)
+                            .map(|endpoint| tokio::spawn(serve_quic(endpoint, resolvers))),
+                        _ => bind_tcp(address).await.map(|listener| match transport {
+                            Transport::Tls => {
+                                tokio::spawn(serve_tls(listener, config, resolvers, proxy.clone()))
+                            }
+                            _ => tokio::spawn(serve_https(
+                                listener,
+                                config,
+                                resolvers,
+                                proxy.clone(),
+                            )),
+                        }),
+                    }
+                }
+            };
+            match server {
+                Ok(server) => servers.push(server),
+                Err(e) if listener.best_effort => {
+                    warn!("Not listening on {} over {}: {}", address, transport, e);
+                }
+                Err(e) => {
+                    error!("Failed to listen on {} over {}: {}", address, transport, e);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    signal(SignalKind::interrupt())?.recv().await;
+    info!("Shutdown signal received. Closing server...");
+    for server in servers {
+        server.abort();
+    }
+    if let Some(rrl) = &resolvers.rrl {
+        let (dropped, slipped) = rrl.counters();
+        info!(
+            "Rate limiting dropped {} and slipped {} responses",
+            dropped, slipped
+        );
     }
     Ok(())
 }
 
-// Forward the query to another DNS server (e.g., 8.8.8.8)
-async fn forward_query(
-    remote_dns_server: &str,
-    query: &[u8],
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    let timeout_duration = Duration::from_secs(5);
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-    // Create a new socket for forwarding the query
-    let local_socket = UdpSocket::bind("0.0.0.0:0").await?;
-    local_socket.connect(remote_dns_server).await?;
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
 
-    // Send the query to the remote DNS server
-    local_socket.send(query).await?;
+    use super::*;
+    use crate::dns::resource_record::{RecordClass, ResourceRecord};
+    use crate::forwarder::upstream::Upstream;
+    use crate::forwarder::{Strategy, UpstreamGroup};
+    use crate::zone::catalog::Catalog;
 
-    let mut response = vec![0u8; 512]; // Standard DNS response buffer
-    let res = timeout(timeout_duration, local_socket.recv(&mut response)).await;
+    const PRIVATE_TYPE: u16 = 65280;
 
-    match res {
-        Ok(Ok(len)) => {
-            response.truncate(len);
-            Ok(response)
+    // A server on loopback answering every query with one record of the
+    // queried type, passing on each query it receives as sent. A client
+    // subnet is echoed with a scope as long as its source prefix.
+    async fn upstream() -> (UpstreamGroup, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+        let (queries, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, client)) = socket.recv_from(&mut buffer).await {
+                let _ = queries.send(buffer[..len].to_vec());
+                let Ok(query) = DNSMessage::parse(&buffer[..len]) else {
+                    continue;
+                };
+                let mut response = DNSMessage::response_to(&query);
+                let question = &query.questions[0];
+                response.answers.push(ResourceRecord::new(
+                    question.name.clone(),
+                    question.record_type,
+                    RecordClass::IN,
+                    60,
+                    vec![1, 2, 3],
+                ));
+                let subnet = query.edns.as_ref().and_then(|edns| edns.client_subnet());
+                if let (Some(edns), Some(subnet)) = (response.edns.as_mut(), subnet) {
+                    edns.set_client_subnet(Some(ClientSubnet {
+                        scope_prefix: subnet.source_prefix,
+                        ..subnet
+                    }));
+                }
+                let _ = socket.send_to(&response.to_bytes(), client).await;
+            }
+        });
+        let upstream = Upstream::parse(&address).unwrap();
+        let group = UpstreamGroup::new(vec![upstream], Duration::from_secs(2), Strategy::Failover);
+        (group, received)
+    }
+
+    // The type and class of the first question in a raw message.
+    fn question_type_and_class(message: &[u8]) -> (u16, u16) {
+        let (_, end) = crate::dns::name::parse_name(message, 12).unwrap();
+        let fixed = &message[end..end + 4];
+        (
+            u16::from_be_bytes([fixed[0], fixed[1]]),
+            u16::from_be_bytes([fixed[2], fixed[3]]),
+        )
+    }
+
+    #[tokio::test]
+    async fn forwards_unknown_types_unchanged() {
+        let (group, mut received) = upstream().await;
+        let mut resolvers = Resolvers::serving(Catalog::default());
+        resolvers.views[0].forwarder.add("", group);
+
+        // The query carries EDNS, so it is rebuilt on the way upstream.
+        let query = DNSMessage::query(7, "www.example.test", RecordType::from_u16(PRIVATE_TYPE));
+        assert!(query.edns.is_some());
+        let client = "127.0.0.1:5300".parse().unwrap();
+        let local = "127.0.0.1:53".parse().unwrap();
+        let response = resolvers
+            .handle(&query.to_bytes(), client, local, Transport::Udp)
+            .await
+            .unwrap();
+
+        let sent = received.recv().await.unwrap();
+        assert_eq!(question_type_and_class(&sent), (PRIVATE_TYPE, 1));
+        assert_eq!(question_type_and_class(&response), (PRIVATE_TYPE, 1));
+        let response = DNSMessage::parse(&response).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        let answer = &response.answers[0];
+        assert_eq!(answer.record_type.to_u16(), PRIVATE_TYPE);
+        assert_eq!(answer.record_type.to_string(), "TYPE65280");
+        assert_eq!(answer.data, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn answers_transfers_with_notimp_or_refused() {
+        let mut resolvers = Resolvers::serving(Catalog::default());
+        let client = "192.0.2.1:5300".parse().unwrap();
+        let local = "127.0.0.1:53".parse().unwrap();
+        for record_type in [RecordType::AXFR, RecordType::IXFR] {
+            let query = DNSMessage::query(7, "example.test", record_type).to_bytes();
+            let response = resolvers
+                .handle(&query, client, local, Transport::Tcp)
+                .await
+                .unwrap();
+            let rcode = DNSMessage::parse(&response).unwrap().header.flags.rcode;
+            assert_eq!(rcode, ResponseCode::NotImp as u8);
         }
-        Ok(Err(e)) => {
-            error!("Error receiving response from remote server: {}", e);
-            Err(Box::new(e))
+
+        resolvers.acl.transfer = acl::AccessList::parse("none").unwrap();
+        let query = DNSMessage::query(7, "example.test", RecordType::AXFR).to_bytes();
+        let response = resolvers
+            .handle(&query, client, local, Transport::Tcp)
+            .await
+            .unwrap();
+        let rcode = DNSMessage::parse(&response).unwrap().header.flags.rcode;
+        assert_eq!(rcode, ResponseCode::Refused as u8);
+    }
+
+    #[tokio::test]
+    async fn caches_forwarded_answers_per_client_subnet() {
+        let (group, mut received) = upstream().await;
+        let mut resolvers = Resolvers::serving(Catalog::default());
+        resolvers.views[0].forwarder.add("", group);
+        resolvers.ecs = Some(EcsConfig {
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            exclude: acl::AccessList::parse("none").unwrap(),
+        });
+        let local = "127.0.0.1:53".parse().unwrap();
+        let query = DNSMessage::query(7, "www.example.test", RecordType::A).to_bytes();
+        for client in ["192.0.2.1:5300", "192.0.2.2:5300", "198.51.100.1:5300"] {
+            resolvers
+                .handle(&query, client.parse().unwrap(), local, Transport::Udp)
+                .await
+                .unwrap();
         }
-        Err(_) => {
-            warn!("Timeout while waiting for response from remote DNS server");
-            Err("Timeout while waiting for response".into())
+        let mut subnets = Vec::new();
+        while let Ok(sent) = received.try_recv() {
+            let sent = DNSMessage::parse(&sent).unwrap();
+            subnets.push(sent.edns.unwrap().client_subnet().unwrap().address);
         }
+        // The second client shares the first one's /24 and its answer.
+        assert_eq!(
+            subnets,
+            [
+                "192.0.2.0".parse::<IpAddr>().unwrap(),
+                "198.51.100.0".parse().unwrap()
+            ]
+        );
     }
 }
-
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use log::info;
+
+use crate::acl::AccessList;
+use crate::cidr::Cidr;
+use crate::config::env_or;
+
+// Past this many buckets, full ones are forgotten.
+const MAX_BUCKETS: usize = 100_000;
+
+/// What happens to queries over a client's limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitAction {
+    Refuse,
+    Drop,
+}
+
+/// Settings for per-client query limits.
+#[derive(Debug, Clone)]
+pub struct ClientLimitConfig {
+    /// Sustained queries per second allowed per client network; 0 for no limit.
+    pub queries_per_second: f64,
+    /// How many queries a quiet client may send at once.
+    pub burst: f64,
+    /// Upstream queries a client network may have outstanding; 0 for no limit.
+    pub max_in_flight: usize,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    pub action: LimitAction,
+    pub exempt: AccessList,
+}
+
+impl ClientLimitConfig {
+    /// Reads CLIENT_QUERIES_PER_SECOND and CLIENT_MAX_IN_FLIGHT, either of
+    /// which enables limiting, along with CLIENT_QUERY_BURST (default: one
+    /// second's worth), CLIENT_IPV4_PREFIX (default 32), CLIENT_IPV6_PREFIX
+    /// (default 64), CLIENT_LIMIT_ACTION (`refused` or `drop`) and
+    /// CLIENT_LIMIT_EXEMPT (networks, default none).
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let queries_per_second: f64 = env_or("CLIENT_QUERIES_PER_SECOND", 0.0)?;
+        let max_in_flight = env_or("CLIENT_MAX_IN_FLIGHT", 0)?;
+        if queries_per_second <= 0.0 && max_in_flight == 0 {
+            return Ok(None);
+        }
+        let ipv4_prefix = env_or("CLIENT_IPV4_PREFIX", 32)?;
+        let ipv6_prefix = env_or("CLIENT_IPV6_PREFIX", 64)?;
+        if ipv4_prefix > 32 || ipv6_prefix > 128 {
+            return Err("Client prefix lengths must fit the address family".into());
+        }
+        let action = match std::env::var("CLIENT_LIMIT_ACTION").as_deref() {
+            Ok("drop") => LimitAction::Drop,
+            Ok("refused") | Err(_) => LimitAction::Refuse,
+            Ok(other) => {
+                return Err(format!("Invalid value for CLIENT_LIMIT_ACTION: {:?}", other).into())
+            }
+        };
+        let exempt = std::env::var("CLIENT_LIMIT_EXEMPT").unwrap_or_default();
+        Ok(Some(ClientLimitConfig {
+            queries_per_second,
+            burst: env_or("CLIENT_QUERY_BURST", queries_per_second)?,
+            max_in_flight,
+            ipv4_prefix,
+            ipv6_prefix,
+            action,
+            exempt: AccessList::parse(&exempt)
+                .map_err(|e| format!("Invalid value for CLIENT_LIMIT_EXEMPT: {}", e))?,
+        }))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limited: bool,
+}
+
+/// Token bucket query rate limits and upstream concurrency quotas per
+/// client network, so one busy host cannot starve the rest.
+pub struct ClientLimiter {
+    config: ClientLimitConfig,
+    buckets: Mutex<HashMap<Cidr, Bucket>>,
+    in_flight: Mutex<HashMap<Cidr, usize>>,
+}
+
+/// An upstream query counted against its client until dropped.
+pub struct InFlight<'a> {
+    limiter: &'a ClientLimiter,
+    // `None` for clients whose queries are not counted.
+    network: Option<Cidr>,
+}
+
+impl ClientLimiter {
+    pub fn new(config: ClientLimitConfig) -> Self {
+        ClientLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn action(&self) -> LimitAction {
+        self.config.action
+    }
+
+    /// Takes a token for a query, returning false when the client has none.
+    pub fn admit(&self, client: IpAddr) -> bool {
+        self.admit_at(client, Instant::now())
+    }
+
+    fn admit_at(&self, client: IpAddr, now: Instant) -> bool {
+        if self.config.queries_per_second <= 0.0 || self.config.exempt.allows(client) {
+            return true;
+        }
+        let network = self.network(client);
+        let rate = self.config.queries_per_second;
+        let burst = self.config.burst.max(1.0);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(network).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            limited: false,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = false;
+            return true;
+        }
+        if !bucket.limited {
+            bucket.limited = true;
+            info!("Query rate limit reached for {}", network);
+        }
+        false
+    }
+
+    /// Counts an upstream query against the client, or returns `None` when it
+    /// already has as many outstanding as it may.
+    pub fn begin_upstream(&self, client: IpAddr) -> Option<InFlight<'_>> {
+        if self.config.max_in_flight == 0 || self.config.exempt.allows(client) {
+            return Some(InFlight {
+                limiter: self,
+                network: None,
+            });
+        }
+        let network = self.network(client);
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(network).or_insert(0);
+        if *count >= self.config.max_in_flight {
+            info!("Upstream query quota reached for {}", network);
+            return None;
+        }
+        *count += 1;
+        Some(InFlight {
+            limiter: self,
+            network: Some(network),
+        })
+    }
+
+    fn network(&self, client: IpAddr) -> Cidr {
+        let prefix = if client.is_ipv4() {
+            self.config.ipv4_prefix
+        } else {
+            self.config.ipv6_prefix
+        };
+        Cidr::new(client, prefix).expect("prefix checked in config")
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let Some(network) = self.network else {
+            return;
+        };
+        let mut in_flight = self.limiter.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&network) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&network);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limiter(queries_per_second: f64, burst: f64, max_in_flight: usize) -> ClientLimiter {
+        ClientLimiter::new(ClientLimitConfig {
+            queries_per_second,
+            burst,
+            max_in_flight,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            action: LimitAction::Refuse,
+            exempt: AccessList::parse("192.0.2.53").unwrap(),
+        })
+    }
+
+    fn client(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn refills_buckets_at_the_query_rate() {
+        let limiter = limiter(2.0, 4.0, 0);
+        let start = Instant::now();
+        let admit = |address: &str, millis: u64| {
+            limiter.admit_at(client(address), start + Duration::from_millis(millis))
+        };
+        // A quiet client may send a burst, then two queries a second.
+        let burst: Vec<bool> = (0..5).map(|_| admit("198.51.100.1", 0)).collect();
+        assert_eq!(burst, [true, true, true, true, false]);
+        assert!(!admit("198.51.100.2", 250));
+        assert!(admit("198.51.100.1", 500));
+        assert!(!admit("198.51.100.1", 500));
+        // Other networks and exempt clients have buckets of their own, or none.
+        assert!(admit("198.51.101.1", 500));
+        assert!((0..10).all(|_| admit("192.0.2.53", 500)));
+        // Refills stop at the burst size.
+        let refilled: Vec<bool> = (0..5).map(|_| admit("198.51.100.1", 60_000)).collect();
+        assert_eq!(refilled, [true, true, true, true, false]);
+    }
+
+    #[test]
+    fn releases_in_flight_slots_on_drop() {
+        let limiter = limiter(0.0, 0.0, 2);
+        let first = limiter.begin_upstream(client("198.51.100.1")).unwrap();
+        let second = limiter.begin_upstream(client("198.51.100.2")).unwrap();
+        assert!(limiter.begin_upstream(client("198.51.100.3")).is_none());
+        assert!(limiter.begin_upstream(client("198.51.101.1")).is_some());
+        assert!(limiter.begin_upstream(client("192.0.2.53")).is_some());
+        drop(first);
+        let third = limiter.begin_upstream(client("198.51.100.3")).unwrap();
+        drop(second);
+        drop(third);
+        assert!(limiter.in_flight.lock().unwrap().is_empty());
+        // Without a rate every query is admitted.
+        assert!((0..100).all(|_| limiter.admit(client("198.51.100.1"))));
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::cidr::Cidr;
+use crate::dns::dnssec::rrsets;
+use crate::dns::header::ResponseCode;
+use crate::dns::name::{normalize, parent};
+use crate::dns::resource_record::{RecordType, ResourceRecord};
+use crate::recursor::root_hints::NameServer;
+
+// Upper bounds on how long anything is trusted, whatever the TTL says.
+const MAX_TTL: u32 = 86400;
+const MAX_NEGATIVE_TTL: u32 = 3600;
+// Past this many entries expired data is swept, and if that is not enough
+// the oldest entries go, an eighth of the cache at a time.
+const MAX_ENTRIES: usize = 100_000;
+// Client subnets kept for one RRset; the oldest goes first.
+const MAX_SCOPES: usize = 64;
+
+struct Entry {
+    records: Vec<ResourceRecord>,
+    // The RRSIGs covering the records, kept for DNSSEC validation, and
+    // for an RRset expanded from a wildcard the NSEC or NSEC3 records that
+    // show no closer name exists.
+    signatures: Vec<ResourceRecord>,
+    proof: Vec<ResourceRecord>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl Entry {
+    fn new(
+        records: Vec<ResourceRecord>,
+        signatures: Vec<ResourceRecord>,
+        proof: Vec<ResourceRecord>,
+    ) -> Option<((String, RecordType), Self)> {
+        let first = records.first()?;
+        let key = (normalize(&first.name), first.record_type);
+        let ttl = records
+            .iter()
+            .chain(&signatures)
+            .chain(&proof)
+            .map(|r| r.ttl)
+            .min()
+            .unwrap_or(0)
+            .min(MAX_TTL);
+        let now = Instant::now();
+        Some((
+            key,
+            Entry {
+                records,
+                signatures,
+                proof,
+                stored: now,
+                expires: now + Duration::from_secs(ttl as u64),
+            },
+        ))
+    }
+
+    // The records with their TTLs reduced by the time spent in the cache,
+    // unless they have expired.
+    fn current(&self) -> Option<Vec<ResourceRecord>> {
+        self.aged(&self.records)
+    }
+
+    // Like `current`, with the RRSIGs and any wildcard proof.
+    fn current_signed(&self) -> Option<Signed> {
+        let mut records = self.aged(&self.records)?;
+        records.extend(self.aged(&self.signatures)?);
+        Some(Signed {
+            records,
+            proof: self.aged(&self.proof)?,
+        })
+    }
+
+    fn aged(&self, records: &[ResourceRecord]) -> Option<Vec<ResourceRecord>> {
+        let now = Instant::now();
+        if self.expires <= now {
+            return None;
+        }
+        let elapsed = now.duration_since(self.stored).as_secs() as u32;
+        Some(
+            records
+                .iter()
+                .map(|record| {
+                    let mut record = record.clone();
+                    record.ttl = record.ttl.saturating_sub(elapsed);
+                    record
+                })
+                .collect(),
+        )
+    }
+}
+
+struct NegativeEntry {
+    rcode: ResponseCode,
+    authority: Vec<ResourceRecord>,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// The name servers for a zone cut learned from a referral.
+#[derive(Debug, Clone)]
+pub struct Delegation {
+    pub zone: String,
+    pub name_servers: Vec<NameServer>,
+}
+
+struct DelegationEntry {
+    delegation: Delegation,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// A cached RRset followed by the RRSIGs covering it, and the denial records
+/// for the authority section when it was expanded from a wildcard.
+pub struct Signed {
+    pub records: Vec<ResourceRecord>,
+    pub proof: Vec<ResourceRecord>,
+}
+
+/// A cached negative answer: NXDOMAIN for the name, or NODATA for the type.
+/// The authority records are the SOA and any NSEC or NSEC3 records proving
+/// the answer, with their RRSIGs.
+pub struct Negative {
+    pub rcode: ResponseCode,
+    pub authority: Vec<ResourceRecord>,
+}
+
+/// RRsets, negative answers and delegations learned while resolving.
+pub struct RecordCache {
+    records: HashMap<(String, RecordType), Entry>,
+    // Answers that hold only for clients in a subnet (RFC 7871 section 7.3).
+    scoped: HashMap<(String, RecordType), Vec<(Cidr, Entry)>>,
+    // NXDOMAIN is stored without a type since it covers every type.
+    negative: HashMap<(String, Option<RecordType>), NegativeEntry>,
+    delegations: HashMap<String, DelegationEntry>,
+    // Entries across all of the maps, kept as they change.
+    count: usize,
+    capacity: usize,
+}
+
+impl Default for RecordCache {
+    fn default() -> Self {
+        RecordCache::with_capacity(MAX_ENTRIES)
+    }
+}
+
+impl RecordCache {
+    fn with_capacity(capacity: usize) -> Self {
+        RecordCache {
+            records: HashMap::new(),
+            scoped: HashMap::new(),
+            negative: HashMap::new(),
+            delegations: HashMap::new(),
+            count: 0,
+            capacity,
+        }
+    }
+
+    /// Cached records with their TTLs reduced by the time spent in the cache.
+    pub fn get(&self, name: &str, record_type: RecordType) -> Option<Vec<ResourceRecord>> {
+        self.records
+            .get(&(normalize(name), record_type))
+            .and_then(Entry::current)
+    }
+
+    /// Like `get`, with the records' RRSIGs and any wildcard proof.
+    pub fn get_signed(&self, name: &str, record_type: RecordType) -> Option<Signed> {
+        self.records
+            .get(&(normalize(name), record_type))
+            .and_then(Entry::current_signed)
+    }
+
+    /// Signed records cached for the most specific subnet containing
+    /// `client`, with that subnet's prefix length.
+    pub fn get_scoped(
+        &self,
+        name: &str,
+        record_type: RecordType,
+        client: IpAddr,
+    ) -> Option<(Signed, u8)> {
+        self.scoped
+            .get(&(normalize(name), record_type))?
+            .iter()
+            .filter(|(scope, _)| scope.contains(client))
+            .filter_map(|(scope, entry)| Some((entry.current_signed()?, scope.prefix())))
+            .max_by_key(|(_, prefix)| *prefix)
+    }
+
+    /// Stores an RRset, with the RRSIGs covering it and any wildcard proof;
+    /// all records must share owner name and type.
+    pub fn insert(
+        &mut self,
+        records: Vec<ResourceRecord>,
+        signatures: Vec<ResourceRecord>,
+        proof: Vec<ResourceRecord>,
+    ) {
+        let Some((key, entry)) = Entry::new(records, signatures, proof) else {
+            return;
+        };
+        self.make_room();
+        for key_type in [Some(key.1), None] {
+            if self.negative.remove(&(key.0.clone(), key_type)).is_some() {
+                self.count -= 1;
+            }
+        }
+        if self.records.insert(key, entry).is_none() {
+            self.count += 1;
+        }
+    }
+
+    /// Stores an RRset that holds only for clients in `scope`.
+    pub fn insert_scoped(
+        &mut self,
+        records: Vec<ResourceRecord>,
+        signatures: Vec<ResourceRecord>,
+        proof: Vec<ResourceRecord>,
+        scope: Cidr,
+    ) {
+        let Some((key, entry)) = Entry::new(records, signatures, proof) else {
+            return;
+        };
+        self.make_room();
+        let scopes = self.scoped.entry(key).or_default();
+        let before = scopes.len();
+        scopes.retain(|(existing, _)| *existing != scope);
+        if scopes.len() >= MAX_SCOPES {
+            scopes.remove(0);
+        }
+        scopes.push((scope, entry));
+        self.count = self.count + scopes.len() - before;
+    }
+
+    /// Groups the loose records of one answer into RRsets and stores each
+    /// one with its RRSIGs and the answer's wildcard `proof`. Signatures
+    /// over records not among them are dropped.
+    pub fn insert_all(&mut self, records: &[ResourceRecord], proof: &[ResourceRecord]) {
+        for (rrset, signatures) in rrsets(records) {
+            self.insert(rrset, signatures, proof.to_vec());
+        }
+    }
+
+    /// Like `insert_all`, for records that hold only for clients in `scope`.
+    pub fn insert_all_scoped(
+        &mut self,
+        records: &[ResourceRecord],
+        proof: &[ResourceRecord],
+        scope: Cidr,
+    ) {
+        for (rrset, signatures) in rrsets(records) {
+            self.insert_scoped(rrset, signatures, proof.to_vec(), scope);
+        }
+    }
+
+    pub fn get_negative(&self, name: &str, record_type: RecordType) -> Option<Negative> {
+        let name = normalize(name);
+        let now = Instant::now();
+        [(name.clone(), None), (name, Some(record_type))]
+            .iter()
+            .filter_map(|key| self.negative.get(key))
+            .find(|entry| entry.expires > now)
+            .map(|entry| Negative {
+                rcode: entry.rcode,
+                authority: entry.authority.clone(),
+            })
+    }
+
+    /// Caches NXDOMAIN or NODATA for the time given by the SOA (RFC 2308).
+    pub fn insert_negative(
+        &mut self,
+        name: &str,
+        record_type: RecordType,
+        rcode: ResponseCode,
+        authority: Vec<ResourceRecord>,
+    ) {
+        // Without an SOA there is no negative TTL, so nothing is cached.
+        let Some(ttl) = authority
+            .iter()
+            .find(|record| record.record_type == RecordType::SOA)
+            .map(|record| record.ttl.min(MAX_NEGATIVE_TTL))
+        else {
+            return;
+        };
+        let key_type = (rcode != ResponseCode::NXDomain).then_some(record_type);
+        self.make_room();
+        let now = Instant::now();
+        let entry = NegativeEntry {
+            rcode,
+            authority,
+            stored: now,
+            expires: now + Duration::from_secs(ttl as u64),
+        };
+        if self
+            .negative
+            .insert((normalize(name), key_type), entry)
+            .is_none()
+        {
+            self.count += 1;
+        }
+    }
+
+    /// The deepest cached delegation at or above `name`.
+    pub fn closest_delegation(&self, name: &str) -> Option<Delegation> {
+        let now = Instant::now();
+        let name = normalize(name);
+        let mut candidate = Some(name.as_str());
+        while let Some(zone) = candidate {
+            if let Some(entry) = self.delegations.get(zone) {
+                if entry.expires > now {
+                    return Some(entry.delegation.clone());
+                }
+            }
+            candidate = parent(zone);
+        }
+        None
+    }
+
+    pub fn insert_delegation(&mut self, delegation: Delegation, ttl: u32) {
+        self.make_room();
+        let now = Instant::now();
+        let zone = normalize(&delegation.zone);
+        let entry = DelegationEntry {
+            delegation,
+            stored: now,
+            expires: now + Duration::from_secs(ttl.min(MAX_TTL) as u64),
+        };
+        if self.delegations.insert(zone, entry).is_none() {
+            self.count += 1;
+        }
+    }
+
+    fn make_room(&mut self) {
+        if self.count < self.capacity {
+            return;
+        }
+        let now = Instant::now();
+        self.retain(|_, expires| expires > now);
+        if self.count < self.capacity {
+            return;
+        }
+        // Everything stored at or before the cutoff goes, which leaves an
+        // eighth of the cache free.
+        let mut stored: Vec<Instant> = self
+            .records
+            .values()
+            .chain(self.scoped.values().flatten().map(|(_, entry)| entry))
+            .map(|entry| entry.stored)
+            .chain(self.negative.values().map(|entry| entry.stored))
+            .chain(self.delegations.values().map(|entry| entry.stored))
+            .collect();
+        let excess = self.count + 1 - self.capacity + self.capacity / 8;
+        let cutoff = *stored.select_nth_unstable(excess.min(self.count) - 1).1;
+        self.retain(|stored, _| stored > cutoff);
+    }
+
+    // Keeps the entries for which `keep(stored, expires)` holds, and counts
+    // them again.
+    fn retain(&mut self, keep: impl Fn(Instant, Instant) -> bool) {
+        self.records
+            .retain(|_, entry| keep(entry.stored, entry.expires));
+        self.scoped.retain(|_, scopes| {
+            scopes.retain(|(_, entry)| keep(entry.stored, entry.expires));
+            !scopes.is_empty()
+        });
+        self.negative
+            .retain(|_, entry| keep(entry.stored, entry.expires));
+        self.delegations
+            .retain(|_, entry| keep(entry.stored, entry.expires));
+        self.count = self.records.len()
+            + self.scoped.values().map(Vec::len).sum::<usize>()
+            + self.negative.len()
+            + self.delegations.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+    use crate::dns::resource_record::RecordClass;
+
+    fn record(name: &str, record_type: RecordType, data: Vec<u8>) -> ResourceRecord {
+        ResourceRecord::new(name.to_string(), record_type, RecordClass::IN, 3600, data)
+    }
+
+    fn insert(cache: &mut RecordCache, name: &str) {
+        cache.insert(
+            vec![record(name, RecordType::A, vec![192, 0, 2, 1])],
+            vec![],
+            vec![],
+        );
+        // Apart in time, so which entry is oldest is never a tie.
+        sleep(Duration::from_millis(1));
+    }
+
+    #[test]
+    fn counts_entries_as_they_change() {
+        let mut cache = RecordCache::default();
+        insert(&mut cache, "a.test");
+        insert(&mut cache, "a.test");
+        assert_eq!(cache.count, 1);
+        let soa = record("test", RecordType::SOA, vec![0; 22]);
+        cache.insert_negative("b.test", RecordType::A, ResponseCode::NXDomain, vec![soa]);
+        assert_eq!(cache.count, 2);
+        insert(&mut cache, "b.test");
+        assert_eq!(cache.count, 2);
+        let scope: Cidr = "192.0.2.0/24".parse().unwrap();
+        let scoped = vec![record("c.test", RecordType::A, vec![192, 0, 2, 2])];
+        cache.insert_scoped(scoped.clone(), vec![], vec![], scope);
+        cache.insert_scoped(scoped, vec![], vec![], scope);
+        assert_eq!(cache.count, 3);
+    }
+
+    #[test]
+    fn evicts_the_oldest_entries_when_full() {
+        let mut cache = RecordCache::with_capacity(16);
+        for n in 0..17 {
+            insert(&mut cache, &format!("{n}.test"));
+        }
+        // Full at the seventeenth, so the three oldest made room for it
+        // and an eighth of the cache.
+        assert_eq!(cache.count, 14);
+        assert!(cache.get("2.test", RecordType::A).is_none());
+        assert!(cache.get("3.test", RecordType::A).is_some());
+        assert!(cache.get("16.test", RecordType::A).is_some());
+    }
+
+    #[test]
+    fn keeps_unknown_types_apart() {
+        let mut cache = RecordCache::default();
+        let private = RecordType::from_u16(65280);
+        cache.insert(
+            vec![record("a.test", private, vec![1, 2, 3])],
+            vec![],
+            vec![],
+        );
+        let records = cache.get("a.test", private).unwrap();
+        assert_eq!(records[0].record_type.to_u16(), 65280);
+        assert!(cache.get("a.test", RecordType::from_u16(65281)).is_none());
+    }
+}
@@ -0,0 +1,82 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Instant};
+
+use crate::dns::message::DNSMessage;
+use crate::recursor::ResolveError;
+
+// Large enough for any EDNS response we ask for.
+const RECEIVE_BUFFER: usize = 4096;
+
+/// Sends `query` to a single server over UDP, retrying over TCP when the
+/// answer comes back truncated. Datagrams that do not match the query's ID
+/// and question are ignored as possible spoofing attempts.
+pub async fn exchange(
+    server: SocketAddr,
+    query: &DNSMessage,
+    timeout_duration: Duration,
+) -> Result<DNSMessage, ResolveError> {
+    let bytes = query.to_bytes();
+    let bind_addr = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(server).await?;
+    socket.send(&bytes).await?;
+
+    let deadline = Instant::now() + timeout_duration;
+    let mut buf = vec![0u8; RECEIVE_BUFFER];
+    let response = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let len = timeout(remaining, socket.recv(&mut buf))
+            .await
+            .map_err(|_| ResolveError::Timeout(server))??;
+        match DNSMessage::parse(&buf[..len]) {
+            Ok(response) if matches_query(query, &response) => break response,
+            _ => continue,
+        }
+    };
+
+    if !response.header.flags.tc {
+        return Ok(response);
+    }
+    timeout(timeout_duration, exchange_tcp(server, &bytes))
+        .await
+        .map_err(|_| ResolveError::Timeout(server))?
+        .and_then(|response| {
+            if matches_query(query, &response) {
+                Ok(response)
+            } else {
+                Err(ResolveError::Mismatch(server))
+            }
+        })
+}
+
+async fn exchange_tcp(server: SocketAddr, bytes: &[u8]) -> Result<DNSMessage, ResolveError> {
+    let mut stream = TcpStream::connect(server).await?;
+    let mut framed = (bytes.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(bytes);
+    stream.write_all(&framed).await?;
+
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length).await?;
+    let mut response = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(DNSMessage::parse(&response)?)
+}
+
+fn matches_query(query: &DNSMessage, response: &DNSMessage) -> bool {
+    response.header.flags.qr
+        && response.header.transaction_id == query.header.transaction_id
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(&query.questions)
+            .all(|(a, b)| a.name.eq_ignore_ascii_case(&b.name) && a.record_type == b.record_type)
+}
@@ -0,0 +1,858 @@
+pub mod cache;
+pub mod client;
+pub mod root_hints;
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, warn};
+use rand::seq::SliceRandom;
+
+use crate::config::env_or;
+use crate::dns::dnssec::covered_type;
+use crate::dns::edns::{ClientSubnet, Edns, ExtendedError, SERVER_UDP_PAYLOAD};
+use crate::dns::header::ResponseCode;
+use crate::dns::message::{DNSMessage, DNSParseError};
+use crate::dns::name::{is_subdomain, labels, names_equal, parent};
+use crate::dns::resource_record::{RecordType, ResourceRecord};
+use crate::ecs::answer_scope;
+use crate::recursor::cache::{Delegation, Negative, RecordCache};
+use crate::recursor::root_hints::{record_address, NameServer};
+
+const MAX_CNAME_CHAIN: usize = 8;
+// RFC 9156 section 2.3: cap the number of minimised queries for one name so
+// long names cannot be used to make us send dozens of queries.
+const MAX_MINIMISE_COUNT: usize = 10;
+
+/// Settings for iterative resolution, read from the environment.
+#[derive(Debug, Clone)]
+pub struct RecursorConfig {
+    pub root_hints: Vec<NameServer>,
+    /// Port authoritative servers are contacted on. Only worth changing when
+    /// testing against stand-in servers.
+    pub port: u16,
+    pub timeout: Duration,
+    /// How deeply glueless name server lookups and CNAME targets may nest.
+    pub max_depth: usize,
+    /// Upper bound on queries sent while answering one client question.
+    pub max_queries: usize,
+    /// Send each server only as much of the name as it needs (RFC 9156).
+    pub qname_minimisation: bool,
+}
+
+impl RecursorConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let root_hints = match std::env::var("ROOT_HINTS") {
+            Ok(path) => root_hints::load(Path::new(&path))?,
+            Err(_) => root_hints::builtin(),
+        };
+        if root_hints.iter().all(|hint| hint.addresses.is_empty()) {
+            return Err("No root server addresses in ROOT_HINTS".into());
+        }
+
+        Ok(RecursorConfig {
+            root_hints,
+            port: env_or("RECURSION_PORT", 53)?,
+            timeout: Duration::from_millis(env_or("RECURSION_TIMEOUT_MS", 2000)?),
+            max_depth: env_or("RECURSION_MAX_DEPTH", 8)?,
+            max_queries: env_or("RECURSION_MAX_QUERIES", 64)?,
+            qname_minimisation: std::env::var("QNAME_MINIMISATION").as_deref() != Ok("off"),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    Io(std::io::Error),
+    Parse(DNSParseError),
+    Timeout(SocketAddr),
+    Mismatch(SocketAddr),
+    NoReachableServers(String),
+    DepthExceeded,
+    QueryLimitExceeded,
+    CnameChainTooLong,
+}
+
+impl ResolveError {
+    /// How the failure is reported to clients (RFC 8914).
+    pub fn extended_error(&self) -> ExtendedError {
+        match self {
+            ResolveError::Io(_) => ExtendedError::NetworkError,
+            ResolveError::Timeout(_)
+            | ResolveError::Mismatch(_)
+            | ResolveError::NoReachableServers(_) => ExtendedError::NoReachableAuthority,
+            ResolveError::Parse(_)
+            | ResolveError::DepthExceeded
+            | ResolveError::QueryLimitExceeded
+            | ResolveError::CnameChainTooLong => ExtendedError::Other,
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::Io(e) => write!(f, "I/O error: {}", e),
+            ResolveError::Parse(e) => write!(f, "Malformed response: {}", e),
+            ResolveError::Timeout(server) => write!(f, "Timeout waiting for {}", server),
+            ResolveError::Mismatch(server) => write!(f, "Mismatched response from {}", server),
+            ResolveError::NoReachableServers(zone) => {
+                write!(f, "No name server for {:?} answered", zone)
+            }
+            ResolveError::DepthExceeded => write!(f, "Resolution nested too deeply"),
+            ResolveError::QueryLimitExceeded => write!(f, "Query limit exceeded"),
+            ResolveError::CnameChainTooLong => write!(f, "CNAME chain too long"),
+        }
+    }
+}
+
+impl Error for ResolveError {}
+
+impl From<std::io::Error> for ResolveError {
+    fn from(e: std::io::Error) -> Self {
+        ResolveError::Io(e)
+    }
+}
+
+impl From<DNSParseError> for ResolveError {
+    fn from(e: DNSParseError) -> Self {
+        ResolveError::Parse(e)
+    }
+}
+
+/// The outcome of resolving one question, ready to be copied into a response.
+#[derive(Debug)]
+pub struct Resolution {
+    pub rcode: ResponseCode,
+    pub answers: Vec<ResourceRecord>,
+    pub authority: Vec<ResourceRecord>,
+    /// How much of the client subnet the answers depend on; 0 when they
+    /// hold for every client.
+    pub scope_prefix: u8,
+}
+
+// What one name resolved to, before CNAMEs are followed. Answers carry
+// their RRSIGs, then any wildcard proof and the subnet scope.
+enum Step {
+    Answer(Vec<ResourceRecord>, Vec<ResourceRecord>, u8),
+    Alias(Vec<ResourceRecord>, String, Vec<ResourceRecord>, u8),
+    Negative(Negative),
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// An iterative resolver that starts from the root hints and follows
+/// referrals down to the authoritative servers.
+pub struct Recursor {
+    config: RecursorConfig,
+    cache: Mutex<RecordCache>,
+}
+
+impl Recursor {
+    pub fn new(config: RecursorConfig) -> Self {
+        Recursor {
+            config,
+            cache: Mutex::new(RecordCache::default()),
+        }
+    }
+
+    /// Resolves the question in `query` and builds the response to it.
+    /// With a client `subnet`, the response carries it back with the scope
+    /// the answer holds for, as an upstream server's would. RRSIG, NSEC and
+    /// NSEC3 records are included whatever the query's DO bit says.
+    pub async fn answer(&self, query: &DNSMessage, subnet: Option<&ClientSubnet>) -> DNSMessage {
+        let mut response = DNSMessage::response_to(query);
+        response.header.flags.ra = true;
+
+        let [question] = query.questions.as_slice() else {
+            response.set_response_code(ResponseCode::FormErr);
+            return response;
+        };
+
+        match self
+            .resolve(&question.name, question.record_type, subnet)
+            .await
+        {
+            Ok(resolution) => {
+                response.set_response_code(resolution.rcode);
+                response.answers = resolution.answers;
+                response.authority_records = resolution.authority;
+                if let Some(subnet) = subnet {
+                    response
+                        .edns
+                        .get_or_insert_with(|| Edns::new(SERVER_UDP_PAYLOAD))
+                        .set_client_subnet(Some(ClientSubnet {
+                            scope_prefix: resolution.scope_prefix,
+                            ..*subnet
+                        }));
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to resolve {} {}: {}",
+                    question.name, question.record_type, e
+                );
+                response.set_response_code(ResponseCode::ServFail);
+                response.set_extended_error(e.extended_error(), &e.to_string());
+            }
+        }
+
+        response.add_additional_addresses(|name, record_type| {
+            self.cached(name, record_type).unwrap_or_default()
+        });
+        response
+    }
+
+    pub async fn resolve(
+        &self,
+        qname: &str,
+        qtype: RecordType,
+        subnet: Option<&ClientSubnet>,
+    ) -> Result<Resolution, ResolveError> {
+        let queries = AtomicUsize::new(0);
+        self.resolve_at_depth(qname.to_string(), qtype, subnet, 0, &queries)
+            .await
+    }
+
+    /// Records for `name` already in the cache.
+    pub fn cached(&self, name: &str, record_type: RecordType) -> Option<Vec<ResourceRecord>> {
+        self.cache.lock().unwrap().get(name, record_type)
+    }
+
+    // Resolves a name, following CNAMEs wherever they lead. Boxed because
+    // glueless name servers make resolution recursive.
+    fn resolve_at_depth<'a>(
+        &'a self,
+        qname: String,
+        qtype: RecordType,
+        subnet: Option<&'a ClientSubnet>,
+        depth: usize,
+        queries: &'a AtomicUsize,
+    ) -> BoxFuture<'a, Result<Resolution, ResolveError>> {
+        Box::pin(async move {
+            if depth > self.config.max_depth {
+                return Err(ResolveError::DepthExceeded);
+            }
+
+            let mut answers = Vec::new();
+            let mut authority = Vec::new();
+            let mut scope_prefix = 0;
+            let mut name = qname;
+            for _ in 0..MAX_CNAME_CHAIN {
+                match self
+                    .resolve_step(&name, qtype, subnet, depth, queries)
+                    .await?
+                {
+                    Step::Answer(records, proof, scope) => {
+                        answers.extend(records);
+                        authority.extend(proof);
+                        return Ok(Resolution {
+                            rcode: ResponseCode::NoError,
+                            answers,
+                            authority,
+                            scope_prefix: scope_prefix.max(scope),
+                        });
+                    }
+                    Step::Alias(cname, target, proof, scope) => {
+                        answers.extend(cname);
+                        authority.extend(proof);
+                        scope_prefix = scope_prefix.max(scope);
+                        name = target;
+                    }
+                    Step::Negative(negative) => {
+                        authority.extend(negative.authority);
+                        return Ok(Resolution {
+                            rcode: negative.rcode,
+                            answers,
+                            authority,
+                            scope_prefix,
+                        });
+                    }
+                }
+            }
+            Err(ResolveError::CnameChainTooLong)
+        })
+    }
+
+    async fn resolve_step(
+        &self,
+        name: &str,
+        qtype: RecordType,
+        subnet: Option<&ClientSubnet>,
+        depth: usize,
+        queries: &AtomicUsize,
+    ) -> Result<Step, ResolveError> {
+        if let Some(step) = self.cached_step(name, qtype, subnet) {
+            return Ok(step);
+        }
+
+        // DS records are served by the parent, so never start below it.
+        let start = match qtype {
+            RecordType::DS => parent(name).unwrap_or(""),
+            _ => name,
+        };
+        let mut delegation = self.closest_delegation(start);
+        let mut minimise = self.config.qname_minimisation;
+        let mut sent_labels = 0;
+        let mut minimised_queries = 0;
+
+        loop {
+            let zone = delegation.zone.clone();
+
+            if minimise && minimised_queries < MAX_MINIMISE_COUNT {
+                if let Some(partial) = minimised_name(name, &zone, sent_labels) {
+                    minimised_queries += 1;
+                    sent_labels = labels(&partial).count();
+                    let response = match self
+                        .query_delegation(
+                            &delegation,
+                            &partial,
+                            RecordType::A,
+                            None,
+                            depth,
+                            queries,
+                        )
+                        .await
+                    {
+                        Ok(response) => response,
+                        Err(ResolveError::QueryLimitExceeded) => {
+                            return Err(ResolveError::QueryLimitExceeded)
+                        }
+                        Err(e) => {
+                            debug!(
+                                "Minimised query for {} failed ({}), sending {}",
+                                partial, e, name
+                            );
+                            minimise = false;
+                            continue;
+                        }
+                    };
+
+                    // Some servers wrongly answer NXDOMAIN for empty
+                    // non-terminals, so only the full name is believed.
+                    if response.header.flags.rcode == ResponseCode::NXDomain as u8 {
+                        debug!("NXDOMAIN for minimised {}, sending {}", partial, name);
+                        minimise = false;
+                        continue;
+                    }
+                    if let Some((next, ttl)) = referral(&response, name, &zone) {
+                        debug!(
+                            "Referred from {:?} to {:?} for {}",
+                            zone, next.zone, partial
+                        );
+                        self.cache
+                            .lock()
+                            .unwrap()
+                            .insert_delegation(next.clone(), ttl);
+                        delegation = next;
+                    } else if response.answers.iter().any(|r| {
+                        r.record_type == RecordType::CNAME && names_equal(&r.name, &partial)
+                    }) {
+                        // An alias has no children; ask for the name itself.
+                        minimise = false;
+                    }
+                    // Otherwise the name exists inside this zone: reveal one more label.
+                    continue;
+                }
+            }
+
+            let response = self
+                .query_delegation(&delegation, name, qtype, subnet, depth, queries)
+                .await?;
+            let scope = subnet.and_then(|subnet| answer_scope(subnet, &response));
+            let scope_prefix = scope.map_or(0, |scope| scope.prefix());
+
+            // Only trust records the responding servers are authoritative for.
+            let answers: Vec<ResourceRecord> = response
+                .answers
+                .iter()
+                .filter(|record| is_subdomain(&record.name, &zone))
+                .cloned()
+                .collect();
+            if !answers.is_empty() {
+                // Answers synthesised from a wildcard come with the NSEC or
+                // NSEC3 records showing the name itself does not exist.
+                let proof = denial_records(&response, &zone);
+                match scope {
+                    Some(scope) => self
+                        .cache
+                        .lock()
+                        .unwrap()
+                        .insert_all_scoped(&answers, &proof, scope),
+                    None => self.cache.lock().unwrap().insert_all(&answers, &proof),
+                }
+                let owned = |record: &&ResourceRecord| names_equal(&record.name, name);
+                let of_type = |record: &ResourceRecord, record_type| {
+                    record.record_type == record_type || covered_type(record) == Some(record_type)
+                };
+                let matching: Vec<ResourceRecord> = answers
+                    .iter()
+                    .filter(owned)
+                    .filter(|r| qtype == RecordType::ANY || of_type(r, qtype))
+                    .cloned()
+                    .collect();
+                if matching
+                    .iter()
+                    .any(|r| r.record_type == qtype || r.record_type != RecordType::RRSIG)
+                {
+                    return Ok(Step::Answer(matching, proof, scope_prefix));
+                }
+                let cname: Vec<ResourceRecord> = answers
+                    .iter()
+                    .filter(owned)
+                    .filter(|r| of_type(r, RecordType::CNAME))
+                    .cloned()
+                    .collect();
+                if let Some(target) = cname
+                    .iter()
+                    .find(|r| r.record_type == RecordType::CNAME)
+                    .and_then(ResourceRecord::target_name)
+                {
+                    return Ok(Step::Alias(cname, target, proof, scope_prefix));
+                }
+            }
+
+            // The SOA gives the negative TTL; NSEC or NSEC3 records and
+            // signatures let a validator check the denial.
+            let mut soa: Vec<ResourceRecord> = response
+                .authority_records
+                .iter()
+                .filter(|r| r.record_type == RecordType::SOA && is_subdomain(name, &r.name))
+                .take(1)
+                .cloned()
+                .collect();
+            if let Some(owner) = soa.first().map(|r| r.name.clone()) {
+                soa.extend(
+                    response
+                        .authority_records
+                        .iter()
+                        .filter(|r| {
+                            covered_type(r) == Some(RecordType::SOA) && names_equal(&r.name, &owner)
+                        })
+                        .cloned(),
+                );
+            }
+            soa.extend(denial_records(&response, &zone));
+
+            if response.header.flags.rcode == ResponseCode::NXDomain as u8 {
+                return Ok(Step::Negative(self.negative(
+                    name,
+                    qtype,
+                    ResponseCode::NXDomain,
+                    soa,
+                )));
+            }
+
+            match referral(&response, name, &zone) {
+                Some((next, ttl)) => {
+                    debug!("Referred from {:?} to {:?} for {}", zone, next.zone, name);
+                    self.cache
+                        .lock()
+                        .unwrap()
+                        .insert_delegation(next.clone(), ttl);
+                    delegation = next;
+                }
+                None => {
+                    return Ok(Step::Negative(self.negative(
+                        name,
+                        qtype,
+                        ResponseCode::NoError,
+                        soa,
+                    )))
+                }
+            }
+        }
+    }
+
+    // Answers cached for the client's subnet take precedence over those
+    // that hold for everyone.
+    fn cached_step(
+        &self,
+        name: &str,
+        qtype: RecordType,
+        subnet: Option<&ClientSubnet>,
+    ) -> Option<Step> {
+        let cache = self.cache.lock().unwrap();
+        let get = |record_type| {
+            subnet
+                .and_then(|subnet| cache.get_scoped(name, record_type, subnet.address))
+                .or_else(|| {
+                    cache
+                        .get_signed(name, record_type)
+                        .map(|signed| (signed, 0))
+                })
+        };
+        if let Some((signed, scope)) = get(qtype) {
+            return Some(Step::Answer(signed.records, signed.proof, scope));
+        }
+        if qtype != RecordType::CNAME {
+            if let Some((cname, scope)) = get(RecordType::CNAME) {
+                if let Some(target) = cname.records.first().and_then(ResourceRecord::target_name) {
+                    return Some(Step::Alias(cname.records, target, cname.proof, scope));
+                }
+            }
+        }
+        cache.get_negative(name, qtype).map(Step::Negative)
+    }
+
+    fn negative(
+        &self,
+        name: &str,
+        qtype: RecordType,
+        rcode: ResponseCode,
+        authority: Vec<ResourceRecord>,
+    ) -> Negative {
+        self.cache
+            .lock()
+            .unwrap()
+            .insert_negative(name, qtype, rcode, authority.clone());
+        Negative { rcode, authority }
+    }
+
+    fn closest_delegation(&self, name: &str) -> Delegation {
+        self.cache
+            .lock()
+            .unwrap()
+            .closest_delegation(name)
+            .unwrap_or_else(|| Delegation {
+                zone: String::new(),
+                name_servers: self.config.root_hints.clone(),
+            })
+    }
+
+    // Asks the servers of `delegation` in random order until one gives a
+    // usable answer. SERVFAIL, REFUSED and the like count as lame.
+    async fn query_delegation(
+        &self,
+        delegation: &Delegation,
+        name: &str,
+        qtype: RecordType,
+        subnet: Option<&ClientSubnet>,
+        depth: usize,
+        queries: &AtomicUsize,
+    ) -> Result<DNSMessage, ResolveError> {
+        let mut name_servers = delegation.name_servers.clone();
+        name_servers.shuffle(&mut rand::thread_rng());
+
+        for name_server in &name_servers {
+            let addresses = self
+                .addresses_for(name_server, &delegation.zone, depth, queries)
+                .await;
+            for address in addresses {
+                if queries.fetch_add(1, Ordering::Relaxed) >= self.config.max_queries {
+                    return Err(ResolveError::QueryLimitExceeded);
+                }
+                let server = SocketAddr::new(address, self.config.port);
+                let mut query = DNSMessage::query(rand::random(), name, qtype);
+                if let Some(edns) = query.edns.as_mut() {
+                    edns.set_client_subnet(subnet.copied());
+                    // Signatures are asked for even when not validating, so
+                    // cached answers can serve validating clients too.
+                    edns.dnssec_ok = true;
+                }
+                match client::exchange(server, &query, self.config.timeout).await {
+                    Ok(response)
+                        if response.header.flags.rcode == ResponseCode::NoError as u8
+                            || response.header.flags.rcode == ResponseCode::NXDomain as u8 =>
+                    {
+                        return Ok(response)
+                    }
+                    Ok(response) => debug!(
+                        "{} ({}) answered {} {} with rcode {}",
+                        name_server.name, server, name, qtype, response.header.flags.rcode
+                    ),
+                    Err(e) => debug!("{} ({}): {}", name_server.name, server, e),
+                }
+            }
+        }
+
+        Err(ResolveError::NoReachableServers(delegation.zone.clone()))
+    }
+
+    // Addresses from glue when we have it; otherwise the name server's name
+    // is resolved on its own, unless it sits inside the very zone it serves.
+    async fn addresses_for(
+        &self,
+        name_server: &NameServer,
+        zone: &str,
+        depth: usize,
+        queries: &AtomicUsize,
+    ) -> Vec<IpAddr> {
+        let mut addresses = name_server.addresses.clone();
+        if addresses.is_empty() {
+            for record_type in [RecordType::A, RecordType::AAAA] {
+                if let Some(records) = self.cached(&name_server.name, record_type) {
+                    addresses.extend(records.iter().filter_map(|r| record_address(&r.data)));
+                }
+            }
+        }
+        if addresses.is_empty() && !is_subdomain(&name_server.name, zone) {
+            match self
+                .resolve_at_depth(
+                    name_server.name.clone(),
+                    RecordType::A,
+                    None,
+                    depth + 1,
+                    queries,
+                )
+                .await
+            {
+                Ok(resolution) => addresses.extend(
+                    resolution
+                        .answers
+                        .iter()
+                        .filter(|r| r.record_type == RecordType::A)
+                        .filter_map(|r| record_address(&r.data)),
+                ),
+                Err(e) => debug!("Could not resolve name server {}: {}", name_server.name, e),
+            }
+        }
+        // IPv4 first: it works from more places.
+        addresses.sort_by_key(IpAddr::is_ipv6);
+        addresses
+    }
+}
+
+// The ancestor of `name` one label below `zone`, or below what was last
+// sent if that is deeper. `None` once that would be the full name, which is
+// then asked for with the real query type.
+fn minimised_name(name: &str, zone: &str, sent_labels: usize) -> Option<String> {
+    let name_labels: Vec<&str> = labels(name).collect();
+    let wanted = labels(zone).count().max(sent_labels) + 1;
+    if wanted >= name_labels.len() {
+        return None;
+    }
+    Some(name_labels[name_labels.len() - wanted..].join("."))
+}
+
+// NSEC and NSEC3 records from the authority section of a response from
+// `zone`, with their signatures.
+fn denial_records(response: &DNSMessage, zone: &str) -> Vec<ResourceRecord> {
+    let is_denial = |record_type| matches!(record_type, Some(RecordType::NSEC | RecordType::NSEC3));
+    response
+        .authority_records
+        .iter()
+        .filter(|r| is_subdomain(&r.name, zone))
+        .filter(|r| is_denial(Some(r.record_type)) || is_denial(covered_type(r)))
+        .cloned()
+        .collect()
+}
+
+// A referral hands us NS records for a zone strictly between the one we
+// asked and the query name. Glue is only accepted for names inside the zone
+// of the server that sent it.
+fn referral(response: &DNSMessage, name: &str, zone: &str) -> Option<(Delegation, u32)> {
+    let cut = response
+        .authority_records
+        .iter()
+        .find(|r| {
+            r.record_type == RecordType::NS
+                && is_subdomain(name, &r.name)
+                && is_subdomain(&r.name, zone)
+                && !names_equal(&r.name, zone)
+        })?
+        .name
+        .clone();
+
+    let ns_records: Vec<&ResourceRecord> = response
+        .authority_records
+        .iter()
+        .filter(|r| r.record_type == RecordType::NS && names_equal(&r.name, &cut))
+        .collect();
+    let ttl = ns_records.iter().map(|r| r.ttl).min().unwrap_or(0);
+
+    let name_servers = ns_records
+        .iter()
+        .filter_map(|r| r.target_name())
+        .map(|ns_name| {
+            let addresses = if is_subdomain(&ns_name, zone) {
+                response
+                    .additional_records
+                    .iter()
+                    .filter(|r| {
+                        matches!(r.record_type, RecordType::A | RecordType::AAAA)
+                            && names_equal(&r.name, &ns_name)
+                    })
+                    .filter_map(|r| record_address(&r.data))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            NameServer {
+                name: ns_name,
+                addresses,
+            }
+        })
+        .collect();
+
+    Some((
+        Delegation {
+            zone: cut,
+            name_servers,
+        },
+        ttl,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::zone::catalog::Catalog;
+    use crate::zone::parser::parse_zone_str;
+    use crate::zone::Zone;
+
+    // Leading whitespace would repeat the previous owner, hence no indent.
+    const ROOT: &str = "\
+@ 3600 SOA a.root-servers.test. host.test. 1 3600 600 86400 60
+@ 3600 NS a.root-servers.test.
+a.root-servers.test. 3600 A 127.0.0.1
+test. 3600 NS ns.test.
+ns.test. 3600 A 127.0.0.2
+other. 3600 NS ns.glueless.test.
+";
+    const TEST: &str = "\
+@ 3600 SOA ns host 1 3600 600 86400 60
+@ 3600 NS ns
+ns 3600 A 127.0.0.2
+www 3600 A 192.0.2.1
+alias 3600 CNAME www.other.
+ns.glueless 3600 A 127.0.0.3
+";
+    const OTHER: &str = "\
+@ 3600 SOA ns.glueless.test. host.other. 1 3600 600 86400 60
+@ 3600 NS ns.glueless.test.
+www 3600 A 192.0.2.3
+";
+
+    // An authoritative stand-in for `origin`, counting the queries it gets.
+    async fn stand_in(address: SocketAddr, origin: &str, text: &str) -> Arc<AtomicUsize> {
+        let mut catalog = Catalog::default();
+        catalog.add(Zone::new(origin, parse_zone_str(text, origin).unwrap()));
+        let socket = UdpSocket::bind(address).await.unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, client)) = socket.recv_from(&mut buffer).await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let Ok(query) = DNSMessage::parse(&buffer[..len]) else {
+                    continue;
+                };
+                if let Some(response) = catalog.answer(&query) {
+                    let _ = socket.send_to(&response.to_bytes(), client).await;
+                }
+            }
+        });
+        queries
+    }
+
+    // A root, `test` and `other` on 127.0.0.1 to .3, all on one free port.
+    // The name server of `other` has no glue.
+    async fn recursor() -> (Recursor, Arc<AtomicUsize>) {
+        let probe = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = probe.local_addr().unwrap().port();
+        drop(probe);
+        let at = |host: &str| SocketAddr::new(host.parse().unwrap(), port);
+        let root = stand_in(at("127.0.0.1"), "", ROOT).await;
+        stand_in(at("127.0.0.2"), "test", TEST).await;
+        stand_in(at("127.0.0.3"), "other", OTHER).await;
+        let config = RecursorConfig {
+            root_hints: vec![NameServer {
+                name: "a.root-servers.test".to_string(),
+                addresses: vec!["127.0.0.1".parse().unwrap()],
+            }],
+            port,
+            timeout: Duration::from_millis(500),
+            max_depth: 8,
+            max_queries: 64,
+            qname_minimisation: true,
+        };
+        (Recursor::new(config), root)
+    }
+
+    fn addresses(resolution: &Resolution) -> Vec<IpAddr> {
+        resolution
+            .answers
+            .iter()
+            .filter(|record| record.record_type == RecordType::A)
+            .filter_map(|record| record_address(&record.data))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn follows_referrals_from_the_root() {
+        let (recursor, _) = recursor().await;
+        let resolution = recursor
+            .resolve("www.test", RecordType::A, None)
+            .await
+            .unwrap();
+        assert_eq!(resolution.rcode, ResponseCode::NoError);
+        assert_eq!(
+            addresses(&resolution),
+            ["192.0.2.1".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_cnames_into_zones_with_glueless_name_servers() {
+        let (recursor, _) = recursor().await;
+        let resolution = recursor
+            .resolve("alias.test", RecordType::A, None)
+            .await
+            .unwrap();
+        assert_eq!(resolution.answers[0].record_type, RecordType::CNAME);
+        assert_eq!(
+            addresses(&resolution),
+            ["192.0.2.3".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_names_that_do_not_exist() {
+        let (recursor, _) = recursor().await;
+        let resolution = recursor
+            .resolve("missing.test", RecordType::A, None)
+            .await
+            .unwrap();
+        assert_eq!(resolution.rcode, ResponseCode::NXDomain);
+        assert!(resolution.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn caches_delegations() {
+        let (recursor, root) = recursor().await;
+        recursor
+            .resolve("www.test", RecordType::A, None)
+            .await
+            .unwrap();
+        let asked = root.load(Ordering::Relaxed);
+        let resolution = recursor
+            .resolve("ns.glueless.test", RecordType::A, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            addresses(&resolution),
+            ["127.0.0.3".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(root.load(Ordering::Relaxed), asked);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_query_limit() {
+        let (mut recursor, _) = recursor().await;
+        recursor.config.max_queries = 1;
+        let result = recursor.resolve("alias.test", RecordType::A, None).await;
+        assert!(result.is_err());
+    }
+}
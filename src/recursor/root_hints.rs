@@ -0,0 +1,70 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::dns::resource_record::RecordType;
+use crate::zone::parser::{parse_zone_file, ZoneParseError};
+use crate::zone::Zone;
+
+/// A name server known by name together with the addresses it answers on.
+#[derive(Debug, Clone)]
+pub struct NameServer {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+}
+
+// IANA root hints (https://www.internic.net/domain/named.root).
+const ROOT_SERVERS: [(&str, &str, &str); 13] = [
+    ("a.root-servers.net", "198.41.0.4", "2001:503:ba3e::2:30"),
+    ("b.root-servers.net", "170.247.170.2", "2801:1b8:10::b"),
+    ("c.root-servers.net", "192.33.4.12", "2001:500:2::c"),
+    ("d.root-servers.net", "199.7.91.13", "2001:500:2d::d"),
+    ("e.root-servers.net", "192.203.230.10", "2001:500:a8::e"),
+    ("f.root-servers.net", "192.5.5.241", "2001:500:2f::f"),
+    ("g.root-servers.net", "192.112.36.4", "2001:500:12::d0d"),
+    ("h.root-servers.net", "198.97.190.53", "2001:500:1::53"),
+    ("i.root-servers.net", "192.36.148.17", "2001:7fe::53"),
+    ("j.root-servers.net", "192.58.128.30", "2001:503:c27::2:30"),
+    ("k.root-servers.net", "193.0.14.129", "2001:7fd::1"),
+    ("l.root-servers.net", "199.7.83.42", "2001:500:9f::42"),
+    ("m.root-servers.net", "202.12.27.33", "2001:dc3::35"),
+];
+
+/// The built-in root server list.
+pub fn builtin() -> Vec<NameServer> {
+    ROOT_SERVERS
+        .iter()
+        .map(|(name, ipv4, ipv6)| NameServer {
+            name: name.to_string(),
+            addresses: vec![ipv4.parse().unwrap(), ipv6.parse().unwrap()],
+        })
+        .collect()
+}
+
+/// Reads a hints file in master file format, such as `named.root` or a list
+/// of stand-in servers for testing: NS records at the root plus A/AAAA
+/// records for their names.
+pub fn load(path: &Path) -> Result<Vec<NameServer>, ZoneParseError> {
+    let zone = Zone::new("", parse_zone_file(path, "")?);
+    let hints = zone
+        .records_at("", RecordType::NS)
+        .filter_map(|record| record.target_name())
+        .map(|name| {
+            let addresses = zone
+                .records_at(&name, RecordType::A)
+                .chain(zone.records_at(&name, RecordType::AAAA))
+                .filter_map(|record| record_address(&record.data))
+                .collect();
+            NameServer { name, addresses }
+        })
+        .collect();
+    Ok(hints)
+}
+
+/// Interprets A or AAAA RDATA as an address.
+pub fn record_address(data: &[u8]) -> Option<IpAddr> {
+    match data.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(data).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(data).ok()?)),
+        _ => None,
+    }
+}
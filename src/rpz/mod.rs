@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::cidr::Cidr;
+use crate::dns::edns::ExtendedError;
+use crate::dns::header::ResponseCode;
+use crate::dns::message::DNSMessage;
+use crate::dns::name::{encode_name, is_subdomain, normalize, parent};
+use crate::dns::resource_record::{RecordType, ResourceRecord};
+use crate::recursor::root_hints::record_address;
+use crate::server::Transport;
+use crate::zone::parser::parse_zone_file;
+
+const CLIENT_IP_LABEL: &str = "rpz-client-ip";
+const RESPONSE_IP_LABEL: &str = "rpz-ip";
+
+/// What a policy rule does with a query it matches.
+#[derive(Debug, Clone)]
+pub enum Action {
+    NXDomain,
+    NoData,
+    /// Answer normally and skip every later rule.
+    Passthru,
+    /// Send no response at all.
+    Drop,
+    /// Answer UDP queries with TC set so the client retries over TCP.
+    TcpOnly,
+    /// Answer with the rule's records instead of the real data.
+    LocalData(Vec<ResourceRecord>),
+}
+
+impl Action {
+    // Special CNAME targets select the built-in actions; any other data is
+    // served in place of the real answer.
+    fn from_records(records: Vec<ResourceRecord>) -> Self {
+        if let [record] = records.as_slice() {
+            if record.record_type == RecordType::CNAME {
+                match record.target_name().as_deref() {
+                    Some("") => return Action::NXDomain,
+                    Some("*") => return Action::NoData,
+                    Some("rpz-passthru") => return Action::Passthru,
+                    Some("rpz-drop") => return Action::Drop,
+                    Some("rpz-tcp-only") => return Action::TcpOnly,
+                    _ => {}
+                }
+            }
+        }
+        Action::LocalData(records)
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::NXDomain => write!(f, "NXDOMAIN"),
+            Action::NoData => write!(f, "NODATA"),
+            Action::Passthru => write!(f, "PASSTHRU"),
+            Action::Drop => write!(f, "DROP"),
+            Action::TcpOnly => write!(f, "TCP-ONLY"),
+            Action::LocalData(_) => write!(f, "local data"),
+        }
+    }
+}
+
+/// The result of applying policy to a query or response.
+pub enum Verdict {
+    /// Nothing matched; carry on.
+    NoMatch,
+    /// A PASSTHRU rule matched; answer normally without checking further.
+    Passthru,
+    Drop,
+    Respond(DNSMessage),
+}
+
+/// The rules of one policy zone, keyed by trigger.
+#[derive(Debug, Default)]
+pub struct PolicyZone {
+    name: String,
+    qnames: HashMap<String, Action>,
+    // `*.example.com` triggers, keyed by `example.com`.
+    wildcards: HashMap<String, Action>,
+    client_ips: Vec<(Cidr, Action)>,
+    response_ips: Vec<(Cidr, Action)>,
+}
+
+impl PolicyZone {
+    /// Reads a policy zone from a master file. Owner names relative to the
+    /// zone name are the triggers.
+    pub fn load(path: &Path, name: &str) -> Result<Self, Box<dyn Error>> {
+        let name = normalize(name);
+        let mut rules: HashMap<String, Vec<ResourceRecord>> = HashMap::new();
+        for record in parse_zone_file(path, &name)? {
+            let owner = normalize(&record.name);
+            if owner == name || !is_subdomain(&owner, &name) {
+                continue;
+            }
+            let trigger = owner[..owner.len() - name.len()].trim_end_matches('.');
+            rules.entry(trigger.to_string()).or_default().push(record);
+        }
+
+        let mut zone = PolicyZone {
+            name,
+            ..PolicyZone::default()
+        };
+        for (trigger, records) in rules {
+            let action = Action::from_records(records);
+            if let Some(address) = trigger_suffix(&trigger, CLIENT_IP_LABEL) {
+                match parse_ip_trigger(address) {
+                    Some(cidr) => zone.client_ips.push((cidr, action)),
+                    None => warn!(
+                        "Ignoring invalid client IP trigger {} in {}",
+                        trigger, zone.name
+                    ),
+                }
+            } else if let Some(address) = trigger_suffix(&trigger, RESPONSE_IP_LABEL) {
+                match parse_ip_trigger(address) {
+                    Some(cidr) => zone.response_ips.push((cidr, action)),
+                    None => warn!(
+                        "Ignoring invalid response IP trigger {} in {}",
+                        trigger, zone.name
+                    ),
+                }
+            } else if trigger.starts_with("rpz-") || trigger.contains(".rpz-") {
+                // NSDNAME and NSIP triggers need the delegation path, which
+                // forwarded answers do not reveal.
+                warn!("Ignoring unsupported trigger {} in {}", trigger, zone.name);
+            } else if let Some(base) = trigger.strip_prefix("*.") {
+                zone.wildcards.insert(base.to_string(), action);
+            } else {
+                zone.qnames.insert(trigger, action);
+            }
+        }
+        Ok(zone)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn len(&self) -> usize {
+        self.qnames.len() + self.wildcards.len() + self.client_ips.len() + self.response_ips.len()
+    }
+
+    // An exact trigger beats a wildcard, and a closer wildcard beats one
+    // further up.
+    fn match_qname(&self, qname: &str) -> Option<&Action> {
+        if let Some(action) = self.qnames.get(qname) {
+            return Some(action);
+        }
+        let mut ancestor = parent(qname);
+        while let Some(name) = ancestor {
+            if let Some(action) = self.wildcards.get(name) {
+                return Some(action);
+            }
+            ancestor = parent(name);
+        }
+        None
+    }
+}
+
+/// Policy zones in order of precedence.
+#[derive(Debug, Default)]
+pub struct Policy {
+    zones: Vec<PolicyZone>,
+}
+
+impl Policy {
+    /// Loads the zones in RPZ_ZONES, a comma separated list of `name=path`
+    /// pairs in order of precedence. Returns `None` when none are set.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let mut policy = Policy::default();
+        let zone_files = std::env::var("RPZ_ZONES").unwrap_or_default();
+        for entry in zone_files
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (name, path) = entry.split_once('=').ok_or_else(|| {
+                format!("Invalid RPZ_ZONES entry {:?}, expected name=path", entry)
+            })?;
+            let zone = PolicyZone::load(Path::new(path.trim()), name.trim())?;
+            info!("Loaded policy zone {} ({} rules)", zone.name(), zone.len());
+            policy.zones.push(zone);
+        }
+        Ok((!policy.zones.is_empty()).then_some(policy))
+    }
+
+    /// Applies client IP and QNAME triggers before a query is resolved. In
+    /// each zone client IP triggers take precedence, and the first zone with
+    /// a match decides.
+    pub fn check_query(
+        &self,
+        query: &DNSMessage,
+        client: SocketAddr,
+        transport: Transport,
+    ) -> Verdict {
+        let Some(question) = query.questions.first() else {
+            return Verdict::NoMatch;
+        };
+        let qname = normalize(&question.name);
+        for zone in &self.zones {
+            let hit = longest_match(&zone.client_ips, client.ip())
+                .map(|(cidr, action)| (format!("client-ip {}", cidr), action))
+                .or_else(|| {
+                    zone.match_qname(&qname)
+                        .map(|action| (format!("qname {}", qname), action))
+                });
+            if let Some((trigger, action)) = hit {
+                info!(
+                    "RPZ {} {} for {} {} from {}: {}",
+                    zone.name, trigger, question.name, question.record_type, client, action
+                );
+                return verdict(query, action, transport);
+            }
+        }
+        Verdict::NoMatch
+    }
+
+    /// Applies response IP triggers to the addresses in an answer.
+    pub fn check_response(
+        &self,
+        query: &DNSMessage,
+        response: &DNSMessage,
+        client: SocketAddr,
+        transport: Transport,
+    ) -> Verdict {
+        let addresses: Vec<IpAddr> = response
+            .answers
+            .iter()
+            .filter(|record| matches!(record.record_type, RecordType::A | RecordType::AAAA))
+            .filter_map(|record| record_address(&record.data))
+            .collect();
+        if addresses.is_empty() {
+            return Verdict::NoMatch;
+        }
+        for zone in &self.zones {
+            let hit = addresses
+                .iter()
+                .filter_map(|address| longest_match(&zone.response_ips, *address))
+                .max_by_key(|(cidr, _)| cidr.prefix());
+            if let Some((cidr, action)) = hit {
+                let question = &query.questions[0];
+                info!(
+                    "RPZ {} response-ip {} for {} {} from {}: {}",
+                    zone.name, cidr, question.name, question.record_type, client, action
+                );
+                return verdict(query, action, transport);
+            }
+        }
+        Verdict::NoMatch
+    }
+}
+
+fn longest_match(rules: &[(Cidr, Action)], address: IpAddr) -> Option<(Cidr, &Action)> {
+    rules
+        .iter()
+        .filter(|(cidr, _)| cidr.contains(address))
+        .max_by_key(|(cidr, _)| cidr.prefix())
+        .map(|(cidr, action)| (*cidr, action))
+}
+
+// TCP-ONLY truncates UDP responses so the client retries over TCP, and lets
+// queries that came over anything else through.
+fn verdict(query: &DNSMessage, action: &Action, transport: Transport) -> Verdict {
+    let mut response = DNSMessage::response_to(query);
+    response.header.flags.ra = true;
+    match action {
+        Action::Passthru => return Verdict::Passthru,
+        Action::Drop => return Verdict::Drop,
+        Action::NXDomain => {
+            response.set_response_code(ResponseCode::NXDomain);
+            response.set_extended_error(ExtendedError::Blocked, "");
+        }
+        Action::NoData => response.set_extended_error(ExtendedError::Blocked, ""),
+        Action::TcpOnly if transport == Transport::Udp => response.header.flags.tc = true,
+        Action::TcpOnly => return Verdict::Passthru,
+        Action::LocalData(records) => {
+            response.answers = local_data(query, records);
+            response.set_extended_error(ExtendedError::ForgedAnswer, "");
+        }
+    }
+    Verdict::Respond(response)
+}
+
+// The rule's records that answer the question, renamed to the query name. A
+// CNAME answers any type, and `*.` at the front of its target stands for the
+// query name.
+fn local_data(query: &DNSMessage, records: &[ResourceRecord]) -> Vec<ResourceRecord> {
+    let question = &query.questions[0];
+    let cname = records
+        .iter()
+        .find(|record| record.record_type == RecordType::CNAME);
+    if let Some(cname) = cname {
+        let mut record = cname.clone();
+        record.name = question.name.clone();
+        if let Some(rest) = cname
+            .target_name()
+            .as_deref()
+            .and_then(|t| t.strip_prefix("*."))
+        {
+            let target = format!("{}.{}", normalize(&question.name), rest);
+            record.data.clear();
+            encode_name(&target, &mut record.data);
+            record.data_length = record.data.len() as u16;
+        }
+        return vec![record];
+    }
+    records
+        .iter()
+        .filter(|record| {
+            question.record_type == RecordType::ANY || record.record_type == question.record_type
+        })
+        .map(|record| ResourceRecord {
+            name: question.name.clone(),
+            ..record.clone()
+        })
+        .collect()
+}
+
+// The part of a trigger in front of a `.rpz-client-ip` style label.
+fn trigger_suffix<'a>(trigger: &'a str, label: &str) -> Option<&'a str> {
+    trigger.strip_suffix(label)?.strip_suffix('.')
+}
+
+// IP triggers are written as the prefix length followed by the address
+// labels in reverse: `24.0.2.0.192` is 192.0.2.0/24 and `48.zz.db8.2001` is
+// 2001:db8::/48.
+fn parse_ip_trigger(text: &str) -> Option<Cidr> {
+    let mut labels: Vec<&str> = text.split('.').collect();
+    let prefix: u8 = labels.remove(0).parse().ok()?;
+    labels.reverse();
+    let address: IpAddr = if labels.len() == 4 && labels.iter().all(|l| l.parse::<u8>().is_ok()) {
+        labels.join(".").parse().ok()?
+    } else {
+        let mut text = labels
+            .iter()
+            .map(|label| if *label == "zz" { "" } else { label })
+            .collect::<Vec<_>>()
+            .join(":");
+        if text.starts_with(':') {
+            text.insert(0, ':');
+        }
+        if text.ends_with(':') {
+            text.push(':');
+        }
+        text.parse().ok()?
+    };
+    Cidr::new(address, prefix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::dns::name::parse_name;
+
+    const CLIENT: &str = "192.0.2.10:5353";
+
+    fn load(name: &str, text: &str) -> PolicyZone {
+        let path =
+            std::env::temp_dir().join(format!("tinydns-rpz-{}-{}", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let zone = PolicyZone::load(&path, name);
+        fs::remove_file(&path).unwrap();
+        zone.unwrap()
+    }
+
+    fn cidr(text: &str) -> Cidr {
+        text.parse().unwrap()
+    }
+
+    fn query(name: &str, record_type: RecordType) -> DNSMessage {
+        DNSMessage::query(1, name, record_type)
+    }
+
+    // A short description of a verdict, as `Verdict` is not comparable.
+    fn outcome(verdict: Verdict) -> String {
+        match verdict {
+            Verdict::NoMatch => "no match".to_string(),
+            Verdict::Passthru => "passthru".to_string(),
+            Verdict::Drop => "drop".to_string(),
+            Verdict::Respond(response) if response.header.flags.tc => "truncated".to_string(),
+            Verdict::Respond(response) => format!(
+                "rcode {} with {} answers",
+                response.header.flags.rcode,
+                response.answers.len()
+            ),
+        }
+    }
+
+    fn check(policy: &Policy, name: &str, client: &str) -> String {
+        let query = query(name, RecordType::A);
+        outcome(policy.check_query(&query, client.parse().unwrap(), Transport::Udp))
+    }
+
+    #[test]
+    fn parses_reversed_ip_triggers() {
+        assert_eq!(parse_ip_trigger("24.0.2.0.192"), Some(cidr("192.0.2.0/24")));
+        assert_eq!(parse_ip_trigger("32.1.2.0.192"), Some(cidr("192.0.2.1/32")));
+        assert_eq!(parse_ip_trigger("8.1.2.0.192"), Some(cidr("192.0.0.0/8")));
+        assert_eq!(
+            parse_ip_trigger("48.zz.db8.2001"),
+            Some(cidr("2001:db8::/48"))
+        );
+        assert_eq!(
+            parse_ip_trigger("128.1.zz.2.db8.2001"),
+            Some(cidr("2001:db8:2::1/128"))
+        );
+        assert_eq!(
+            parse_ip_trigger("64.0.0.0.0.0.0.db8.2001"),
+            Some(cidr("2001:db8::/64"))
+        );
+        assert_eq!(parse_ip_trigger("128.1.zz"), Some(cidr("::1/128")));
+
+        assert_eq!(parse_ip_trigger("33.1.2.0.192"), None);
+        assert_eq!(parse_ip_trigger("x.1.2.0.192"), None);
+        assert_eq!(parse_ip_trigger("24.2.0.192"), None);
+        assert_eq!(parse_ip_trigger("48.zz.zz.2001"), None);
+        assert_eq!(parse_ip_trigger("24"), None);
+    }
+
+    #[test]
+    fn loads_triggers_by_kind() {
+        let zone = load(
+            "rpz.test",
+            "$TTL 60\n\
+             @ SOA ns.rpz.test. admin.rpz.test. 1 3600 600 86400 60\n\
+             @ NS ns.rpz.test.\n\
+             blocked.example CNAME .\n\
+             nodata.example CNAME *.\n\
+             *.wild.example CNAME rpz-drop.\n\
+             local.example A 192.0.2.1\n\
+             local.example TXT \"policy\"\n\
+             24.0.2.0.192.rpz-client-ip CNAME rpz-drop.\n\
+             128.1.zz.db8.2001.rpz-ip CNAME .\n\
+             bad.rpz-ip CNAME .\n\
+             ns.example.rpz-nsdname CNAME .\n\
+             outside.test. A 192.0.2.2\n",
+        );
+
+        assert_eq!(zone.name(), "rpz.test");
+        assert_eq!(zone.len(), 6);
+        assert!(matches!(zone.qnames["blocked.example"], Action::NXDomain));
+        assert!(matches!(zone.qnames["nodata.example"], Action::NoData));
+        assert!(matches!(
+            &zone.qnames["local.example"],
+            Action::LocalData(records) if records.len() == 2
+        ));
+        assert!(matches!(zone.wildcards["wild.example"], Action::Drop));
+        assert!(!zone.qnames.contains_key("*.wild.example"));
+        assert_eq!(zone.client_ips.len(), 1);
+        assert_eq!(zone.client_ips[0].0, cidr("192.0.2.0/24"));
+        assert!(matches!(zone.client_ips[0].1, Action::Drop));
+        assert_eq!(zone.response_ips.len(), 1);
+        assert_eq!(zone.response_ips[0].0, cidr("2001:db8::1/128"));
+    }
+
+    #[test]
+    fn rewrites_local_data_to_the_query_name() {
+        let zone = load(
+            "local.rpz",
+            "$TTL 60\n\
+             walled.example CNAME *.garden.test.\n\
+             fixed.example CNAME portal.test.\n\
+             multi.example A 192.0.2.1\n\
+             multi.example AAAA 2001:db8::1\n",
+        );
+        let action = |name: &str| match &zone.qnames[name] {
+            Action::LocalData(records) => records.clone(),
+            action => panic!("unexpected action {}", action),
+        };
+        let target = |record: &ResourceRecord| parse_name(&record.data, 0).unwrap().0;
+
+        let records = local_data(
+            &query("Walled.Example", RecordType::A),
+            &action("walled.example"),
+        );
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, "Walled.Example");
+        assert_eq!(target(&records[0]), "walled.example.garden.test");
+        assert_eq!(records[0].data_length as usize, records[0].data.len());
+
+        let records = local_data(
+            &query("fixed.example", RecordType::MX),
+            &action("fixed.example"),
+        );
+        assert_eq!(target(&records[0]), "portal.test");
+        // CNAME queries get the rewritten CNAME too.
+        let records = local_data(
+            &query("walled.example", RecordType::CNAME),
+            &action("walled.example"),
+        );
+        assert_eq!(target(&records[0]), "walled.example.garden.test");
+
+        let records = local_data(
+            &query("multi.example", RecordType::AAAA),
+            &action("multi.example"),
+        );
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record_type, RecordType::AAAA);
+        let records = local_data(
+            &query("multi.example", RecordType::ANY),
+            &action("multi.example"),
+        );
+        assert_eq!(records.len(), 2);
+        assert!(local_data(
+            &query("multi.example", RecordType::TXT),
+            &action("multi.example")
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn first_zone_and_client_ip_triggers_take_precedence() {
+        let first = load(
+            "first.rpz",
+            "$TTL 60\n\
+             *.example CNAME .\n\
+             exact.example CNAME rpz-passthru.\n\
+             32.1.2.0.198.rpz-client-ip CNAME rpz-drop.\n",
+        );
+        let second = load(
+            "second.rpz",
+            "$TTL 60\n\
+             exact.example CNAME .\n\
+             other.test CNAME *.\n\
+             24.0.2.0.192.rpz-client-ip CNAME rpz-drop.\n\
+             tcp.test CNAME rpz-tcp-only.\n",
+        );
+        let policy = Policy {
+            zones: vec![first, second],
+        };
+
+        // An exact trigger beats a wildcard in the same zone.
+        assert_eq!(check(&policy, "exact.example", CLIENT), "passthru");
+        assert_eq!(
+            check(&policy, "a.b.example", CLIENT),
+            "rcode 3 with 0 answers"
+        );
+        // The second zone's client IP rule loses to the first zone's qname rule...
+        assert_eq!(
+            check(&policy, "x.example", CLIENT),
+            "rcode 3 with 0 answers"
+        );
+        // ...and decides when the first zone has nothing to say.
+        assert_eq!(check(&policy, "other.test", CLIENT), "drop");
+        assert_eq!(
+            check(&policy, "other.test", "203.0.113.1:53"),
+            "rcode 0 with 0 answers"
+        );
+        // Within a zone, a client IP trigger beats a qname trigger.
+        assert_eq!(check(&policy, "exact.example", "198.0.2.1:53"), "drop");
+        assert_eq!(
+            check(&policy, "unlisted.test", "203.0.113.1:53"),
+            "no match"
+        );
+
+        let query = query("tcp.test", RecordType::A);
+        let client: SocketAddr = "203.0.113.1:53".parse().unwrap();
+        assert_eq!(
+            outcome(policy.check_query(&query, client, Transport::Udp)),
+            "truncated"
+        );
+        assert_eq!(
+            outcome(policy.check_query(&query, client, Transport::Tcp)),
+            "passthru"
+        );
+    }
+
+    #[test]
+    fn matches_the_longest_response_ip_trigger() {
+        let zone = load(
+            "response.rpz",
+            "$TTL 60\n\
+             16.0.0.0.10.rpz-ip CNAME rpz-passthru.\n\
+             24.0.2.0.10.rpz-ip CNAME .\n",
+        );
+        let policy = Policy { zones: vec![zone] };
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let query = query("host.example", RecordType::A);
+        let respond = |address: [u8; 4]| {
+            let mut response = DNSMessage::response_to(&query);
+            response.answers.push(ResourceRecord::new(
+                "host.example".to_string(),
+                RecordType::A,
+                crate::dns::resource_record::RecordClass::IN,
+                60,
+                address.to_vec(),
+            ));
+            outcome(policy.check_response(&query, &response, client, Transport::Udp))
+        };
+
+        assert_eq!(respond([10, 0, 2, 7]), "rcode 3 with 0 answers");
+        assert_eq!(respond([10, 0, 3, 7]), "passthru");
+        assert_eq!(respond([10, 1, 0, 1]), "no match");
+    }
+}
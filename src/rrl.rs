@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::acl::AccessList;
+use crate::cidr::Cidr;
+use crate::config::env_or;
+use crate::dns::header::ResponseCode;
+use crate::dns::message::DNSMessage;
+use crate::dns::name::normalize;
+use crate::dns::resource_record::RecordType;
+
+// Past this many accounts, idle ones are forgotten.
+const MAX_ACCOUNTS: usize = 100_000;
+
+/// What to do with a response after rate limiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RrlAction {
+    Send,
+    /// Send an empty truncated response so a real client retries over TCP.
+    Slip,
+    Drop,
+}
+
+/// Settings for response rate limiting.
+#[derive(Debug, Clone)]
+pub struct RrlConfig {
+    pub responses_per_second: u32,
+    /// Every `slip`th limited response is slipped rather than dropped; 0
+    /// drops them all.
+    pub slip: u32,
+    /// How far back excess responses are remembered, which is how long a
+    /// flood keeps being limited after it stops.
+    pub window: Duration,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    pub exempt: AccessList,
+}
+
+impl RrlConfig {
+    /// Reads RRL_RESPONSES_PER_SECOND, which enables rate limiting, along
+    /// with RRL_SLIP (default 2), RRL_WINDOW (seconds, default 15),
+    /// RRL_IPV4_PREFIX (default 24), RRL_IPV6_PREFIX (default 56) and
+    /// RRL_EXEMPT (networks, default none).
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let responses_per_second = env_or("RRL_RESPONSES_PER_SECOND", 0)?;
+        if responses_per_second == 0 {
+            return Ok(None);
+        }
+        let ipv4_prefix = env_or("RRL_IPV4_PREFIX", 24)?;
+        let ipv6_prefix = env_or("RRL_IPV6_PREFIX", 56)?;
+        if ipv4_prefix > 32 || ipv6_prefix > 128 {
+            return Err("RRL prefix lengths must fit the address family".into());
+        }
+        let exempt = std::env::var("RRL_EXEMPT").unwrap_or_default();
+        Ok(Some(RrlConfig {
+            responses_per_second,
+            slip: env_or("RRL_SLIP", 2)?,
+            window: Duration::from_secs(env_or("RRL_WINDOW", 15)?),
+            ipv4_prefix,
+            ipv6_prefix,
+            exempt: AccessList::parse(&exempt)
+                .map_err(|e| format!("Invalid value for RRL_EXEMPT: {}", e))?,
+        }))
+    }
+}
+
+// Responses count against the same account when they go to the same client
+// network and say the same thing: the same answer, the same NXDOMAIN zone,
+// the same referral, or any error.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Identity {
+    Answer(String, RecordType),
+    NXDomain(String),
+    Referral(String),
+    Error,
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identity::Answer(name, record_type) => write!(f, "{} {}", name, record_type),
+            Identity::NXDomain(zone) => write!(f, "NXDOMAIN in {}", zone),
+            Identity::Referral(zone) => write!(f, "referral to {}", zone),
+            Identity::Error => write!(f, "errors"),
+        }
+    }
+}
+
+struct Account {
+    balance: f64,
+    updated: Instant,
+    limited: u64,
+}
+
+/// Response rate limiting against reflection attacks, after BIND's RRL.
+pub struct ResponseRateLimiter {
+    config: RrlConfig,
+    accounts: Mutex<HashMap<(Cidr, Identity), Account>>,
+    dropped: AtomicU64,
+    slipped: AtomicU64,
+}
+
+impl ResponseRateLimiter {
+    pub fn new(config: RrlConfig) -> Self {
+        ResponseRateLimiter {
+            config,
+            accounts: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+            slipped: AtomicU64::new(0),
+        }
+    }
+
+    /// Charges a UDP response to its account and decides whether it may go.
+    pub fn check(&self, client: IpAddr, response: &DNSMessage) -> RrlAction {
+        self.check_at(client, response, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, response: &DNSMessage, now: Instant) -> RrlAction {
+        if self.config.exempt.allows(client) {
+            return RrlAction::Send;
+        }
+        let prefix = if client.is_ipv4() {
+            self.config.ipv4_prefix
+        } else {
+            self.config.ipv6_prefix
+        };
+        let network = Cidr::new(client, prefix).expect("prefix checked in config");
+        let identity = identity(response);
+        let rate = self.config.responses_per_second as f64;
+
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.len() >= MAX_ACCOUNTS {
+            let window = self.config.window;
+            accounts.retain(|_, account| now.duration_since(account.updated) < window);
+        }
+        let key = (network, identity);
+        let account = accounts.entry(key.clone()).or_insert(Account {
+            balance: rate,
+            updated: now,
+            limited: 0,
+        });
+        let elapsed = now.duration_since(account.updated).as_secs_f64();
+        account.balance = (account.balance + elapsed * rate).min(rate) - 1.0;
+        account.updated = now;
+        if account.balance >= 0.0 {
+            account.limited = 0;
+            return RrlAction::Send;
+        }
+
+        let debt = rate * self.config.window.as_secs_f64();
+        account.balance = account.balance.max(-debt);
+        account.limited += 1;
+        if account.limited == 1 {
+            info!("Rate limiting responses to {} for {}", key.0, key.1);
+        }
+        if self.config.slip > 0 && account.limited.is_multiple_of(self.config.slip as u64) {
+            self.slipped.fetch_add(1, Ordering::Relaxed);
+            RrlAction::Slip
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            RrlAction::Drop
+        }
+    }
+
+    /// Responses dropped and slipped so far.
+    pub fn counters(&self) -> (u64, u64) {
+        (
+            self.dropped.load(Ordering::Relaxed),
+            self.slipped.load(Ordering::Relaxed),
+        )
+    }
+}
+
+fn identity(response: &DNSMessage) -> Identity {
+    let qname = response
+        .questions
+        .first()
+        .map_or_else(String::new, |q| normalize(&q.name));
+    let record_type = response
+        .questions
+        .first()
+        .map_or(RecordType::ANY, |q| q.record_type);
+    let authority_owner = |record_type: RecordType| {
+        response
+            .authority_records
+            .iter()
+            .find(|record| record.record_type == record_type)
+            .map(|record| normalize(&record.name))
+    };
+
+    match response.header.flags.rcode {
+        rcode if rcode == ResponseCode::NoError as u8 => {
+            if !response.answers.is_empty() {
+                Identity::Answer(qname, record_type)
+            } else if let Some(zone) = authority_owner(RecordType::NS) {
+                Identity::Referral(zone)
+            } else {
+                // NODATA counts as an answer of nothing for the type.
+                Identity::Answer(qname, record_type)
+            }
+        }
+        rcode if rcode == ResponseCode::NXDomain as u8 => {
+            Identity::NXDomain(authority_owner(RecordType::SOA).unwrap_or(qname))
+        }
+        _ => Identity::Error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::resource_record::{RecordClass, ResourceRecord};
+
+    fn limiter(slip: u32) -> ResponseRateLimiter {
+        ResponseRateLimiter::new(RrlConfig {
+            responses_per_second: 2,
+            slip,
+            window: Duration::from_secs(15),
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            exempt: AccessList::parse("192.0.2.53").unwrap(),
+        })
+    }
+
+    fn record(name: &str, record_type: RecordType) -> ResourceRecord {
+        ResourceRecord::new(
+            name.to_string(),
+            record_type,
+            RecordClass::IN,
+            60,
+            vec![0; 4],
+        )
+    }
+
+    fn answer(name: &str) -> DNSMessage {
+        let mut response = DNSMessage::response_to(&DNSMessage::query(1, name, RecordType::A));
+        response.answers.push(record(name, RecordType::A));
+        response
+    }
+
+    fn nxdomain(name: &str) -> DNSMessage {
+        let mut response = DNSMessage::response_to(&DNSMessage::query(1, name, RecordType::A));
+        response.set_response_code(ResponseCode::NXDomain);
+        response
+            .authority_records
+            .push(record("example.test", RecordType::SOA));
+        response
+    }
+
+    fn client(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn slips_every_second_limited_response() {
+        let limiter = limiter(2);
+        let now = Instant::now();
+        let www = answer("www.example.test");
+        let actions: Vec<RrlAction> = (0..6)
+            .map(|_| limiter.check_at(client("198.51.100.1"), &www, now))
+            .collect();
+        let (send, drop, slip) = (RrlAction::Send, RrlAction::Drop, RrlAction::Slip);
+        assert_eq!(actions, [send, send, drop, slip, drop, slip]);
+        assert_eq!(limiter.counters(), (2, 2));
+    }
+
+    #[test]
+    fn drops_every_limited_response_without_slip() {
+        let limiter = limiter(0);
+        let now = Instant::now();
+        let www = answer("www.example.test");
+        for _ in 0..2 {
+            limiter.check_at(client("198.51.100.1"), &www, now);
+        }
+        for _ in 0..4 {
+            assert_eq!(
+                limiter.check_at(client("198.51.100.1"), &www, now),
+                RrlAction::Drop
+            );
+        }
+        assert_eq!(limiter.counters(), (4, 0));
+    }
+
+    #[test]
+    fn keeps_an_account_per_network_and_response() {
+        let limiter = limiter(2);
+        let now = Instant::now();
+        let www = answer("www.example.test");
+        for _ in 0..2 {
+            limiter.check_at(client("198.51.100.1"), &www, now);
+        }
+        // The same /24 shares the account, other networks and answers do not.
+        let check =
+            |address: &str, response: &DNSMessage| limiter.check_at(client(address), response, now);
+        assert_eq!(check("198.51.100.200", &www), RrlAction::Drop);
+        assert_eq!(check("198.51.101.1", &www), RrlAction::Send);
+        assert_eq!(
+            check("198.51.100.1", &answer("mail.example.test")),
+            RrlAction::Send
+        );
+        // NXDOMAIN answers count against their zone, whatever the name.
+        assert_eq!(
+            check("198.51.100.1", &nxdomain("a.example.test")),
+            RrlAction::Send
+        );
+        assert_eq!(
+            check("198.51.100.1", &nxdomain("b.example.test")),
+            RrlAction::Send
+        );
+        assert_eq!(
+            check("198.51.100.1", &nxdomain("c.example.test")),
+            RrlAction::Drop
+        );
+        // Exempt clients are never limited.
+        for _ in 0..10 {
+            assert_eq!(check("192.0.2.53", &www), RrlAction::Send);
+        }
+    }
+
+    #[test]
+    fn remembers_a_flood_for_the_window() {
+        let limiter = limiter(0);
+        let start = Instant::now();
+        let www = answer("www.example.test");
+        let check = |seconds: u64| {
+            limiter.check_at(
+                client("198.51.100.1"),
+                &www,
+                start + Duration::from_secs(seconds),
+            )
+        };
+        for _ in 0..100 {
+            check(0);
+        }
+        // The debt is capped at a window's worth of responses, 30 here,
+        // which refill at two a second.
+        assert_eq!(check(15), RrlAction::Drop);
+        assert_eq!(check(16), RrlAction::Send);
+        assert_eq!(check(16), RrlAction::Drop);
+    }
+}
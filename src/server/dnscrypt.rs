@@ -0,0 +1,432 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use crypto_box::aead::OsRng;
+use crypto_box::SecretKey;
+use log::{debug, error, info, warn};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+use super::tcp::{bind_tcp, IDLE_TIMEOUT};
+use super::udp::bind_udp;
+use super::Transport;
+use crate::dns::dnscrypt::{
+    pad, sealed_length, unix_time, unpad, Certificate, Construction, SharedKey, Stamp,
+    CLIENT_MAGIC_LENGTH, HALF_NONCE_LENGTH, PUBLIC_KEY_LENGTH, QUERY_HEADER_LENGTH, RESOLVER_MAGIC,
+    RESPONSE_HEADER_LENGTH,
+};
+use crate::dns::header::ResponseCode;
+use crate::dns::message::DNSMessage;
+use crate::dns::name::normalize;
+use crate::dns::resource_record::{RecordClass, RecordType, ResourceRecord};
+use crate::Resolvers;
+
+// Resolver keys are replaced twice a day and their certificates stay valid
+// for a day, so clients holding the previous one have time to move on.
+const KEY_LIFETIME: u32 = 86400;
+const KEY_ROTATION: u32 = 43200;
+const CERT_TTL: u32 = 3600;
+// Room for the largest query a client may pad.
+const RECEIVE_BUFFER: usize = 4096;
+
+/// Serves DNSCrypt v2 as the provider DNSCRYPT_PROVIDER_NAME (e.g.
+/// `2.dnscrypt-cert.example.com`), signing certificates with the Ed25519
+/// key whose base64 seed is DNSCRYPT_PROVIDER_KEY. Resolver keys are
+/// generated at startup and then as they age, and never leave memory.
+pub struct DnsCryptServer {
+    provider_name: String,
+    provider: Ed25519KeyPair,
+    keys: RwLock<Vec<ResolverKey>>,
+}
+
+struct ResolverKey {
+    certificate: Certificate,
+    signed: Vec<u8>,
+    secret: SecretKey,
+}
+
+impl DnsCryptServer {
+    /// Returns `None` when neither variable is set.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let (name, key) = match (
+            std::env::var("DNSCRYPT_PROVIDER_NAME"),
+            std::env::var("DNSCRYPT_PROVIDER_KEY"),
+        ) {
+            (Ok(name), Ok(key)) => (name, key),
+            (Err(_), Err(_)) => return Ok(None),
+            _ => {
+                return Err(
+                    "DNSCRYPT_PROVIDER_NAME and DNSCRYPT_PROVIDER_KEY must be set together".into(),
+                )
+            }
+        };
+        let seed = BASE64
+            .decode(key.trim())
+            .map_err(|_| "Invalid value for DNSCRYPT_PROVIDER_KEY: not base64")?;
+        Ok(Some(DnsCryptServer::new(&name, &seed)?))
+    }
+
+    pub fn new(provider_name: &str, seed: &[u8]) -> Result<Self, String> {
+        let provider = Ed25519KeyPair::from_seed_unchecked(seed)
+            .map_err(|_| "A DNSCrypt provider key is a 32 byte Ed25519 seed".to_string())?;
+        let server = DnsCryptServer {
+            provider_name: normalize(provider_name),
+            provider,
+            keys: RwLock::new(Vec::new()),
+        };
+        server.rotate(unix_time());
+        Ok(server)
+    }
+
+    /// The stamp clients use to reach this server at `address`.
+    pub fn stamp(&self, address: SocketAddr) -> Stamp {
+        let mut provider_key = [0u8; PUBLIC_KEY_LENGTH];
+        provider_key.copy_from_slice(self.provider.public_key().as_ref());
+        Stamp {
+            address,
+            provider_key,
+            provider_name: self.provider_name.clone(),
+        }
+    }
+
+    // Replaces resolver keys that are due, and forgets expired ones.
+    fn rotate(&self, now: u32) {
+        let due = |keys: &[ResolverKey]| {
+            keys.iter()
+                .map(|key| key.certificate.valid_from)
+                .max()
+                .is_none_or(|newest| now >= newest + KEY_ROTATION)
+        };
+        if !due(&self.keys.read().unwrap()) {
+            return;
+        }
+        let mut keys = self.keys.write().unwrap();
+        if !due(&keys) {
+            return;
+        }
+        keys.retain(|key| key.certificate.is_current(now));
+        for construction in Construction::ALL {
+            let secret = SecretKey::generate(&mut OsRng);
+            let resolver_key = secret.public_key().to_bytes();
+            let mut client_magic = [0u8; CLIENT_MAGIC_LENGTH];
+            client_magic.copy_from_slice(&resolver_key[..CLIENT_MAGIC_LENGTH]);
+            let certificate = Certificate {
+                construction,
+                resolver_key,
+                client_magic,
+                serial: now,
+                valid_from: now,
+                valid_until: now + KEY_LIFETIME,
+            };
+            let signed = certificate.sign(&self.provider);
+            keys.push(ResolverKey {
+                certificate,
+                signed,
+                secret,
+            });
+        }
+        info!(
+            "New DNSCrypt resolver keys for {}, serial {}",
+            self.provider_name, now
+        );
+    }
+
+    // The response to a packet, or `None` to send nothing. Packets that do
+    // not start with a client magic are plain queries, which may only ask
+    // for the certificates. Encrypted responses over UDP are never longer
+    // than the query, to give nothing to amplify.
+    async fn answer(
+        &self,
+        packet: &[u8],
+        client: SocketAddr,
+        local: SocketAddr,
+        udp: bool,
+        resolvers: &Resolvers,
+    ) -> Option<Vec<u8>> {
+        let now = unix_time();
+        self.rotate(now);
+        let Some((shared, client_nonce, query)) = self.open(packet, now) else {
+            return self.answer_plain(packet, udp);
+        };
+        let mut response = resolvers
+            .handle(&query, client, local, Transport::DnsCrypt)
+            .await?;
+        if udp && RESPONSE_HEADER_LENGTH + sealed_length(response.len()) > packet.len() {
+            let mut truncated = DNSMessage::response_to(&DNSMessage::parse(&query).ok()?);
+            truncated.header.flags.tc = true;
+            response = truncated.to_bytes();
+        }
+
+        let mut nonce = [0u8; 2 * HALF_NONCE_LENGTH];
+        nonce[..HALF_NONCE_LENGTH].copy_from_slice(&client_nonce);
+        nonce[HALF_NONCE_LENGTH..].copy_from_slice(&rand::random::<[u8; HALF_NONCE_LENGTH]>());
+        pad(&mut response, 0);
+        let mut sealed = RESOLVER_MAGIC.to_vec();
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(shared.seal(&nonce, &response));
+        Some(sealed)
+    }
+
+    // Decrypts a query sent to one of our resolver keys, returning the key
+    // to encrypt the response with and the client's half of the nonce.
+    fn open(
+        &self,
+        packet: &[u8],
+        now: u32,
+    ) -> Option<(SharedKey, [u8; HALF_NONCE_LENGTH], Vec<u8>)> {
+        if packet.len() < QUERY_HEADER_LENGTH {
+            return None;
+        }
+        let (header, sealed) = packet.split_at(QUERY_HEADER_LENGTH);
+        let keys = self.keys.read().unwrap();
+        let key = keys.iter().find(|key| {
+            key.certificate.client_magic == header[..CLIENT_MAGIC_LENGTH]
+                && key.certificate.is_current(now)
+        })?;
+        let client_key: [u8; PUBLIC_KEY_LENGTH] = header
+            [CLIENT_MAGIC_LENGTH..CLIENT_MAGIC_LENGTH + PUBLIC_KEY_LENGTH]
+            .try_into()
+            .ok()?;
+        let mut client_nonce = [0u8; HALF_NONCE_LENGTH];
+        client_nonce.copy_from_slice(&header[CLIENT_MAGIC_LENGTH + PUBLIC_KEY_LENGTH..]);
+        // Queries carry only the client's half; the rest is zeros.
+        let mut nonce = [0u8; 2 * HALF_NONCE_LENGTH];
+        nonce[..HALF_NONCE_LENGTH].copy_from_slice(&client_nonce);
+
+        let shared = SharedKey::new(key.certificate.construction, &client_key, &key.secret);
+        let mut query = shared.open(&nonce, sealed).ok()?;
+        unpad(&mut query).ok()?;
+        Some((shared, client_nonce, query))
+    }
+
+    // Answers a plain query for the provider name's TXT records with the
+    // current certificates, and refuses anything else.
+    fn answer_plain(&self, packet: &[u8], udp: bool) -> Option<Vec<u8>> {
+        let query = DNSMessage::parse(packet).ok()?;
+        let mut response = DNSMessage::response_to(&query);
+        match query.questions.as_slice() {
+            [question]
+                if question.record_type == RecordType::TXT
+                    && normalize(&question.name) == self.provider_name =>
+            {
+                response.header.flags.aa = true;
+                let now = unix_time();
+                for key in self.keys.read().unwrap().iter() {
+                    if !key.certificate.is_current(now) {
+                        continue;
+                    }
+                    let mut data = vec![key.signed.len() as u8];
+                    data.extend_from_slice(&key.signed);
+                    response.answers.push(ResourceRecord::new(
+                        question.name.clone(),
+                        RecordType::TXT,
+                        RecordClass::IN,
+                        CERT_TTL,
+                        data,
+                    ));
+                }
+            }
+            _ => response.set_response_code(ResponseCode::Refused),
+        }
+        let limit = match udp {
+            true => query.max_response_size(),
+            false => u16::MAX as usize,
+        };
+        Some(response.to_bytes_with_limit(limit))
+    }
+}
+
+/// Binds UDP and TCP on `address`, as DNSCrypt serves both.
+pub async fn bind_dnscrypt(address: SocketAddr) -> std::io::Result<(UdpSocket, TcpListener)> {
+    let socket = bind_udp(address).await?;
+    // A free port picked for UDP is used for TCP as well.
+    let listener = bind_tcp(socket.local_addr()?).await?;
+    Ok((socket, listener))
+}
+
+/// Answers DNSCrypt queries over UDP and TCP until the task is dropped.
+pub async fn serve_dnscrypt(
+    socket: UdpSocket,
+    listener: TcpListener,
+    server: Arc<DnsCryptServer>,
+    resolvers: Arc<Resolvers>,
+) {
+    let local = match socket.local_addr() {
+        Ok(local) => local,
+        Err(e) => {
+            error!("Error reading the local DNSCrypt address: {}", e);
+            return;
+        }
+    };
+    info!(
+        "Serving DNSCrypt on {} as {}, stamp {}",
+        local,
+        server.provider_name,
+        server.stamp(local)
+    );
+    tokio::join!(
+        serve_datagrams(socket, local, server.clone(), resolvers.clone()),
+        serve_connections(listener, local, server, resolvers),
+    );
+}
+
+async fn serve_datagrams(
+    socket: UdpSocket,
+    local: SocketAddr,
+    server: Arc<DnsCryptServer>,
+    resolvers: Arc<Resolvers>,
+) {
+    let socket = Arc::new(socket);
+    let mut buf = [0u8; RECEIVE_BUFFER];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Error receiving from socket: {}", e);
+                continue;
+            }
+        };
+        let packet = buf[..len].to_vec();
+        let socket = socket.clone();
+        let server = server.clone();
+        let resolvers = resolvers.clone();
+        tokio::spawn(async move {
+            let Some(response) = server
+                .answer(&packet, client, local, true, &resolvers)
+                .await
+            else {
+                return;
+            };
+            if let Err(e) = socket.send_to(&response, client).await {
+                error!("Error sending response to {}: {}", client, e);
+            }
+        });
+    }
+}
+
+async fn serve_connections(
+    listener: TcpListener,
+    local: SocketAddr,
+    server: Arc<DnsCryptServer>,
+    resolvers: Arc<Resolvers>,
+) {
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Error accepting DNSCrypt connection: {}", e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        let resolvers = resolvers.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, client, local, &server, &resolvers).await {
+                warn!("DNSCrypt connection from {} failed: {}", client, e);
+            }
+        });
+    }
+}
+
+// Answers length-prefixed packets in turn, like plain TCP.
+async fn serve_connection(
+    mut stream: TcpStream,
+    client: SocketAddr,
+    local: SocketAddr,
+    server: &DnsCryptServer,
+    resolvers: &Resolvers,
+) -> std::io::Result<()> {
+    loop {
+        let mut length = [0u8; 2];
+        match timeout(IDLE_TIMEOUT, stream.read_exact(&mut length)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Ok(()),
+        }
+        let mut packet = vec![0u8; u16::from_be_bytes(length) as usize];
+        timeout(IDLE_TIMEOUT, stream.read_exact(&mut packet))
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+        let Some(response) = server
+            .answer(&packet, client, local, false, resolvers)
+            .await
+        else {
+            debug!("Dropping DNSCrypt query from {}", client);
+            continue;
+        };
+        let Ok(length) = u16::try_from(response.len()) else {
+            error!(
+                "Response to {} is too large for {}",
+                client,
+                Transport::DnsCrypt
+            );
+            continue;
+        };
+        let mut framed = length.to_be_bytes().to_vec();
+        framed.extend_from_slice(&response);
+        stream.write_all(&framed).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::dnscrypt::Construction;
+
+    const SEED: [u8; 32] = [7; 32];
+
+    #[test]
+    fn publishes_a_signed_certificate_per_construction() {
+        let server = DnsCryptServer::new("2.dnscrypt-cert.example.test.", &SEED).unwrap();
+        let stamp = server.stamp("127.0.0.1:443".parse().unwrap());
+        assert_eq!(stamp.provider_name, "2.dnscrypt-cert.example.test");
+
+        let query = DNSMessage::query(1, "2.dnscrypt-cert.example.test", RecordType::TXT);
+        let response = server.answer_plain(&query.to_bytes(), true).unwrap();
+        let response = DNSMessage::parse(&response).unwrap();
+        assert!(response.header.flags.aa);
+        let mut constructions = response
+            .answers
+            .iter()
+            .map(|record| {
+                assert_eq!(record.data[0] as usize, record.data.len() - 1);
+                let cert = Certificate::parse(&record.data[1..], &stamp.provider_key).unwrap();
+                assert!(cert.is_current(unix_time()));
+                cert.construction
+            })
+            .collect::<Vec<_>>();
+        constructions.sort();
+        assert_eq!(constructions, Construction::ALL);
+    }
+
+    #[test]
+    fn refuses_other_plain_queries() {
+        let server = DnsCryptServer::new("2.dnscrypt-cert.example.test", &SEED).unwrap();
+        let query = DNSMessage::query(1, "www.example.test", RecordType::A);
+        let response = server.answer_plain(&query.to_bytes(), true).unwrap();
+        let response = DNSMessage::parse(&response).unwrap();
+        assert_eq!(response.header.flags.rcode, ResponseCode::Refused as u8);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn keeps_old_keys_until_they_expire() {
+        let server = DnsCryptServer::new("2.dnscrypt-cert.example.test", &SEED).unwrap();
+        let start = server.keys.read().unwrap()[0].certificate.valid_from;
+        server.rotate(start + KEY_ROTATION - 1);
+        assert_eq!(server.keys.read().unwrap().len(), 2);
+        server.rotate(start + KEY_ROTATION);
+        assert_eq!(server.keys.read().unwrap().len(), 4);
+        server.rotate(start + KEY_LIFETIME);
+        let keys = server.keys.read().unwrap();
+        assert_eq!(keys.len(), 4);
+        assert!(keys.iter().all(|key| key.certificate.valid_from > start));
+    }
+}
@@ -0,0 +1,199 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{debug, error, info};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use super::proxy::ProxyProtocol;
+use super::tcp::IDLE_TIMEOUT;
+use super::tls::handshake;
+use super::{Transport, MAX_STREAM_MESSAGE};
+use crate::dns::message::DNSMessage;
+use crate::Resolvers;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const PATH: &str = "/dns-query";
+
+/// Accepts DNS over HTTPS connections (RFC 8484) on `listener` until the task
+/// is dropped, over HTTP/2 or HTTP/1.1 as the client prefers.
+pub async fn serve_https(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    resolvers: Arc<Resolvers>,
+    proxy: Option<Arc<ProxyProtocol>>,
+) {
+    let local = match listener.local_addr() {
+        Ok(local) => local,
+        Err(e) => {
+            error!("Error reading the local HTTPS address: {}", e);
+            return;
+        }
+    };
+    info!("Serving HTTPS on {}{}", local, PATH);
+    let mut config = (*config).clone();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let mut http = Http::new();
+    http.http1_header_read_timeout(IDLE_TIMEOUT);
+
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Error accepting HTTPS connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let resolvers = resolvers.clone();
+        let http = http.clone();
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            let client = match &proxy {
+                Some(proxy) => match proxy.stream_client(&mut stream, peer).await {
+                    Some(client) => client,
+                    None => return,
+                },
+                None => peer,
+            };
+            let Some(stream) = handshake(&acceptor, stream, client).await else {
+                return;
+            };
+            let service = service_fn(|request| {
+                let resolvers = resolvers.clone();
+                async move {
+                    Ok::<_, Infallible>(serve_request(request, client, local, &resolvers).await)
+                }
+            });
+            if let Err(e) = http.serve_connection(stream, service).await {
+                debug!("HTTPS connection from {} failed: {}", client, e);
+            }
+        });
+    }
+}
+
+async fn serve_request(
+    request: Request<Body>,
+    client: SocketAddr,
+    local: SocketAddr,
+    resolvers: &Resolvers,
+) -> Response<Body> {
+    if request.uri().path() != PATH {
+        return status(StatusCode::NOT_FOUND);
+    }
+    let query = match read_query(request).await {
+        Ok(query) => query,
+        Err(code) => return status(code),
+    };
+    let Some(response) = resolvers
+        .handle(&query, client, local, Transport::Https)
+        .await
+    else {
+        return status(StatusCode::SERVICE_UNAVAILABLE);
+    };
+
+    let mut reply = Response::new(Body::empty());
+    let headers = reply.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+    if let Some(max_age) = max_age(&response) {
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&format!("max-age={}", max_age)).expect("valid header"),
+        );
+    }
+    *reply.body_mut() = Body::from(response);
+    reply
+}
+
+// The wire format query from a GET `dns` parameter or a POST body.
+async fn read_query(request: Request<Body>) -> Result<Vec<u8>, StatusCode> {
+    match *request.method() {
+        Method::GET => {
+            if !accepts_dns_message(&request) {
+                return Err(StatusCode::NOT_ACCEPTABLE);
+            }
+            let encoded = request
+                .uri()
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("dns="))
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            URL_SAFE_NO_PAD
+                .decode(encoded.trim_end_matches('='))
+                .map_err(|_| StatusCode::BAD_REQUEST)
+        }
+        Method::POST => {
+            let media_type = request
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.split(';').next().unwrap_or("").trim());
+            if !media_type.is_some_and(|media| media.eq_ignore_ascii_case(DNS_MESSAGE)) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            let length = request
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+            if length.is_some_and(|length| length > MAX_STREAM_MESSAGE as u64) {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            read_body(request.into_body()).await
+        }
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+// Collects a POST body, giving up as soon as it outgrows a DNS message
+// rather than buffering whatever the client sends.
+async fn read_body(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    let mut query = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if query.len() + chunk.len() > MAX_STREAM_MESSAGE {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        query.extend_from_slice(&chunk);
+    }
+    Ok(query)
+}
+
+fn accepts_dns_message(request: &Request<Body>) -> bool {
+    request.headers().get_all(ACCEPT).iter().all(|accept| {
+        accept.to_str().is_ok_and(|accept| {
+            accept.split(',').any(|media| {
+                let media = media.split(';').next().unwrap_or("").trim();
+                matches!(media, DNS_MESSAGE | "application/*" | "*/*")
+            })
+        })
+    })
+}
+
+// How long the response may be cached: no longer than its shortest lived
+// record (RFC 8484 section 5.1), which for negative answers is the SOA.
+fn max_age(response: &[u8]) -> Option<u32> {
+    let response = DNSMessage::parse(response).ok()?;
+    response
+        .answers
+        .iter()
+        .chain(&response.authority_records)
+        .map(|record| record.ttl)
+        .min()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
@@ -0,0 +1,153 @@
+pub mod dnscrypt;
+pub mod https;
+pub mod proxy;
+pub mod quic;
+pub mod tcp;
+pub mod tls;
+pub mod udp;
+
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::dns::message::DNSMessage;
+use crate::forwarder::upstream::parse_socket_addr;
+
+// DNS over TLS has its own port (RFC 7858 section 3.1), which DNS over QUIC
+// shares over UDP (RFC 9250 section 4.1.1).
+const TLS_PORT: u16 = 853;
+const HTTPS_PORT: u16 = 443;
+
+// Largest message a two byte length prefix can frame.
+const MAX_STREAM_MESSAGE: usize = u16::MAX as usize;
+
+/// How a query reached us, which decides how large the response may be and
+/// whether it is subject to response rate limiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+    Quic,
+    DnsCrypt,
+}
+
+impl Transport {
+    pub fn max_response_size(self, query: &DNSMessage) -> usize {
+        match self {
+            Transport::Udp => query.max_response_size(),
+            Transport::Tcp
+            | Transport::Tls
+            | Transport::Https
+            | Transport::Quic
+            | Transport::DnsCrypt => MAX_STREAM_MESSAGE,
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "udp"),
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Tls => write!(f, "tls"),
+            Transport::Https => write!(f, "https"),
+            Transport::Quic => write!(f, "quic"),
+            Transport::DnsCrypt => write!(f, "dnscrypt"),
+        }
+    }
+}
+
+/// An address to serve on and the transports to serve it with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub address: SocketAddr,
+    pub transports: Vec<Transport>,
+    /// Set for the default IPv6 listeners, which hosts without IPv6 cannot
+    /// bind; failing to is then only worth a warning.
+    pub best_effort: bool,
+}
+
+impl Listener {
+    /// Reads LISTEN, a comma separated list of addresses, each optionally
+    /// followed by `/udp`, `/tcp`, `/tls`, `/https`, `/quic` or `/dnscrypt`
+    /// to serve only that transport, e.g. `0.0.0.0:53, [::1]:5353/udp,
+    /// 192.168.1.1/tls`. Addresses without a port use PORT (default 53), 853
+    /// for TLS and QUIC or 443 for HTTPS and DNSCrypt. Without LISTEN, UDP and TCP are served on every IPv4
+    /// and IPv6 address, and so are TLS and QUIC when `tls` says a
+    /// certificate is configured.
+    pub fn from_env(tls: bool) -> Result<Vec<Listener>, Box<dyn Error>> {
+        let port = match std::env::var("PORT") {
+            Ok(port) => port
+                .parse()
+                .map_err(|_| format!("Invalid value for PORT: {:?}", port))?,
+            Err(_) => 53,
+        };
+        let explicit = std::env::var("LISTEN").ok();
+        let list = explicit.clone().unwrap_or_else(|| match tls {
+            true => "0.0.0.0, [::], 0.0.0.0/tls, [::]/tls, 0.0.0.0/quic, [::]/quic".to_string(),
+            false => "0.0.0.0, [::]".to_string(),
+        });
+        let mut listeners = list
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| Listener::parse(entry, port))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid value for LISTEN: {}", e))?;
+        if listeners.is_empty() {
+            return Err("LISTEN names no addresses".into());
+        }
+        if explicit.is_none() {
+            for listener in &mut listeners {
+                listener.best_effort = listener.address.is_ipv6();
+            }
+        }
+        Ok(listeners)
+    }
+
+    fn parse(entry: &str, default_port: u16) -> Result<Listener, String> {
+        let (address, transports) = match entry.rsplit_once('/') {
+            Some((address, "udp")) => (address, vec![Transport::Udp]),
+            Some((address, "tcp")) => (address, vec![Transport::Tcp]),
+            Some((address, "tls")) => (address, vec![Transport::Tls]),
+            Some((address, "https")) => (address, vec![Transport::Https]),
+            Some((address, "quic")) => (address, vec![Transport::Quic]),
+            Some((address, "dnscrypt")) => (address, vec![Transport::DnsCrypt]),
+            Some((_, other)) => return Err(format!("unknown transport {:?}", other)),
+            None => (entry, vec![Transport::Udp, Transport::Tcp]),
+        };
+        let default_port = match transports[..] {
+            [Transport::Tls] | [Transport::Quic] => TLS_PORT,
+            [Transport::Https] | [Transport::DnsCrypt] => HTTPS_PORT,
+            _ => default_port,
+        };
+        Ok(Listener {
+            address: parse_socket_addr(address.trim(), default_port)?,
+            transports,
+            best_effort: false,
+        })
+    }
+}
+
+// Binds a socket of `kind` for `address`. IPv6 sockets only accept IPv6 so
+// that the same port can also be bound on IPv4 addresses.
+fn bind(address: SocketAddr, kind: Type) -> std::io::Result<Socket> {
+    let protocol = match kind {
+        Type::DGRAM => Protocol::UDP,
+        _ => Protocol::TCP,
+    };
+    let socket = Socket::new(Domain::for_address(address), kind, Some(protocol))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    Ok(socket)
+}
@@ -0,0 +1,410 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use log::warn;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+use super::tcp::IDLE_TIMEOUT;
+use crate::acl::AccessList;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// "PROXY UNKNOWN\r\n", the shortest v1 header.
+const V1_MIN_LENGTH: usize = 15;
+const V1_MAX_LENGTH: usize = 107;
+const V2_HEADER_LENGTH: usize = 16;
+
+/// Why a PROXY protocol header was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyError {
+    Missing,
+    Malformed,
+    UnsupportedVersion(u8),
+    Truncated,
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Missing => write!(f, "no PROXY protocol header"),
+            ProxyError::Malformed => write!(f, "malformed PROXY protocol header"),
+            ProxyError::UnsupportedVersion(version) => {
+                write!(f, "unsupported PROXY protocol version {}", version)
+            }
+            ProxyError::Truncated => write!(f, "truncated PROXY protocol header"),
+        }
+    }
+}
+
+impl Error for ProxyError {}
+
+/// The peers, typically load balancers, that introduce every connection
+/// and datagram with a PROXY protocol header naming the real client.
+pub struct ProxyProtocol {
+    trusted: AccessList,
+}
+
+impl ProxyProtocol {
+    /// Reads PROXY_PROTOCOL_FROM, the networks whose headers are trusted.
+    /// Without it no headers are expected.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let Ok(list) = std::env::var("PROXY_PROTOCOL_FROM") else {
+            return Ok(None);
+        };
+        let trusted = AccessList::parse(&list)
+            .map_err(|e| format!("Invalid value for PROXY_PROTOCOL_FROM: {}", e))?;
+        Ok(Some(ProxyProtocol { trusted }))
+    }
+
+    /// The client a stream connection is for: the one named in its header
+    /// when `peer` is trusted, otherwise the peer itself. `None` means the
+    /// connection should be closed.
+    pub async fn stream_client<S>(&self, stream: &mut S, peer: SocketAddr) -> Option<SocketAddr>
+    where
+        S: AsyncRead + Unpin,
+    {
+        if !self.trusted.allows(peer.ip()) {
+            return Some(peer);
+        }
+        match timeout(IDLE_TIMEOUT, read_header(stream)).await {
+            Ok(Ok(client)) => Some(client.unwrap_or(peer)),
+            Ok(Err(e)) => {
+                warn!("Closing connection from {}: {}", peer, e);
+                None
+            }
+            Err(_) => {
+                warn!("Closing connection from {}: {}", peer, ProxyError::Missing);
+                None
+            }
+        }
+    }
+
+    /// The client a datagram is for and the query it carries. Only version
+    /// 2 headers are accepted on datagrams.
+    pub fn datagram_client<'a>(
+        &self,
+        datagram: &'a [u8],
+        peer: SocketAddr,
+    ) -> Result<(SocketAddr, &'a [u8]), ProxyError> {
+        if !self.trusted.allows(peer.ip()) {
+            return Ok((peer, datagram));
+        }
+        if datagram.len() < V2_HEADER_LENGTH || &datagram[..12] != V2_SIGNATURE {
+            return Err(ProxyError::Missing);
+        }
+        let length = V2_HEADER_LENGTH + u16::from_be_bytes([datagram[14], datagram[15]]) as usize;
+        let addresses = datagram
+            .get(V2_HEADER_LENGTH..length)
+            .ok_or(ProxyError::Truncated)?;
+        let client = parse_v2(datagram[12], datagram[13], addresses)?;
+        Ok((client.unwrap_or(peer), &datagram[length..]))
+    }
+}
+
+// Reads a version 1 or 2 header without reading past it. `None` means the
+// header names no client, as for health checks.
+async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = vec![0u8; V1_MIN_LENGTH];
+    read(stream, &mut header).await?;
+    if header.starts_with(V2_SIGNATURE) {
+        header.resize(V2_HEADER_LENGTH, 0);
+        read(stream, &mut header[V1_MIN_LENGTH..]).await?;
+        let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut addresses = vec![0u8; length];
+        read(stream, &mut addresses).await?;
+        return parse_v2(header[12], header[13], &addresses);
+    }
+    if !header.starts_with(b"PROXY ") {
+        return Err(ProxyError::Missing);
+    }
+    while !header.ends_with(b"\r\n") {
+        if header.len() == V1_MAX_LENGTH {
+            return Err(ProxyError::Malformed);
+        }
+        let mut byte = [0u8];
+        read(stream, &mut byte).await?;
+        header.push(byte[0]);
+    }
+    let line =
+        std::str::from_utf8(&header[..header.len() - 2]).map_err(|_| ProxyError::Malformed)?;
+    parse_v1(line)
+}
+
+async fn read<S>(stream: &mut S, buf: &mut [u8]) -> Result<(), ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    stream
+        .read_exact(buf)
+        .await
+        .map(|_| ())
+        .map_err(|_| ProxyError::Truncated)
+}
+
+// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 53`, or `PROXY UNKNOWN ...`.
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, ProxyError> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| ProxyError::Malformed)?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(ProxyError::Malformed);
+            }
+            let port = port.parse().map_err(|_| ProxyError::Malformed)?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(ProxyError::Malformed),
+    }
+}
+
+// The source address of a version 2 header. LOCAL commands and unspecified
+// families name no client; TLVs after the addresses are ignored.
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> Result<Option<SocketAddr>, ProxyError> {
+    if version_command >> 4 != 2 {
+        return Err(ProxyError::UnsupportedVersion(version_command >> 4));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(ProxyError::Malformed),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family >> 4 {
+        0 => Ok(None),
+        1 => {
+            if addresses.len() < 12 {
+                return Err(ProxyError::Truncated);
+            }
+            let ip: [u8; 4] = addresses[0..4].try_into().expect("four bytes");
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        2 => {
+            if addresses.len() < 36 {
+                return Err(ProxyError::Truncated);
+            }
+            let ip: [u8; 16] = addresses[0..16].try_into().expect("sixteen bytes");
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        // Unix sockets say nothing useful about the client.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &[u8] = b"\x12\x34query";
+
+    // A version 2 header for `command` (0 LOCAL, 1 PROXY) with the given
+    // family byte and address block.
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn v4_addresses() -> Vec<u8> {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&53u16.to_be_bytes());
+        addresses
+    }
+
+    fn v6_addresses() -> Vec<u8> {
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&53u16.to_be_bytes());
+        addresses
+    }
+
+    fn client(text: &str) -> Option<SocketAddr> {
+        Some(text.parse().unwrap())
+    }
+
+    // Reads a header off `input` and returns it with whatever is left.
+    async fn read_from(input: &[u8]) -> (Result<Option<SocketAddr>, ProxyError>, Vec<u8>) {
+        let mut stream = input;
+        let result = read_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    #[test]
+    fn parses_v1_lines() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 53"),
+            Ok(client("192.0.2.1:56324"))
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::1 ::1 56324 53"),
+            Ok(client("[2001:db8::1]:56324"))
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN"), Ok(None));
+        assert_eq!(
+            parse_v1("PROXY UNKNOWN 192.0.2.1 198.51.100.1 56324 53"),
+            Ok(None)
+        );
+
+        for line in [
+            "PROXY TCP4 2001:db8::1 ::1 56324 53",
+            "PROXY TCP6 192.0.2.1 198.51.100.1 56324 53",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 65536 53",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 56324",
+            "PROXY UDP4 192.0.2.1 198.51.100.1 56324 53",
+            "PROXY  TCP4 192.0.2.1 198.51.100.1 56324 53",
+            "PROXY",
+        ] {
+            assert_eq!(parse_v1(line), Err(ProxyError::Malformed), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parses_v2_addresses() {
+        assert_eq!(
+            parse_v2(0x21, 0x11, &v4_addresses()),
+            Ok(client("192.0.2.1:56324"))
+        );
+        assert_eq!(
+            parse_v2(0x21, 0x22, &v6_addresses()),
+            Ok(client("[2001:db8::1]:56324"))
+        );
+        // TLVs after the addresses are skipped.
+        let mut with_tlv = v4_addresses();
+        with_tlv.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(
+            parse_v2(0x21, 0x11, &with_tlv),
+            Ok(client("192.0.2.1:56324"))
+        );
+
+        // LOCAL, UNSPEC and Unix sockets name no client.
+        assert_eq!(parse_v2(0x20, 0x11, &v4_addresses()), Ok(None));
+        assert_eq!(parse_v2(0x21, 0x00, &[]), Ok(None));
+        assert_eq!(parse_v2(0x21, 0x31, &[0; 216]), Ok(None));
+
+        assert_eq!(
+            parse_v2(0x11, 0x11, &v4_addresses()),
+            Err(ProxyError::UnsupportedVersion(1))
+        );
+        assert_eq!(
+            parse_v2(0x22, 0x11, &v4_addresses()),
+            Err(ProxyError::Malformed)
+        );
+        assert_eq!(
+            parse_v2(0x21, 0x11, &v4_addresses()[..11]),
+            Err(ProxyError::Truncated)
+        );
+        assert_eq!(
+            parse_v2(0x21, 0x21, &v4_addresses()),
+            Err(ProxyError::Truncated)
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_stream_headers_and_nothing_more() {
+        let mut input = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 53\r\n".to_vec();
+        input.extend_from_slice(QUERY);
+        assert_eq!(
+            read_from(&input).await,
+            (Ok(client("192.0.2.1:56324")), QUERY.to_vec())
+        );
+
+        let mut input = b"PROXY UNKNOWN\r\n".to_vec();
+        input.extend_from_slice(QUERY);
+        assert_eq!(read_from(&input).await, (Ok(None), QUERY.to_vec()));
+
+        let mut input = v2(1, 0x21, &v6_addresses());
+        input.extend_from_slice(QUERY);
+        assert_eq!(
+            read_from(&input).await,
+            (Ok(client("[2001:db8::1]:56324")), QUERY.to_vec())
+        );
+
+        let mut input = v2(0, 0x00, &[]);
+        input.extend_from_slice(QUERY);
+        assert_eq!(read_from(&input).await, (Ok(None), QUERY.to_vec()));
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_and_missing_stream_headers() {
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 53\r\n";
+        assert_eq!(read_from(&v1[..10]).await.0, Err(ProxyError::Truncated));
+        assert_eq!(
+            read_from(&v1[..v1.len() - 1]).await.0,
+            Err(ProxyError::Truncated)
+        );
+        let v2 = v2(1, 0x11, &v4_addresses());
+        for length in [14, V2_HEADER_LENGTH, v2.len() - 1] {
+            assert_eq!(
+                read_from(&v2[..length]).await.0,
+                Err(ProxyError::Truncated),
+                "{} bytes",
+                length
+            );
+        }
+
+        assert_eq!(
+            read_from(b"\x12\x34 not a proxy header").await.0,
+            Err(ProxyError::Missing)
+        );
+        let endless = format!("PROXY {}", "x".repeat(200));
+        assert_eq!(
+            read_from(endless.as_bytes()).await.0,
+            Err(ProxyError::Malformed)
+        );
+    }
+
+    #[test]
+    fn reads_datagram_headers_from_trusted_peers() {
+        let proxy = ProxyProtocol {
+            trusted: AccessList::parse("10.0.0.0/8").unwrap(),
+        };
+        let balancer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let stranger: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+
+        let mut datagram = v2(1, 0x12, &v4_addresses());
+        datagram.extend_from_slice(QUERY);
+        assert_eq!(
+            proxy.datagram_client(&datagram, balancer),
+            Ok((client("192.0.2.1:56324").unwrap(), QUERY))
+        );
+        // Untrusted peers are taken at their word, header and all.
+        assert_eq!(
+            proxy.datagram_client(&datagram, stranger),
+            Ok((stranger, datagram.as_slice()))
+        );
+
+        let mut local = v2(0, 0x00, &[]);
+        local.extend_from_slice(QUERY);
+        assert_eq!(
+            proxy.datagram_client(&local, balancer),
+            Ok((balancer, QUERY))
+        );
+
+        assert_eq!(
+            proxy.datagram_client(QUERY, balancer),
+            Err(ProxyError::Missing)
+        );
+        let v1 = b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 53\r\n";
+        assert_eq!(
+            proxy.datagram_client(v1, balancer),
+            Err(ProxyError::Missing)
+        );
+        assert_eq!(
+            proxy.datagram_client(&datagram[..20], balancer),
+            Err(ProxyError::Truncated)
+        );
+    }
+}
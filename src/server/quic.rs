@@ -0,0 +1,189 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use quinn::{Connection, Endpoint, EndpointConfig, RecvStream, SendStream, TokioRuntime, VarInt};
+use socket2::Type;
+use tokio_rustls::rustls::ServerConfig;
+
+use super::{bind, Transport, MAX_STREAM_MESSAGE};
+use crate::Resolvers;
+
+// Application error codes (RFC 9250 section 4.3).
+pub const DOQ_NO_ERROR: VarInt = VarInt::from_u32(0x0);
+pub const DOQ_PROTOCOL_ERROR: VarInt = VarInt::from_u32(0x2);
+pub const DOQ_REQUEST_CANCELLED: VarInt = VarInt::from_u32(0x3);
+
+/// Opens a DNS over QUIC endpoint (RFC 9250) on `address`, using the
+/// certificate of the TLS listeners with the "doq" ALPN token.
+pub fn bind_quic(address: SocketAddr, tls: &ServerConfig) -> std::io::Result<Endpoint> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![b"doq".to_vec()];
+    let config = quinn::ServerConfig::with_crypto(Arc::new(tls));
+    let socket = bind(address, Type::DGRAM)?;
+    Endpoint::new(
+        EndpointConfig::default(),
+        Some(config),
+        socket.into(),
+        Arc::new(TokioRuntime),
+    )
+}
+
+/// Accepts DNS over QUIC connections on `endpoint` until the task is
+/// dropped. Each query arrives on a stream of its own, answered on the same
+/// stream.
+pub async fn serve_quic(endpoint: Endpoint, resolvers: Arc<Resolvers>) {
+    let local = match endpoint.local_addr() {
+        Ok(local) => local,
+        Err(e) => {
+            error!("Error reading the local QUIC address: {}", e);
+            return;
+        }
+    };
+    info!("Serving QUIC on {}", local);
+    while let Some(connecting) = endpoint.accept().await {
+        let resolvers = resolvers.clone();
+        tokio::spawn(async move {
+            let client = connecting.remote_address();
+            match connecting.await {
+                Ok(connection) => serve_connection(connection, local, resolvers).await,
+                Err(e) => debug!("QUIC handshake with {} failed: {}", client, e),
+            }
+        });
+    }
+}
+
+async fn serve_connection(connection: Connection, local: SocketAddr, resolvers: Arc<Resolvers>) {
+    let client = connection.remote_address();
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                debug!("QUIC connection from {} closed: {}", client, e);
+                return;
+            }
+        };
+        let connection = connection.clone();
+        let resolvers = resolvers.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_query(send, recv, client, local, &resolvers).await {
+                warn!("QUIC query from {} failed: {}", client, e);
+                connection.close(DOQ_PROTOCOL_ERROR, e.as_bytes());
+            }
+        });
+    }
+}
+
+// Answers the one query a stream carries. Errors are protocol violations
+// that end the whole connection (RFC 9250 section 4.2).
+async fn serve_query(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    client: SocketAddr,
+    local: SocketAddr,
+    resolvers: &Resolvers,
+) -> Result<(), &'static str> {
+    let message = match recv.read_to_end(MAX_STREAM_MESSAGE + 2).await {
+        Ok(message) => message,
+        Err(e) => {
+            debug!("Reading query from {} failed: {}", client, e);
+            return Ok(());
+        }
+    };
+    let query = match message.split_first_chunk::<2>() {
+        Some((length, query)) if u16::from_be_bytes(*length) as usize == query.len() => query,
+        _ => return Err("Malformed query length"),
+    };
+    // Stream IDs identify queries, so message IDs must be zero.
+    if query.get(0..2).is_some_and(|id| id != [0, 0]) {
+        return Err("Query ID is not zero");
+    }
+    let Some(response) = resolvers
+        .handle(query, client, local, Transport::Quic)
+        .await
+    else {
+        let _ = send.reset(DOQ_REQUEST_CANCELLED);
+        return Ok(());
+    };
+    let Ok(length) = u16::try_from(response.len()) else {
+        error!(
+            "Response to {} is too large for {}",
+            client,
+            Transport::Quic
+        );
+        let _ = send.reset(DOQ_REQUEST_CANCELLED);
+        return Ok(());
+    };
+    let mut framed = length.to_be_bytes().to_vec();
+    framed.extend_from_slice(&response);
+    if let Err(e) = send.write_all(&framed).await {
+        debug!("Sending response to {} failed: {}", client, e);
+        return Ok(());
+    }
+    let _ = send.finish().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use quinn::{ClientConfig, ConnectionError};
+
+    use super::*;
+    use crate::dns::message::DNSMessage;
+    use crate::dns::resource_record::RecordType;
+    use crate::server::tls::load_tls_config;
+    use crate::server::tls::tests::{client_config, resolvers, CERT, KEY};
+
+    // A DoQ listener on loopback with the self-signed test certificate, and
+    // a connection to it.
+    async fn connect() -> Connection {
+        let config = load_tls_config(CERT, KEY).unwrap();
+        let endpoint = bind_quic("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let address = endpoint.local_addr().unwrap();
+        tokio::spawn(serve_quic(endpoint, resolvers()));
+
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(ClientConfig::new(Arc::new(client_config(b"doq"))));
+        client.connect(address, "localhost").unwrap().await.unwrap()
+    }
+
+    async fn ask(connection: &Connection, id: u16, name: &str) -> Result<Vec<u8>, String> {
+        let query = DNSMessage::query(id, name, RecordType::A).to_bytes();
+        let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+        framed.extend(query);
+        let (mut send, mut recv) = connection.open_bi().await.map_err(|e| e.to_string())?;
+        send.write_all(&framed).await.map_err(|e| e.to_string())?;
+        send.finish().await.map_err(|e| e.to_string())?;
+        recv.read_to_end(MAX_STREAM_MESSAGE + 2)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    #[tokio::test]
+    async fn answers_each_query_on_its_own_stream() {
+        let connection = connect().await;
+        let (www, nx) = tokio::join!(
+            ask(&connection, 0, "www.example.test"),
+            ask(&connection, 0, "nx.example.test"),
+        );
+        let www = www.unwrap();
+        assert_eq!(u16::from_be_bytes([www[0], www[1]]) as usize, www.len() - 2);
+        let www = DNSMessage::parse(&www[2..]).unwrap();
+        assert_eq!(www.header.transaction_id, 0);
+        assert_eq!(www.answers[0].data, [192, 0, 2, 1]);
+        let nx = DNSMessage::parse(&nx.unwrap()[2..]).unwrap();
+        assert!(nx.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn closes_connections_sending_message_ids() {
+        let connection = connect().await;
+        assert!(ask(&connection, 7, "www.example.test").await.is_err());
+        match connection.closed().await {
+            ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.error_code, DOQ_PROTOCOL_ERROR)
+            }
+            e => panic!("unexpected close: {}", e),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use socket2::Type;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use super::proxy::ProxyProtocol;
+use super::{bind, Transport};
+use crate::Resolvers;
+
+// How long a connection may sit idle between queries (RFC 7766 section 6.2.3).
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const BACKLOG: i32 = 1024;
+
+pub async fn bind_tcp(address: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = bind(address, Type::STREAM)?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Accepts connections on `listener` until the task is dropped.
+pub async fn serve_tcp(
+    listener: TcpListener,
+    resolvers: Arc<Resolvers>,
+    proxy: Option<Arc<ProxyProtocol>>,
+) {
+    match listener.local_addr() {
+        Ok(local) => info!("Serving TCP on {}", local),
+        Err(e) => error!("Error reading the local TCP address: {}", e),
+    }
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Error accepting TCP connection: {}", e);
+                continue;
+            }
+        };
+        let resolvers = resolvers.clone();
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            let client = match &proxy {
+                Some(proxy) => match proxy.stream_client(&mut stream, peer).await {
+                    Some(client) => client,
+                    None => return,
+                },
+                None => peer,
+            };
+            if let Err(e) = serve_tcp_connection(stream, client, &resolvers).await {
+                warn!("TCP connection from {} failed: {}", client, e);
+            }
+        });
+    }
+}
+
+async fn serve_tcp_connection(
+    stream: TcpStream,
+    client: SocketAddr,
+    resolvers: &Resolvers,
+) -> std::io::Result<()> {
+    let local = stream.local_addr()?;
+    serve_stream(stream, client, local, Transport::Tcp, resolvers).await
+}
+
+/// Answers length-prefixed queries in turn until the client closes the
+/// connection or leaves it idle. TLS connections are framed the same way.
+pub async fn serve_stream<S>(
+    mut stream: S,
+    client: SocketAddr,
+    local: SocketAddr,
+    transport: Transport,
+    resolvers: &Resolvers,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let mut length = [0u8; 2];
+        match timeout(IDLE_TIMEOUT, stream.read_exact(&mut length)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Ok(()),
+        }
+        let mut query = vec![0u8; u16::from_be_bytes(length) as usize];
+        timeout(IDLE_TIMEOUT, stream.read_exact(&mut query))
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+        let Some(response) = resolvers.handle(&query, client, local, transport).await else {
+            continue;
+        };
+        let Ok(length) = u16::try_from(response.len()) else {
+            error!("Response to {} is too large for {}", client, transport);
+            continue;
+        };
+        let mut framed = length.to_be_bytes().to_vec();
+        framed.extend_from_slice(&response);
+        stream.write_all(&framed).await?;
+        stream.flush().await?;
+    }
+}
@@ -0,0 +1,255 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use rustls_pemfile::Item;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig, Ticketer};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use super::proxy::ProxyProtocol;
+use super::tcp::serve_stream;
+use super::Transport;
+use crate::Resolvers;
+
+// How long a client may take to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads TLS_CERT_FILE and TLS_KEY_FILE, PEM files holding the certificate
+/// chain and its private key. Returns `None` when neither is set.
+pub fn tls_config_from_env() -> Result<Option<Arc<ServerConfig>>, Box<dyn Error>> {
+    let (cert_path, key_path) = match (
+        std::env::var("TLS_CERT_FILE"),
+        std::env::var("TLS_KEY_FILE"),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => (cert_path, key_path),
+        (Err(_), Err(_)) => return Ok(None),
+        _ => return Err("TLS_CERT_FILE and TLS_KEY_FILE must be set together".into()),
+    };
+    load_tls_config(&cert_path, &key_path).map(Some)
+}
+
+/// The server settings for the certificate chain and key in the PEM files
+/// given.
+pub fn load_tls_config(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+    let certs = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", cert_path).into());
+    }
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("{}: no private key found", key_path))?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("{}: {}", cert_path, e))?;
+    // Resume sessions from tickets as well as from the server's session
+    // cache, so reconnecting clients skip the full handshake.
+    config.ticketer = Ticketer::new()?;
+    config.alpn_protocols = vec![b"dot".to_vec()];
+    info!("Loaded TLS certificate from {}", cert_path);
+    Ok(Arc::new(config))
+}
+
+fn read_pem(path: &str) -> Result<Vec<Item>, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path, e).into())
+}
+
+/// Accepts DNS over TLS connections (RFC 7858) on `listener` until the task
+/// is dropped.
+pub async fn serve_tls(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    resolvers: Arc<Resolvers>,
+    proxy: Option<Arc<ProxyProtocol>>,
+) {
+    let local = match listener.local_addr() {
+        Ok(local) => local,
+        Err(e) => {
+            error!("Error reading the local TLS address: {}", e);
+            return;
+        }
+    };
+    info!("Serving TLS on {}", local);
+    let acceptor = TlsAcceptor::from(config);
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Error accepting TLS connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let resolvers = resolvers.clone();
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            let client = match &proxy {
+                Some(proxy) => match proxy.stream_client(&mut stream, peer).await {
+                    Some(client) => client,
+                    None => return,
+                },
+                None => peer,
+            };
+            let Some(stream) = handshake(&acceptor, stream, client).await else {
+                return;
+            };
+            if let Err(e) = serve_stream(stream, client, local, Transport::Tls, &resolvers).await {
+                warn!("TLS connection from {} failed: {}", client, e);
+            }
+        });
+    }
+}
+
+/// Completes the server side of a TLS handshake, giving up on clients that
+/// fail it or take too long.
+pub async fn handshake(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    client: SocketAddr,
+) -> Option<TlsStream<TcpStream>> {
+    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Some(stream),
+        Ok(Err(e)) => {
+            debug!("TLS handshake with {} failed: {}", client, e);
+            None
+        }
+        Err(_) => {
+            debug!("TLS handshake with {} timed out", client);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+    use crate::dns::message::DNSMessage;
+    use crate::dns::resource_record::RecordType;
+    use crate::zone::catalog::Catalog;
+    use crate::zone::parser::parse_zone_str;
+    use crate::zone::Zone;
+
+    pub(crate) const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.crt");
+    pub(crate) const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.key");
+
+    const ZONE: &str = "\
+@ 3600 IN SOA ns.example.test. admin.example.test. 1 3600 600 86400 300
+@ 3600 IN NS ns.example.test.
+www 300 IN A 192.0.2.1
+";
+
+    // Answers any client from example.test.
+    pub(crate) fn resolvers() -> Arc<Resolvers> {
+        let mut catalog = Catalog::default();
+        let records = parse_zone_str(ZONE, "example.test").unwrap();
+        catalog.add(Zone::new("example.test", records));
+        Arc::new(Resolvers::serving(catalog))
+    }
+
+    // Client settings trusting only the self-signed test certificate, which
+    // is issued to localhost and 127.0.0.1.
+    pub(crate) fn client_config(alpn: &[u8]) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        for item in read_pem(CERT).unwrap() {
+            if let Item::X509Certificate(der) = item {
+                roots.add(&Certificate(der)).unwrap();
+            }
+        }
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        config
+    }
+
+    // A DoT listener on loopback with the test certificate.
+    async fn listener() -> SocketAddr {
+        let config = load_tls_config(CERT, KEY).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_tls(listener, config, resolvers(), None));
+        address
+    }
+
+    async fn connect(address: SocketAddr) -> tokio_rustls::client::TlsStream<TcpStream> {
+        let stream = TcpStream::connect(address).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let stream = TlsConnector::from(Arc::new(client_config(b"dot")))
+            .connect(name, stream)
+            .await
+            .unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"dot"[..]));
+        stream
+    }
+
+    fn framed(id: u16, name: &str) -> Vec<u8> {
+        let query = DNSMessage::query(id, name, RecordType::A).to_bytes();
+        let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+        framed.extend(query);
+        framed
+    }
+
+    async fn read_message<S: AsyncReadExt + Unpin>(stream: &mut S) -> DNSMessage {
+        let mut length = [0u8; 2];
+        stream.read_exact(&mut length).await.unwrap();
+        let mut message = vec![0u8; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut message).await.unwrap();
+        DNSMessage::parse(&message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_over_tls() {
+        let mut stream = connect(listener().await).await;
+        stream
+            .write_all(&framed(1, "www.example.test"))
+            .await
+            .unwrap();
+        let response = read_message(&mut stream).await;
+        assert_eq!(response.header.transaction_id, 1);
+        assert!(response.header.flags.aa);
+        assert_eq!(response.answers[0].data, [192, 0, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn answers_pipelined_queries_on_one_connection() {
+        let mut stream = connect(listener().await).await;
+        let mut queries = framed(1, "www.example.test");
+        queries.extend(framed(2, "nx.example.test"));
+        stream.write_all(&queries).await.unwrap();
+        let mut ids = vec![
+            read_message(&mut stream).await.header.transaction_id,
+            read_message(&mut stream).await.header.transaction_id,
+        ];
+        ids.sort();
+        assert_eq!(ids, [1, 2]);
+    }
+}
@@ -0,0 +1,71 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use socket2::Type;
+use tokio::net::UdpSocket;
+
+use super::proxy::ProxyProtocol;
+use super::{bind, Transport};
+use crate::Resolvers;
+
+// Room for EDNS queries and their TSIG records.
+const RECEIVE_BUFFER: usize = 4096;
+
+pub async fn bind_udp(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    UdpSocket::from_std(bind(address, Type::DGRAM)?.into())
+}
+
+/// Answers datagrams on `socket` until the task is dropped. Responses to
+/// proxied queries go back to the proxy.
+pub async fn serve_udp(
+    socket: UdpSocket,
+    resolvers: Arc<Resolvers>,
+    proxy: Option<Arc<ProxyProtocol>>,
+) {
+    let socket = Arc::new(socket);
+    let local = match socket.local_addr() {
+        Ok(local) => local,
+        Err(e) => {
+            error!("Error reading the local UDP address: {}", e);
+            return;
+        }
+    };
+    info!("Serving UDP on {}", local);
+
+    let mut buf = [0u8; RECEIVE_BUFFER];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Error receiving from socket: {}", e);
+                continue;
+            }
+        };
+        // Each query is handled on its own task so a slow upstream only
+        // holds up the client waiting for it.
+        let (client, query) = match &proxy {
+            Some(proxy) => match proxy.datagram_client(&buf[..len], peer) {
+                Ok((client, query)) => (client, query.to_vec()),
+                Err(e) => {
+                    warn!("Dropping datagram from {}: {}", peer, e);
+                    continue;
+                }
+            },
+            None => (peer, buf[..len].to_vec()),
+        };
+        let socket = socket.clone();
+        let resolvers = resolvers.clone();
+        tokio::spawn(async move {
+            let Some(response) = resolvers
+                .handle(&query, client, local, Transport::Udp)
+                .await
+            else {
+                return;
+            };
+            if let Err(e) = socket.send_to(&response, peer).await {
+                error!("Error sending response to {}: {}", peer, e);
+            }
+        });
+    }
+}
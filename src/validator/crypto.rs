@@ -0,0 +1,197 @@
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+
+use crate::dns::dnssec::{Dnskey, Rrsig};
+use crate::dns::name::{encode_name, labels};
+use crate::dns::resource_record::ResourceRecord;
+
+// DNSSEC algorithm numbers (RFC 8624 section 3.1) we can verify.
+const RSASHA256: u8 = 8;
+const ECDSAP256SHA256: u8 = 13;
+const ECDSAP384SHA384: u8 = 14;
+const ED25519: u8 = 15;
+
+/// Whether signatures of `algorithm` can be checked. Zones signed only
+/// with other algorithms are treated as unsigned (RFC 4035 section 5.2).
+pub fn is_supported(algorithm: u8) -> bool {
+    matches!(
+        algorithm,
+        RSASHA256 | ECDSAP256SHA256 | ECDSAP384SHA384 | ED25519
+    )
+}
+
+/// Checks `sig` over the RRset `records` with `key`. The records must all
+/// share owner name, class and type.
+pub fn verify_rrset(records: &[ResourceRecord], sig: &Rrsig, key: &Dnskey) -> bool {
+    let Some(first) = records.first() else {
+        return false;
+    };
+    if key.algorithm != sig.algorithm || key.key_tag != sig.key_tag {
+        return false;
+    }
+
+    // Wildcard expansions are signed under the wildcard's name (RFC 4035
+    // section 5.3.2).
+    let owner_labels: Vec<&str> = labels(&first.name).collect();
+    let owner = match owner_labels.len().checked_sub(sig.labels as usize) {
+        Some(0) => first.name.to_ascii_lowercase(),
+        Some(extra) => format!("*.{}", owner_labels[extra..].join(".")).to_ascii_lowercase(),
+        None => return false,
+    };
+    let mut owner_wire = Vec::new();
+    encode_name(&owner, &mut owner_wire);
+
+    let mut rdatas: Vec<Vec<u8>> = records.iter().map(ResourceRecord::canonical_data).collect();
+    rdatas.sort();
+    rdatas.dedup();
+
+    let mut message = sig.signed_fields();
+    for rdata in &rdatas {
+        message.extend_from_slice(&owner_wire);
+        message.extend_from_slice(&first.record_type.to_u16().to_be_bytes());
+        message.extend_from_slice(&first.class.to_u16().to_be_bytes());
+        message.extend_from_slice(&sig.original_ttl.to_be_bytes());
+        message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        message.extend_from_slice(rdata);
+    }
+    verify(key.algorithm, &key.public_key, &message, &sig.signature)
+}
+
+fn verify(algorithm: u8, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    match algorithm {
+        RSASHA256 => {
+            // RFC 3110 section 2: the exponent's length comes first, in one
+            // byte or, when that is zero, in the next two.
+            let (length, rest) = match public_key {
+                [0, high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]) as usize, rest),
+                [length, rest @ ..] => (*length as usize, rest),
+                [] => return false,
+            };
+            if length == 0 || rest.len() <= length {
+                return false;
+            }
+            let (e, n) = rest.split_at(length);
+            RsaPublicKeyComponents { n, e }
+                .verify(
+                    &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    message,
+                    signature,
+                )
+                .is_ok()
+        }
+        ECDSAP256SHA256 | ECDSAP384SHA384 => {
+            // RFC 6605 keys are the bare point; ring wants it uncompressed.
+            let algorithm = match algorithm {
+                ECDSAP256SHA256 => &signature::ECDSA_P256_SHA256_FIXED,
+                _ => &signature::ECDSA_P384_SHA384_FIXED,
+            };
+            let mut point = vec![4];
+            point.extend_from_slice(public_key);
+            UnparsedPublicKey::new(algorithm, point)
+                .verify(message, signature)
+                .is_ok()
+        }
+        ED25519 => UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message, signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+
+    use super::*;
+    use crate::dns::resource_record::{RecordClass, RecordType};
+
+    fn dnskey(algorithm: u8, public_key: &str) -> Dnskey {
+        let mut data = vec![1, 1, 3, algorithm];
+        data.extend(BASE64.decode(public_key).unwrap());
+        Dnskey::parse(&data).unwrap()
+    }
+
+    fn rrsig(
+        type_covered: RecordType,
+        algorithm: u8,
+        labels: u8,
+        times: (u32, u32),
+        key_tag: u16,
+        signer: &str,
+        signature: &str,
+    ) -> Rrsig {
+        let mut data = type_covered.to_u16().to_be_bytes().to_vec();
+        data.extend([algorithm, labels]);
+        data.extend(3600u32.to_be_bytes());
+        data.extend(times.0.to_be_bytes());
+        data.extend(times.1.to_be_bytes());
+        data.extend(key_tag.to_be_bytes());
+        encode_name(signer, &mut data);
+        data.extend(BASE64.decode(signature).unwrap());
+        Rrsig::parse(&data).unwrap()
+    }
+
+    fn record(name: &str, record_type: RecordType, data: Vec<u8>) -> ResourceRecord {
+        ResourceRecord::new(name.to_string(), record_type, RecordClass::IN, 3600, data)
+    }
+
+    // RFC 6605 section 6.1.
+    #[test]
+    fn verifies_ecdsa_p256_example() {
+        let key = dnskey(
+            ECDSAP256SHA256,
+            "GojIhhXUN/u4v54ZQqGSnyhWJwaubCvTmeexv7bR6edbkrSqQpF64cYbcB7wNcP+e+MAnLr+Wi9xMWyQLc8NAA==",
+        );
+        assert_eq!(key.key_tag, 55648);
+        let sig = rrsig(
+            RecordType::A,
+            ECDSAP256SHA256,
+            3,
+            (1284026679, 1281607479),
+            55648,
+            "example.net",
+            "qx6wLYqmh+l9oCKTN6qIc+bw6ya+KJ8oMz0YP107epXAyGmt+3SNruPFKG7tZoLBLlUzGGus7ZwmwWep666VCw==",
+        );
+        let records = [record("www.example.net", RecordType::A, vec![192, 0, 2, 1])];
+        assert!(verify_rrset(&records, &sig, &key));
+
+        let forged = [record("www.example.net", RecordType::A, vec![192, 0, 2, 2])];
+        assert!(!verify_rrset(&forged, &sig, &key));
+    }
+
+    // RFC 8080 section 6.1.
+    #[test]
+    fn verifies_ed25519_example() {
+        let key = dnskey(ED25519, "l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=");
+        assert_eq!(key.key_tag, 3613);
+        let sig = rrsig(
+            RecordType::MX,
+            ED25519,
+            2,
+            (1440021600, 1438207200),
+            3613,
+            "example.com",
+            "oL9krJun7xfBOIWcGHi7mag5/hdZrKWw15jPGrHpjQeRAvTdszaPD+QLs3fx8A4M3e23mRZ9VrbpMngwcrqNAg==",
+        );
+        let mut mx = vec![0, 10];
+        encode_name("mail.example.com", &mut mx);
+        let records = [record("example.com", RecordType::MX, mx)];
+        assert!(verify_rrset(&records, &sig, &key));
+    }
+
+    #[test]
+    fn rejects_signature_by_another_key() {
+        let key = dnskey(ED25519, "l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=");
+        let sig = rrsig(
+            RecordType::A,
+            ECDSAP256SHA256,
+            3,
+            (1284026679, 1281607479),
+            55648,
+            "example.net",
+            "qx6wLYqmh+l9oCKTN6qIc+bw6ya+KJ8oMz0YP107epXAyGmt+3SNruPFKG7tZoLBLlUzGGus7ZwmwWep666VCw==",
+        );
+        let records = [record("www.example.net", RecordType::A, vec![192, 0, 2, 1])];
+        assert!(!verify_rrset(&records, &sig, &key));
+    }
+}
@@ -0,0 +1,472 @@
+use std::cmp::Ordering;
+
+use crate::dns::dnssec::{hashed_owner, Nsec, Nsec3, TypeBitmap, OPT_OUT};
+use crate::dns::name::{is_subdomain, labels, names_equal, parent};
+use crate::dns::resource_record::{RecordType, ResourceRecord};
+use crate::zone::writer::canonical_name_cmp;
+
+// NSEC3 records with more iterations than this are treated as insecure
+// rather than spent time on (RFC 9276 section 3.2).
+const MAX_NSEC3_ITERATIONS: u16 = 100;
+
+/// What a set of NSEC or NSEC3 records shows about a name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    Proven,
+    /// The name may sit under an unsigned delegation covered by an opt-out
+    /// span, or the records are too costly to check.
+    Insecure,
+    Unproven,
+}
+
+/// The validated NSEC and NSEC3 records of one response, all from `zone`.
+pub struct Proofs {
+    nsec: Vec<(String, Nsec)>,
+    nsec3: Vec<(Vec<u8>, Nsec3)>,
+}
+
+impl Proofs {
+    pub fn new(zone: &str, records: &[ResourceRecord]) -> Self {
+        let mut proofs = Proofs {
+            nsec: Vec::new(),
+            nsec3: Vec::new(),
+        };
+        for record in records {
+            match record.record_type {
+                RecordType::NSEC if is_subdomain(&record.name, zone) => {
+                    if let Some(nsec) = Nsec::parse(&record.data) {
+                        proofs.nsec.push((record.name.clone(), nsec));
+                    }
+                }
+                RecordType::NSEC3 if parent(&record.name).is_some_and(|p| names_equal(p, zone)) => {
+                    let hash = hashed_owner(&record.name);
+                    if let (Some(hash), Some(nsec3)) = (hash, Nsec3::parse(&record.data)) {
+                        proofs.nsec3.push((hash, nsec3));
+                    }
+                }
+                _ => {}
+            }
+        }
+        proofs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nsec.is_empty() && self.nsec3.is_empty()
+    }
+
+    /// Proves that `name` does not exist (RFC 4035 section 5.4, RFC 5155
+    /// section 8.4): nothing covers it and no wildcard could have.
+    pub fn nxdomain(&self, name: &str) -> Denial {
+        if self.nsec3_too_costly() {
+            return Denial::Insecure;
+        }
+        if let Some(encloser) = self.nsec_closest_encloser(name) {
+            return self.nsec_no_wildcard(&encloser);
+        }
+        match self.nsec3_closest_encloser(name) {
+            Some((encloser, opt_out)) => {
+                if !self.nsec3_covers(&wildcard(&encloser)) {
+                    Denial::Unproven
+                } else if opt_out {
+                    Denial::Insecure
+                } else {
+                    Denial::Proven
+                }
+            }
+            None => Denial::Unproven,
+        }
+    }
+
+    /// Proves that `name` has no records of `record_type` (RFC 4035
+    /// section 5.4, RFC 5155 sections 8.5 to 8.7).
+    pub fn nodata(&self, name: &str, record_type: RecordType) -> Denial {
+        if self.nsec3_too_costly() {
+            return Denial::Insecure;
+        }
+        if let Some(types) = self.types_at(name) {
+            return if lacks(types, record_type) {
+                Denial::Proven
+            } else {
+                Denial::Unproven
+            };
+        }
+
+        // An empty non-terminal has no NSEC, but lies between two that do.
+        let covering = self
+            .nsec
+            .iter()
+            .find(|(owner, nsec)| nsec_covers(owner, &nsec.next, name));
+        if let Some((_, nsec)) = covering {
+            if is_subdomain(&nsec.next, name) {
+                return Denial::Proven;
+            }
+        }
+        // Otherwise a wildcard matched, without the type.
+        if let Some(encloser) = self.nsec_closest_encloser(name) {
+            return match self.nsec_at(&wildcard(&encloser)) {
+                Some(nsec) if lacks(&nsec.types, record_type) => Denial::Proven,
+                _ => Denial::Unproven,
+            };
+        }
+
+        let Some((encloser, opt_out)) = self.nsec3_closest_encloser(name) else {
+            return Denial::Unproven;
+        };
+        // A DS query for an unsigned delegation in an opt-out span.
+        if record_type == RecordType::DS && opt_out {
+            return Denial::Insecure;
+        }
+        match self.nsec3_at(&wildcard(&encloser)) {
+            Some(nsec3) if lacks(&nsec3.types, record_type) => Denial::Proven,
+            _ => Denial::Unproven,
+        }
+    }
+
+    /// Proves that an answer expanded from the wildcard at `encloser` could
+    /// not have come from a closer name (RFC 4035 section 5.3.4, RFC 5155
+    /// section 8.8).
+    pub fn wildcard_expansion(&self, name: &str, encloser: &str) -> Denial {
+        if self.nsec3_too_costly() {
+            return Denial::Insecure;
+        }
+        if self
+            .nsec
+            .iter()
+            .any(|(owner, nsec)| nsec_covers(owner, &nsec.next, name))
+        {
+            return Denial::Proven;
+        }
+        let Some(next_closer) = next_closer(name, encloser) else {
+            return Denial::Unproven;
+        };
+        match self.nsec3_covering(&next_closer) {
+            Some(nsec3) if nsec3.flags & OPT_OUT != 0 => Denial::Insecure,
+            Some(_) => Denial::Proven,
+            None => Denial::Unproven,
+        }
+    }
+
+    /// The types at `name` when a record matches it exactly.
+    pub fn types_at(&self, name: &str) -> Option<&TypeBitmap> {
+        self.nsec_at(name)
+            .map(|nsec| &nsec.types)
+            .or_else(|| self.nsec3_at(name).map(|nsec3| &nsec3.types))
+    }
+
+    fn nsec_at(&self, name: &str) -> Option<&Nsec> {
+        self.nsec
+            .iter()
+            .find(|(owner, _)| names_equal(owner, name))
+            .map(|(_, nsec)| nsec)
+    }
+
+    // The closest encloser of `name` according to an NSEC covering it: the
+    // longer of the ancestors it shares with the NSEC's owner and next name.
+    fn nsec_closest_encloser(&self, name: &str) -> Option<String> {
+        self.nsec
+            .iter()
+            .filter(|(owner, nsec)| nsec_covers(owner, &nsec.next, name))
+            .filter(|(owner, nsec)| !is_delegation_above(owner, &nsec.types, name))
+            .map(|(owner, nsec)| {
+                let a = common_ancestor(name, owner);
+                let b = common_ancestor(name, &nsec.next);
+                if labels(&a).count() >= labels(&b).count() {
+                    a
+                } else {
+                    b
+                }
+            })
+            .next()
+    }
+
+    fn nsec_no_wildcard(&self, encloser: &str) -> Denial {
+        let source = wildcard(encloser);
+        if self
+            .nsec
+            .iter()
+            .any(|(owner, nsec)| nsec_covers(owner, &nsec.next, &source))
+        {
+            Denial::Proven
+        } else {
+            Denial::Unproven
+        }
+    }
+
+    fn nsec3_too_costly(&self) -> bool {
+        self.nsec3
+            .iter()
+            .any(|(_, nsec3)| nsec3.iterations > MAX_NSEC3_ITERATIONS)
+    }
+
+    fn nsec3_at(&self, name: &str) -> Option<&Nsec3> {
+        self.nsec3
+            .iter()
+            .find(|(owner, nsec3)| nsec3.hash(name).as_ref() == Some(owner))
+            .map(|(_, nsec3)| nsec3)
+    }
+
+    fn nsec3_covering(&self, name: &str) -> Option<&Nsec3> {
+        self.nsec3
+            .iter()
+            .find(|(owner, nsec3)| {
+                nsec3.hash(name).is_some_and(|hash| {
+                    covers(
+                        owner.as_slice(),
+                        nsec3.next_hashed.as_slice(),
+                        hash.as_slice(),
+                        Ord::cmp,
+                    )
+                })
+            })
+            .map(|(_, nsec3)| nsec3)
+    }
+
+    fn nsec3_covers(&self, name: &str) -> bool {
+        self.nsec3_covering(name).is_some()
+    }
+
+    // The closest encloser proof of RFC 5155 section 8.3: an ancestor of
+    // `name` that exists, and the name one label below it that does not.
+    // Returns the encloser, and whether the span hiding the next closer
+    // name is opt-out.
+    fn nsec3_closest_encloser(&self, name: &str) -> Option<(String, bool)> {
+        let mut child = name;
+        let mut candidate = parent(name);
+        while let Some(encloser) = candidate {
+            if let Some(nsec3) = self.nsec3_at(encloser) {
+                // A parent-side delegation or DNAME proves nothing below it.
+                if is_delegation_above(encloser, &nsec3.types, name)
+                    || nsec3.types.contains(RecordType::DNAME)
+                {
+                    return None;
+                }
+                let covering = self.nsec3_covering(child)?;
+                return Some((encloser.to_string(), covering.flags & OPT_OUT != 0));
+            }
+            child = encloser;
+            candidate = parent(encloser);
+        }
+        None
+    }
+}
+
+// Whether the type bitmap of a name rules out `record_type` there. A CNAME
+// would have been answered instead, and RFC 6840 section 4.4 keeps an NSEC
+// from the wrong side of a zone cut from proving anything.
+fn lacks(types: &TypeBitmap, record_type: RecordType) -> bool {
+    if types.contains(record_type) || types.contains(RecordType::CNAME) {
+        return false;
+    }
+    let delegation = types.contains(RecordType::NS) && !types.contains(RecordType::SOA);
+    match record_type {
+        RecordType::DS => !types.contains(RecordType::SOA),
+        _ => !delegation,
+    }
+}
+
+// A parent-side NSEC at a delegation proves nothing about names below it
+// (RFC 6840 section 4.1).
+fn is_delegation_above(owner: &str, types: &TypeBitmap, name: &str) -> bool {
+    !names_equal(owner, name)
+        && is_subdomain(name, owner)
+        && ((types.contains(RecordType::NS) && !types.contains(RecordType::SOA))
+            || types.contains(RecordType::DNAME))
+}
+
+// Whether `name` falls strictly between `owner` and `next`; the last NSEC of
+// a zone wraps around to the apex.
+fn nsec_covers(owner: &str, next: &str, name: &str) -> bool {
+    covers(owner, next, name, canonical_name_cmp)
+}
+
+fn covers<T: ?Sized>(owner: &T, next: &T, name: &T, cmp: impl Fn(&T, &T) -> Ordering) -> bool {
+    let after_owner = cmp(owner, name) == Ordering::Less;
+    let before_next = cmp(name, next) == Ordering::Less;
+    if cmp(owner, next) == Ordering::Less {
+        after_owner && before_next
+    } else {
+        after_owner || before_next
+    }
+}
+
+fn common_ancestor(a: &str, b: &str) -> String {
+    let shared: Vec<&str> = labels(a)
+        .rev()
+        .zip(labels(b).rev())
+        .take_while(|(x, y)| names_equal(x, y))
+        .map(|(x, _)| x)
+        .collect();
+    shared.into_iter().rev().collect::<Vec<_>>().join(".")
+}
+
+fn wildcard(encloser: &str) -> String {
+    if encloser.is_empty() {
+        "*".to_string()
+    } else {
+        format!("*.{}", encloser)
+    }
+}
+
+// The ancestor of `name` one label below `encloser`.
+fn next_closer(name: &str, encloser: &str) -> Option<String> {
+    let name_labels: Vec<&str> = labels(name).collect();
+    let keep = labels(encloser).count() + 1;
+    let skip = name_labels.len().checked_sub(keep)?;
+    Some(name_labels[skip..].join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::name::encode_name;
+    use crate::dns::resource_record::RecordClass;
+
+    fn bitmap(types: &[RecordType]) -> Vec<u8> {
+        let mut bits = [0u8; 32];
+        let mut length = 0;
+        for record_type in types {
+            let value = record_type.to_u16();
+            assert!(value < 256, "window 0 only");
+            bits[value as usize / 8] |= 0x80 >> (value % 8);
+            length = length.max(value as usize / 8 + 1);
+        }
+        let mut out = vec![0, length as u8];
+        out.extend_from_slice(&bits[..length]);
+        out
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[RecordType]) -> ResourceRecord {
+        let mut data = Vec::new();
+        encode_name(next, &mut data);
+        data.extend(bitmap(types));
+        ResourceRecord::new(
+            owner.to_string(),
+            RecordType::NSEC,
+            RecordClass::IN,
+            3600,
+            data,
+        )
+    }
+
+    // An NSEC3 record of the RFC 5155 appendix A zone: SHA-1, 12 extra
+    // iterations, salt aabbccdd. Hashes are given in base32hex.
+    fn nsec3(owner: &str, next: &str, flags: u8, types: &[RecordType]) -> ResourceRecord {
+        let next = hashed_owner(next).unwrap();
+        let mut data = vec![1, flags, 0, 12, 4, 0xaa, 0xbb, 0xcc, 0xdd, next.len() as u8];
+        data.extend(next);
+        data.extend(bitmap(types));
+        let owner = format!("{}.example", owner);
+        ResourceRecord::new(owner, RecordType::NSEC3, RecordClass::IN, 3600, data)
+    }
+
+    // The proof of RFC 5155 appendix B.1 that a.c.x.w.example does not
+    // exist: x.w.example matches, c.x.w.example and *.x.w.example are
+    // covered.
+    fn nxdomain_proof(flags: u8) -> Vec<ResourceRecord> {
+        use RecordType::*;
+        vec![
+            nsec3(
+                "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom",
+                "2t7b4g4vsa5smi47k61mv5bv1a22bojr",
+                flags,
+                &[MX, DNSKEY, NS, SOA, NSEC3PARAM, RRSIG],
+            ),
+            nsec3(
+                "b4um86eghhds6nea196smvmlo4ors995",
+                "gjeqe526plbf1g8mklp59enfd789njgi",
+                flags,
+                &[MX, RRSIG],
+            ),
+            nsec3(
+                "35mthgpgcu1qg68fab165klnsnk3dpvl",
+                "b4um86eghhds6nea196smvmlo4ors995",
+                flags,
+                &[NS, DS, RRSIG],
+            ),
+        ]
+    }
+
+    #[test]
+    fn nsec_span_wraps_around_to_the_apex() {
+        assert!(nsec_covers("b.example", "d.example", "c.example"));
+        assert!(!nsec_covers("b.example", "d.example", "e.example"));
+        // The last NSEC of the zone points back at the apex.
+        assert!(nsec_covers("z.example", "example", "zz.example"));
+        assert!(nsec_covers("z.example", "example", "a.z.example"));
+        assert!(!nsec_covers("z.example", "example", "a.example"));
+        assert!(!nsec_covers("z.example", "example", "example"));
+        // A zone with a single name covers everything else in it.
+        assert!(nsec_covers("example", "example", "a.example"));
+    }
+
+    #[test]
+    fn nsec3_span_wraps_around_to_the_first_hash() {
+        let (low, high) = ([0x10u8], [0xf0u8]);
+        assert!(covers(&high[..], &low[..], &[0xf8][..], Ord::cmp));
+        assert!(covers(&high[..], &low[..], &[0x01][..], Ord::cmp));
+        assert!(!covers(&high[..], &low[..], &[0x80][..], Ord::cmp));
+        assert!(!covers(&high[..], &low[..], &high[..], Ord::cmp));
+    }
+
+    #[test]
+    fn nsec3_closest_encloser_of_rfc5155_example() {
+        let proofs = Proofs::new("example", &nxdomain_proof(1));
+        assert_eq!(
+            proofs.nsec3_closest_encloser("a.c.x.w.example"),
+            Some(("x.w.example".to_string(), true))
+        );
+        let proofs = Proofs::new("example", &nxdomain_proof(0));
+        assert_eq!(
+            proofs.nsec3_closest_encloser("a.c.x.w.example"),
+            Some(("x.w.example".to_string(), false))
+        );
+        assert_eq!(proofs.nxdomain("a.c.x.w.example"), Denial::Proven);
+        // Opt-out spans cannot rule out an unsigned delegation.
+        let proofs = Proofs::new("example", &nxdomain_proof(1));
+        assert_eq!(proofs.nxdomain("a.c.x.w.example"), Denial::Insecure);
+    }
+
+    #[test]
+    fn nsec3_proves_nothing_below_a_delegation() {
+        // a.example is a delegation; a record matching it says nothing
+        // about names in the child zone.
+        let proofs = Proofs::new("example", &nxdomain_proof(0));
+        assert_eq!(proofs.nsec3_closest_encloser("b.a.example"), None);
+        assert_eq!(proofs.nxdomain("b.a.example"), Denial::Unproven);
+    }
+
+    #[test]
+    fn delegation_bitmaps_only_deny_ds() {
+        use RecordType::*;
+        let delegation = TypeBitmap::parse(&bitmap(&[NS, RRSIG, NSEC])).unwrap();
+        assert!(lacks(&delegation, DS));
+        assert!(!lacks(&delegation, A));
+        let secure_delegation = TypeBitmap::parse(&bitmap(&[NS, DS, RRSIG, NSEC])).unwrap();
+        assert!(!lacks(&secure_delegation, DS));
+        // At an apex the NSEC comes from the child, which cannot speak for
+        // the DS record in its parent.
+        let apex = TypeBitmap::parse(&bitmap(&[NS, SOA, RRSIG, NSEC, DNSKEY])).unwrap();
+        assert!(!lacks(&apex, DS));
+        assert!(lacks(&apex, A));
+        let alias = TypeBitmap::parse(&bitmap(&[CNAME, RRSIG, NSEC])).unwrap();
+        assert!(!lacks(&alias, A));
+    }
+
+    #[test]
+    fn nsec_at_delegation_proves_missing_ds_only() {
+        use RecordType::*;
+        let records = [nsec("sub.example", "www.example", &[NS, RRSIG, NSEC])];
+        let proofs = Proofs::new("example", &records);
+        assert_eq!(proofs.nodata("sub.example", DS), Denial::Proven);
+        assert_eq!(proofs.nodata("sub.example", A), Denial::Unproven);
+        assert_eq!(proofs.nxdomain("host.sub.example"), Denial::Unproven);
+    }
+
+    #[test]
+    fn empty_non_terminal_between_nsec_records() {
+        use RecordType::*;
+        let records = [nsec("a.example", "x.w.example", &[A, RRSIG, NSEC])];
+        let proofs = Proofs::new("example", &records);
+        assert_eq!(proofs.nodata("w.example", A), Denial::Proven);
+    }
+}
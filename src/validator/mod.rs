@@ -0,0 +1,656 @@
+pub mod crypto;
+pub mod denial;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+use crate::dns::dnssec::{covered_type, rrsets, Dnskey, Ds, Rrsig};
+use crate::dns::edns::ExtendedError;
+use crate::dns::header::ResponseCode;
+use crate::dns::message::DNSMessage;
+use crate::dns::name::{is_subdomain, labels, names_equal, normalize};
+use crate::dns::resource_record::{RecordType, ResourceRecord};
+use crate::validator::denial::{Denial, Proofs};
+use crate::zone::parser::{parse_zone_file, parse_zone_str};
+
+// The root zone's key signing keys, KSK-2017 and KSK-2024, as published by
+// IANA.
+const ROOT_TRUST_ANCHORS: &str = "\
+. 172800 IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+. 172800 IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+// Longest a link in a chain of trust is remembered, whatever the TTLs say.
+const MAX_CUT_TTL: u32 = 3600;
+const MAX_CNAME_CHAIN: usize = 16;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where the validator gets the DS and DNSKEY records it needs.
+pub trait Lookup: Sync {
+    /// Answers a query for `name` with CD and DO set, unvalidated; `None`
+    /// when no answer could be had.
+    fn lookup<'a>(
+        &'a self,
+        name: &'a str,
+        record_type: RecordType,
+    ) -> BoxFuture<'a, Option<DNSMessage>>;
+}
+
+/// The outcome of validating a response (RFC 4035 section 4.3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Security {
+    Secure,
+    Insecure,
+    /// Answered with SERVFAIL, explained by the error code and text.
+    Bogus(ExtendedError, String),
+}
+
+type Bogus = (ExtendedError, String);
+
+/// DNSSEC validation settings, read from the environment.
+#[derive(Debug, Clone)]
+pub struct ValidatorConfig {
+    /// DS records for the keys that start every chain of trust.
+    pub trust_anchors: Vec<ResourceRecord>,
+    /// Domains whose answers are never validated (RFC 7646).
+    pub negative_trust_anchors: Vec<String>,
+}
+
+impl ValidatorConfig {
+    /// Reads DNSSEC=validate, which enables validation, along with
+    /// DNSSEC_TRUST_ANCHORS (a zone file of DS records, default: the root
+    /// zone's keys) and DNSSEC_NEGATIVE_TRUST_ANCHORS (comma-separated
+    /// domains).
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        match std::env::var("DNSSEC").as_deref() {
+            Ok("validate") => {}
+            Ok("off") | Err(_) => return Ok(None),
+            Ok(other) => return Err(format!("Invalid value for DNSSEC: {:?}", other).into()),
+        }
+        let records = match std::env::var("DNSSEC_TRUST_ANCHORS") {
+            Ok(path) => parse_zone_file(Path::new(&path), "")
+                .map_err(|e| format!("DNSSEC_TRUST_ANCHORS: {}", e))?,
+            Err(_) => parse_zone_str(ROOT_TRUST_ANCHORS, "")?,
+        };
+        let trust_anchors: Vec<ResourceRecord> = records
+            .into_iter()
+            .filter(|record| record.record_type == RecordType::DS)
+            .collect();
+        if trust_anchors.is_empty() {
+            return Err("DNSSEC_TRUST_ANCHORS holds no DS records".into());
+        }
+        let negative_trust_anchors = std::env::var("DNSSEC_NEGATIVE_TRUST_ANCHORS")
+            .unwrap_or_default()
+            .split(',')
+            .map(normalize)
+            .filter(|name| !name.is_empty())
+            .collect();
+        Ok(Some(ValidatorConfig {
+            trust_anchors,
+            negative_trust_anchors,
+        }))
+    }
+}
+
+// What a DS query one label below a secure zone showed.
+#[derive(Debug, Clone)]
+enum Cut {
+    /// A signed zone starts here, with these keys.
+    Secure(Vec<Dnskey>),
+    /// The name is inside the zone above.
+    Inside,
+    /// An unsigned zone starts here, or one we cannot validate.
+    Insecure,
+    /// The name does not exist, so neither does anything below it.
+    Missing,
+}
+
+// The zone whose keys sign a name, or none when it is provably unsigned.
+enum Chain {
+    Secure(String, Vec<Dnskey>),
+    Insecure,
+}
+
+/// Validates responses by building chains of trust from the trust anchors
+/// down through DS and DNSKEY records (RFC 4035 section 5).
+pub struct Validator {
+    config: ValidatorConfig,
+    cuts: Mutex<HashMap<String, (Cut, Instant)>>,
+}
+
+impl Validator {
+    pub fn new(config: ValidatorConfig) -> Self {
+        Validator {
+            config,
+            cuts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the answer and, for negative answers, the proof of
+    /// nonexistence in `response`, fetching keys through `lookup`.
+    pub async fn validate(&self, response: &DNSMessage, lookup: &dyn Lookup) -> Security {
+        match self.check_response(response, lookup).await {
+            Ok(true) => Security::Secure,
+            Ok(false) => Security::Insecure,
+            Err((error, text)) => {
+                debug!("Bogus response: {}", text);
+                Security::Bogus(error, text)
+            }
+        }
+    }
+
+    // Whether every part of the response is secure; errors for bogus ones.
+    async fn check_response(
+        &self,
+        response: &DNSMessage,
+        lookup: &dyn Lookup,
+    ) -> Result<bool, Bogus> {
+        let Some(question) = response.questions.first() else {
+            return Ok(false);
+        };
+        let rcode = response.header.flags.rcode;
+        if rcode != ResponseCode::NoError as u8 && rcode != ResponseCode::NXDomain as u8 {
+            return Ok(false);
+        }
+        let Some(proofs) = self.authority_proofs(response, lookup).await? else {
+            return Ok(false);
+        };
+
+        let mut secure = true;
+        let mut validated = false;
+        for (rrset, signatures) in rrsets(&response.answers) {
+            if is_synthesised(&rrset, &signatures, &response.answers) {
+                continue;
+            }
+            let Some(signature) = self.check_rrset(&rrset, &signatures, lookup).await? else {
+                secure = false;
+                continue;
+            };
+            validated = true;
+            // Expanded from a wildcard: the name itself must not exist.
+            let owner = &rrset[0].name;
+            let owner_labels: Vec<&str> = labels(owner).collect();
+            if (signature.labels as usize) < owner_labels.len() {
+                let encloser =
+                    owner_labels[owner_labels.len() - signature.labels as usize..].join(".");
+                match proofs.wildcard_expansion(owner, &encloser) {
+                    Denial::Proven => {}
+                    Denial::Insecure => secure = false,
+                    Denial::Unproven => {
+                        return Err((
+                            ExtendedError::NsecMissing,
+                            format!("No proof that {} was expanded from a wildcard", owner),
+                        ))
+                    }
+                }
+            }
+        }
+
+        let qtype = question.record_type;
+        let name = final_name(response);
+        let answered = response.answers.iter().any(|record| {
+            names_equal(&record.name, &name)
+                && (qtype == RecordType::ANY || record.record_type == qtype)
+        });
+        if rcode == ResponseCode::NXDomain as u8 || !answered {
+            if proofs.is_empty() {
+                return match self.chain(&name, lookup).await? {
+                    Chain::Insecure => Ok(false),
+                    Chain::Secure(..) => Err((
+                        ExtendedError::NsecMissing,
+                        format!("No NSEC or NSEC3 records deny {} {}", display(&name), qtype),
+                    )),
+                };
+            }
+            let denial = if rcode == ResponseCode::NXDomain as u8 {
+                proofs.nxdomain(&name)
+            } else {
+                proofs.nodata(&name, qtype)
+            };
+            match denial {
+                Denial::Proven => validated = true,
+                Denial::Insecure => secure = false,
+                Denial::Unproven => {
+                    return Err((
+                        ExtendedError::NsecMissing,
+                        format!("The denial of {} {} is not proven", display(&name), qtype),
+                    ))
+                }
+            }
+        }
+        Ok(secure && validated)
+    }
+
+    // Checks the SOA, NSEC and NSEC3 records of the authority section,
+    // returning the denial records; `None` when any of them is insecure.
+    async fn authority_proofs(
+        &self,
+        response: &DNSMessage,
+        lookup: &dyn Lookup,
+    ) -> Result<Option<Proofs>, Bogus> {
+        let mut zone = None;
+        let mut records = Vec::new();
+        for (rrset, signatures) in rrsets(&response.authority_records) {
+            if !matches!(
+                rrset[0].record_type,
+                RecordType::SOA | RecordType::NSEC | RecordType::NSEC3
+            ) {
+                continue;
+            }
+            let Some(signature) = self.check_rrset(&rrset, &signatures, lookup).await? else {
+                return Ok(None);
+            };
+            if rrset[0].record_type != RecordType::SOA {
+                zone.get_or_insert(signature.signer);
+                records.extend(rrset);
+            }
+        }
+        Ok(Some(Proofs::new(zone.as_deref().unwrap_or(""), &records)))
+    }
+
+    // Verifies one RRset with the keys of the zone that signed it. Returns
+    // the signature that checked out, or `None` when the RRset is provably
+    // unsigned.
+    async fn check_rrset(
+        &self,
+        rrset: &[ResourceRecord],
+        signatures: &[ResourceRecord],
+        lookup: &dyn Lookup,
+    ) -> Result<Option<Rrsig>, Bogus> {
+        let owner = &rrset[0].name;
+        let record_type = rrset[0].record_type;
+        let owner_labels = labels(owner).count();
+        let signatures: Vec<Rrsig> = signatures
+            .iter()
+            .filter_map(|record| Rrsig::parse(&record.data))
+            .filter(|sig| is_subdomain(owner, &sig.signer) && sig.labels as usize <= owner_labels)
+            .collect();
+        let Some(signer) = signatures.first().map(|sig| normalize(&sig.signer)) else {
+            return match self.chain(owner, lookup).await? {
+                Chain::Insecure => Ok(None),
+                Chain::Secure(..) => Err((
+                    ExtendedError::RrsigsMissing,
+                    format!("No signatures on {} {}", display(owner), record_type),
+                )),
+            };
+        };
+        match self.chain(&signer, lookup).await? {
+            Chain::Insecure => Ok(None),
+            Chain::Secure(zone, keys) if names_equal(&zone, &signer) => {
+                verify(rrset, &signatures, &zone, &keys).map(Some)
+            }
+            Chain::Secure(..) => Err((
+                ExtendedError::DnssecBogus,
+                format!(
+                    "{} is signed by {}, which is no zone",
+                    display(owner),
+                    display(&signer)
+                ),
+            )),
+        }
+    }
+
+    // Follows the chain of trust from the closest trust anchor down to
+    // `name`, one label at a time, to the zone the name belongs to.
+    async fn chain(&self, name: &str, lookup: &dyn Lookup) -> Result<Chain, Bogus> {
+        if self
+            .config
+            .negative_trust_anchors
+            .iter()
+            .any(|anchor| is_subdomain(name, anchor))
+        {
+            return Ok(Chain::Insecure);
+        }
+        let Some(anchor) = self
+            .config
+            .trust_anchors
+            .iter()
+            .map(|record| normalize(&record.name))
+            .filter(|anchor| is_subdomain(name, anchor))
+            .max_by_key(|anchor| labels(anchor).count())
+        else {
+            return Ok(Chain::Insecure);
+        };
+        let mut keys = match self.anchor(&anchor, lookup).await? {
+            Cut::Secure(keys) => keys,
+            _ => return Ok(Chain::Insecure),
+        };
+        let mut zone = anchor;
+
+        let name_labels: Vec<&str> = labels(name).collect();
+        for depth in labels(&zone).count() + 1..=name_labels.len() {
+            let child = normalize(&name_labels[name_labels.len() - depth..].join("."));
+            match self.cut(&child, &zone, &keys, lookup).await? {
+                Cut::Secure(child_keys) => {
+                    zone = child;
+                    keys = child_keys;
+                }
+                Cut::Inside => {}
+                Cut::Missing => break,
+                Cut::Insecure => return Ok(Chain::Insecure),
+            }
+        }
+        Ok(Chain::Secure(zone, keys))
+    }
+
+    // The keys of a trust anchor's zone.
+    async fn anchor(&self, anchor: &str, lookup: &dyn Lookup) -> Result<Cut, Bogus> {
+        if let Some(cut) = self.cached(anchor) {
+            return Ok(cut);
+        }
+        let ds: Vec<Ds> = self
+            .config
+            .trust_anchors
+            .iter()
+            .filter(|record| names_equal(&normalize(&record.name), anchor))
+            .filter_map(|record| Ds::parse(&record.data))
+            .filter(is_usable_ds)
+            .collect();
+        let cut = if ds.is_empty() {
+            Cut::Insecure
+        } else {
+            Cut::Secure(self.dnskeys(anchor, &ds, lookup).await?)
+        };
+        self.remember(anchor, &cut, MAX_CUT_TTL);
+        Ok(cut)
+    }
+
+    // What lies at `child`, one label below `zone`: a DS query asked of the
+    // zone either finds a signed delegation, or proves there is none.
+    async fn cut(
+        &self,
+        child: &str,
+        zone: &str,
+        keys: &[Dnskey],
+        lookup: &dyn Lookup,
+    ) -> Result<Cut, Bogus> {
+        if let Some(cut) = self.cached(child) {
+            return Ok(cut);
+        }
+        let response = fetch(lookup, child, RecordType::DS).await?;
+        let at_child = |record: &&ResourceRecord| names_equal(&record.name, child);
+
+        let ds: Vec<ResourceRecord> = response
+            .answers
+            .iter()
+            .filter(at_child)
+            .filter(|record| record.record_type == RecordType::DS)
+            .cloned()
+            .collect();
+        let (cut, ttl) = if !ds.is_empty() {
+            let signatures: Vec<Rrsig> = response
+                .answers
+                .iter()
+                .filter(at_child)
+                .filter(|record| covered_type(record) == Some(RecordType::DS))
+                .filter_map(|record| Rrsig::parse(&record.data))
+                .collect();
+            verify(&ds, &signatures, zone, keys)?;
+            let usable: Vec<Ds> = ds
+                .iter()
+                .filter_map(|record| Ds::parse(&record.data))
+                .filter(is_usable_ds)
+                .collect();
+            let cut = if usable.is_empty() {
+                Cut::Insecure
+            } else {
+                Cut::Secure(self.dnskeys(child, &usable, lookup).await?)
+            };
+            (cut, min_ttl(&ds))
+        } else if let Some(cname) = response
+            .answers
+            .iter()
+            .filter(at_child)
+            .find(|record| record.record_type == RecordType::CNAME)
+        {
+            // An alias cannot be the apex of a zone.
+            (Cut::Inside, cname.ttl)
+        } else {
+            let mut records = Vec::new();
+            for (rrset, signatures) in rrsets(&response.authority_records) {
+                if !matches!(rrset[0].record_type, RecordType::NSEC | RecordType::NSEC3) {
+                    continue;
+                }
+                let signatures: Vec<Rrsig> = signatures
+                    .iter()
+                    .filter_map(|record| Rrsig::parse(&record.data))
+                    .collect();
+                verify(&rrset, &signatures, zone, keys)?;
+                records.extend(rrset);
+            }
+            let proofs = Proofs::new(zone, &records);
+            let nxdomain = response.header.flags.rcode == ResponseCode::NXDomain as u8;
+            let denial = if nxdomain {
+                proofs.nxdomain(child)
+            } else {
+                proofs.nodata(child, RecordType::DS)
+            };
+            let cut = match denial {
+                Denial::Proven if nxdomain => Cut::Missing,
+                Denial::Proven
+                    if proofs
+                        .types_at(child)
+                        .is_some_and(|types| types.contains(RecordType::NS)) =>
+                {
+                    Cut::Insecure
+                }
+                Denial::Proven => Cut::Inside,
+                Denial::Insecure => Cut::Insecure,
+                Denial::Unproven => {
+                    return Err((
+                        ExtendedError::NsecMissing,
+                        format!("No proof that {} has no DS records", display(child)),
+                    ))
+                }
+            };
+            (cut, min_ttl(&records))
+        };
+        self.remember(child, &cut, ttl);
+        Ok(cut)
+    }
+
+    // The DNSKEY RRset of `zone`, accepted when a key matching one of the
+    // DS records signs it. Returns the keys that may sign zone data.
+    async fn dnskeys(
+        &self,
+        zone: &str,
+        ds: &[Ds],
+        lookup: &dyn Lookup,
+    ) -> Result<Vec<Dnskey>, Bogus> {
+        let response = fetch(lookup, zone, RecordType::DNSKEY).await?;
+        let at_zone = |record: &&ResourceRecord| names_equal(&record.name, zone);
+        let records: Vec<ResourceRecord> = response
+            .answers
+            .iter()
+            .filter(at_zone)
+            .filter(|record| record.record_type == RecordType::DNSKEY)
+            .cloned()
+            .collect();
+        let signatures: Vec<Rrsig> = response
+            .answers
+            .iter()
+            .filter(at_zone)
+            .filter(|record| covered_type(record) == Some(RecordType::DNSKEY))
+            .filter_map(|record| Rrsig::parse(&record.data))
+            .collect();
+
+        let trusted = records.iter().any(|record| {
+            ds.iter().any(|ds| ds.matches(zone, record))
+                && Dnskey::parse(&record.data).is_some_and(|key| {
+                    key.is_usable() && verify(&records, &signatures, zone, &[key]).is_ok()
+                })
+        });
+        if !trusted {
+            return Err((
+                ExtendedError::DnskeyMissing,
+                format!(
+                    "No DNSKEY of {} matching its DS records signs the key set",
+                    display(zone)
+                ),
+            ));
+        }
+        Ok(records
+            .iter()
+            .filter_map(|record| Dnskey::parse(&record.data))
+            .filter(|key| key.is_usable() && crypto::is_supported(key.algorithm))
+            .collect())
+    }
+
+    fn cached(&self, name: &str) -> Option<Cut> {
+        let cuts = self.cuts.lock().unwrap();
+        cuts.get(name)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(cut, _)| cut.clone())
+    }
+
+    fn remember(&self, name: &str, cut: &Cut, ttl: u32) {
+        let expires = Instant::now() + Duration::from_secs(ttl.min(MAX_CUT_TTL) as u64);
+        let mut cuts = self.cuts.lock().unwrap();
+        cuts.retain(|_, (_, expires)| *expires > Instant::now());
+        cuts.insert(name.to_string(), (cut.clone(), expires));
+    }
+}
+
+// Checks an RRset against the signatures made by `zone` with its keys,
+// succeeding with the first that verifies.
+fn verify(
+    rrset: &[ResourceRecord],
+    signatures: &[Rrsig],
+    zone: &str,
+    keys: &[Dnskey],
+) -> Result<Rrsig, Bogus> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0);
+    let mut expired = false;
+    for signature in signatures
+        .iter()
+        .filter(|sig| names_equal(&sig.signer, zone))
+    {
+        if !signature.is_current(now) {
+            expired = true;
+            continue;
+        }
+        if keys
+            .iter()
+            .any(|key| crypto::verify_rrset(rrset, signature, key))
+        {
+            return Ok(signature.clone());
+        }
+    }
+    let owner = rrset.first().map_or("", |record| record.name.as_str());
+    let record_type = rrset
+        .first()
+        .map_or(RecordType::ANY, |record| record.record_type);
+    if expired {
+        Err((
+            ExtendedError::SignatureExpired,
+            format!(
+                "Signatures on {} {} have expired",
+                display(owner),
+                record_type
+            ),
+        ))
+    } else if signatures.is_empty() {
+        Err((
+            ExtendedError::RrsigsMissing,
+            format!("No signatures on {} {}", display(owner), record_type),
+        ))
+    } else {
+        Err((
+            ExtendedError::DnssecBogus,
+            format!("No valid signature on {} {}", display(owner), record_type),
+        ))
+    }
+}
+
+async fn fetch(
+    lookup: &dyn Lookup,
+    name: &str,
+    record_type: RecordType,
+) -> Result<DNSMessage, Bogus> {
+    lookup
+        .lookup(name, record_type)
+        .await
+        .filter(|response| {
+            response.header.flags.rcode == ResponseCode::NoError as u8
+                || response.header.flags.rcode == ResponseCode::NXDomain as u8
+        })
+        .ok_or_else(|| {
+            (
+                ExtendedError::NoReachableAuthority,
+                format!(
+                    "No answer for {} {} while validating",
+                    display(name),
+                    record_type
+                ),
+            )
+        })
+}
+
+// DS records we can follow: a known digest of a key we can verify with.
+fn is_usable_ds(ds: &Ds) -> bool {
+    ds.digest_algorithm().is_some() && crypto::is_supported(ds.algorithm)
+}
+
+// A CNAME a server made up from a DNAME is not signed, and needs not be
+// (RFC 6672 section 5.3.1).
+fn is_synthesised(
+    rrset: &[ResourceRecord],
+    signatures: &[ResourceRecord],
+    answers: &[ResourceRecord],
+) -> bool {
+    let owner = &rrset[0].name;
+    rrset[0].record_type == RecordType::CNAME
+        && signatures.is_empty()
+        && answers.iter().any(|record| {
+            record.record_type == RecordType::DNAME
+                && is_subdomain(owner, &record.name)
+                && !names_equal(owner, &record.name)
+        })
+}
+
+// The name the question's CNAME chain ends at.
+fn final_name(response: &DNSMessage) -> String {
+    let Some(question) = response.questions.first() else {
+        return String::new();
+    };
+    let mut name = question.name.clone();
+    if question.record_type == RecordType::CNAME {
+        return name;
+    }
+    for _ in 0..MAX_CNAME_CHAIN {
+        let target = response
+            .answers
+            .iter()
+            .find(|record| {
+                record.record_type == RecordType::CNAME && names_equal(&record.name, &name)
+            })
+            .and_then(ResourceRecord::target_name);
+        match target {
+            Some(target) => name = target,
+            None => break,
+        }
+    }
+    name
+}
+
+fn min_ttl(records: &[ResourceRecord]) -> u32 {
+    records.iter().map(|record| record.ttl).min().unwrap_or(0)
+}
+
+fn display(name: &str) -> &str {
+    if name.is_empty() {
+        "."
+    } else {
+        name
+    }
+}
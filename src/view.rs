@@ -0,0 +1,309 @@
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use log::info;
+
+use crate::acl::AccessList;
+use crate::dns::message::DNSMessage;
+use crate::dns::name::normalize;
+use crate::dns::resource_record::RecordType;
+use crate::ecs::SubnetCache;
+use crate::forwarder::Forwarder;
+use crate::hosts::Hosts;
+use crate::recursor::{Recursor, RecursorConfig};
+use crate::server::Listener;
+use crate::validator::{BoxFuture, Lookup, Validator, ValidatorConfig};
+use crate::zone::catalog::Catalog;
+use crate::zone::Zone;
+
+/// A set of data served to the clients it matches: its own zones, hosts
+/// overrides and forwarding rules, plus a cache when resolving recursively.
+/// Forwarded answers are cached only when they hold for a client subnet. Each view validates with its own
+/// validator, since the keys it fetches depend on how the view resolves.
+pub struct View {
+    pub name: String,
+    clients: Option<AccessList>,
+    destinations: Option<Vec<IpAddr>>,
+    keys: Option<Vec<String>>,
+    pub hosts: Option<Hosts>,
+    pub catalog: Catalog,
+    pub forwarder: Forwarder,
+    pub recursor: Option<Recursor>,
+    pub validator: Option<Validator>,
+    pub subnets: SubnetCache,
+}
+
+impl View {
+    /// True when the client address, the address it reached us on and the
+    /// TSIG key it signed with all satisfy the view's criteria. Criteria
+    /// left out match anything.
+    pub fn matches(&self, client: IpAddr, local: SocketAddr, key: Option<&str>) -> bool {
+        self.clients.as_ref().is_none_or(|acl| acl.allows(client))
+            && self
+                .destinations
+                .as_ref()
+                .is_none_or(|addresses| addresses.contains(&local.ip()))
+            && self
+                .keys
+                .as_ref()
+                .is_none_or(|keys| key.is_some_and(|key| keys.iter().any(|k| *k == key)))
+    }
+}
+
+/// Refuses views that set `match-destinations` when a listener is bound to a
+/// wildcard address. Listeners report the address they are bound to rather
+/// than the one each query was sent to, so such a view would never match.
+pub fn check_destinations(views: &[View], listeners: &[Listener]) -> Result<(), String> {
+    let Some(view) = views.iter().find(|view| view.destinations.is_some()) else {
+        return Ok(());
+    };
+    match listeners
+        .iter()
+        .find(|listener| listener.address.ip().is_unspecified())
+    {
+        Some(listener) => Err(format!(
+            "View {} sets match-destinations, which needs every LISTEN address \
+             to be a specific one, not {}",
+            view.name, listener.address
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+impl View {
+    /// A view matching every client that answers from `catalog` alone.
+    pub fn serving(catalog: Catalog) -> Self {
+        View {
+            name: "default".to_string(),
+            clients: None,
+            destinations: None,
+            keys: None,
+            hosts: None,
+            catalog,
+            forwarder: Forwarder::default(),
+            recursor: None,
+            validator: None,
+            subnets: SubnetCache::default(),
+        }
+    }
+}
+
+/// Validation fetches DS and DNSKEY records the way the view resolves
+/// anything else: through its forwarding rules, or else recursively.
+impl Lookup for View {
+    fn lookup<'a>(
+        &'a self,
+        name: &'a str,
+        record_type: RecordType,
+    ) -> BoxFuture<'a, Option<DNSMessage>> {
+        Box::pin(async move {
+            let mut query = DNSMessage::query(rand::random(), name, record_type);
+            query.header.flags.rd = true;
+            query.header.flags.cd = true;
+            if let Some(edns) = query.edns.as_mut() {
+                edns.dnssec_ok = true;
+            }
+            if let Some(group) = self.forwarder.route(name) {
+                let response = group.forward(&query.to_bytes()).await.ok()?;
+                return DNSMessage::parse(&response).ok();
+            }
+            Some(self.recursor.as_ref()?.answer(&query, None).await)
+        })
+    }
+}
+
+/// Reads the views in VIEWS_FILE, or builds a single view matching every
+/// client from ZONE_FILES, HOSTS_FILES and FORWARD_RULES when it is not set.
+/// Each view resolving recursively gets its own cache, and each view its own
+/// validator when validation is enabled.
+pub fn load_views(
+    recursion: Option<&RecursorConfig>,
+    validation: Option<&ValidatorConfig>,
+) -> Result<Vec<View>, Box<dyn Error>> {
+    let recursor = || recursion.map(|config| Recursor::new(config.clone()));
+    let validator = || validation.map(|config| Validator::new(config.clone()));
+    let Ok(path) = std::env::var("VIEWS_FILE") else {
+        return Ok(vec![View {
+            name: "default".to_string(),
+            clients: None,
+            destinations: None,
+            keys: None,
+            hosts: Hosts::from_env()?,
+            catalog: load_zones(&std::env::var("ZONE_FILES").unwrap_or_default())?,
+            forwarder: Forwarder::from_env(recursion.is_none())?,
+            recursor: recursor(),
+            validator: validator(),
+            subnets: SubnetCache::default(),
+        }]);
+    };
+
+    let mut views = Vec::new();
+    for section in parse_views_file(Path::new(&path))? {
+        let option = |key: &str| {
+            section
+                .options
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.as_str())
+        };
+        let context = |e: Box<dyn Error>| format!("{}: view {}: {}", path, section.name, e);
+        let view = View {
+            clients: option("match-clients")
+                .map(AccessList::parse)
+                .transpose()
+                .map_err(|e| context(e.into()))?,
+            destinations: option("match-destinations")
+                .map(|list| {
+                    list.split(',')
+                        .map(|address| address.trim().parse::<IpAddr>())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()
+                .map_err(|e| context(e.into()))?,
+            keys: option("match-keys").map(|list| list.split(',').map(normalize).collect()),
+            hosts: Hosts::from_list(option("hosts").unwrap_or("")).map_err(context)?,
+            catalog: load_zones(option("zones").unwrap_or("")).map_err(context)?,
+            forwarder: Forwarder::new(option("forward-rules").map(Path::new), recursion.is_none())
+                .map_err(context)?,
+            recursor: recursor(),
+            validator: validator(),
+            subnets: SubnetCache::default(),
+            name: section.name,
+        };
+        info!("Loaded view {}", view.name);
+        views.push(view);
+    }
+    Ok(views)
+}
+
+/// Loads a comma separated list of `origin=path` pairs (e.g.
+/// `example.com=/etc/tinydns/example.com.zone`).
+pub fn load_zones(zone_files: &str) -> Result<Catalog, Box<dyn Error>> {
+    let mut catalog = Catalog::default();
+    for entry in zone_files
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+    {
+        let (origin, path) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid zones entry {:?}, expected origin=path", entry))?;
+        catalog.add(Zone::load(Path::new(path.trim()), origin.trim())?);
+    }
+    Ok(catalog)
+}
+
+struct Section {
+    name: String,
+    options: Vec<(String, String)>,
+}
+
+// Views are written as sections in the order they are tried:
+//
+//     [internal]
+//     match-clients = 10.0.0.0/8, 192.168.0.0/16
+//     zones = example.com=/etc/tinydns/internal/example.com.zone
+//
+//     [external]
+//     zones = example.com=/etc/tinydns/external/example.com.zone
+//
+// Besides `match-clients`, a view may set `match-destinations` (addresses
+// we listen on, each of which LISTEN must name rather than a wildcard),
+// `match-keys` (TSIG key names), `hosts` and `forward-rules`.
+fn parse_views_file(path: &Path) -> Result<Vec<Section>, Box<dyn Error>> {
+    const OPTIONS: [&str; 6] = [
+        "match-clients",
+        "match-destinations",
+        "match-keys",
+        "zones",
+        "hosts",
+        "forward-rules",
+    ];
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut sections: Vec<Section> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: &str| format!("{}:{}: {}", path.display(), index + 1, message);
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push(Section {
+                name: name.trim().to_string(),
+                options: Vec::new(),
+            });
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected key = value"))?;
+        let key = key.trim();
+        if !OPTIONS.contains(&key) {
+            return Err(error(&format!("unknown option {:?}", key)).into());
+        }
+        let section = sections
+            .last_mut()
+            .ok_or_else(|| error("option outside a [view] section"))?;
+        section
+            .options
+            .push((key.to_string(), value.trim().to_string()));
+    }
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Transport;
+
+    fn view(destinations: &str, keys: &str) -> View {
+        let mut view = View::serving(Catalog::default());
+        view.destinations = Some(
+            destinations
+                .split(',')
+                .map(|a| a.parse().unwrap())
+                .collect(),
+        );
+        view.keys = Some(keys.split(',').map(normalize).collect());
+        view
+    }
+
+    fn listeners(addresses: &[&str]) -> Vec<Listener> {
+        addresses
+            .iter()
+            .map(|address| Listener {
+                address: address.parse().unwrap(),
+                transports: vec![Transport::Udp, Transport::Tcp],
+                best_effort: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_destinations_and_keys() {
+        let view = view("192.0.2.53,2001:db8::53", "internal.key");
+        let client = "10.0.0.1".parse().unwrap();
+        let local = "192.0.2.53:53".parse().unwrap();
+        assert!(view.matches(client, local, Some("internal.key")));
+        assert!(!view.matches(client, local, None));
+        assert!(!view.matches(client, local, Some("other.key")));
+        let elsewhere = "192.0.2.54:53".parse().unwrap();
+        assert!(!view.matches(client, elsewhere, Some("internal.key")));
+    }
+
+    #[test]
+    fn refuses_destinations_behind_wildcard_listeners() {
+        let views = [view("192.0.2.53", "internal.key")];
+        assert!(
+            check_destinations(&views, &listeners(&["192.0.2.53:53", "[2001:db8::53]:853"]))
+                .is_ok()
+        );
+        let error =
+            check_destinations(&views, &listeners(&["192.0.2.53:53", "[::]:53"])).unwrap_err();
+        assert!(error.contains("[::]:53"), "{}", error);
+        let open = [View::serving(Catalog::default())];
+        assert!(check_destinations(&open, &listeners(&["0.0.0.0:53"])).is_ok());
+    }
+}
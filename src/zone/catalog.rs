@@ -0,0 +1,231 @@
+use std::io;
+use std::path::Path;
+
+use log::info;
+
+use crate::dns::header::ResponseCode;
+use crate::dns::message::DNSMessage;
+use crate::dns::name::{is_subdomain, normalize};
+use crate::dns::resource_record::{RecordClass, RecordType, ResourceRecord};
+use crate::zone::writer::write_zone;
+use crate::zone::{Lookup, Zone};
+
+// Bounds CNAME chasing inside our own zones.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The set of zones tinydns is authoritative for.
+#[derive(Debug, Default)]
+pub struct Catalog {
+    zones: Vec<Zone>,
+}
+
+impl Catalog {
+    pub fn add(&mut self, zone: Zone) {
+        info!(
+            "Loaded zone {} ({} records)",
+            zone.origin(),
+            zone.records().count()
+        );
+        self.zones.push(zone);
+    }
+
+    /// Writes each zone as a canonical master file named after its origin
+    /// into `dir`, so that what is served can be diffed against its source.
+    pub fn dump(&self, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        for zone in &self.zones {
+            let name = match zone.origin() {
+                "" => "root",
+                origin => origin,
+            };
+            let path = dir.join(format!("{}.zone", name));
+            let records: Vec<ResourceRecord> = zone.records().cloned().collect();
+            std::fs::write(&path, write_zone(zone.origin(), &records))?;
+            info!("Wrote zone {} to {}", zone.origin(), path.display());
+        }
+        Ok(())
+    }
+
+    /// The most specific zone containing `name`.
+    pub fn find_zone(&self, name: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| is_subdomain(name, zone.origin()))
+            .max_by_key(|zone| zone.origin().len())
+    }
+
+    /// Builds an authoritative response when the question falls inside one of
+    /// our zones. Returns `None` so the caller can resolve the query elsewhere.
+    pub fn answer(&self, query: &DNSMessage) -> Option<DNSMessage> {
+        if query.header.flags.opcode != 0 || query.questions.len() != 1 {
+            return None;
+        }
+        let question = &query.questions[0];
+        if question.class != RecordClass::IN {
+            return None;
+        }
+        let zone = self.find_zone(&question.name)?;
+
+        let mut response = DNSMessage::response_to(query);
+        response.header.flags.aa = true;
+
+        let mut qname = normalize(&question.name);
+        for _ in 0..MAX_CNAME_CHAIN {
+            match zone.lookup(&qname, question.record_type) {
+                Lookup::Answer(records) => {
+                    response.answers.extend(records);
+                    break;
+                }
+                Lookup::Alias(cname) => {
+                    let target = cname.target_name();
+                    response.answers.push(cname);
+                    match target {
+                        Some(target) if is_subdomain(&target, zone.origin()) => {
+                            qname = normalize(&target)
+                        }
+                        // The target is elsewhere; the client follows it.
+                        _ => break,
+                    }
+                }
+                Lookup::Referral { name_servers, glue } => {
+                    // Data below a zone cut is not ours to answer for.
+                    response.header.flags.aa = false;
+                    response.authority_records.extend(name_servers);
+                    response.additional_records.extend(glue);
+                    break;
+                }
+                Lookup::NoData => {
+                    response.authority_records.extend(negative_soa(zone));
+                    break;
+                }
+                Lookup::NXDomain => {
+                    // With a CNAME in the answer the rcode describes the
+                    // final target (RFC 6604).
+                    response.set_response_code(ResponseCode::NXDomain);
+                    response.authority_records.extend(negative_soa(zone));
+                    break;
+                }
+            }
+        }
+
+        response.add_additional_addresses(|name, record_type| self.addresses(name, record_type));
+        Some(response)
+    }
+
+    // Authoritative address records for `name`, if it is in one of our zones.
+    fn addresses(&self, name: &str, record_type: RecordType) -> Vec<ResourceRecord> {
+        match self
+            .find_zone(name)
+            .map(|zone| zone.lookup(name, record_type))
+        {
+            Some(Lookup::Answer(records)) => records,
+            _ => Vec::new(),
+        }
+    }
+}
+
+// The SOA placed in the authority section of negative answers carries the
+// negative caching TTL: the lesser of its own TTL and its MINIMUM field
+// (RFC 2308 section 3).
+fn negative_soa(zone: &Zone) -> Option<ResourceRecord> {
+    let mut soa = zone.soa()?.clone();
+    if soa.record_type == RecordType::SOA && soa.data.len() >= 4 {
+        let minimum = &soa.data[soa.data.len() - 4..];
+        let minimum = u32::from_be_bytes([minimum[0], minimum[1], minimum[2], minimum[3]]);
+        soa.ttl = soa.ttl.min(minimum);
+    }
+    Some(soa)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::parser::parse_zone_str;
+
+    const ZONE: &str = "\
+$TTL 3600
+@ SOA ns hostmaster 1 7200 900 1209600 300
+@ NS ns
+@ NS ns.other.test.
+@ MX 10 mail
+@ MX 20 mail.other.test.
+ns A 192.0.2.1
+mail A 192.0.2.25
+mail AAAA 2001:db8::25
+loop1 CNAME loop2
+loop2 CNAME loop1
+";
+
+    fn catalog(text: &str) -> Catalog {
+        let mut catalog = Catalog::default();
+        catalog.add(Zone::new(
+            "example.test",
+            parse_zone_str(text, "example.test").unwrap(),
+        ));
+        catalog
+    }
+
+    fn names(records: &[ResourceRecord]) -> Vec<String> {
+        records
+            .iter()
+            .map(|record| format!("{} {}", record.name, record.record_type))
+            .collect()
+    }
+
+    fn ask(catalog: &Catalog, name: &str, record_type: RecordType) -> DNSMessage {
+        catalog
+            .answer(&DNSMessage::query(1, name, record_type))
+            .unwrap()
+    }
+
+    #[test]
+    fn adds_addresses_of_in_zone_targets() {
+        let catalog = catalog(ZONE);
+        let response = ask(&catalog, "example.test", RecordType::MX);
+        assert!(response.header.flags.aa);
+        assert_eq!(names(&response.answers).len(), 2);
+        // Nothing is known about mail.other.test, so it gets no addresses.
+        assert_eq!(
+            names(&response.additional_records),
+            ["mail.example.test A", "mail.example.test AAAA"]
+        );
+        let response = ask(&catalog, "example.test", RecordType::NS);
+        assert_eq!(names(&response.additional_records), ["ns.example.test A"]);
+    }
+
+    #[test]
+    fn answers_negatively_with_the_soa() {
+        let catalog = catalog(ZONE);
+        let response = ask(&catalog, "missing.example.test", RecordType::A);
+        assert_eq!(response.header.flags.rcode, ResponseCode::NXDomain as u8);
+        // The TTL is the SOA minimum, which is less than its own TTL.
+        assert_eq!(response.authority_records[0].ttl, 300);
+        let response = ask(&catalog, "mail.example.test", RecordType::TXT);
+        assert_eq!(response.header.flags.rcode, ResponseCode::NoError as u8);
+        assert_eq!(names(&response.authority_records), ["example.test SOA"]);
+        assert!(catalog
+            .answer(&DNSMessage::query(1, "example.other", RecordType::A))
+            .is_none());
+    }
+
+    #[test]
+    fn stops_chasing_cnames_after_eight() {
+        let mut text = ZONE.to_string();
+        for n in 0..10 {
+            text.push_str(&format!("chain{} CNAME chain{}\n", n, n + 1));
+        }
+        text.push_str("chain10 A 192.0.2.10\n");
+        let catalog = catalog(&text);
+        let response = ask(&catalog, "chain0.example.test", RecordType::A);
+        assert_eq!(response.answers.len(), MAX_CNAME_CHAIN);
+        assert!(response
+            .answers
+            .iter()
+            .all(|record| record.record_type == RecordType::CNAME));
+        let response = ask(&catalog, "chain3.example.test", RecordType::A);
+        assert_eq!(response.answers.len(), 8);
+        assert_eq!(response.answers[7].record_type, RecordType::A);
+        let response = ask(&catalog, "loop1.example.test", RecordType::A);
+        assert_eq!(response.answers.len(), MAX_CNAME_CHAIN);
+    }
+}
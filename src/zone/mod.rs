@@ -0,0 +1,370 @@
+pub mod catalog;
+pub mod parser;
+pub mod writer;
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use log::warn;
+
+use crate::dns::name::{is_subdomain, normalize, parent};
+use crate::dns::resource_record::{RecordType, ResourceRecord};
+use crate::zone::parser::{parse_zone_file, ZoneParseError};
+
+/// An authoritative zone held in memory, indexed by owner name.
+#[derive(Debug)]
+pub struct Zone {
+    origin: String,
+    nodes: HashMap<String, Vec<ResourceRecord>>,
+    // Every owner name plus its ancestors up to the apex, so empty
+    // non-terminals answer NODATA rather than NXDOMAIN.
+    names: HashSet<String>,
+}
+
+/// Outcome of looking a single name up in one zone.
+#[derive(Debug)]
+pub enum Lookup {
+    Answer(Vec<ResourceRecord>),
+    /// The name is an alias; the CNAME record is returned for the caller to chase.
+    Alias(ResourceRecord),
+    /// The name lies at or below a zone cut inside this zone.
+    Referral {
+        name_servers: Vec<ResourceRecord>,
+        glue: Vec<ResourceRecord>,
+    },
+    NoData,
+    NXDomain,
+}
+
+impl Zone {
+    pub fn new(origin: &str, records: Vec<ResourceRecord>) -> Self {
+        let origin = normalize(origin);
+        let mut nodes: HashMap<String, Vec<ResourceRecord>> = HashMap::new();
+        let mut names = HashSet::new();
+
+        for record in records {
+            if !is_subdomain(&record.name, &origin) {
+                warn!(
+                    "Ignoring out-of-zone record {} in zone {}",
+                    record.name, origin
+                );
+                continue;
+            }
+            let key = normalize(&record.name);
+            let mut name = key.as_str();
+            while names.insert(name.to_string()) && name != origin {
+                name = parent(name).unwrap_or("");
+            }
+            nodes.entry(key).or_default().push(record);
+        }
+
+        Zone {
+            origin,
+            nodes,
+            names,
+        }
+    }
+
+    pub fn load(path: &Path, origin: &str) -> Result<Self, ZoneParseError> {
+        Ok(Zone::new(origin, parse_zone_file(path, origin)?))
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &ResourceRecord> {
+        self.nodes.values().flatten()
+    }
+
+    pub fn soa(&self) -> Option<&ResourceRecord> {
+        self.records_at(&self.origin, RecordType::SOA).next()
+    }
+
+    /// Records stored at `name` of the given type, ignoring zone cuts.
+    pub fn records_at<'a>(
+        &'a self,
+        name: &str,
+        record_type: RecordType,
+    ) -> impl Iterator<Item = &'a ResourceRecord> {
+        self.nodes
+            .get(&normalize(name))
+            .into_iter()
+            .flatten()
+            .filter(move |record| {
+                record_type == RecordType::ANY || record.record_type == record_type
+            })
+    }
+
+    pub fn lookup(&self, qname: &str, qtype: RecordType) -> Lookup {
+        let qname = normalize(qname);
+
+        if let Some(cut) = self.find_zone_cut(&qname, qtype) {
+            let name_servers: Vec<ResourceRecord> =
+                self.records_at(&cut, RecordType::NS).cloned().collect();
+            let glue = self.glue_for(&name_servers);
+            return Lookup::Referral { name_servers, glue };
+        }
+
+        let (node, synthesized) = match self.nodes.get(&qname) {
+            Some(node) => (node, false),
+            // An empty non-terminal exists, so no wildcard applies to it.
+            None if self.names.contains(&qname) => return Lookup::NoData,
+            None => match self.wildcard_for(&qname) {
+                Some(node) => (node, true),
+                None => return Lookup::NXDomain,
+            },
+        };
+
+        // Records synthesized from a wildcard take the query name as owner.
+        let owned = |record: &ResourceRecord| {
+            let mut record = record.clone();
+            if synthesized {
+                record.name = qname.clone();
+            }
+            record
+        };
+
+        // ANY is answered with what the node holds, a CNAME included, rather
+        // than by following the alias.
+        if qtype != RecordType::CNAME && qtype != RecordType::ANY {
+            if let Some(cname) = node.iter().find(|r| r.record_type == RecordType::CNAME) {
+                return Lookup::Alias(owned(cname));
+            }
+        }
+
+        let answers: Vec<ResourceRecord> = node
+            .iter()
+            .filter(|record| qtype == RecordType::ANY || record.record_type == qtype)
+            .map(owned)
+            .collect();
+        if answers.is_empty() {
+            Lookup::NoData
+        } else {
+            Lookup::Answer(answers)
+        }
+    }
+
+    // RFC 4592 section 3.3.1: the closest encloser is the longest existing
+    // ancestor of `qname` (empty non-terminals included), and only a wildcard
+    // directly beneath it may answer. Called only for names that do not exist.
+    fn wildcard_for(&self, qname: &str) -> Option<&Vec<ResourceRecord>> {
+        let mut closest_encloser = parent(qname)?;
+        while !self.names.contains(closest_encloser) {
+            if closest_encloser == self.origin {
+                return None;
+            }
+            closest_encloser = parent(closest_encloser)?;
+        }
+        self.nodes.get(&format!("*.{}", closest_encloser))
+    }
+
+    // Walks from just below the apex down to `qname` looking for NS records,
+    // which mark the top of a delegated child zone. DS records live on the
+    // parent side of the cut, so a DS query for the cut itself is answered here.
+    fn find_zone_cut(&self, qname: &str, qtype: RecordType) -> Option<String> {
+        let mut path = Vec::new();
+        let mut name = qname;
+        while name != self.origin {
+            path.push(name);
+            name = parent(name)?;
+        }
+
+        path.into_iter()
+            .rev()
+            .find(|&name| {
+                (name != qname || qtype != RecordType::DS)
+                    && self.records_at(name, RecordType::NS).next().is_some()
+            })
+            .map(str::to_string)
+    }
+
+    /// Address records for name servers whose names fall inside this zone.
+    pub fn glue_for(&self, name_servers: &[ResourceRecord]) -> Vec<ResourceRecord> {
+        let mut glue = Vec::new();
+        for target in name_servers.iter().filter_map(ResourceRecord::target_name) {
+            if !is_subdomain(&target, &self.origin) {
+                continue;
+            }
+            glue.extend(self.records_at(&target, RecordType::A).cloned());
+            glue.extend(self.records_at(&target, RecordType::AAAA).cloned());
+        }
+        glue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::parser::parse_zone_str;
+
+    fn load(text: &str) -> Zone {
+        Zone::new(
+            "example.test",
+            parse_zone_str(text, "example.test").unwrap(),
+        )
+    }
+
+    fn owners(records: &[ResourceRecord]) -> Vec<String> {
+        records
+            .iter()
+            .map(|record| format!("{} {}", record.name, record.record_type))
+            .collect()
+    }
+
+    const DELEGATIONS: &str = "\
+$TTL 3600
+@ SOA ns hostmaster 1 7200 900 1209600 300
+@ NS ns
+ns A 192.0.2.1
+child NS ns.child
+child NS ns.elsewhere.test.
+child NS ns.sibling
+child DS 12345 13 2 0123456789ABCDEF
+ns.child A 192.0.2.2
+ns.child AAAA 2001:db8::2
+www.child A 192.0.2.3
+ns.sibling A 192.0.2.4
+";
+
+    #[test]
+    fn refers_queries_below_a_zone_cut() {
+        let zone = load(DELEGATIONS);
+        let Lookup::Referral { name_servers, glue } =
+            zone.lookup("www.child.example.test", RecordType::A)
+        else {
+            panic!("expected a referral");
+        };
+        assert_eq!(name_servers.len(), 3);
+        assert!(name_servers.iter().all(|r| r.name == "child.example.test"));
+        // Data below the cut is occluded: the referral is all there is, with
+        // glue for the in-zone name servers and none for ns.elsewhere.test.
+        assert_eq!(
+            owners(&glue),
+            [
+                "ns.child.example.test A",
+                "ns.child.example.test AAAA",
+                "ns.sibling.example.test A"
+            ]
+        );
+        for qtype in [RecordType::NS, RecordType::A, RecordType::ANY] {
+            assert!(matches!(
+                zone.lookup("child.example.test", qtype),
+                Lookup::Referral { .. }
+            ));
+        }
+        assert!(matches!(
+            zone.lookup("missing.child.example.test", RecordType::A),
+            Lookup::Referral { .. }
+        ));
+    }
+
+    #[test]
+    fn answers_ds_at_a_cut_from_the_parent_side() {
+        let zone = load(DELEGATIONS);
+        let Lookup::Answer(records) = zone.lookup("child.example.test", RecordType::DS) else {
+            panic!("expected the DS RRset");
+        };
+        assert_eq!(owners(&records), ["child.example.test DS"]);
+        // Below the cut even DS belongs to the child.
+        assert!(matches!(
+            zone.lookup("sub.child.example.test", RecordType::DS),
+            Lookup::Referral { .. }
+        ));
+        // An unsigned delegation has no DS, and says so from the parent.
+        let unsigned = load(&DELEGATIONS.replace("child DS", "other DS"));
+        assert!(matches!(
+            unsigned.lookup("child.example.test", RecordType::DS),
+            Lookup::NoData
+        ));
+    }
+
+    #[test]
+    fn answers_for_the_apex_despite_its_ns_records() {
+        let zone = load(DELEGATIONS);
+        let Lookup::Answer(records) = zone.lookup("example.test", RecordType::NS) else {
+            panic!("expected the apex NS RRset");
+        };
+        assert_eq!(owners(&records), ["example.test NS"]);
+    }
+
+    // The example zone of RFC 4592 section 2.2.1.
+    const WILDCARDS: &str = "\
+$TTL 3600
+@ SOA ns.example.com. hostmaster 1 7200 900 1209600 300
+@ NS ns.example.com.
+@ NS ns.example.net.
+* TXT \"this is a wildcard\"
+* MX 10 host1
+sub.* TXT \"this is not a wildcard\"
+host1 A 192.0.2.1
+_ssh._tcp.host1 SRV 0 0 22 host1
+_ssh._tcp.host2 SRV 0 0 22 host2
+subdel NS ns.example.com.
+subdel NS ns.example.net.
+alias CNAME host1
+";
+
+    // RFC 4592 section 2.2.1 lists which names the wildcard answers for.
+    #[test]
+    fn synthesizes_from_the_wildcard_at_the_closest_encloser() {
+        let zone = load(WILDCARDS);
+        for qname in ["host3.example.test", "foo.bar.example.test"] {
+            let Lookup::Answer(records) = zone.lookup(qname, RecordType::MX) else {
+                panic!("expected {} to match the wildcard", qname);
+            };
+            assert_eq!(owners(&records), [format!("{} MX", qname)]);
+        }
+        // The closest encloser of these has no wildcard child.
+        assert!(matches!(
+            zone.lookup("_telnet._tcp.host1.example.test", RecordType::SRV),
+            Lookup::NXDomain
+        ));
+        assert!(matches!(
+            zone.lookup("ghost.*.example.test", RecordType::MX),
+            Lookup::NXDomain
+        ));
+    }
+
+    #[test]
+    fn answers_nodata_at_a_wildcard_without_the_type() {
+        let zone = load(WILDCARDS);
+        assert!(matches!(
+            zone.lookup("host3.example.test", RecordType::A),
+            Lookup::NoData
+        ));
+    }
+
+    #[test]
+    fn does_not_apply_the_wildcard_to_existing_names() {
+        let zone = load(WILDCARDS);
+        for qname in ["host1.example.test", "sub.*.example.test"] {
+            assert!(matches!(zone.lookup(qname, RecordType::MX), Lookup::NoData));
+        }
+        assert!(matches!(
+            zone.lookup("host.subdel.example.test", RecordType::MX),
+            Lookup::Referral { .. }
+        ));
+    }
+
+    #[test]
+    fn answers_nodata_at_empty_non_terminals() {
+        let zone = load(WILDCARDS);
+        for qname in ["_tcp.host1.example.test", "host2.example.test"] {
+            assert!(matches!(zone.lookup(qname, RecordType::MX), Lookup::NoData));
+        }
+    }
+
+    #[test]
+    fn answers_any_with_the_records_at_an_alias() {
+        let zone = load(WILDCARDS);
+        let Lookup::Answer(records) = zone.lookup("alias.example.test", RecordType::ANY) else {
+            panic!("expected the records at the alias");
+        };
+        assert_eq!(owners(&records), ["alias.example.test CNAME"]);
+        assert!(matches!(
+            zone.lookup("alias.example.test", RecordType::A),
+            Lookup::Alias(_)
+        ));
+    }
+}
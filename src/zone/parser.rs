@@ -0,0 +1,868 @@
+use std::fmt;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use crate::dns::name::encode_name;
+use crate::dns::resource_record::{RecordClass, RecordType, ResourceRecord};
+use crate::zone::writer::is_valid_rdata;
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum ZoneParseError {
+    Io {
+        file: String,
+        error: std::io::Error,
+    },
+    Syntax {
+        file: String,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ZoneParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZoneParseError::Io { file, error } => write!(f, "{}: {}", file, error),
+            ZoneParseError::Syntax {
+                file,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", file, line, message),
+        }
+    }
+}
+
+impl std::error::Error for ZoneParseError {}
+
+/// Reads an RFC 1035 master file from disk. `origin` is the initial `$ORIGIN`
+/// and is normally the zone apex.
+pub fn parse_zone_file(path: &Path, origin: &str) -> Result<Vec<ResourceRecord>, ZoneParseError> {
+    let mut parser = ZoneParser::new(origin);
+    parser.parse_file(path, 0)?;
+    Ok(parser.records)
+}
+
+/// Parses master file text held in memory. `$INCLUDE` paths are resolved
+/// relative to the current directory.
+pub fn parse_zone_str(input: &str, origin: &str) -> Result<Vec<ResourceRecord>, ZoneParseError> {
+    let mut parser = ZoneParser::new(origin);
+    parser.parse_text(input, "<input>", Path::new("."), 0)?;
+    Ok(parser.records)
+}
+
+struct ZoneParser {
+    origin: String,
+    default_ttl: Option<u32>,
+    last_owner: Option<String>,
+    last_ttl: Option<u32>,
+    last_class: RecordClass,
+    records: Vec<ResourceRecord>,
+}
+
+// A single token of a logical record. Escapes are kept verbatim so names and
+// character-strings can each decode them the way they need to.
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+// One logical record, which may span several physical lines in parentheses.
+struct Entry {
+    line: usize,
+    owner_omitted: bool,
+    tokens: Vec<Token>,
+}
+
+impl ZoneParser {
+    fn new(origin: &str) -> Self {
+        ZoneParser {
+            origin: origin.trim_end_matches('.').to_string(),
+            default_ttl: None,
+            last_owner: None,
+            last_ttl: None,
+            last_class: RecordClass::IN,
+            records: Vec::new(),
+        }
+    }
+
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<(), ZoneParseError> {
+        let file = path.display().to_string();
+        let input = fs::read_to_string(path).map_err(|error| ZoneParseError::Io {
+            file: file.clone(),
+            error,
+        })?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        self.parse_text(&input, &file, base, depth)
+    }
+
+    fn parse_text(
+        &mut self,
+        input: &str,
+        file: &str,
+        base: &Path,
+        depth: usize,
+    ) -> Result<(), ZoneParseError> {
+        let error = |line: usize, message: String| ZoneParseError::Syntax {
+            file: file.to_string(),
+            line,
+            message,
+        };
+
+        for entry in tokenize(input).map_err(|(line, message)| error(line, message))? {
+            let line = entry.line;
+            match entry.tokens[0].text.as_str() {
+                "$ORIGIN" if !entry.owner_omitted => {
+                    let name = entry
+                        .tokens
+                        .get(1)
+                        .ok_or_else(|| error(line, "$ORIGIN requires a name".into()))?;
+                    self.origin = self.resolve_name(&name.text).map_err(|m| error(line, m))?;
+                }
+                "$TTL" if !entry.owner_omitted => {
+                    let ttl = entry
+                        .tokens
+                        .get(1)
+                        .ok_or_else(|| error(line, "$TTL requires a value".into()))?;
+                    self.default_ttl = Some(parse_ttl(&ttl.text).map_err(|m| error(line, m))?);
+                }
+                "$INCLUDE" if !entry.owner_omitted => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(error(line, "$INCLUDE nested too deeply".into()));
+                    }
+                    let target = entry
+                        .tokens
+                        .get(1)
+                        .ok_or_else(|| error(line, "$INCLUDE requires a file name".into()))?;
+                    let file_name = decode_text(&target.text).map_err(|m| error(line, m))?;
+                    let path = base.join(String::from_utf8_lossy(&file_name).as_ref());
+                    let include_origin = match entry.tokens.get(2) {
+                        Some(origin) => self
+                            .resolve_name(&origin.text)
+                            .map_err(|m| error(line, m))?,
+                        None => self.origin.clone(),
+                    };
+                    // The included file must not change the origin or owner of
+                    // the file that included it (RFC 1035 section 5.1).
+                    let saved_origin = std::mem::replace(&mut self.origin, include_origin);
+                    let saved_owner = self.last_owner.clone();
+                    self.parse_file(&path, depth + 1)?;
+                    self.origin = saved_origin;
+                    self.last_owner = saved_owner;
+                }
+                directive if directive.starts_with('$') && !entry.owner_omitted => {
+                    return Err(error(line, format!("unsupported directive {}", directive)));
+                }
+                _ => {
+                    let record = self.parse_record(&entry).map_err(|m| error(line, m))?;
+                    self.records.push(record);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_record(&mut self, entry: &Entry) -> Result<ResourceRecord, String> {
+        let mut tokens = entry.tokens.iter();
+
+        let owner = if entry.owner_omitted {
+            self.last_owner
+                .clone()
+                .ok_or("record has no owner and there is no previous owner")?
+        } else {
+            let token = tokens.next().unwrap();
+            self.resolve_name(&token.text)?
+        };
+
+        // TTL and class may appear in either order before the type.
+        let mut ttl = None;
+        let mut class = None;
+        let record_type = loop {
+            let token = tokens.next().ok_or("missing record type")?;
+            if token.quoted {
+                return Err(format!("unexpected quoted string {:?}", token.text));
+            }
+            if ttl.is_none() && token.text.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(&token.text)?);
+            } else if let (None, Some(parsed)) = (class, RecordClass::from_name(&token.text)) {
+                class = Some(parsed);
+            } else if let Some(parsed) = RecordType::from_name(&token.text) {
+                break parsed;
+            } else {
+                return Err(format!("unknown record type {}", token.text));
+            }
+        };
+
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or("no TTL specified and no $TTL default")?;
+        let class = class.unwrap_or(self.last_class);
+
+        let rdata: Vec<&Token> = tokens.collect();
+        let data = self.parse_rdata(record_type, &rdata)?;
+        if data.len() > u16::MAX as usize {
+            return Err("RDATA too long".into());
+        }
+
+        self.last_owner = Some(owner.clone());
+        self.last_ttl = Some(ttl);
+        self.last_class = class;
+
+        Ok(ResourceRecord::new(owner, record_type, class, ttl, data))
+    }
+
+    fn parse_rdata(&self, record_type: RecordType, tokens: &[&Token]) -> Result<Vec<u8>, String> {
+        if tokens.first().is_some_and(|t| !t.quoted && t.text == "\\#") {
+            // Generic RDATA for a known type must still be valid for it
+            // (RFC 3597 section 5).
+            let data = parse_generic_rdata(&tokens[1..])?;
+            if !is_valid_rdata(record_type, &data) {
+                return Err(format!(
+                    "generic RDATA is not a valid {} record",
+                    record_type
+                ));
+            }
+            return Ok(data);
+        }
+
+        let mut fields = RdataFields {
+            tokens,
+            index: 0,
+            parser: self,
+        };
+        let mut data = Vec::new();
+
+        match record_type {
+            RecordType::A => {
+                let address: Ipv4Addr =
+                    fields.next()?.parse().map_err(|_| "invalid IPv4 address")?;
+                data.extend_from_slice(&address.octets());
+            }
+            RecordType::AAAA => {
+                let address: Ipv6Addr =
+                    fields.next()?.parse().map_err(|_| "invalid IPv6 address")?;
+                data.extend_from_slice(&address.octets());
+            }
+            RecordType::NS
+            | RecordType::CNAME
+            | RecordType::PTR
+            | RecordType::DNAME
+            | RecordType::MB
+            | RecordType::MD
+            | RecordType::MF
+            | RecordType::MG
+            | RecordType::MR => fields.name(&mut data)?,
+            RecordType::MX | RecordType::AFSDB | RecordType::RT | RecordType::KX => {
+                fields.u16(&mut data)?;
+                fields.name(&mut data)?;
+            }
+            RecordType::MINFO | RecordType::RP => {
+                fields.name(&mut data)?;
+                fields.name(&mut data)?;
+            }
+            RecordType::SOA => {
+                fields.name(&mut data)?;
+                fields.name(&mut data)?;
+                let serial: u32 = fields.next()?.parse().map_err(|_| "invalid SOA serial")?;
+                data.extend_from_slice(&serial.to_be_bytes());
+                for _ in 0..4 {
+                    data.extend_from_slice(&parse_ttl(fields.next()?)?.to_be_bytes());
+                }
+            }
+            RecordType::TXT | RecordType::SPF => {
+                fields.character_string(&mut data)?;
+                while !fields.is_empty() {
+                    fields.character_string(&mut data)?;
+                }
+            }
+            RecordType::HINFO => {
+                fields.character_string(&mut data)?;
+                fields.character_string(&mut data)?;
+            }
+            RecordType::SRV => {
+                fields.u16(&mut data)?;
+                fields.u16(&mut data)?;
+                fields.u16(&mut data)?;
+                fields.name(&mut data)?;
+            }
+            RecordType::NAPTR => {
+                fields.u16(&mut data)?;
+                fields.u16(&mut data)?;
+                fields.character_string(&mut data)?;
+                fields.character_string(&mut data)?;
+                fields.character_string(&mut data)?;
+                fields.name(&mut data)?;
+            }
+            RecordType::CAA => {
+                fields.u8(&mut data)?;
+                let tag = decode_text(fields.next()?)?;
+                if tag.is_empty() || tag.len() > 255 {
+                    return Err("invalid CAA tag".into());
+                }
+                data.push(tag.len() as u8);
+                data.extend_from_slice(&tag);
+                data.extend_from_slice(&decode_text(fields.next()?)?);
+            }
+            RecordType::SSHFP => {
+                fields.u8(&mut data)?;
+                fields.u8(&mut data)?;
+                fields.hex(&mut data)?;
+            }
+            RecordType::TLSA | RecordType::SMIMEA => {
+                fields.u8(&mut data)?;
+                fields.u8(&mut data)?;
+                fields.u8(&mut data)?;
+                fields.hex(&mut data)?;
+            }
+            RecordType::DS | RecordType::CDS => {
+                fields.u16(&mut data)?;
+                fields.u8(&mut data)?;
+                fields.u8(&mut data)?;
+                fields.hex(&mut data)?;
+            }
+            other => {
+                return Err(format!(
+                    "no presentation format support for {} records, use \\# generic RDATA",
+                    other
+                ))
+            }
+        }
+
+        if !fields.is_empty() {
+            return Err(format!("trailing data in {} record", record_type));
+        }
+        Ok(data)
+    }
+
+    /// Turns a name as written in the file into an absolute name.
+    fn resolve_name(&self, text: &str) -> Result<String, String> {
+        if text == "@" {
+            return Ok(self.origin.clone());
+        }
+        if text == "." {
+            return Ok(String::new());
+        }
+
+        let absolute = text.ends_with('.') && !text.ends_with("\\.");
+        let text = if absolute {
+            &text[..text.len() - 1]
+        } else {
+            text
+        };
+        let mut labels = Vec::new();
+        for raw in split_labels(text) {
+            let label = decode_text(raw)?;
+            if label.is_empty() {
+                return Err(format!("empty label in name {:?}", text));
+            }
+            if label.len() > 63 {
+                return Err(format!("label too long in name {:?}", text));
+            }
+            if label.contains(&b'.') {
+                return Err(format!(
+                    "escaped dots in labels are not supported: {:?}",
+                    text
+                ));
+            }
+            labels.push(String::from_utf8(label).map_err(|_| format!("invalid name {:?}", text))?);
+        }
+
+        let mut name = labels.join(".");
+        if !absolute && !self.origin.is_empty() {
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(&self.origin);
+        }
+        if name.len() > 253 {
+            return Err(format!("name too long: {:?}", name));
+        }
+        Ok(name)
+    }
+}
+
+// Cursor over the RDATA tokens of a record that appends wire-format fields.
+struct RdataFields<'a> {
+    tokens: &'a [&'a Token],
+    index: usize,
+    parser: &'a ZoneParser,
+}
+
+impl RdataFields<'_> {
+    fn next(&mut self) -> Result<&str, String> {
+        let token = self.tokens.get(self.index).ok_or("missing RDATA field")?;
+        self.index += 1;
+        Ok(&token.text)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.index >= self.tokens.len()
+    }
+
+    fn u8(&mut self, data: &mut Vec<u8>) -> Result<(), String> {
+        let text = self.next()?;
+        let value: u8 = text
+            .parse()
+            .map_err(|_| format!("invalid 8-bit value {:?}", text))?;
+        data.push(value);
+        Ok(())
+    }
+
+    fn u16(&mut self, data: &mut Vec<u8>) -> Result<(), String> {
+        let text = self.next()?;
+        let value: u16 = text
+            .parse()
+            .map_err(|_| format!("invalid 16-bit value {:?}", text))?;
+        data.extend_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    fn name(&mut self, data: &mut Vec<u8>) -> Result<(), String> {
+        let parser = self.parser;
+        let name = parser.resolve_name(self.next()?)?;
+        encode_name(&name, data);
+        Ok(())
+    }
+
+    fn character_string(&mut self, data: &mut Vec<u8>) -> Result<(), String> {
+        let bytes = decode_text(self.next()?)?;
+        if bytes.len() > 255 {
+            return Err("character-string longer than 255 bytes".into());
+        }
+        data.push(bytes.len() as u8);
+        data.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    // Hex fields may be split over several tokens and run to the end of RDATA.
+    fn hex(&mut self, data: &mut Vec<u8>) -> Result<(), String> {
+        let mut hex = String::new();
+        while !self.is_empty() {
+            hex.push_str(self.next()?);
+        }
+        data.extend_from_slice(&decode_hex(&hex)?);
+        Ok(())
+    }
+}
+
+// RFC 3597 section 5: `\# <length> <hex>...`
+fn parse_generic_rdata(tokens: &[&Token]) -> Result<Vec<u8>, String> {
+    let length: usize = tokens
+        .first()
+        .ok_or("missing length in generic RDATA")?
+        .text
+        .parse()
+        .map_err(|_| "invalid length in generic RDATA")?;
+    let hex: String = tokens[1..].iter().map(|t| t.text.as_str()).collect();
+    let data = decode_hex(&hex)?;
+    if data.len() != length {
+        return Err(format!(
+            "generic RDATA length {} does not match {} bytes of data",
+            length,
+            data.len()
+        ));
+    }
+    Ok(data)
+}
+
+/// Parses a TTL given in seconds or with BIND style units such as `1h30m`.
+pub fn parse_ttl(text: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid TTL {:?}", text);
+    if let Ok(seconds) = text.parse::<u32>() {
+        return Ok(seconds);
+    }
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        total += value * multiplier;
+        number.clear();
+    }
+    if !number.is_empty() || total > u32::MAX as u64 {
+        return Err(invalid());
+    }
+    Ok(total as u32)
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hex data {:?}", hex))
+        })
+        .collect()
+}
+
+/// Decodes `\X` and `\DDD` escapes in a name label or character-string.
+fn decode_text(text: &str) -> Result<Vec<u8>, String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes.get(i + 1..i + 4) {
+            Some(digits) if digits.iter().all(u8::is_ascii_digit) => {
+                let value: u16 = std::str::from_utf8(digits).unwrap().parse().unwrap();
+                if value > 255 {
+                    return Err(format!("invalid escape in {:?}", text));
+                }
+                out.push(value as u8);
+                i += 4;
+            }
+            _ => {
+                let escaped = bytes
+                    .get(i + 1)
+                    .ok_or_else(|| format!("dangling backslash in {:?}", text))?;
+                out.push(*escaped);
+                i += 2;
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Splits a name on unescaped dots.
+fn split_labels(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let bytes = text.as_bytes();
+    let mut labels = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'.' => {
+                labels.push(&text[start..i]);
+                i += 1;
+                start = i;
+            }
+            _ => i += 1,
+        }
+    }
+    labels.push(&text[start.min(text.len())..]);
+    labels
+}
+
+// Splits master file text into logical records, joining parenthesised
+// continuation lines and dropping comments.
+fn tokenize(input: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    let mut depth = 0usize;
+    let mut paren_line = 0;
+
+    for (line_index, line) in input.lines().enumerate() {
+        let line_number = line_index + 1;
+        let mut chars = line.char_indices().peekable();
+
+        if depth == 0 {
+            if let Some(entry) = current.take() {
+                if !entry.tokens.is_empty() {
+                    entries.push(entry);
+                }
+            }
+            current = Some(Entry {
+                line: line_number,
+                owner_omitted: line.starts_with([' ', '\t']),
+                tokens: Vec::new(),
+            });
+        }
+        let entry = current.as_mut().unwrap();
+
+        while let Some((_, c)) = chars.next() {
+            match c {
+                ' ' | '\t' | '\r' => {}
+                ';' => break,
+                '(' => {
+                    if depth == 0 {
+                        paren_line = line_number;
+                    }
+                    depth += 1;
+                }
+                ')' => {
+                    depth = depth
+                        .checked_sub(1)
+                        .ok_or((line_number, "unbalanced ')'".to_string()))?;
+                }
+                '"' => {
+                    let mut text = String::new();
+                    let mut closed = false;
+                    while let Some((_, c)) = chars.next() {
+                        match c {
+                            '"' => {
+                                closed = true;
+                                break;
+                            }
+                            '\\' => {
+                                text.push(c);
+                                if let Some((_, escaped)) = chars.next() {
+                                    text.push(escaped);
+                                }
+                            }
+                            _ => text.push(c),
+                        }
+                    }
+                    if !closed {
+                        return Err((line_number, "unterminated quoted string".into()));
+                    }
+                    entry.tokens.push(Token { text, quoted: true });
+                }
+                _ => {
+                    let mut text = String::from(c);
+                    let mut escaped = c == '\\';
+                    while let Some(&(_, next)) = chars.peek() {
+                        if !escaped && matches!(next, ' ' | '\t' | '\r' | ';' | '(' | ')' | '"') {
+                            break;
+                        }
+                        escaped = !escaped && next == '\\';
+                        text.push(next);
+                        chars.next();
+                    }
+                    entry.tokens.push(Token {
+                        text,
+                        quoted: false,
+                    });
+                }
+            }
+        }
+    }
+
+    if depth > 0 {
+        return Err((paren_line, "unbalanced '('".into()));
+    }
+    if let Some(entry) = current {
+        if !entry.tokens.is_empty() {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn parse(text: &str) -> Vec<ResourceRecord> {
+        parse_zone_str(text, "example.test").unwrap()
+    }
+
+    fn error(text: &str) -> String {
+        parse_zone_str(text, "example.test")
+            .unwrap_err()
+            .to_string()
+    }
+
+    fn name(text: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        encode_name(text, &mut data);
+        data
+    }
+
+    // A directory of its own for each test that writes include files.
+    fn directory(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tinydns-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn applies_origin_and_ttl_directives() {
+        let records = parse(
+            "\
+$TTL 1h
+www A 192.0.2.1
+$ORIGIN sub.example.test.
+host 60 A 192.0.2.2
+other.test. AAAA 2001:db8::1
+@ CNAME host
+",
+        );
+        let owners: Vec<&str> = records.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            owners,
+            [
+                "www.example.test",
+                "host.sub.example.test",
+                "other.test",
+                "sub.example.test"
+            ]
+        );
+        let ttls: Vec<u32> = records.iter().map(|r| r.ttl).collect();
+        assert_eq!(ttls, [3600, 60, 3600, 3600]);
+        assert_eq!(records[3].data, name("host.sub.example.test"));
+    }
+
+    #[test]
+    fn inherits_owner_ttl_and_class() {
+        let records = parse(
+            "\
+www 300 CH TXT \"one\"
+    TXT \"two\"
+mail IN 600 A 192.0.2.1
+     A 192.0.2.2
+",
+        );
+        assert_eq!(records[1].name, "www.example.test");
+        assert_eq!(records[1].ttl, 300);
+        assert_eq!(records[1].class, RecordClass::CH);
+        assert_eq!(records[3].name, "mail.example.test");
+        assert_eq!(records[3].ttl, 600);
+        assert_eq!(records[3].class, RecordClass::IN);
+    }
+
+    #[test]
+    fn prefers_the_ttl_directive_over_the_previous_ttl() {
+        let records = parse("www 300 A 192.0.2.1\n$TTL 60\nmail A 192.0.2.2\n");
+        assert_eq!(records[1].ttl, 60);
+    }
+
+    #[test]
+    fn joins_parentheses_and_drops_comments() {
+        let records = parse(
+            "\
+@ 3600 SOA ns hostmaster ( ; the apex
+        2024010101 ; serial
+        1h 15m 1w
+        300 )
+; a comment on its own line
+www 3600 A 192.0.2.1 ; trailing comment
+",
+        );
+        assert_eq!(records.len(), 2);
+        let soa = &records[0].data;
+        let times = &soa[soa.len() - 20..];
+        assert_eq!(times[..4], 2024010101u32.to_be_bytes());
+        assert_eq!(times[16..], 300u32.to_be_bytes());
+        assert_eq!(records[1].name, "www.example.test");
+    }
+
+    #[test]
+    fn reads_quoted_strings() {
+        let records = parse("@ 60 TXT \"a; (b)\" \"say \\\"hi\\\"\" \"\\065\" plain\n");
+        assert_eq!(
+            records[0].data,
+            b"\x06a; (b)\x08say \"hi\"\x01A\x05plain".to_vec()
+        );
+        assert!(error("@ 60 TXT \"open\n").contains("unterminated quoted string"));
+    }
+
+    #[test]
+    fn reads_numbered_types_and_classes() {
+        let records = parse("@ 60 CLASS32 TYPE65280 \\# 3 010203\n@ 60 TYPE1 1.2.3.4\n");
+        assert_eq!(records[0].record_type.to_u16(), 65280);
+        assert_eq!(records[0].class.to_u16(), 32);
+        assert_eq!(records[0].data, [1, 2, 3]);
+        assert_eq!(records[1].record_type, RecordType::A);
+        assert_eq!(records[1].class.to_u16(), 32);
+    }
+
+    #[test]
+    fn checks_generic_rdata_of_known_types() {
+        let records = parse("@ 60 A \\# 4 C0000201\n");
+        assert_eq!(records[0].data, [192, 0, 2, 1]);
+        assert_eq!(
+            error("@ 60 A \\# 3 C00002\n"),
+            "<input>:1: generic RDATA is not a valid A record"
+        );
+        assert!(error("@ 60 MX \\# 3 000A03\n").contains("not a valid MX record"));
+        assert!(error("@ 60 TYPE65280 \\# 2 01\n").contains("does not match"));
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        assert_eq!(
+            error("www 60 A 192.0.2.1\n\nwww 60 BOGUS x\n"),
+            "<input>:3: unknown record type BOGUS"
+        );
+        assert_eq!(
+            error("www 60 A 192.0.2.1\n@ 60 SOA ns host (\n1 2 3 4 5\n"),
+            "<input>:2: unbalanced '('"
+        );
+        assert_eq!(
+            error("\n    A 192.0.2.1\n"),
+            "<input>:2: record has no owner and there is no previous owner"
+        );
+        assert_eq!(
+            error("www A 192.0.2.1\n"),
+            "<input>:1: no TTL specified and no $TTL default"
+        );
+        assert_eq!(
+            error("$GENERATE 1-2 x A 1.2.3.4\n"),
+            "<input>:1: unsupported directive $GENERATE"
+        );
+    }
+
+    #[test]
+    fn includes_files_with_their_own_origin() {
+        let dir = directory("include");
+        fs::write(dir.join("hosts.inc"), "host 60 A 192.0.2.1\n").unwrap();
+        fs::write(
+            dir.join("zone"),
+            "\
+www 60 A 192.0.2.1
+$INCLUDE hosts.inc sub.example.test.
+    60 A 192.0.2.2
+$INCLUDE \"hosts.inc\"
+",
+        )
+        .unwrap();
+        let result = parse_zone_file(&dir.join("zone"), "example.test");
+        fs::remove_dir_all(&dir).unwrap();
+        let records = result.unwrap();
+        let owners: Vec<&str> = records.iter().map(|r| r.name.as_str()).collect();
+        // Neither the origin nor the owner of the included file carry over.
+        assert_eq!(
+            owners,
+            [
+                "www.example.test",
+                "host.sub.example.test",
+                "www.example.test",
+                "host.example.test"
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_include_depth() {
+        let dir = directory("depth");
+        fs::write(dir.join("loop"), "$INCLUDE loop\n").unwrap();
+        let result = parse_zone_file(&dir.join("loop"), "example.test");
+        fs::remove_dir_all(&dir).unwrap();
+        let message = result.unwrap_err().to_string();
+        assert!(
+            message.ends_with("loop:1: $INCLUDE nested too deeply"),
+            "{}",
+            message
+        );
+    }
+}
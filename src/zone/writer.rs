@@ -0,0 +1,468 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::dns::name::{labels, parse_name};
+use crate::dns::resource_record::{RecordType, ResourceRecord};
+
+/// Renders `records` as a canonical master file for the zone at `origin`.
+///
+/// Records are sorted in DNSSEC canonical name order (RFC 4034 section 6.1)
+/// with the apex SOA first, so two dumps of the same data are byte-identical
+/// regardless of load order. Names under `origin` are written relative to it.
+pub fn write_zone(origin: &str, records: &[ResourceRecord]) -> String {
+    let origin = origin.trim_end_matches('.');
+    let default_ttl = most_common_ttl(records);
+
+    // RDATA sorts in canonical form (RFC 4034 section 6.2), where names are
+    // lower case. Whatever that leaves equal is ordered by the data as given
+    // so the duplicate that survives does not depend on load order.
+    let mut sorted: Vec<(&ResourceRecord, Vec<u8>)> = records
+        .iter()
+        .map(|record| (record, record.canonical_data()))
+        .collect();
+    sorted.sort_by(|(a, a_data), (b, b_data)| {
+        canonical_name_cmp(&a.name, &b.name)
+            .then_with(|| type_rank(a.record_type).cmp(&type_rank(b.record_type)))
+            .then_with(|| a.class.to_u16().cmp(&b.class.to_u16()))
+            .then_with(|| a_data.cmp(b_data))
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.data.cmp(&b.data))
+            .then_with(|| a.ttl.cmp(&b.ttl))
+    });
+    sorted.dedup_by(|(a, a_data), (b, b_data)| {
+        a.name.eq_ignore_ascii_case(&b.name)
+            && a.record_type == b.record_type
+            && a.class == b.class
+            && a_data == b_data
+    });
+
+    let mut out = String::new();
+    writeln!(out, "$ORIGIN {}", absolute(origin)).unwrap();
+    if let Some(ttl) = default_ttl {
+        writeln!(out, "$TTL {}", ttl).unwrap();
+    }
+
+    for (record, _) in sorted {
+        out.push_str(&relative(&record.name, origin));
+        if Some(record.ttl) != default_ttl {
+            write!(out, " {}", record.ttl).unwrap();
+        }
+        write!(out, " {} {} ", record.class, record.record_type).unwrap();
+        out.push_str(&rdata_to_text(record, origin));
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Formats the RDATA of `record` in presentation form, falling back to the
+/// RFC 3597 generic encoding for types without a known text format or when
+/// the wire data is malformed.
+pub fn rdata_to_text(record: &ResourceRecord, origin: &str) -> String {
+    known_rdata_to_text(record.record_type, &record.data, origin)
+        .unwrap_or_else(|| generic_rdata(&record.data))
+}
+
+/// Whether `data` is well-formed RDATA for `record_type`. Types without a
+/// presentation format here are opaque, so any RDATA passes for them.
+pub fn is_valid_rdata(record_type: RecordType, data: &[u8]) -> bool {
+    !has_text_format(record_type) || known_rdata_to_text(record_type, data, "").is_some()
+}
+
+fn has_text_format(record_type: RecordType) -> bool {
+    matches!(
+        record_type,
+        RecordType::A
+            | RecordType::AAAA
+            | RecordType::NS
+            | RecordType::CNAME
+            | RecordType::PTR
+            | RecordType::DNAME
+            | RecordType::MB
+            | RecordType::MD
+            | RecordType::MF
+            | RecordType::MG
+            | RecordType::MR
+            | RecordType::MX
+            | RecordType::AFSDB
+            | RecordType::RT
+            | RecordType::KX
+            | RecordType::MINFO
+            | RecordType::RP
+            | RecordType::SOA
+            | RecordType::TXT
+            | RecordType::SPF
+            | RecordType::HINFO
+            | RecordType::SRV
+            | RecordType::NAPTR
+            | RecordType::CAA
+            | RecordType::SSHFP
+            | RecordType::TLSA
+            | RecordType::SMIMEA
+            | RecordType::DS
+            | RecordType::CDS
+    )
+}
+
+fn known_rdata_to_text(record_type: RecordType, data: &[u8], origin: &str) -> Option<String> {
+    let mut fields = WireFields {
+        data,
+        index: 0,
+        origin,
+    };
+    let mut parts: Vec<String> = Vec::new();
+
+    match record_type {
+        RecordType::A => {
+            let octets: [u8; 4] = data.try_into().ok()?;
+            fields.index = 4;
+            parts.push(Ipv4Addr::from(octets).to_string());
+        }
+        RecordType::AAAA => {
+            let octets: [u8; 16] = data.try_into().ok()?;
+            fields.index = 16;
+            parts.push(Ipv6Addr::from(octets).to_string());
+        }
+        RecordType::NS
+        | RecordType::CNAME
+        | RecordType::PTR
+        | RecordType::DNAME
+        | RecordType::MB
+        | RecordType::MD
+        | RecordType::MF
+        | RecordType::MG
+        | RecordType::MR => parts.push(fields.name()?),
+        RecordType::MX | RecordType::AFSDB | RecordType::RT | RecordType::KX => {
+            parts.push(fields.u16()?.to_string());
+            parts.push(fields.name()?);
+        }
+        RecordType::MINFO | RecordType::RP => {
+            parts.push(fields.name()?);
+            parts.push(fields.name()?);
+        }
+        RecordType::SOA => {
+            parts.push(fields.name()?);
+            parts.push(fields.name()?);
+            for _ in 0..5 {
+                parts.push(fields.u32()?.to_string());
+            }
+        }
+        RecordType::TXT | RecordType::SPF => {
+            parts.push(fields.character_string()?);
+            while !fields.is_empty() {
+                parts.push(fields.character_string()?);
+            }
+        }
+        RecordType::HINFO => {
+            parts.push(fields.character_string()?);
+            parts.push(fields.character_string()?);
+        }
+        RecordType::SRV => {
+            for _ in 0..3 {
+                parts.push(fields.u16()?.to_string());
+            }
+            parts.push(fields.name()?);
+        }
+        RecordType::NAPTR => {
+            parts.push(fields.u16()?.to_string());
+            parts.push(fields.u16()?.to_string());
+            for _ in 0..3 {
+                parts.push(fields.character_string()?);
+            }
+            parts.push(fields.name()?);
+        }
+        RecordType::CAA => {
+            parts.push(fields.u8()?.to_string());
+            let tag_length = fields.u8()? as usize;
+            let tag = fields.bytes(tag_length)?;
+            if tag.is_empty() || !tag.iter().all(u8::is_ascii_alphanumeric) {
+                return None;
+            }
+            parts.push(String::from_utf8_lossy(tag).into_owned());
+            let value = fields.bytes(data.len() - fields.index)?;
+            parts.push(quote(value));
+        }
+        RecordType::SSHFP => {
+            parts.push(fields.u8()?.to_string());
+            parts.push(fields.u8()?.to_string());
+            parts.push(fields.hex_rest()?);
+        }
+        RecordType::TLSA | RecordType::SMIMEA => {
+            for _ in 0..3 {
+                parts.push(fields.u8()?.to_string());
+            }
+            parts.push(fields.hex_rest()?);
+        }
+        RecordType::DS | RecordType::CDS => {
+            parts.push(fields.u16()?.to_string());
+            parts.push(fields.u8()?.to_string());
+            parts.push(fields.u8()?.to_string());
+            parts.push(fields.hex_rest()?);
+        }
+        _ => return None,
+    }
+
+    if !fields.is_empty() {
+        return None;
+    }
+    Some(parts.join(" "))
+}
+
+// Cursor over wire-format RDATA; every accessor returns `None` on truncation
+// so the caller can fall back to generic RDATA.
+struct WireFields<'a> {
+    data: &'a [u8],
+    index: usize,
+    origin: &'a str,
+}
+
+impl<'a> WireFields<'a> {
+    fn is_empty(&self) -> bool {
+        self.index >= self.data.len()
+    }
+
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.index..self.index + length)?;
+        self.index += length;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.bytes(4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn name(&mut self) -> Option<String> {
+        // Names stored in RDATA are uncompressed, so a pointer means the
+        // record was copied out of a message without being decompressed.
+        if self.data.get(self.index)? & 0xC0 != 0 {
+            return None;
+        }
+        let (name, next) = parse_name(self.data, self.index).ok()?;
+        self.index = next;
+        Some(relative(&name, self.origin))
+    }
+
+    fn character_string(&mut self) -> Option<String> {
+        let length = self.u8()? as usize;
+        Some(quote(self.bytes(length)?))
+    }
+
+    fn hex_rest(&mut self) -> Option<String> {
+        let rest = self.bytes(self.data.len() - self.index)?;
+        if rest.is_empty() {
+            return None;
+        }
+        Some(hex(rest))
+    }
+}
+
+fn generic_rdata(data: &[u8]) -> String {
+    if data.is_empty() {
+        "\\# 0".to_string()
+    } else {
+        format!("\\# {} {}", data.len(), hex(data))
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            0x20..=0x7E => out.push(b as char),
+            _ => write!(out, "\\{:03}", b).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Escapes characters that would otherwise be read as master file syntax.
+fn escape_label(label: &str) -> String {
+    let mut out = String::new();
+    for &b in label.as_bytes() {
+        match b {
+            b'"' | b'\\' | b'(' | b')' | b';' | b'@' | b'$' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            0x21..=0x7E => out.push(b as char),
+            _ => write!(out, "\\{:03}", b).unwrap(),
+        }
+    }
+    out
+}
+
+fn absolute(name: &str) -> String {
+    let escaped: Vec<String> = labels(name).map(escape_label).collect();
+    format!("{}.", escaped.join("."))
+}
+
+/// Writes `name` relative to `origin` when it lies inside it.
+fn relative(name: &str, origin: &str) -> String {
+    if name.eq_ignore_ascii_case(origin) {
+        return "@".to_string();
+    }
+    let suffix_start = name.len().checked_sub(origin.len() + 1);
+    match suffix_start {
+        Some(start)
+            if !origin.is_empty()
+                && name.as_bytes()[start] == b'.'
+                && name[start + 1..].eq_ignore_ascii_case(origin) =>
+        {
+            let escaped: Vec<String> = labels(&name[..start]).map(escape_label).collect();
+            escaped.join(".")
+        }
+        _ if origin.is_empty() && !name.is_empty() => {
+            let escaped: Vec<String> = labels(name).map(escape_label).collect();
+            escaped.join(".")
+        }
+        _ => absolute(name),
+    }
+}
+
+/// Orders names by their labels from the root down, case-insensitively.
+pub fn canonical_name_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_labels = labels(a).rev();
+    let mut b_labels = labels(b).rev();
+    loop {
+        match (a_labels.next(), b_labels.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => {
+                let ordering = x
+                    .bytes()
+                    .map(|c| c.to_ascii_lowercase())
+                    .cmp(y.bytes().map(|c| c.to_ascii_lowercase()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+// SOA leads each owner so the apex reads the way zone files are written by hand.
+fn type_rank(record_type: RecordType) -> u32 {
+    match record_type {
+        RecordType::SOA => 0,
+        other => other.to_u16() as u32 + 1,
+    }
+}
+
+fn most_common_ttl(records: &[ResourceRecord]) -> Option<u32> {
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for record in records {
+        *counts.entry(record.ttl).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|(ttl_a, count_a), (ttl_b, count_b)| {
+            count_a.cmp(count_b).then_with(|| ttl_b.cmp(ttl_a))
+        })
+        .map(|(ttl, _)| ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::parser::parse_zone_str;
+
+    const ZONE: &str = "\
+$TTL 3600
+@ SOA ns hostmaster 1 7200 900 1209600 300
+@ NS ns
+@ NS ns.other.test.
+@ MX 10 mail
+@ TXT \"v=spf1 -all\" \"quote \\\" and \\\\ backslash\"
+ns A 192.0.2.1
+mail 300 AAAA 2001:db8::25
+_sip._udp SRV 10 20 5060 sip
+sip CH TXT \"chaos\"
+caa CAA 0 issue \"ca.test\"
+private TYPE65280 \\# 3 010203
+empty TYPE65281 \\# 0
+*.wild CNAME www.other.test.
+";
+
+    fn key(record: &ResourceRecord) -> (String, u16, u16, u32, Vec<u8>) {
+        (
+            record.name.clone(),
+            record.record_type.to_u16(),
+            record.class.to_u16(),
+            record.ttl,
+            record.data.clone(),
+        )
+    }
+
+    fn sorted(records: &[ResourceRecord]) -> Vec<(String, u16, u16, u32, Vec<u8>)> {
+        let mut keys: Vec<_> = records.iter().map(key).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn parses_back_to_the_same_records() {
+        let records = parse_zone_str(ZONE, "example.test").unwrap();
+        let text = write_zone("example.test", &records);
+        let parsed = parse_zone_str(&text, "example.test").unwrap();
+        assert_eq!(sorted(&parsed), sorted(&records));
+        assert_eq!(write_zone("example.test", &parsed), text);
+    }
+
+    #[test]
+    fn writes_the_same_text_whatever_the_load_order() {
+        let records = parse_zone_str(ZONE, "example.test").unwrap();
+        let reversed: Vec<ResourceRecord> = records.iter().rev().cloned().collect();
+        assert_eq!(
+            write_zone("example.test", &records),
+            write_zone("example.test", &reversed)
+        );
+    }
+
+    #[test]
+    fn orders_rdata_canonically() {
+        let text = "\
+@ 60 MX 10 b.example.test.
+@ 60 MX 10 A.example.test.
+@ 60 MX 10 a.example.test.
+@ 60 SOA ns hostmaster 1 2 3 4 5
+";
+        let records = parse_zone_str(text, "example.test").unwrap();
+        let reversed: Vec<ResourceRecord> = records.iter().rev().cloned().collect();
+        let written = write_zone("example.test", &records);
+        // Upper case sorts first byte for byte, but names in RDATA compare
+        // in lower case, and a name differing only in case is a duplicate.
+        assert_eq!(
+            written,
+            "\
+$ORIGIN example.test.
+$TTL 60
+@ IN SOA ns hostmaster 1 2 3 4 5
+@ IN MX 10 A
+@ IN MX 10 b
+"
+        );
+        assert_eq!(write_zone("example.test", &reversed), written);
+    }
+}